@@ -0,0 +1,117 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Benchmarks of reading and writing files, replaying commits, merging and
+//! diffing states, over workloads of varying element count and size (see
+//! `pippin::testutil::Workload`).
+//! 
+//! Run with `cargo bench`; pass a filter to run a subset, e.g.
+//! `cargo bench -- snapshot`.
+
+#[macro_use]
+extern crate criterion;
+extern crate pippin;
+
+use criterion::Criterion;
+
+use pippin::commit::Commit;
+use pippin::format::{ReadLimits, LATEST_VERSION, read_snapshot, write_snapshot,
+        read_log, start_log, write_commit};
+use pippin::merge::{AncestorSolver2W, TwoWayMerge, TwoWaySolveUseA, TwoWaySolverChain};
+use pippin::state::PartState;
+use pippin::testutil::{Workload, FixedMeta};
+
+// Element counts and lengths (bytes) of state workloads
+const STATE_MATRIX: [(usize, usize); 4] = [(100, 16), (100, 1024), (10_000, 16), (10_000, 1024)];
+// Number of commits and changes per commit of history workloads (on 1000
+// elements of 64 bytes)
+const HISTORY_MATRIX: [(usize, usize); 2] = [(100, 1), (100, 50)];
+
+fn snapshots(c: &mut Criterion) {
+    for &(num_elts, elt_len) in &STATE_MATRIX {
+        let state = Workload::new(num_elts, elt_len).state();
+        let mut buf = Vec::new();
+        write_snapshot(&state, &mut buf).unwrap();
+        let data = buf.clone();
+        
+        c.bench_function(&format!("snapshot write {}x{}", num_elts, elt_len), move |b| {
+            b.iter(|| {
+                buf.clear();
+                write_snapshot(&state, &mut buf).unwrap();
+            })
+        });
+        c.bench_function(&format!("snapshot read {}x{}", num_elts, elt_len), move |b| {
+            b.iter(|| {
+                read_snapshot::<Vec<u8>>(&mut &data[..], LATEST_VERSION,
+                        &ReadLimits::default()).unwrap()
+            })
+        });
+    }
+}
+
+fn commits(c: &mut Criterion) {
+    for &(num_commits, changes) in &HISTORY_MATRIX {
+        let (initial, history) = Workload::new(1000, 64).with_commits(num_commits, changes)
+                .history();
+        let commits: Vec<_> = history.into_iter().map(|(commit, _)| commit).collect();
+        let mut buf = Vec::new();
+        write_log(&commits, &mut buf);
+        let data = buf.clone();
+        
+        c.bench_function(&format!("commit write {}x{}", num_commits, changes), move |b| {
+            b.iter(|| {
+                buf.clear();
+                write_log(&commits, &mut buf);
+            })
+        });
+        c.bench_function(&format!("commit replay {}x{}", num_commits, changes), move |b| {
+            b.iter(|| {
+                let mut commits: Vec<Commit<Vec<u8>>> = Vec::new();
+                let mut r = &data[..];
+                read_log(&mut r, &mut commits, LATEST_VERSION, &ReadLimits::default()).unwrap();
+                let mut state = initial.clone_exact();
+                for commit in &commits {
+                    state = PartState::from_state_commit(&state, commit).unwrap();
+                }
+                state
+            })
+        });
+    }
+}
+
+fn write_log(commits: &[Commit<Vec<u8>>], buf: &mut Vec<u8>) {
+    start_log(buf).unwrap();
+    for commit in commits {
+        write_commit(commit, buf).unwrap();
+    }
+}
+
+fn merges(c: &mut Criterion) {
+    for &(num_elts, elt_len) in &STATE_MATRIX {
+        let (common, a, b) = Workload::new(num_elts, elt_len).with_commits(1, num_elts / 10)
+                .fork();
+        c.bench_function(&format!("merge {}x{}", num_elts, elt_len), move |bench| {
+            // Changes to one side are taken; where both sides changed, A wins
+            let (s1, s2) = (AncestorSolver2W::new(), TwoWaySolveUseA::new());
+            let solver = TwoWaySolverChain::new(&s1, &s2);
+            bench.iter(|| {
+                let merge = TwoWayMerge::new(&a, &b, &common).solve_inline(&solver);
+                merge.make_commit(&FixedMeta).unwrap()
+            })
+        });
+    }
+}
+
+fn diffs(c: &mut Criterion) {
+    for &(num_elts, elt_len) in &STATE_MATRIX {
+        let (common, a, _) = Workload::new(num_elts, elt_len).with_commits(1, num_elts / 10)
+                .fork();
+        c.bench_function(&format!("state diff {}x{}", num_elts, elt_len), move |b| {
+            b.iter(|| Commit::from_diff(&common, &a).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, snapshots, commits, merges, diffs);
+criterion_main!(benches);
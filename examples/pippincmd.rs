@@ -52,6 +52,9 @@ Options:
   
   -c --commit COMMIT    Select commit COMMIT. If not specified, most operations
                         on commits will use the head (i.e. the latest state).
+                        COMMIT may be a (partial) state-sum, 'tip' or '#N'
+                        (commit number N), optionally followed by '~N' to
+                        select the N-th ancestor.
   -E --elements         List all elements
   -g --get ELT          Read the contents of an element to standard output.
   -e --edit ELT         Write an element to a temporary file and invoke the
@@ -197,7 +200,12 @@ fn inner(path: PathBuf, op: Operation, args: Rest) -> Result<()>
             println!("Reading header from: {}", path.display());
             let head = read_head(&mut fs::File::open(path)?)?;
             println!("{} file, version: {}",
-                match head.ftype { FileType::Snapshot(_) => "Snapshot", FileType::CommitLog(_) => "Commit log" },
+                match head.ftype {
+                    FileType::Snapshot(_) => "Snapshot",
+                    FileType::CommitLog(_) => "Commit log",
+                    FileType::Delta(_) => "Delta",
+                    FileType::AdminLog(_) => "Admin log",
+                },
                 head.ftype.ver());
             println!("Repository name: {}", head.name);
             
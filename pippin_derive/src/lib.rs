@@ -0,0 +1,93 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Derive macro for Pippin's `Element` trait. Use via the `derive` feature of
+//! `pippin`, which re-exports `PippinElement`.
+//! 
+//! `#[derive(PippinElement)]` applies to structs whose fields all implement
+//! `Element`. Each field is written in order, prefixed by its length (see
+//! `pippin::elt::write_value`); sums are calculated from this serialisation
+//! as usual. Changing the fields of a type thus changes its serialisation:
+//! data written before cannot be read afterwards.
+//! 
+//! Generated code names all items by absolute paths, thus is unaffected by
+//! local items shadowing `std` or the prelude.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{Data, DeriveInput, Fields, GenericParam, Index, parse_macro_input, parse_quote};
+
+/// Implement `pippin::elt::Element` for a struct (see crate documentation)
+#[proc_macro_derive(PippinElement)]
+pub fn derive_element(input: TokenStream) -> TokenStream {
+    let mut ast = parse_macro_input!(input as DeriveInput);
+    let name = ast.ident.clone();
+    
+    let fields = match ast.data {
+        Data::Struct(ref data) => data.fields.clone(),
+        _ => {
+            return syn::Error::new(Span::call_site(),
+                    "PippinElement can only be derived for structs")
+                    .to_compile_error().into();
+        }
+    };
+    
+    // Each type parameter must itself be an element
+    for param in &mut ast.generics.params {
+        if let GenericParam::Type(ref mut param) = *param {
+            param.bounds.push(parse_quote!(::pippin::elt::Element));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    
+    let (write, read) = match fields {
+        Fields::Named(ref fields) => {
+            let names: Vec<_> = fields.named.iter()
+                    .map(|f| f.ident.clone().expect("named field")).collect();
+            let write = quote! {
+                #(::pippin::elt::write_value(writer, &self.#names)?;)*
+            };
+            let read = quote! {
+                #name { #(#names: ::pippin::elt::read_value(&mut r)?,)* }
+            };
+            (write, read)
+        },
+        Fields::Unnamed(ref fields) => {
+            let indices: Vec<_> = (0..fields.unnamed.len()).map(Index::from).collect();
+            let write = quote! {
+                #(::pippin::elt::write_value(writer, &self.#indices)?;)*
+            };
+            let reads = indices.iter().map(|_| quote! { ::pippin::elt::read_value(&mut r)? });
+            let read = quote! { #name ( #(#reads,)* ) };
+            (write, read)
+        },
+        Fields::Unit => (quote! {}, quote! { #name }),
+    };
+    
+    // Note: `dyn (...)` since Rust 2015 would parse `dyn ::std` as a path
+    let expanded = quote! {
+        impl #impl_generics ::pippin::elt::Element for #name #ty_generics #where_clause {
+            fn write_buf(&self, writer: &mut dyn (::std::io::Write)) ->
+                    ::pippin::error::Result<()>
+            {
+                #write
+                ::std::result::Result::Ok(())
+            }
+            fn read_buf(buf: &[u8]) -> ::pippin::error::Result<Self> {
+                #[allow(unused_mut)]
+                let mut r: &[u8] = buf;
+                let elt = #read;
+                ::pippin::elt::check_consumed(r)?;
+                ::std::result::Result::Ok(elt)
+            }
+        }
+    };
+    expanded.into()
+}
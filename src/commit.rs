@@ -4,19 +4,22 @@
 
 //! Pippin: commit structs and functionality
 
-use std::collections::{HashMap, hash_map};
+use std::collections::{BTreeMap, HashMap, btree_map, hash_map};
 use std::clone::Clone;
 use std::rc::Rc;
 use std::u32;
-use std::cmp::max;
+use std::cmp::{max, Ordering};
 use std::ops::BitOr;
 
+use byteorder::{BigEndian, ByteOrder};
+
+#[cfg(feature = "clock")]
 use chrono::{DateTime, NaiveDateTime, UTC};
 
-use state::{PartState, MutPartState, StateWrite};
+use state::{PartState, MutPartState, StateRead, StateWrite};
 use elt::{Element, EltId};
 use sum::Sum;
-use error::{Result, ElementOp, OtherError};
+use error::{Result, ElementOp, PatchOp, OtherError};
 
 
 /// User-specified extra commit metadata. This allows users to tag commits with extra information
@@ -36,8 +39,11 @@ pub enum UserMeta {
 // const FLAG_RECLASSIFY_BIT: u16 = 0b10;
 // const FLAG_RECLASSIFY_MASK: u16 = 0b11;
 
+// vector clock (extension 2): active, not essential; see `VectorClock`
+const FLAG_CLOCK: u16 = 0b1000;
+
 const FLAG_ESSENTIAL: u16 = 0b01010101_01010101;
-const FLAG_UNKNOWN: u16 = 0b11111111_11111100;
+const FLAG_UNKNOWN: u16 = 0b11111111_11110000;
 
 /// Abstraction around metadata flags.
 // TODO: should this be `Eq`? What does equality mean on unknown flags anyway?
@@ -65,6 +71,126 @@ impl MetaFlags {
     pub fn zero() -> MetaFlags {
         MetaFlags { flags: 0 }
     }
+    
+    /// True if the vector clock extension is active (see `VectorClock`)
+    pub fn has_clock(self) -> bool {
+        (self.flags & FLAG_CLOCK) != 0
+    }
+    // Set or clear the vector clock flag
+    fn with_clock(self, clock: bool) -> MetaFlags {
+        let flags = if clock { self.flags | FLAG_CLOCK } else { self.flags & !FLAG_CLOCK };
+        MetaFlags { flags: flags }
+    }
+}
+
+/// Per-device logical counters (a "vector clock"), allowing commits made on
+/// different devices to be ordered regardless of (possibly skewed)
+/// timestamps.
+/// 
+/// A commit's clock is the maximum of its parents' clocks, with the counter
+/// of the device making the commit incremented (see
+/// `MakeCommitMeta::device_id`; without a device identifier the clock is
+/// inherited unchanged). Commit X happened before commit Y if X's clock is
+/// less than Y's; if neither clock is less (and they differ), the commits
+/// were made concurrently. Comparison is available via `PartialOrd`.
+/// 
+/// Clocks are stored as extension data of commit metadata (at most 255
+/// devices) and are not included in state sums.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct VectorClock {
+    counters: BTreeMap<u32, u32>,
+}
+impl VectorClock {
+    /// Create an empty clock
+    pub fn new() -> VectorClock {
+        VectorClock::default()
+    }
+    
+    /// Get the counter of a device (zero if not present)
+    pub fn get(&self, device: u32) -> u32 {
+        self.counters.get(&device).cloned().unwrap_or(0)
+    }
+    
+    /// Number of devices with a counter
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+    
+    /// True if no device has a counter
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+    
+    /// Iterate over pairs `(device, counter)`, ordered by device
+    pub fn iter(&self) -> btree_map::Iter<u32, u32> {
+        self.counters.iter()
+    }
+    
+    /// Increment the counter of a device
+    pub fn increment(&mut self, device: u32) {
+        let counter = self.counters.entry(device).or_insert(0);
+        if *counter < u32::MAX {
+            *counter += 1;
+        }
+    }
+    
+    /// Update to the maximum of each counter in this and `other`
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (&device, &counter) in &other.counters {
+            let value = self.counters.entry(device).or_insert(0);
+            *value = max(*value, counter);
+        }
+    }
+    
+    /// True if this clock is less than `other`: the commit with this clock
+    /// happened before that with `other`.
+    pub fn happens_before(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+    
+    // Read from commit meta extension data: pairs of u32 (device, counter)
+    fn from_ext_data(data: &[u8]) -> Result<VectorClock, OtherError> {
+        if data.len() % 8 != 0 {
+            return Err(OtherError::new("vector clock data has invalid length"));
+        }
+        let mut clock = VectorClock::new();
+        for pair in data.chunks(8) {
+            let counter = BigEndian::read_u32(&pair[4..8]);
+            if counter > 0 {    // zero is padding
+                clock.counters.insert(BigEndian::read_u32(&pair[0..4]), counter);
+            }
+        }
+        Ok(clock)
+    }
+    
+    /// Encode as commit meta extension data (8 bytes per device, padded
+    /// with zeros to a multiple of 16 bytes)
+    pub(crate) fn ext_data(&self) -> Vec<u8> {
+        let mut data = vec![0; 16 * ((self.counters.len() + 1) / 2)];
+        for (i, (&device, &counter)) in self.counters.iter().enumerate() {
+            BigEndian::write_u32(&mut data[8*i .. 8*i+4], device);
+            BigEndian::write_u32(&mut data[8*i+4 .. 8*i+8], counter);
+        }
+        data
+    }
+}
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &VectorClock) -> Option<Ordering> {
+        let (mut less, mut greater) = (false, false);
+        for &device in self.counters.keys().chain(other.counters.keys()) {
+            match self.get(device).cmp(&other.get(device)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {},
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
 }
 
 impl BitOr<MetaFlags> for MetaFlags {
@@ -100,6 +226,8 @@ pub struct CommitMeta {
     ext_flags: MetaFlags,
     /// User-provided extra metadata
     extra: UserMeta,
+    /// Per-device counters (extension data; see `VectorClock`)
+    clock: VectorClock,
 }
 
 /// Partial version of metadata (used by some functions on `CommitMeta`).
@@ -115,22 +243,37 @@ impl CommitMeta {
     pub fn new_parents(parents: Vec<(&Sum, &CommitMeta)>, mcm: &MakeCommitMeta) -> Self {
         let number = parents.iter().fold(0, |prev, &p| max(prev, p.1.next_number()));
         let ext_flags = parents.iter().fold(MetaFlags::zero(), |prev, &p| prev | p.1.ext_flags());
+        let mut clock = VectorClock::new();
+        for &(_, meta) in &parents {
+            clock.merge(meta.clock());
+        }
+        if let Some(device) = mcm.device_id() {
+            clock.increment(device);
+        }
         CommitMeta {
             number: number,
             timestamp: mcm.make_commit_timestamp(),
-            ext_flags: ext_flags,
+            ext_flags: ext_flags.with_clock(!clock.is_empty()),
             extra: mcm.make_commit_extra(number, parents),
+            clock: clock,
         }
     }
-    /// Create, explicitly providing all fields.
+    /// Create, explicitly providing all fields. `ext_data` is the extension
+    /// data read with `ext_flags` (currently only a vector clock is stored
+    /// there; see `VectorClock`).
     pub fn new_explicit(number: u32, timestamp: i64, ext_flags: MetaFlags,
-            _ext_data: Vec<u8>, extra: UserMeta) -> Result<Self, OtherError>
+            ext_data: Vec<u8>, extra: UserMeta) -> Result<Self, OtherError>
     {
         if (ext_flags.unknown_essential()) {
             return Err(OtherError::new("found essential unknown commit meta flag"));
         }
-        // ignore ext_data because we can and don't currently store anything there
-        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra })
+        let clock = if ext_flags.has_clock() {
+            VectorClock::from_ext_data(&ext_data)?
+        } else {
+            VectorClock::new()
+        };
+        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra,
+                clock: clock })
     }
     /// Create a partial new version from a single parent.
     /// 
@@ -148,18 +291,25 @@ impl CommitMeta {
     pub fn from_partial(partial: CommitMetaPartial, mcm: &MakeCommitMeta) -> CommitMeta {
         let number = partial.parent.1.next_number();
         let parent = (&partial.parent.0, &partial.parent.1);
+        let mut clock = partial.parent.1.clock().clone();
+        if let Some(device) = mcm.device_id() {
+            clock.increment(device);
+        }
         
         CommitMeta {
             number: number,
             timestamp: mcm.make_commit_timestamp(),
-            ext_flags: partial.ext_flags,
+            ext_flags: partial.ext_flags.with_clock(!clock.is_empty()),
             extra: mcm.make_commit_extra(number, vec![parent]),
+            clock: clock,
         }
     }
     
     /// Utility method to create a timestamp representing this moment.
     /// 
-    /// Code is `UTC::now().timestamp()`, using `chrono::UTC`.
+    /// Code is `UTC::now().timestamp()`, using `chrono::UTC`. Requires the
+    /// `clock` feature.
+    #[cfg(feature = "clock")]
     pub fn timestamp_now() -> i64 {
         UTC::now().timestamp()
     }
@@ -168,7 +318,9 @@ impl CommitMeta {
     pub fn timestamp(&self) -> i64 {
         self.timestamp
     }
-    /// Convert the internal timestamp to a `chrono::DateTime`.
+    /// Convert the internal timestamp to a `chrono::DateTime`. Requires the
+    /// `clock` feature.
+    #[cfg(feature = "clock")]
     pub fn date_time(&self) -> DateTime<UTC> {
         DateTime::<UTC>::from_utc(
                 NaiveDateTime::from_timestamp(self.timestamp, 0),
@@ -200,6 +352,19 @@ impl CommitMeta {
     pub fn extra(&self) -> &UserMeta {
         &self.extra
     }
+    
+    /// Get the commit's vector clock (empty unless device identifiers are
+    /// used; see `VectorClock`)
+    pub fn clock(&self) -> &VectorClock {
+        &self.clock
+    }
+    
+    /// True if this commit is known to have happened before `other`, by
+    /// comparison of vector clocks. False if the commits are concurrent or
+    /// either has no clock.
+    pub fn happens_before(&self, other: &CommitMeta) -> bool {
+        !self.clock.is_empty() && self.clock.happens_before(&other.clock)
+    }
 }
 
 impl CommitMetaPartial {
@@ -228,12 +393,12 @@ impl CommitMetaPartial {
 /// Interface used to customise commit metadata
 pub trait MakeCommitMeta {
     /// Controls creation of commit timestamps. The default implementation simply wraps
-    /// `CommitMeta::timestamp_now()`.
+    /// `CommitMeta::timestamp_now()`, or returns 0 without the `clock` feature.
     /// 
     /// The library itself does not depend on the value of these timestamps, it simply provides
     /// them as a convenience.
     fn make_commit_timestamp(&self) -> i64 {
-        CommitMeta::timestamp_now()
+        default_timestamp()
     }
     
     /// Make an extra-metadata item. The default implementation simply
@@ -245,9 +410,122 @@ pub trait MakeCommitMeta {
     fn make_commit_extra(&self, _number: u32, _parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
         UserMeta::None
     }
+    
+    /// Identify the source (e.g. device or user) of a commit from its
+    /// metadata, typically from a field of the extra metadata made by
+    /// `make_commit_extra`. Used to record the provenance of the parents of
+    /// merge commits (see `ProvenanceMeta`). The default implementation
+    /// returns `None` (unknown).
+    fn commit_source(&self, _meta: &CommitMeta) -> Option<String> {
+        None
+    }
+    
+    /// Identifier of this device (or writer), used to maintain the vector
+    /// clocks of new commits (see `VectorClock`). Each device writing to a
+    /// partition should use a distinct identifier. The default
+    /// implementation returns `None`, in which case clocks are inherited
+    /// without change.
+    fn device_id(&self) -> Option<u32> {
+        None
+    }
+}
+
+// Prefix of lines of extra metadata recording the source of a merge parent
+const PROVENANCE_PREFIX: &'static str = "merge-parent ";
+
+/// Wraps a `MakeCommitMeta`, recording the provenance of each parent in the
+/// extra metadata of merge commits, such that auditing can tell which side
+/// each value came from. `Partition::merge` uses this automatically.
+/// 
+/// For commits with multiple parents, a line `merge-parent SUM SOURCE` is
+/// appended to the text made by the wrapped `make_commit_extra` for each
+/// parent (in order) whose source is known (see
+/// `MakeCommitMeta::commit_source`). Other commits are not affected. Use
+/// `provenance` to read these back.
+pub struct ProvenanceMeta<'a> {
+    inner: &'a MakeCommitMeta,
+}
+impl<'a> ProvenanceMeta<'a> {
+    /// Create, wrapping `inner`
+    pub fn new(inner: &'a MakeCommitMeta) -> Self {
+        ProvenanceMeta { inner: inner }
+    }
+}
+impl<'a> MakeCommitMeta for ProvenanceMeta<'a> {
+    fn make_commit_timestamp(&self) -> i64 {
+        self.inner.make_commit_timestamp()
+    }
+    fn make_commit_extra(&self, number: u32, parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+        let extra = self.inner.make_commit_extra(number, parents.clone());
+        if parents.len() < 2 {
+            return extra;
+        }
+        let mut lines = vec![];
+        for (sum, meta) in parents {
+            if let Some(source) = self.inner.commit_source(meta) {
+                lines.push(format!("{}{} {}", PROVENANCE_PREFIX, sum.as_string(false), source));
+            }
+        }
+        if lines.is_empty() {
+            return extra;
+        }
+        match extra {
+            UserMeta::None => UserMeta::Text(lines.join("\n")),
+            UserMeta::Text(text) => UserMeta::Text(format!("{}\n{}", text, lines.join("\n"))),
+        }
+    }
+    fn commit_source(&self, meta: &CommitMeta) -> Option<String> {
+        self.inner.commit_source(meta)
+    }
+    fn device_id(&self) -> Option<u32> {
+        self.inner.device_id()
+    }
+}
+
+/// Get the provenance recorded in a merge commit's extra metadata by
+/// `ProvenanceMeta`: the sum and source of each parent with known source,
+/// in order. Returns an empty list if nothing was recorded.
+pub fn provenance(meta: &CommitMeta) -> Vec<(Sum, String)> {
+    let text = match *meta.extra() {
+        UserMeta::Text(ref text) => text,
+        UserMeta::None => return vec![],
+    };
+    text.lines().filter_map(|line| {
+        if !line.starts_with(PROVENANCE_PREFIX) {
+            return None;
+        }
+        let mut parts = line[PROVENANCE_PREFIX.len()..].splitn(2, ' ');
+        let sum = parts.next().and_then(Sum::from_hex);
+        match (sum, parts.next()) {
+            (Some(sum), Some(source)) => Some((sum, source.to_string())),
+            _ => None,
+        }
+    }).collect()
+}
+
+// Timestamp used by `MakeCommitMeta::make_commit_timestamp` by default
+#[cfg(feature = "clock")]
+pub(crate) fn default_timestamp() -> i64 {
+    CommitMeta::timestamp_now()
+}
+#[cfg(not(feature = "clock"))]
+pub(crate) fn default_timestamp() -> i64 {
+    0
 }
 
 
+/// Summary of the state a commit yields, as found by `Commit::apply_dry`
+/// without building the state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SumOnly {
+    /// Sum of the state (equal to the commit's statesum)
+    pub statesum: Sum,
+    /// Number of elements in the state
+    pub num_elts: usize,
+    /// Total length of element data, in bytes (see `PartState::byte_len`)
+    pub byte_len: usize,
+}
+
 /// A commit: a set of changes.
 /// 
 /// The number of parents is at least one; where more this is a merge commit.
@@ -322,6 +600,64 @@ impl<E: Element> Commit<E> {
     /// for most applications).
     pub fn from_diff(old_state: &PartState<E>, new_state: &PartState<E>)
             -> Option<Commit<E>>
+    {
+        let changes = Commit::diff_changes(old_state, new_state);
+        if changes.is_empty() {
+            None
+        } else {
+            Some(Commit {
+                statesum: new_state.statesum().clone(),
+                parents: vec![old_state.statesum().clone()],
+                changes: changes,
+                meta: new_state.meta().clone(),
+            })
+        }
+    }
+    
+    /// Create a commit from a parent state and a set of changes to apply to
+    /// it. The resulting statesum is calculated and metadata is generated via
+    /// `mcm`, as when creating a state with `PartState::from_mut()`.
+    /// 
+    /// This is intended for importers and replication, where the exact
+    /// changes are already known. Fails if any change is not applicable to
+    /// `parent`: an insertion where an element already exists, or a
+    /// replacement or deletion where none does. The set of changes may be
+    /// empty; such a commit only changes metadata.
+    pub fn from_changes(parent: &PartState<E>, changes: HashMap<EltId, EltChange<E>>,
+            mcm: &mut MakeCommitMeta) -> Result<Commit<E>, ElementOp>
+    {
+        let mut commit = Commit {
+            statesum: parent.statesum().clone(),
+            parents: vec![parent.statesum().clone()],
+            changes: changes,
+            meta: parent.meta().clone(),
+        };
+        let mut mut_state = parent.clone_mut();
+        commit.apply_mut(&mut mut_state)?;
+        let state = PartState::from_mut(mut_state, mcm);
+        commit.statesum = state.statesum().clone();
+        commit.meta = state.meta().clone();
+        Ok(commit)
+    }
+    
+    /// Recreate the commit yielding `state` from its first parent,
+    /// `first_parent`. Unlike `from_diff`, all of the state's parents are
+    /// recorded and the commit may have no changes (e.g. a merge).
+    /// 
+    /// This panics if `first_parent` is not the state's first parent.
+    pub fn from_state(first_parent: &PartState<E>, state: &PartState<E>) -> Commit<E> {
+        assert_eq!(state.parents().first(), Some(first_parent.statesum()));
+        Commit {
+            statesum: state.statesum().clone(),
+            parents: state.parents().to_vec(),
+            changes: Commit::diff_changes(first_parent, state),
+            meta: state.meta().clone(),
+        }
+    }
+    
+    // Find element changes between two states
+    fn diff_changes(old_state: &PartState<E>, new_state: &PartState<E>) ->
+            HashMap<EltId, EltChange<E>>
     {
         // #0019: is using `collect()` for a HashMap efficient? Better to add a "clone_map" function to new_state?
         let mut elt_map: HashMap<_,_> = new_state.elts_iter().collect();
@@ -342,17 +678,7 @@ impl<E: Element> Commit<E> {
         for (id, new_elt) in elt_map {
             changes.insert(id, EltChange::insertion(new_elt.clone()));
         }
-        
-        if changes.is_empty() {
-            None
-        } else {
-            Some(Commit {
-                statesum: new_state.statesum().clone(),
-                parents: vec![old_state.statesum().clone()],
-                changes: changes,
-                meta: new_state.meta().clone(),
-            })
-        }
+        changes
     }
     
     /// Apply this commit to a `MutPartState`. This does not verify the final
@@ -378,6 +704,45 @@ impl<E: Element> Commit<E> {
         Ok(())
     }
     
+    /// Check that this commit applies to `parent` and yields its statesum,
+    /// without building the new state: only sums of changed elements are
+    /// calculated and no elements are copied. This allows commits received
+    /// (e.g. in bundles) to be validated cheaply before they are accepted.
+    /// 
+    /// Fails as `PartState::from_state_commit` would.
+    pub fn apply_dry(&self, parent: &PartState<E>) -> Result<SumOnly, PatchOp> {
+        if parent.statesum() != self.first_parent() { return Err(PatchOp::WrongParent); }
+        let mut elt_sum = parent.statesum() ^ &parent.metasum();
+        let mut num_elts = parent.num_avail();
+        let mut byte_len = parent.byte_len();
+        for (id, change) in &self.changes {
+            let old = match *change {
+                EltChange::Insertion(_) => None,
+                _ => Some(parent.get_rc(*id)?),
+            };
+            let new = match *change {
+                EltChange::Deletion => None,
+                EltChange::Insertion(ref elt) | EltChange::Replacement(ref elt) => Some(elt),
+            };
+            if let Some(elt) = old {
+                elt_sum.permute(&elt.sum(*id));
+                byte_len -= elt.byte_len();
+                num_elts -= 1;
+            } else if parent.is_avail(*id) {
+                return Err(PatchOp::PatchApply);
+            }
+            if let Some(elt) = new {
+                elt_sum.permute(&elt.sum(*id));
+                byte_len += elt.byte_len();
+                num_elts += 1;
+            }
+        }
+        
+        let statesum = &elt_sum ^ &Sum::state_meta_sum(self.parents(), self.meta());
+        if statesum != self.statesum { return Err(PatchOp::PatchApply); }
+        Ok(SumOnly { statesum: statesum, num_elts: num_elts, byte_len: byte_len })
+    }
+    
     /// Mutate the metadata in order to yield a new `statesum()` while
     /// otherwise not changing the state.
     /// 
@@ -412,3 +777,99 @@ impl<E: Element> Commit<E> {
     /// Write acces to the commit's meta-data
     pub fn meta_mut(&mut self) -> &mut CommitMeta { &mut self.meta }
 }
+
+// —————  Deltas  —————
+
+/// The element-level differences between two states, typically two
+/// snapshots (see `rw::delta`). A delta can be transferred in place of the
+/// second state where the first is already available.
+/// 
+/// Unlike a commit, the base state need not be a parent of the target.
+/// Internally this holds a commit with the target's sum, parents and
+/// metadata, whose changes apply to the base.
+#[derive(PartialEq, Debug)]
+pub struct Delta<E: Element> {
+    base: Sum,
+    commit: Commit<E>,
+}
+
+impl<E: Element> Delta<E> {
+    /// Create from a base state and a target state. Returns `None` if the
+    /// target has no parents (i.e. is an initial state; these are not
+    /// supported, but have no elements anyway).
+    pub fn from_states(base: &PartState<E>, target: &PartState<E>) -> Option<Delta<E>> {
+        if target.parents().is_empty() {
+            return None;
+        }
+        Some(Delta {
+            base: base.statesum().clone(),
+            commit: Commit::new_explicit(target.statesum().clone(), target.parents().to_vec(),
+                    Commit::diff_changes(base, target), target.meta().clone()),
+        })
+    }
+    
+    /// Create from parts: the base state's sum and a commit holding the
+    /// target's sum, parents and metadata with changes applicable to the
+    /// base. It is suggested not to use this unless you are sure all sums are
+    /// correct.
+    pub fn new_explicit(base: Sum, commit: Commit<E>) -> Delta<E> {
+        Delta { base: base, commit: commit }
+    }
+    
+    /// Get the base state's sum
+    pub fn base(&self) -> &Sum { &self.base }
+    /// Get the target state's sum
+    pub fn statesum(&self) -> &Sum { self.commit.statesum() }
+    /// Get the commit, holding the changes from the base and the target's
+    /// parents and metadata
+    pub fn commit(&self) -> &Commit<E> { &self.commit }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    #[test]
+    fn commit_from_changes() {
+        let state = PartState::<String>::new(&mut MCM);
+        let id = EltId::from(3);
+        let mut changes = HashMap::new();
+        changes.insert(id, EltChange::insertion(Rc::new("three".to_string())));
+        let commit = Commit::from_changes(&state, changes, &mut MCM).expect("from_changes");
+        let state = PartState::from_state_commit(&state, &commit).expect("apply");
+        assert_eq!(state.get(id), Ok(&"three".to_string()));
+        
+        let mut changes = HashMap::new();
+        changes.insert(id, EltChange::insertion(Rc::new("again".to_string())));
+        assert_eq!(Commit::from_changes(&state, changes, &mut MCM).unwrap_err(), ElementOp::IdClash);
+        let mut changes = HashMap::new();
+        changes.insert(EltId::from(4), EltChange::deletion());
+        assert_eq!(Commit::from_changes(&state, changes, &mut MCM).unwrap_err(), ElementOp::EltNotFound);
+    }
+    
+    #[test]
+    fn commit_apply_dry() {
+        let mut state = PartState::new(&mut MCM).clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        state.insert(EltId::from(2), "two".to_string()).unwrap();
+        let state_a = PartState::from_mut(state, &mut MCM);
+        
+        let mut state = state_a.clone_mut();
+        state.insert(EltId::from(3), "three".to_string()).unwrap();
+        state.remove(EltId::from(1)).unwrap();
+        state.replace(EltId::from(2), "deux".to_string()).unwrap();
+        let state_b = PartState::from_mut(state, &mut MCM);
+        let commit = Commit::from_diff(&state_a, &state_b).unwrap();
+        
+        assert_eq!(commit.apply_dry(&state_a), Ok(SumOnly {
+            statesum: state_b.statesum().clone(),
+            num_elts: 2,
+            byte_len: state_b.byte_len(),
+        }));
+        assert_eq!(commit.apply_dry(&state_b), Err(PatchOp::WrongParent));
+    }
+}
@@ -4,14 +4,23 @@
 
 //! Pippin: control traits
 
-use std::usize;
+use std::{u64, usize};
 use std::marker::PhantomData;
+use std::rc::Rc;
+use std::str;
+#[cfg(feature = "clock")]
+use std::time::{Duration, Instant};
 
-use commit::MakeCommitMeta;
-use elt::Element;
-use error::Result;
+use commit::{Commit, MakeCommitMeta, default_timestamp};
+use elt::{Element, EltId};
+use error::{Result, OtherError};
 use io::RepoIO;
-use rw::header::{UserData, FileHeader};
+use lock::Lease;
+use rw::{ReadLimits, DEFAULT_ALIGN};
+use rw::codec::Codec;
+use rw::header::{FileType, UserData, FileHeader};
+use state::{PartState, Quota};
+use sum::Sum;
 
 
 /// Allows the user to control various repository operations. Library-provided implementations
@@ -72,6 +81,196 @@ pub trait Control: MakeCommitMeta {
     fn read_header(&mut self, _header: &FileHeader) -> Result<()> {
         Ok(())
     }
+    
+    /// Limits on lengths read from files, checked while reading snapshots
+    /// and commit logs. Files exceeding these are rejected.
+    /// 
+    /// The default implementation returns `ReadLimits::default()`.
+    fn read_limits(&self) -> ReadLimits {
+        ReadLimits::default()
+    }
+    
+    /// If true, new snapshots and commit logs are written in format version
+    /// `CRC_VERSION`, which includes a CRC32 checksum of each snapshot and
+    /// commit, allowing quick verification when reading (see
+    /// `ReadLimits::quick_verify`). Files in this format cannot be read by
+    /// library versions older than this one.
+    /// 
+    /// The default implementation returns false.
+    fn write_crc(&self) -> bool {
+        false
+    }
+    
+    /// The codec used to encode element data in files of type `ftype` (e.g.
+    /// to compress or encrypt it; see `rw::codec`). Files are written with
+    /// this codec, which is named in their headers; when reading, a file
+    /// naming a codec can only be read if this returns a codec of that name.
+    /// Files naming no codec are always readable.
+    /// 
+    /// The default implementation returns `None` (data is not encoded).
+    fn codec(&self, _ftype: &FileType) -> Option<Rc<Codec>> {
+        None
+    }
+    
+    /// The alignment of element data written to files of type `ftype`, in
+    /// bytes (one of 1, 2, 4, 8 and 16). Data is padded to a multiple of
+    /// this; a smaller value saves space when elements are small, but
+    /// (unless 16) is named in file headers, thus the files cannot be read by
+    /// older versions of this library. Files of any alignment can be read.
+    /// 
+    /// The default implementation returns `rw::DEFAULT_ALIGN` (16).
+    fn element_align(&self, _ftype: &FileType) -> usize {
+        DEFAULT_ALIGN
+    }
+    
+    /// Limits on the size of each commit log written. When more unsaved
+    /// commits are pending than fit one log, `write_fast()` writes several
+    /// logs (with consecutive numbers).
+    /// 
+    /// The default implementation returns `LogLimits::default()` (no
+    /// limits).
+    fn log_limits(&self) -> LogLimits {
+        LogLimits::default()
+    }
+    
+    /// Hard limits on the number of elements and the length of element data
+    /// of each new state (see `Quota`). `Partition::push_commit` and
+    /// `Partition::push_state` (thus also transactions and merges) fail with
+    /// `PatchOp::QuotaExceeded` for states over quota, and
+    /// `Partition::transaction` checks each operation.
+    /// 
+    /// The default implementation returns `Quota::default()` (no limits).
+    fn quota(&self) -> Quota {
+        Quota::default()
+    }
+    
+    /// "Paranoid" mode, for high-assurance deployments: if true, the
+    /// partition calls `PartState::set_verify_on_get` on each state it holds,
+    /// such that `get` re-checks an element's sum on every access. This
+    /// detects in-memory corruption (or elements mutated via interior
+    /// mutability) before it reaches storage, at the cost of serialising
+    /// each element accessed and keeping a sum per element.
+    /// 
+    /// The default implementation returns false.
+    fn verify_on_get(&self) -> bool {
+        false
+    }
+    
+    /// Memory budget (bytes of element data) for states read from disk on
+    /// demand by `Partition::fetch_state`, i.e. evicted states and those
+    /// older than the snapshots loaded. When over budget, the least recently
+    /// used states are dropped, though the last fetched is always kept.
+    /// 
+    /// The default implementation returns zero (keep only the last fetched).
+    fn state_cache_bytes(&self) -> usize {
+        0
+    }
+    
+    /// Filter applied to elements by `Partition::view_state` and
+    /// `Partition::export_view`, allowing elements to be hidden or redacted,
+    /// e.g. where users with different permissions share a partition.
+    /// Return the element to show it unchanged, a replacement to redact it, or
+    /// `None` to hide it. Stored data and its sums are not affected.
+    /// 
+    /// The default implementation shows all elements.
+    fn view_element(&self, _id: EltId, elt: &Rc<Self::Element>) -> Option<Rc<Self::Element>> {
+        Some(elt.clone())
+    }
+    
+    /// Validate a new commit before it is added by `Partition::push_commit`
+    /// or `Partition::push_state` (thus also transactions and merges). This
+    /// allows domain invariants (e.g. uniqueness constraints) to be enforced
+    /// centrally. `parent` is the commit's first parent and `state` the state
+    /// it yields. Commits loaded from storage are not checked.
+    /// 
+    /// Returning an error rejects the commit: the push fails with
+    /// `PatchOp::Rejected` (the error itself is logged).
+    /// 
+    /// The default implementation accepts all commits.
+    fn validate_commit(&mut self, _parent: &PartState<Self::Element>,
+            _commit: &Commit<Self::Element>, _state: &PartState<Self::Element>) -> Result<()>
+    {
+        Ok(())
+    }
+    
+    /// Called by `Partition::write_fast()` (and thus `write_full()`) before
+    /// writing `num_commits` unsaved commits to a new commit log. Together
+    /// with `post_write` and `abort_write` this allows coordination with an
+    /// external transaction (e.g. a database): prepare the transaction here,
+    /// commit it in `post_write` and roll it back in `abort_write`.
+    /// 
+    /// Returning an error aborts the write before anything is written
+    /// (`abort_write` is not called; commits remain unsaved).
+    /// 
+    /// The default implementation does nothing.
+    fn pre_write(&mut self, _num_commits: usize) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Called after a successful `pre_write` once the commit log has been
+    /// written and flushed.
+    /// 
+    /// The default implementation does nothing.
+    fn post_write(&mut self) {}
+    
+    /// Called after a successful `pre_write` when writing the commit log
+    /// failed. The error is subsequently returned by `write_fast()`. Note that
+    /// a partial log may have been written, and commits already written are
+    /// no longer considered unsaved.
+    /// 
+    /// The default implementation does nothing.
+    fn abort_write(&mut self) {}
+    
+    /// Notification that a commit has been written to a commit log (called
+    /// after the log has been written and flushed, once per commit, in the
+    /// order written). This may be used to trigger downstream processing,
+    /// e.g. search indexing or cache invalidation.
+    /// 
+    /// The default implementation does nothing.
+    fn on_commit(&mut self, _commit: &Commit<Self::Element>) {}
+    
+    /// Notification that snapshot `ss_num` has been written (called after the
+    /// snapshot has been written and flushed).
+    /// 
+    /// The default implementation does nothing.
+    fn on_snapshot(&mut self, _ss_num: usize) {}
+    
+    /// Notification that snapshot number `ss_num` was taken by another
+    /// writer while writing a snapshot. The other writer's snapshot, of state
+    /// `statesum`, has been added to the history (as a tip, unless known),
+    /// and the snapshot is written with a higher number.
+    /// 
+    /// The default implementation does nothing.
+    fn on_ss_collision(&mut self, _ss_num: usize, _statesum: &Sum) {}
+    
+    /// Notification that a new commit changes element `id`, which is locked
+    /// by another owner (see `lock::LockRegistry`). Locks are advisory: the
+    /// commit is not prevented.
+    /// 
+    /// The default implementation does nothing (the conflict is logged as a
+    /// warning regardless).
+    fn on_lock_conflict(&mut self, _id: EltId, _lease: &Lease) {}
+}
+
+/// Limits on the size of commit logs written (see `Control::log_limits()`).
+/// 
+/// A log always holds at least one commit, thus may exceed `max_bytes` if a
+/// single commit is larger than this.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LogLimits {
+    /// Maximum number of commits per log
+    pub max_commits: usize,
+    /// Maximum length of a log file in bytes (including header)
+    pub max_bytes: u64,
+}
+impl Default for LogLimits {
+    /// No limits
+    fn default() -> LogLimits {
+        LogLimits {
+            max_commits: usize::MAX,
+            max_bytes: u64::MAX,
+        }
+    }
 }
 
 /// An interface allowing configuration of snapshot policy.
@@ -89,29 +288,56 @@ pub trait SnapshotPolicy {
     /// Increment an internal counter/counters to record this many `commits` and `edits`.
     fn count(&mut self, commits: usize, edits: usize);
     
+    /// Record that this many bytes of commit log have been written or read
+    /// (since the last snapshot).
+    /// 
+    /// The default implementation does nothing.
+    fn count_bytes(&mut self, _bytes: u64) {}
+    
     /// Defines our snapshot policy: this should return true when a new snapshot is required.
     /// 
-    /// The number of commits and edits saved since the last snapshot 
-    /// The default implementation is
-    /// ```rust
-    /// commits * 5 + edits > 150
-    /// ```
+    /// For example, `DefaultSnapshot` returns true when
+    /// `commits * 5 + edits > 150`, where `commits` and `edits` are the
+    /// numbers counted since the last snapshot.
     fn want_snapshot(&self) -> bool;
 }
 
 /// A convenient implementation of `Control`.
 /// 
-/// Uses `DefaultSnapshot` snapshot policy.
+/// Uses `DefaultSnapshot` snapshot policy, unless configured otherwise via a
+/// `PartConfig`. A configuration passed to `with_config` is stored in the
+/// headers of snapshots written; when none is passed, the first
+/// configuration found when reading snapshots is applied (`Partition::open`
+/// reads the latest snapshot first).
 #[derive(Debug)]
 pub struct DefaultControl<E: Element, IO: RepoIO + 'static> {
     _elt_type: PhantomData<E>,
     io: IO,
-    ss_policy: DefaultSnapshot,
+    ss_policy: AnySnapshot,
+    config: Option<PartConfig>,
+    timestamp: Option<fn() -> i64>,
+    log_limits: LogLimits,
+    quota: Quota,
+    state_cache_bytes: usize,
+    device_id: Option<u32>,
+    verify_on_get: bool,
 }
 impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     /// Create, given I/O provider
     pub fn new(io: IO) -> Self {
-        DefaultControl { _elt_type: Default::default(), io: io, ss_policy: Default::default() }
+        DefaultControl { _elt_type: Default::default(), io: io,
+                ss_policy: AnySnapshot::Default(Default::default()), config: None,
+                timestamp: None, log_limits: LogLimits::default(), quota: Quota::default(),
+                state_cache_bytes: 0, device_id: None, verify_on_get: false }
+    }
+    
+    /// Create, given I/O provider and a configuration (which is applied and
+    /// written to snapshot headers)
+    pub fn with_config(io: IO, config: PartConfig) -> Self {
+        DefaultControl { _elt_type: Default::default(), io: io,
+                ss_policy: config.snapshot.make_policy(), config: Some(config),
+                timestamp: None, log_limits: LogLimits::default(), quota: Quota::default(),
+                state_cache_bytes: 0, device_id: None, verify_on_get: false }
     }
     
     /// Get direct access to the held `IO`
@@ -120,8 +346,38 @@ impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     pub fn io_mut(&mut self) -> &mut IO { &mut self.io }
     /// Unwrap the held `IO`
     pub fn unwrap_io(self) -> IO { self.io }
+    /// Get the configuration in use, if any
+    pub fn config(&self) -> Option<&PartConfig> { self.config.as_ref() }
+    /// Set the source of timestamps for new commits (by default the system
+    /// clock, or zero without the `clock` feature); e.g. to use a
+    /// JavaScript clock on WebAssembly.
+    pub fn set_timestamp_source(&mut self, source: fn() -> i64) { self.timestamp = Some(source); }
+    /// Set limits on the size of commit logs written (by default, none)
+    pub fn set_log_limits(&mut self, limits: LogLimits) { self.log_limits = limits; }
+    /// Set limits on the contents of new states (by default, none; see
+    /// `Control::quota()`)
+    pub fn set_quota(&mut self, quota: Quota) { self.quota = quota; }
+    /// Set whether element sums are checked on each access (see
+    /// `Control::verify_on_get()`)
+    pub fn set_verify_on_get(&mut self, verify: bool) { self.verify_on_get = verify; }
+    /// Set the memory budget for states fetched from disk (by default, zero;
+    /// see `Control::state_cache_bytes()`)
+    pub fn set_state_cache_bytes(&mut self, bytes: usize) { self.state_cache_bytes = bytes; }
+    /// Set the identifier of this device, such that new commits carry
+    /// vector clocks (by default, none; see `commit::VectorClock`)
+    pub fn set_device_id(&mut self, id: u32) { self.device_id = Some(id); }
+}
+impl<E: Element, IO: RepoIO> MakeCommitMeta for DefaultControl<E, IO> {
+    fn make_commit_timestamp(&self) -> i64 {
+        match self.timestamp {
+            Some(source) => source(),
+            None => default_timestamp(),
+        }
+    }
+    fn device_id(&self) -> Option<u32> {
+        self.device_id
+    }
 }
-impl<E: Element, IO: RepoIO> MakeCommitMeta for DefaultControl<E, IO> {}
 impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     type Element = E;
     fn io(&self) -> &RepoIO {
@@ -135,6 +391,199 @@ impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     }
     fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
     fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+    fn make_user_data(&mut self, header: &FileHeader) -> Result<Vec<UserData>> {
+        match (&header.ftype, &self.config) {
+            (&FileType::Snapshot(_), &Some(ref config)) => Ok(vec![config.to_user_data()]),
+            _ => Ok(vec![]),
+        }
+    }
+    fn read_header(&mut self, header: &FileHeader) -> Result<()> {
+        if self.config.is_none() {
+            for data in &header.user {
+                if let Some(config) = PartConfig::from_user_data(data)? {
+                    self.ss_policy = config.snapshot.make_policy();
+                    self.config = Some(config);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn read_limits(&self) -> ReadLimits {
+        self.config.as_ref().map_or_else(ReadLimits::default, |config| config.read_limits)
+    }
+    fn write_crc(&self) -> bool {
+        self.config.as_ref().map_or(false, |config| config.crc)
+    }
+    fn log_limits(&self) -> LogLimits {
+        self.log_limits
+    }
+    fn quota(&self) -> Quota {
+        self.quota
+    }
+    fn verify_on_get(&self) -> bool {
+        self.verify_on_get
+    }
+    fn state_cache_bytes(&self) -> usize {
+        self.state_cache_bytes
+    }
+}
+
+// Prefix identifying a `PartConfig` stored as user data
+const CONFIG_PREFIX: &'static [u8] = b"PIPPIN PARTCONFIG\n";
+
+/// Per-partition configuration, stored in snapshot headers (as user data)
+/// by `DefaultControl`, such that it is re-applied when the partition is
+/// opened elsewhere.
+/// 
+/// Can be constructed with `Default`, which gives default read limits and
+/// the `DefaultSnapshot` policy.
+/// 
+/// The checksum algorithm is not configurable (only BLAKE2b is supported)
+/// and files are not compressed.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PartConfig {
+    /// Limits applied when reading snapshots and commit logs
+    pub read_limits: ReadLimits,
+    /// Snapshot policy
+    pub snapshot: SnapshotConfig,
+    /// Write files with CRC32 checksums (see `Control::write_crc()`)
+    pub crc: bool,
+}
+
+/// Choice of snapshot policy, with parameters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnapshotConfig {
+    /// Use `DefaultSnapshot`
+    Default,
+    /// Use `TimedSnapshot` with this interval, in seconds
+    Timed(u64),
+    /// Use `SizeSnapshot` with this limit, in bytes
+    Size(u64),
+}
+impl Default for SnapshotConfig {
+    fn default() -> SnapshotConfig { SnapshotConfig::Default }
+}
+impl SnapshotConfig {
+    fn make_policy(&self) -> AnySnapshot {
+        match *self {
+            SnapshotConfig::Default => AnySnapshot::Default(Default::default()),
+            #[cfg(feature = "clock")]
+            SnapshotConfig::Timed(secs) =>
+                AnySnapshot::Timed(TimedSnapshot::new(Duration::from_secs(secs))),
+            #[cfg(not(feature = "clock"))]
+            SnapshotConfig::Timed(_) => {
+                warn!("timed snapshot policy requires the 'clock' feature; using default");
+                AnySnapshot::Default(Default::default())
+            },
+            SnapshotConfig::Size(bytes) => AnySnapshot::Size(SizeSnapshot::new(bytes)),
+        }
+    }
+}
+
+impl PartConfig {
+    /// Encode as a header user data field.
+    /// 
+    /// The encoding is `UserData::Data`, starting with a fixed prefix, then
+    /// one `key value` pair per line. Unknown keys are ignored when reading.
+    pub fn to_user_data(&self) -> UserData {
+        let limits = &self.read_limits;
+        let snapshot = match self.snapshot {
+            SnapshotConfig::Default => "default".to_string(),
+            SnapshotConfig::Timed(secs) => format!("timed {}", secs),
+            SnapshotConfig::Size(bytes) => format!("size {}", bytes),
+        };
+        let mut text = format!("max_elt_len {}\nmax_elts {}\nmax_changes {}\nmax_xm_len {}\n\
+                snapshot {}\n", limits.max_elt_len, limits.max_elts, limits.max_changes,
+                limits.max_xm_len, snapshot);
+        // Flags are only written when set, for compatibility:
+        if limits.quick_verify {
+            text.push_str("quick_verify 1\n");
+        }
+        if self.crc {
+            text.push_str("crc 1\n");
+        }
+        let mut data = CONFIG_PREFIX.to_vec();
+        data.extend_from_slice(text.as_bytes());
+        UserData::Data(data)
+    }
+    
+    /// Decode from a header user data field. Returns `Ok(None)` if the field
+    /// is not a configuration, or an error if it is but cannot be decoded.
+    pub fn from_user_data(data: &UserData) -> Result<Option<PartConfig>> {
+        let text = match *data {
+            UserData::Data(ref d) if d.starts_with(CONFIG_PREFIX) => &d[CONFIG_PREFIX.len()..],
+            _ => return Ok(None),
+        };
+        let text = match str::from_utf8(text) {
+            Ok(text) => text,
+            Err(_) => return OtherError::err("partition configuration is not valid UTF-8"),
+        };
+        let mut config = PartConfig::default();
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let num = |i: usize| words.get(i).and_then(|w| w.parse::<u64>().ok());
+            let ok = match (words.get(0).map(|w| *w), num(1)) {
+                (Some("max_elt_len"), Some(n)) => { config.read_limits.max_elt_len = n as usize; true },
+                (Some("max_elts"), Some(n)) => { config.read_limits.max_elts = n as usize; true },
+                (Some("max_changes"), Some(n)) => { config.read_limits.max_changes = n as usize; true },
+                (Some("max_xm_len"), Some(n)) => { config.read_limits.max_xm_len = n as usize; true },
+                (Some("quick_verify"), Some(n)) => { config.read_limits.quick_verify = n != 0; true },
+                (Some("crc"), Some(n)) => { config.crc = n != 0; true },
+                (Some("snapshot"), _) => {
+                    match (words.get(1).map(|w| *w), num(2)) {
+                        (Some("default"), None) => config.snapshot = SnapshotConfig::Default,
+                        (Some("timed"), Some(n)) => config.snapshot = SnapshotConfig::Timed(n),
+                        (Some("size"), Some(n)) => config.snapshot = SnapshotConfig::Size(n),
+                        _ => return OtherError::err("invalid snapshot policy in partition configuration"),
+                    }
+                    true
+                },
+                (Some("max_elt_len"), None) | (Some("max_elts"), None) |
+                (Some("max_changes"), None) | (Some("max_xm_len"), None) |
+                (Some("quick_verify"), None) | (Some("crc"), None) => false,
+                _ => true,  // ignore empty lines and unknown keys
+            };
+            if !ok {
+                return OtherError::err("invalid number in partition configuration");
+            }
+        }
+        Ok(Some(config))
+    }
+}
+
+// Any of the library's snapshot policies
+#[derive(Debug)]
+enum AnySnapshot {
+    Default(DefaultSnapshot),
+    #[cfg(feature = "clock")]
+    Timed(TimedSnapshot),
+    Size(SizeSnapshot),
+}
+impl AnySnapshot {
+    fn policy(&mut self) -> &mut SnapshotPolicy {
+        match *self {
+            AnySnapshot::Default(ref mut p) => p,
+            #[cfg(feature = "clock")]
+            AnySnapshot::Timed(ref mut p) => p,
+            AnySnapshot::Size(ref mut p) => p,
+        }
+    }
+    fn policy_ref(&self) -> &SnapshotPolicy {
+        match *self {
+            AnySnapshot::Default(ref p) => p,
+            #[cfg(feature = "clock")]
+            AnySnapshot::Timed(ref p) => p,
+            AnySnapshot::Size(ref p) => p,
+        }
+    }
+}
+impl SnapshotPolicy for AnySnapshot {
+    fn reset(&mut self) { self.policy().reset() }
+    fn force_snapshot(&mut self) { self.policy().force_snapshot() }
+    fn count(&mut self, commits: usize, edits: usize) { self.policy().count(commits, edits) }
+    fn count_bytes(&mut self, bytes: u64) { self.policy().count_bytes(bytes) }
+    fn want_snapshot(&self) -> bool { self.policy_ref().want_snapshot() }
 }
 
 /// Default snapshot policy: snapshot when `commits * 5 + edits > 150`.
@@ -162,3 +611,150 @@ impl SnapshotPolicy for DefaultSnapshot {
         self.counter > 150
     }
 }
+
+/// Time-based snapshot policy: snapshot when at least one commit has been
+/// made and the given interval has elapsed since the last snapshot (or since
+/// loading). Requires the `clock` feature.
+#[cfg(feature = "clock")]
+#[derive(Debug)]
+pub struct TimedSnapshot {
+    interval: Duration,
+    last: Instant,
+    commits: usize,
+    forced: bool,
+}
+
+#[cfg(feature = "clock")]
+impl TimedSnapshot {
+    /// Create, with the minimum interval between snapshots
+    pub fn new(interval: Duration) -> Self {
+        TimedSnapshot { interval: interval, last: Instant::now(), commits: 0, forced: false }
+    }
+}
+
+#[cfg(feature = "clock")]
+impl SnapshotPolicy for TimedSnapshot {
+    fn reset(&mut self) {
+        self.last = Instant::now();
+        self.commits = 0;
+        self.forced = false;
+    }
+    
+    fn force_snapshot(&mut self) {
+        self.forced = true;
+    }
+    
+    fn count(&mut self, commits: usize, _edits: usize) {
+        self.commits += commits;
+    }
+    
+    fn want_snapshot(&self) -> bool {
+        self.forced || (self.commits > 0 && self.last.elapsed() >= self.interval)
+    }
+}
+
+/// Size-based snapshot policy: snapshot when the size of commit logs written
+/// or read since the last snapshot reaches the given number of bytes.
+#[derive(Debug)]
+pub struct SizeSnapshot {
+    limit: u64,
+    bytes: u64,
+    forced: bool,
+}
+
+impl SizeSnapshot {
+    /// Create, with the number of bytes of logs after which a snapshot is
+    /// wanted
+    pub fn new(limit: u64) -> Self {
+        SizeSnapshot { limit: limit, bytes: 0, forced: false }
+    }
+}
+
+impl SnapshotPolicy for SizeSnapshot {
+    fn reset(&mut self) {
+        self.bytes = 0;
+        self.forced = false;
+    }
+    
+    fn force_snapshot(&mut self) {
+        self.forced = true;
+    }
+    
+    fn count(&mut self, _commits: usize, _edits: usize) {}
+    
+    fn count_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+    
+    fn want_snapshot(&self) -> bool {
+        self.forced || self.bytes >= self.limit
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem::MemRepoIO;
+    use part::Partition;
+    
+    #[cfg(feature = "clock")]
+    #[test]
+    fn timed_snapshot() {
+        use std::thread::sleep;
+        use std::time::Duration;
+        
+        let mut policy = TimedSnapshot::new(Duration::from_millis(20));
+        sleep(Duration::from_millis(30));
+        assert!(!policy.want_snapshot());
+        policy.count(1, 3);
+        assert!(policy.want_snapshot());
+        policy.reset();
+        policy.count(1, 3);
+        assert!(!policy.want_snapshot());
+        policy.force_snapshot();
+        assert!(policy.want_snapshot());
+    }
+    
+    #[test]
+    fn part_config() {
+        let mut config = PartConfig::default();
+        config.read_limits.max_elt_len = 1000;
+        config.snapshot = SnapshotConfig::Timed(3600);
+        let data = config.to_user_data();
+        assert_eq!(PartConfig::from_user_data(&data).expect("decode"), Some(config));
+        assert_eq!(PartConfig::from_user_data(&UserData::Text("x".to_string())).expect("decode"),
+                None);
+        let mut bad = CONFIG_PREFIX.to_vec();
+        bad.extend_from_slice(b"max_elts many\n");
+        assert!(PartConfig::from_user_data(&UserData::Data(bad)).is_err());
+    }
+    
+    #[test]
+    fn config_travels_with_data() {
+        let mut config = PartConfig::default();
+        config.snapshot = SnapshotConfig::Size(4096);
+        let control = DefaultControl::<String, _>::with_config(MemRepoIO::new(), config.clone());
+        let part = Partition::create(control, "config").expect("create");
+        
+        let control = DefaultControl::<String, _>::new(part.unwrap_control().unwrap_io());
+        assert_eq!(control.config(), None);
+        let part = Partition::open(control, true).expect("open");
+        let control = part.unwrap_control();
+        assert_eq!(control.config(), Some(&config));
+        assert_eq!(Control::read_limits(&control), config.read_limits);
+    }
+    
+    #[test]
+    fn size_snapshot() {
+        let mut policy = SizeSnapshot::new(1000);
+        policy.count(100, 1000);
+        assert!(!policy.want_snapshot());
+        policy.count_bytes(600);
+        assert!(!policy.want_snapshot());
+        policy.count_bytes(400);
+        assert!(policy.want_snapshot());
+        policy.reset();
+        assert!(!policy.want_snapshot());
+    }
+}
@@ -0,0 +1,354 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: conflict-free replicated element types (CRDTs).
+//! 
+//! This module is only available with the `crdt` feature. It provides
+//! element wrappers whose versions can always be merged: `GSet` (a
+//! grow-only set), `LwwRegister` (a last-writer-wins value) and `OrSet` (an
+//! observed-remove set, allowing removal). Each implements `Crdt`, and
+//! merges itself via `Element::merge`, thus concurrent changes are merged by
+//! `TwoWayMerge` before any solver is consulted. `CrdtSolver2W` additionally
+//! resolves the remaining case, where one side deleted the element, by
+//! keeping the other version.
+//! 
+//! Values are stored via their own `Element` implementation, thus any
+//! element type which is also `Ord + Clone` may be used (e.g. `String`).
+
+use std::collections::{BTreeMap, BTreeSet, btree_set};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use elt::{Element, write_value, read_value};
+use error::Result;
+use merge::{TwoWaySolver, EltMerge};
+
+/// A conflict-free replicated data type: any two versions can be joined
+/// into one including the changes of both.
+/// 
+/// `join` must be commutative, associative and idempotent, such that
+/// versions merged in any order on any device converge.
+pub trait Crdt: Element {
+    /// Join two versions
+    fn join(&self, other: &Self) -> Self;
+}
+
+/// A grow-only set: elements may be inserted but never removed. Joining
+/// takes the union.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct GSet<T: Element + Ord + Clone> {
+    items: BTreeSet<T>,
+}
+impl<T: Element + Ord + Clone> GSet<T> {
+    /// Create an empty set
+    pub fn new() -> Self {
+        GSet { items: BTreeSet::new() }
+    }
+    /// Insert a value. Returns false if already present.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.items.insert(value)
+    }
+    /// True if the value is present
+    pub fn contains(&self, value: &T) -> bool {
+        self.items.contains(value)
+    }
+    /// Number of values
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    /// True if empty
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    /// Iterate over values, in order
+    pub fn iter(&self) -> btree_set::Iter<T> {
+        self.items.iter()
+    }
+}
+impl<T: Element + Ord + Clone> Element for GSet<T> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.items.len() as u32)?;
+        for item in &self.items {
+            write_value(writer, item)?;
+        }
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        let mut r = buf;
+        let mut set = GSet::new();
+        for _ in 0..r.read_u32::<BigEndian>()? {
+            set.items.insert(read_value(&mut r)?);
+        }
+        Ok(set)
+    }
+    fn merge(_: Option<&Self>, a: &Self, b: &Self) -> Option<Self> {
+        Some(a.join(b))
+    }
+}
+impl<T: Element + Ord + Clone> Crdt for GSet<T> {
+    fn join(&self, other: &Self) -> Self {
+        GSet { items: self.items.union(&other.items).cloned().collect() }
+    }
+}
+
+/// A last-writer-wins register: a single value, tagged with the time it was
+/// assigned and the device assigning it. Joining takes the value with the
+/// later time; equal times are ordered by device identifier, then by value.
+/// 
+/// Devices should use distinct identifiers (e.g. as for
+/// `MakeCommitMeta::device_id`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LwwRegister<T: Element + Ord + Clone> {
+    timestamp: i64,
+    device: u32,
+    value: T,
+}
+impl<T: Element + Ord + Clone> LwwRegister<T> {
+    /// Create, with a value assigned at `timestamp` by `device`
+    pub fn new(value: T, timestamp: i64, device: u32) -> Self {
+        LwwRegister { timestamp: timestamp, device: device, value: value }
+    }
+    /// Get the value
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+    /// Get the time of assignment
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    /// Get the device which assigned the value
+    pub fn device(&self) -> u32 {
+        self.device
+    }
+}
+impl<T: Element + Ord + Clone> Element for LwwRegister<T> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_i64::<BigEndian>(self.timestamp)?;
+        writer.write_u32::<BigEndian>(self.device)?;
+        self.value.write_buf(writer)
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        let mut r = buf;
+        let timestamp = r.read_i64::<BigEndian>()?;
+        let device = r.read_u32::<BigEndian>()?;
+        Ok(LwwRegister { timestamp: timestamp, device: device, value: T::read_buf(r)? })
+    }
+    fn merge(_: Option<&Self>, a: &Self, b: &Self) -> Option<Self> {
+        Some(a.join(b))
+    }
+}
+impl<T: Element + Ord + Clone> Crdt for LwwRegister<T> {
+    fn join(&self, other: &Self) -> Self {
+        let key = |r: &Self| (r.timestamp, r.device);
+        if (key(self), &self.value) >= (key(other), &other.value) {
+            self.clone()
+        } else {
+            other.clone()
+        }
+    }
+}
+
+/// An observed-remove set: values may be inserted and removed. Each
+/// insertion is tagged uniquely; removal removes the tags observed, thus
+/// joining with a concurrent insertion of the same value keeps that value.
+/// 
+/// Tags of removed insertions are kept (without values) in order to merge
+/// correctly, thus the set grows with the number of removals.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct OrSet<T: Element + Ord + Clone> {
+    // Live insertions, by tag
+    adds: BTreeMap<u64, T>,
+    // Tags of removed insertions
+    removed: BTreeSet<u64>,
+}
+impl<T: Element + Ord + Clone> OrSet<T> {
+    /// Create an empty set
+    pub fn new() -> Self {
+        OrSet { adds: BTreeMap::new(), removed: BTreeSet::new() }
+    }
+    /// Insert a value (with a new random tag)
+    pub fn insert(&mut self, value: T) {
+        let mut tag = ::rand::random::<u64>();
+        while self.adds.contains_key(&tag) || self.removed.contains(&tag) {
+            tag = ::rand::random::<u64>();
+        }
+        self.adds.insert(tag, value);
+    }
+    /// Remove a value (all insertions of it observed). Returns false if not
+    /// present.
+    pub fn remove(&mut self, value: &T) -> bool {
+        let tags: Vec<u64> = self.adds.iter().filter(|&(_, v)| v == value)
+                .map(|(&tag, _)| tag).collect();
+        for tag in &tags {
+            self.adds.remove(tag);
+            self.removed.insert(*tag);
+        }
+        !tags.is_empty()
+    }
+    /// True if the value is present
+    pub fn contains(&self, value: &T) -> bool {
+        self.adds.values().any(|v| v == value)
+    }
+    /// Get the values present, in order and without repetition
+    pub fn values(&self) -> BTreeSet<&T> {
+        self.adds.values().collect()
+    }
+    /// Number of distinct values present
+    pub fn len(&self) -> usize {
+        self.values().len()
+    }
+    /// True if no value is present
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+}
+impl<T: Element + Ord + Clone> Element for OrSet<T> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.adds.len() as u32)?;
+        for (tag, value) in &self.adds {
+            writer.write_u64::<BigEndian>(*tag)?;
+            write_value(writer, value)?;
+        }
+        writer.write_u32::<BigEndian>(self.removed.len() as u32)?;
+        for tag in &self.removed {
+            writer.write_u64::<BigEndian>(*tag)?;
+        }
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        let mut r = buf;
+        let mut set = OrSet::new();
+        for _ in 0..r.read_u32::<BigEndian>()? {
+            let tag = r.read_u64::<BigEndian>()?;
+            set.adds.insert(tag, read_value(&mut r)?);
+        }
+        for _ in 0..r.read_u32::<BigEndian>()? {
+            set.removed.insert(r.read_u64::<BigEndian>()?);
+        }
+        Ok(set)
+    }
+    fn merge(_: Option<&Self>, a: &Self, b: &Self) -> Option<Self> {
+        Some(a.join(b))
+    }
+}
+impl<T: Element + Ord + Clone> Crdt for OrSet<T> {
+    fn join(&self, other: &Self) -> Self {
+        let removed: BTreeSet<u64> = self.removed.union(&other.removed).cloned().collect();
+        let adds = self.adds.iter().chain(other.adds.iter())
+                .filter(|&(tag, _)| !removed.contains(tag))
+                .map(|(&tag, value)| (tag, value.clone()))
+                .collect();
+        OrSet { adds: adds, removed: removed }
+    }
+}
+
+/// Solver for elements of CRDT types: versions on both sides are joined
+/// (usually done already via `Element::merge`); where one side deleted the
+/// element, the other version is kept.
+pub struct CrdtSolver2W<E: Crdt> {
+    p: PhantomData<E>
+}
+impl<E: Crdt> CrdtSolver2W<E> {
+    /// Create an instance (requires no parameters)
+    pub fn new() -> Self {
+        CrdtSolver2W { p: PhantomData }
+    }
+}
+impl<E: Crdt> TwoWaySolver<E> for CrdtSolver2W<E> {
+    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
+        _: Option<&Rc<E>>) -> EltMerge<E>
+    {
+        match (a, b) {
+            (Some(a), Some(b)) => EltMerge::Value(Rc::new(a.join(b))),
+            (Some(_), None) => EltMerge::A,
+            (None, Some(_)) => EltMerge::B,
+            (None, None) => EltMerge::Delete,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::*;
+    use merge::TwoWayMerge;
+    use state::{PartState, StateRead, StateWrite};
+    use commit::MakeCommitMeta;
+    
+    fn round_trip<E: Element>(elt: &E) -> E {
+        let mut buf = Vec::new();
+        elt.write_buf(&mut buf).expect("write_buf");
+        E::read_buf(&buf).expect("read_buf")
+    }
+    
+    #[test]
+    fn elements() {
+        let mut a = GSet::new();
+        a.insert("one".to_string());
+        let mut b = a.clone();
+        a.insert("two".to_string());
+        b.insert("three".to_string());
+        let ab = a.join(&b);
+        assert_eq!(ab.len(), 3);
+        assert_eq!(ab, b.join(&a));
+        assert_eq!(round_trip(&ab), ab);
+        
+        let x = LwwRegister::new("x".to_string(), 5, 1);
+        let y = LwwRegister::new("y".to_string(), 5, 2);
+        assert_eq!(x.join(&y).value(), "y");
+        assert_eq!(y.join(&x), x.join(&y));
+        assert_eq!(round_trip(&x), x);
+        
+        let mut a = OrSet::new();
+        a.insert("one".to_string());
+        a.insert("two".to_string());
+        let mut b = a.clone();
+        assert!(a.remove(&"one".to_string()));
+        b.insert("one".to_string());   // concurrent insertion survives
+        b.remove(&"two".to_string());
+        let ab = a.join(&b);
+        assert_eq!(ab, b.join(&a));
+        assert!(ab.contains(&"one".to_string()) && !ab.contains(&"two".to_string()));
+        assert_eq!(ab.len(), 1);
+        assert_eq!(round_trip(&ab), ab);
+        assert!(OrSet::<String>::read_buf(&[0, 0, 0, 1, 0]).is_err());
+    }
+    
+    #[test]
+    fn merge_states() {
+        struct MCM;
+        impl MakeCommitMeta for MCM {}
+        let mut state = PartState::new(&mut MCM).clone_mut();
+        let mut set = GSet::new();
+        set.insert("one".to_string());
+        let id = state.insert_new(set.clone()).expect("insert");
+        let (other_id, other) = (id.next_elt(), GSet::new());
+        state.insert(other_id, other).expect("insert");
+        let c = PartState::from_mut(state, &mut MCM);
+        
+        let mut state = c.clone_mut();
+        let mut set_a = set.clone();
+        set_a.insert("two".to_string());
+        state.replace(id, set_a).expect("replace");
+        state.remove(other_id).expect("remove");
+        let a = PartState::from_mut(state, &mut MCM);
+        let mut state = c.clone_mut();
+        set.insert("three".to_string());
+        state.replace(id, set).expect("replace");
+        let mut other = GSet::new();
+        other.insert("four".to_string());
+        state.replace(other_id, other.clone()).expect("replace");
+        let b = PartState::from_mut(state, &mut MCM);
+        
+        let merge = TwoWayMerge::new(&a, &b, &c).solve_inline(&CrdtSolver2W::new());
+        assert!(merge.is_solved());
+        let merged = merge.preview(&MCM).expect("preview");
+        assert_eq!(merged.get(id).expect("get").len(), 3);
+        assert_eq!(merged.get_rc(other_id).expect("get"), &Rc::new(other));
+    }
+}
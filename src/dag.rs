@@ -0,0 +1,193 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: ancestry of states, without element data.
+//! 
+//! A `StateDag` records each known state's parents, commit number and
+//! timestamp. This is small compared to states themselves, thus it can be
+//! kept for all history (`Partition` keeps one covering all states loaded,
+//! including those evicted) or built from metadata alone (see
+//! `Partition::load_meta`).
+
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use commit::CommitMeta;
+use sum::Sum;
+
+/// Ancestry information on a state (see `StateDag`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DagNode {
+    /// Parents of the state (the first is the state a commit's changes
+    /// apply to)
+    pub parents: Vec<Sum>,
+    /// Commit number (see `CommitMeta::number`)
+    pub number: u32,
+    /// Time of creation (see `CommitMeta::timestamp`)
+    pub timestamp: i64,
+}
+
+/// A directed acyclic graph of states, by state sum, with links to parents.
+/// 
+/// Parents need not be known themselves; such states are included in
+/// ancestry results but are assumed to have no ancestors.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateDag {
+    nodes: HashMap<Sum, DagNode>,
+}
+impl StateDag {
+    /// Create an empty graph
+    pub fn new() -> StateDag {
+        StateDag::default()
+    }
+    
+    /// Add a state (if not already known)
+    pub fn insert(&mut self, statesum: Sum, parents: Vec<Sum>, meta: &CommitMeta) {
+        self.nodes.entry(statesum).or_insert_with(|| DagNode {
+            parents: parents,
+            number: meta.number(),
+            timestamp: meta.timestamp(),
+        });
+    }
+    
+    /// Remove all states
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+    
+    /// Number of states known
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    
+    /// True if no state is known
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+    
+    /// True if the state is known
+    pub fn contains(&self, key: &Sum) -> bool {
+        self.nodes.contains_key(key)
+    }
+    
+    /// Get information on a state, if known
+    pub fn get(&self, key: &Sum) -> Option<&DagNode> {
+        self.nodes.get(key)
+    }
+    
+    /// Get the parents of a state, if known
+    pub fn parents(&self, key: &Sum) -> Option<&[Sum]> {
+        self.nodes.get(key).map(|node| &node.parents[..])
+    }
+    
+    /// Get the ancestors of a state (not including the state itself)
+    pub fn ancestors(&self, key: &Sum) -> HashSet<Sum> {
+        let mut ancestors = HashSet::new();
+        let mut next: Vec<&Sum> = self.parents(key).unwrap_or(&[]).iter().collect();
+        while let Some(key) = next.pop() {
+            if ancestors.insert(key.clone()) {
+                next.extend(self.parents(key).unwrap_or(&[]));
+            }
+        }
+        ancestors
+    }
+    
+    /// Get the states which are not the parent of another known state
+    pub fn tips(&self) -> HashSet<Sum> {
+        let mut tips: HashSet<Sum> = self.nodes.keys().cloned().collect();
+        for node in self.nodes.values() {
+            for parent in &node.parents {
+                tips.remove(parent);
+            }
+        }
+        tips
+    }
+    
+    /// True if `a` is `b` or an ancestor of `b`
+    pub fn is_ancestor(&self, a: &Sum, b: &Sum) -> bool {
+        self.distance(a, b).is_some()
+    }
+    
+    /// The least number of commits from `a` to `b` (following parents from
+    /// `b`): zero if the states are equal, `None` if `a` is not an ancestor
+    /// of `b`.
+    pub fn distance(&self, a: &Sum, b: &Sum) -> Option<usize> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((b, 0));
+        while let Some((key, dist)) = queue.pop_front() {
+            if key == a {
+                return Some(dist);
+            }
+            if seen.insert(key) {
+                for parent in self.parents(key).unwrap_or(&[]) {
+                    queue.push_back((parent, dist + 1));
+                }
+            }
+        }
+        None
+    }
+    
+    /// Find the latest common ancestor of two states: of the states which
+    /// are `a` or its ancestor and `b` or its ancestor, that with the
+    /// greatest commit number (states not known are considered last).
+    /// Returns `None` if there is no common ancestor.
+    pub fn latest_common_ancestor(&self, a: &Sum, b: &Sum) -> Option<Sum> {
+        let mut ancestors = self.ancestors(a);
+        ancestors.insert(a.clone());
+        
+        // Search from b, most recent first
+        let mut seen = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        queue.push((self.nodes.get(b).map(|node| node.number), b));
+        while let Some((_, key)) = queue.pop() {
+            if ancestors.contains(key) {
+                return Some(key.clone());
+            }
+            if seen.insert(key) {
+                for parent in self.parents(key).unwrap_or(&[]) {
+                    queue.push((self.nodes.get(parent).map(|node| node.number), parent));
+                }
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commit::{CommitMeta, MetaFlags, UserMeta};
+    use sum::Sum;
+    
+    #[test]
+    fn ancestry() {
+        // a ← b ← c ← e, a ← d ← e (merge), and f (unrelated; parent unknown)
+        let sum = |n: u8| Sum::load(&[n; 32]);
+        let meta = |n: u32| CommitMeta::new_explicit(n, 0, MetaFlags::zero(), vec![],
+                UserMeta::None).expect("meta");
+        let mut dag = StateDag::new();
+        dag.insert(sum(1), vec![], &meta(0));
+        dag.insert(sum(2), vec![sum(1)], &meta(1));
+        dag.insert(sum(3), vec![sum(2)], &meta(2));
+        dag.insert(sum(4), vec![sum(1)], &meta(1));
+        dag.insert(sum(5), vec![sum(3), sum(4)], &meta(3));
+        dag.insert(sum(6), vec![sum(9)], &meta(5));
+        
+        assert_eq!(dag.len(), 6);
+        assert_eq!(dag.tips(), vec![sum(5), sum(6)].into_iter().collect());
+        assert_eq!(dag.ancestors(&sum(5)).len(), 4);
+        assert!(dag.is_ancestor(&sum(1), &sum(5)));
+        assert!(dag.is_ancestor(&sum(5), &sum(5)));
+        assert!(!dag.is_ancestor(&sum(5), &sum(1)));
+        assert!(!dag.is_ancestor(&sum(4), &sum(3)));
+        assert_eq!(dag.distance(&sum(1), &sum(5)), Some(2));
+        assert_eq!(dag.distance(&sum(2), &sum(5)), Some(2));
+        assert_eq!(dag.distance(&sum(9), &sum(6)), Some(1));
+        assert_eq!(dag.latest_common_ancestor(&sum(3), &sum(4)), Some(sum(1)));
+        assert_eq!(dag.latest_common_ancestor(&sum(5), &sum(3)), Some(sum(3)));
+        assert_eq!(dag.latest_common_ancestor(&sum(2), &sum(5)), Some(sum(2)));
+        assert_eq!(dag.latest_common_ancestor(&sum(5), &sum(6)), None);
+    }
+}
@@ -0,0 +1,231 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Type-erased elements, for partitions holding several kinds of element.
+//! 
+//! A `Partition` is generic over a single element type. `DynElement` is an
+//! element type holding any kind of element as serialised bytes plus a type
+//! tag, thus one partition may store heterogeneous elements, and generic
+//! tools may load any such partition without knowing the types. A
+//! `DynRegistry` maps tags to element types, encoding typed elements and
+//! decoding them by tag.
+//! 
+//! ```
+//! use pippin::dynelt::{DynElement, DynRegistry};
+//! 
+//! let mut registry = DynRegistry::new();
+//! registry.register::<String>(1, "name").unwrap();
+//! registry.register::<u64>(2, "count").unwrap();
+//! 
+//! let elt = registry.encode(&42u64).unwrap();
+//! assert_eq!(elt.tag(), 2);
+//! assert_eq!(registry.name(elt.tag()), Some("count"));
+//! assert_eq!(registry.decode_as::<u64>(&elt).unwrap(), 42);
+//! assert!(registry.decode_as::<String>(&elt).is_err());
+//! assert_eq!(format!("{:?}", registry.decode(&elt).unwrap()), "42");
+//! ```
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::io::{Write, ErrorKind};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use elt::Element;
+use error::{Result, ArgError, OtherError, make_io_err};
+
+/// An element of any kind: serialised data (as written by the kind's
+/// `Element::write_buf`) and a tag identifying the kind.
+/// 
+/// This is serialised as the tag (`u32`, big-endian) followed by the data.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DynElement {
+    tag: u32,
+    data: Vec<u8>,
+}
+impl DynElement {
+    /// Create from a tag and serialised data
+    pub fn new(tag: u32, data: Vec<u8>) -> DynElement {
+        DynElement { tag: tag, data: data }
+    }
+    /// Create by serialising `elt`, tagged `tag`
+    pub fn from_elt<E: Element>(tag: u32, elt: &E) -> Result<DynElement> {
+        let mut data = Vec::new();
+        elt.write_buf(&mut data)?;
+        Ok(DynElement::new(tag, data))
+    }
+    /// Get the type tag
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+    /// Get the serialised data (excluding the tag)
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// Deserialise the data as type `E`, without checking the tag (see
+    /// `DynRegistry::decode_as`)
+    pub fn decode<E: Element>(&self) -> Result<E> {
+        E::read_buf(&self.data)
+    }
+}
+impl Element for DynElement {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.tag)?;
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Self::from_vec(buf.to_vec())
+    }
+    fn from_vec(mut vec: Vec<u8>) -> Result<Self> {
+        if vec.len() < 4 {
+            return make_io_err(ErrorKind::InvalidData, "dynamic element has no type tag");
+        }
+        let tag = BigEndian::read_u32(&vec[0..4]);
+        vec.drain(0..4);
+        Ok(DynElement::new(tag, vec))
+    }
+    fn byte_len(&self) -> usize {
+        4 + self.data.len()
+    }
+}
+
+/// A decoded element of some registered type (see `DynRegistry::decode`).
+/// Use `as_any` to downcast to the type.
+pub trait DynValue: Debug {
+    /// Get as `Any`, for downcasting
+    fn as_any(&self) -> &Any;
+}
+impl<E: Element> DynValue for E {
+    fn as_any(&self) -> &Any {
+        self
+    }
+}
+
+// Deserialises data of a registered type
+type Decoder = Box<Fn(&[u8]) -> Result<Box<DynValue>>>;
+
+/// Maps type tags of `DynElement`s to element types, with a name for each
+/// (for display by tools).
+#[derive(Default)]
+pub struct DynRegistry {
+    // Map of tag to (name, type, decoder)
+    types: HashMap<u32, (String, TypeId, Decoder)>,
+    // Map of type to tag
+    tags: HashMap<TypeId, u32>,
+}
+impl DynRegistry {
+    /// Create an empty registry
+    pub fn new() -> DynRegistry {
+        DynRegistry::default()
+    }
+    
+    /// Register element type `E` with `tag`. Fails if the tag or type is
+    /// already registered.
+    pub fn register<E: Element>(&mut self, tag: u32, name: &str) -> Result<()> {
+        let type_id = TypeId::of::<E>();
+        if self.types.contains_key(&tag) {
+            return ArgError::err(format!("type tag {} already registered", tag));
+        }
+        if self.tags.contains_key(&type_id) {
+            return ArgError::err(format!("element type already registered ({})", name));
+        }
+        let decoder: Decoder = Box::new(|buf| Ok(Box::new(E::read_buf(buf)?)));
+        self.types.insert(tag, (name.to_string(), type_id, decoder));
+        self.tags.insert(type_id, tag);
+        Ok(())
+    }
+    
+    /// Get the name registered with a tag, if any
+    pub fn name(&self, tag: u32) -> Option<&str> {
+        self.types.get(&tag).map(|&(ref name, _, _)| &name[..])
+    }
+    /// Get the tag registered for type `E`, if any
+    pub fn tag_of<E: Element>(&self) -> Option<u32> {
+        self.tags.get(&TypeId::of::<E>()).cloned()
+    }
+    
+    /// Serialise `elt` as a `DynElement` with the tag registered for its
+    /// type. Fails if the type is not registered.
+    pub fn encode<E: Element>(&self, elt: &E) -> Result<DynElement> {
+        match self.tag_of::<E>() {
+            Some(tag) => DynElement::from_elt(tag, elt),
+            None => ArgError::err("element type not registered"),
+        }
+    }
+    
+    /// Decode an element as whichever type is registered with its tag. Fails
+    /// if the tag is not registered or the data is invalid.
+    pub fn decode(&self, elt: &DynElement) -> Result<Box<DynValue>> {
+        match self.types.get(&elt.tag()) {
+            Some(&(_, _, ref decoder)) => decoder(elt.data()),
+            None => OtherError::err(format!("type tag {} not registered", elt.tag())),
+        }
+    }
+    
+    /// Decode an element as type `E`. Fails unless `E` is registered with
+    /// the element's tag.
+    pub fn decode_as<E: Element>(&self, elt: &DynElement) -> Result<E> {
+        if self.tag_of::<E>() != Some(elt.tag()) {
+            return ArgError::err(format!("element has type tag {}, not that of the type \
+                    requested", elt.tag()));
+        }
+        elt.decode()
+    }
+}
+impl Debug for DynRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut types: Vec<_> = self.types.iter()
+                .map(|(tag, &(ref name, _, _))| (*tag, name))
+                .collect();
+        types.sort();
+        f.debug_struct("DynRegistry").field("types", &types).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use control::DefaultControl;
+    use elt::Blob;
+    use io::mem::MemRepoIO;
+    use part::Partition;
+    use state::{StateRead, StateWrite};
+    
+    #[test]
+    fn heterogeneous_partition() {
+        let mut registry = DynRegistry::new();
+        registry.register::<String>(1, "text").expect("register");
+        registry.register::<u64>(2, "number").expect("register");
+        assert!(registry.register::<Blob>(2, "blob").is_err());
+        assert!(registry.register::<String>(3, "text again").is_err());
+        
+        let control = DefaultControl::<DynElement, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "dynamic").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let text = state.insert_new(registry.encode(&"hello".to_string()).expect("encode"))
+                .expect("insert");
+        let number = state.insert_new(registry.encode(&7u64).expect("encode"))
+                .expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        
+        let control = part.unwrap_control();
+        let mut part = Partition::open(control, true).expect("open");
+        part.load_all().expect("load_all");
+        let tip = part.tip().expect("tip");
+        let elt = tip.get(text).expect("get");
+        assert_eq!(registry.decode_as::<String>(elt).expect("decode"), "hello");
+        let value = registry.decode(tip.get(number).expect("get")).expect("decode");
+        assert_eq!(value.as_any().downcast_ref::<u64>(), Some(&7));
+        
+        assert!(registry.encode(&<Blob>::default()).is_err());
+        assert!(registry.decode(&DynElement::new(9, vec![])).is_err());
+        assert!(DynElement::read_buf(&[0, 1]).is_err());
+        let elt = DynElement::read_buf(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 1, 0]).expect("read_buf");
+        assert_eq!(registry.decode_as::<u64>(&elt).expect("decode"), 256);
+    }
+}
@@ -6,13 +6,17 @@
 
 use std::fmt;
 use std::fmt::Debug;
-use std::io::{/*Read,*/ Write};
+use std::io::{Read, Write, ErrorKind};
+use std::marker::PhantomData;
 use std::str::from_utf8;
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::random;
 
 use sum::Sum;
-use error::Result;
+use error::{Result, ArgError, make_io_err};
+#[cfg(feature = "json")]
+use serde_json;
 
 /// An element identifier.
 /// 
@@ -82,8 +86,13 @@ impl fmt::Display for EltId {
 /// ### Implementations
 /// 
 /// It is recommended that an implementation is written specific to each
-/// use-case (using an enum if variadic data typing is needed). There is
-/// however a default implementation for `String`.
+/// use-case (using an enum if variadic data typing is needed). There are
+/// however implementations for `String`, `Vec<u8>`, `Blob`, fixed-width
+/// integers and (with the `json` feature) `serde_json::Value`, enough to get
+/// started without implementing this trait. With the `derive` feature,
+/// `#[derive(PippinElement)]` implements this for structs whose fields are
+/// elements (see `write_value`). To store elements of several types in one
+/// partition, use `dynelt::DynElement`.
 /// 
 /// A trivial example:
 /// 
@@ -124,7 +133,7 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
 //     /// The other functions may simply be empty or panic since they will not be
 //     /// used.
 //     const use_buf_io: bool;
-    
+
     /// Write a serialisation of the element data out to the given writer.
     /// 
     /// The given writer points to a dynamically allocated buffer so that
@@ -133,7 +142,7 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
     fn write_buf(&self, writer: &mut Write) -> Result<()>;
     /// Deserialise the given data into a new element.
     fn read_buf(buf: &[u8]) -> Result<Self>;
-    
+
 //     /// Get the length of data which will be written out by `write()`. This
 //     /// *must* be correct!
 //     fn write_len(&self) -> Result<usize>;
@@ -142,7 +151,7 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
 //     fn write<W: Write>(&self, writer: W) -> Result<()>;
 //     /// Read from a data stream. The implementation *must* read `len` bytes!
 //     fn read<R: Read>(reader: R) -> Result<Self>;
-    
+
     /// Create an instance from a buffer. This implementation wraps `read_buf`;
     /// write your own for more efficiency.
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
@@ -169,6 +178,59 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
         self.write_buf(&mut &mut buf).expect("write_buf does not fail in get_sum");
         Sum::elt_sum(id, &buf)
     }
+    
+    /// Get the length of the element's serialisation (as written by
+    /// `write_buf`). This is used to maintain `PartState::byte_len()`.
+    /// 
+    /// The default implementation serialises the element to find the length;
+    /// override if the length is cheaply available.
+    /// 
+    /// Warning: this implementation panics if `write_buf` has an error!
+    fn byte_len(&self) -> usize {
+        let mut buf = Vec::new();
+        self.write_buf(&mut &mut buf).expect("write_buf does not fail in byte_len");
+        buf.len()
+    }
+    
+    /// If true, `write_stream` is used to write this element to commit logs,
+    /// instead of serialising it to a buffer first: the length written
+    /// before the data is taken from `byte_len`, which must then be exact
+    /// (this is checked). Use for elements too large to buffer. Element data
+    /// encoded with a codec (see `rw::codec`) is still buffered.
+    /// 
+    /// The default implementation returns false.
+    fn is_streamed(&self) -> bool {
+        false
+    }
+    
+    /// Write the same serialisation as `write_buf` to `writer`, which is the
+    /// output stream (not a buffer). Only used if `is_streamed` returns true.
+    /// 
+    /// The default implementation calls `write_buf`.
+    fn write_stream(&self, writer: &mut Write) -> Result<()> {
+        self.write_buf(writer)
+    }
+    
+    /// Merge two differing versions `a` and `b` of an element, given the
+    /// version in their common ancestor state, if any. This is consulted by
+    /// `TwoWayMerge` before any solver, allowing structured elements to be
+    /// merged field-wise.
+    /// 
+    /// Return `None` to leave the conflict to the solver. The default
+    /// implementation always does so.
+    fn merge(_ancestor: Option<&Self>, _a: &Self, _b: &Self) -> Option<Self> {
+        None
+    }
+    
+    /// A stable, user-supplied key identifying this element, which unlike
+    /// the element identifier does not change when elements are moved
+    /// between partitions (see `keys::KeyIndex`). The key should be part of
+    /// the element's data, and unique.
+    /// 
+    /// The default implementation returns `None` (no key).
+    fn external_key(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 impl Element for String {
@@ -183,4 +245,247 @@ impl Element for String {
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
         Ok(String::from_utf8(vec)?)
     }
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Raw bytes: useful where the element type is unknown, e.g. in tools
+/// inspecting files (see `format`).
+impl Element for Vec<u8> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_all(self)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Ok(buf.to_vec())
+    }
+    fn from_vec(vec: Vec<u8>) -> Result<Self>{
+        Ok(vec)
+    }
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+// Integers are written big-endian at their natural width.
+macro_rules! impl_int_element {
+    ($t:ty, $len:expr, $write:ident, $read:ident) => {
+        impl Element for $t {
+            fn write_buf(&self, writer: &mut Write) -> Result<()> {
+                writer.$write::<BigEndian>(*self)?;
+                Ok(())
+            }
+            fn read_buf(buf: &[u8]) -> Result<Self> {
+                if buf.len() != $len {
+                    return make_io_err(ErrorKind::InvalidData, "integer element has wrong length");
+                }
+                let mut r = buf;
+                Ok(r.$read::<BigEndian>()?)
+            }
+            fn byte_len(&self) -> usize {
+                $len
+            }
+        }
+    }
+}
+impl_int_element!(u16, 2, write_u16, read_u16);
+impl_int_element!(u32, 4, write_u32, read_u32);
+impl_int_element!(u64, 8, write_u64, read_u64);
+impl_int_element!(i16, 2, write_i16, read_i16);
+impl_int_element!(i32, 4, write_i32, read_i32);
+impl_int_element!(i64, 8, write_i64, read_i64);
+
+/// Default maximum length of a `Blob`, in bytes (16 MiB).
+pub const BLOB_MAX_LEN: usize = 1 << 24;
+
+/// Selects the maximum length of a `Blob`. Implement this on a unit struct
+/// to use another limit, e.g.:
+/// 
+/// ```
+/// use pippin::elt::{Blob, BlobLimit};
+/// 
+/// struct Small;
+/// impl BlobLimit for Small {
+///     fn max_len() -> usize { 4 }
+/// }
+/// 
+/// assert!(Blob::<Small>::with_limit(vec![1, 2, 3, 4]).is_ok());
+/// assert!(Blob::<Small>::with_limit(vec![1, 2, 3, 4, 5]).is_err());
+/// ```
+pub trait BlobLimit: 'static {
+    /// The maximum length, in bytes
+    fn max_len() -> usize;
+}
+
+/// The default `Blob` limit: `BLOB_MAX_LEN`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DefaultBlobLimit;
+impl BlobLimit for DefaultBlobLimit {
+    fn max_len() -> usize { BLOB_MAX_LEN }
+}
+
+/// Binary data of bounded length: at most `L::max_len()` bytes (by default
+/// `BLOB_MAX_LEN`).
+/// 
+/// Unlike `Vec<u8>`, construction, reading and writing fail on over-long
+/// data, which keeps a bad input from bloating a partition. Since the limit
+/// is part of the type, changing it for stored data requires elements to be
+/// read as the old type and converted.
+pub struct Blob<L: BlobLimit = DefaultBlobLimit> {
+    data: Vec<u8>,
+    limit: PhantomData<L>,
+}
+impl Blob {
+    /// Wrap `data`, failing if it is longer than `BLOB_MAX_LEN`.
+    pub fn new(data: Vec<u8>) -> Result<Blob> {
+        Blob::with_limit(data)
+    }
+}
+impl<L: BlobLimit> Blob<L> {
+    /// Wrap `data`, failing if it is longer than `L::max_len()`.
+    pub fn with_limit(data: Vec<u8>) -> Result<Blob<L>> {
+        if data.len() > L::max_len() {
+            return ArgError::err("blob exceeds maximum length");
+        }
+        Ok(Blob { data: data, limit: PhantomData })
+    }
+    /// Get the contained data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// Unwrap, returning the contained data
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+// Implemented by hand to avoid requiring these traits of `L`:
+impl<L: BlobLimit> Clone for Blob<L> {
+    fn clone(&self) -> Self {
+        Blob { data: self.data.clone(), limit: PhantomData }
+    }
+}
+impl<L: BlobLimit> PartialEq for Blob<L> {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+impl<L: BlobLimit> Eq for Blob<L> {}
+impl<L: BlobLimit> Debug for Blob<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blob").field("data", &self.data).finish()
+    }
+}
+impl<L: BlobLimit> Default for Blob<L> {
+    fn default() -> Self {
+        Blob { data: Vec::new(), limit: PhantomData }
+    }
+}
+impl<L: BlobLimit> Element for Blob<L> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        if self.data.len() > L::max_len() {
+            return make_io_err(ErrorKind::InvalidInput, "blob exceeds maximum length");
+        }
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Self::from_vec(buf.to_vec())
+    }
+    fn from_vec(vec: Vec<u8>) -> Result<Self>{
+        if vec.len() > L::max_len() {
+            return make_io_err(ErrorKind::InvalidData, "blob exceeds maximum length");
+        }
+        Ok(Blob { data: vec, limit: PhantomData })
+    }
+    fn byte_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// JSON values, serialised as compact JSON text. Object keys are written in
+/// sorted order (unless serde_json's `preserve_order` feature is enabled), so
+/// equal values have equal sums.
+/// 
+/// Note that enabling the `json` feature links `serde_json`, which implements
+/// `PartialEq<Value>` for primitive types such as `f64`. Comparisons whose
+/// element type was previously inferred, e.g. of a `&[f64]` with `&[]`, may
+/// then need a type annotation; this affects any crate built with the feature.
+#[cfg(feature = "json")]
+impl Element for serde_json::Value {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        serde_json::to_writer(writer, self)
+                .or_else(|e| make_io_err(ErrorKind::InvalidData, e.to_string()))
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        serde_json::from_slice(buf)
+                .or_else(|e| make_io_err(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// Write `value` prefixed by its length (`u32`), such that several values
+/// can be written one after another and read back with `read_value`. This is
+/// used to serialise elements composed of other elements (e.g. by
+/// `#[derive(PippinElement)]`, with the `derive` feature).
+pub fn write_value<T: Element>(writer: &mut Write, value: &T) -> Result<()> {
+    let mut buf = Vec::new();
+    value.write_buf(&mut buf)?;
+    writer.write_u32::<BigEndian>(buf.len() as u32)?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read a value written by `write_value`, advancing `r` past it
+pub fn read_value<T: Element>(r: &mut &[u8]) -> Result<T> {
+    let len = r.read_u32::<BigEndian>()? as usize;
+    if len > r.len() {
+        return make_io_err(ErrorKind::UnexpectedEof, "value length exceeds data");
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    T::from_vec(buf)
+}
+
+/// Fail if `r` is not empty, i.e. data follows the last value read via
+/// `read_value`
+pub fn check_consumed(r: &[u8]) -> Result<()> {
+    if !r.is_empty() {
+        return make_io_err(ErrorKind::InvalidData, "unexpected data after last value");
+    }
+    Ok(())
+}
+
+#[test]
+fn builtin_elements() {
+    fn round_trip<E: Element>(elt: E) {
+        let mut buf = Vec::new();
+        elt.write_buf(&mut buf).unwrap();
+        assert_eq!(buf.len(), elt.byte_len());
+        assert_eq!(E::read_buf(&buf).unwrap(), elt);
+    }
+    round_trip(0x0102u16);
+    round_trip(u64::max_value());
+    round_trip(-7i32);
+    round_trip(i64::min_value());
+    round_trip(Blob::new(vec![1, 2, 3]).unwrap());
+    #[cfg(feature = "json")]
+    round_trip(serde_json::from_str::<serde_json::Value>(r#"{"b": [1, null], "a": "x"}"#)
+            .unwrap());
+    
+    assert!(u32::read_buf(&[0, 0, 1]).is_err());
+    assert!(i16::read_buf(&[0, 0, 1]).is_err());
+    assert!(Blob::new(vec![0; BLOB_MAX_LEN + 1]).is_err());
+    assert!(<Blob>::from_vec(vec![0; BLOB_MAX_LEN + 1]).is_err());
+    
+    struct Small;
+    impl BlobLimit for Small {
+        fn max_len() -> usize { 2 }
+    }
+    round_trip(Blob::<Small>::with_limit(vec![1, 2]).unwrap());
+    assert!(Blob::<Small>::with_limit(vec![1, 2, 3]).is_err());
+    assert!(Blob::<Small>::read_buf(&[1, 2, 3]).is_err());
+    // Data written with a larger limit cannot be read with a smaller one
+    let mut buf = Vec::new();
+    Blob::new(vec![1, 2, 3]).unwrap().write_buf(&mut buf).unwrap();
+    assert!(Blob::<Small>::read_buf(&buf).is_err());
 }
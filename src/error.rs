@@ -5,9 +5,13 @@
 //! Internal error structs used by Pippin
 
 use std::{io, fmt, result};
+use std::borrow::Cow;
 use std::path::PathBuf;
 use std::cmp::{min, max};
 
+use elt::EltId;
+use io::backup::FileId;
+use rw::{CRC_VERSION, OLDEST_VERSION};
 use util::HexFormatter;
 
 /// Our custom result type
@@ -19,6 +23,135 @@ pub type Error = Box<ErrorTrait>;
 use std::error::Error as ErrorTrait;
 
 
+// —————  ErrorCode  —————
+/// Stable numeric classification of errors, e.g. for reporting across an API
+/// boundary. Values of existing codes will not change; new codes may be
+/// added.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ErrorCode {
+    /// Error of a type not known to Pippin
+    Unknown = 0,
+    /// `std::io::Error`
+    Io = 1,
+    /// `ReadError`
+    Read = 2,
+    /// `ArgError`
+    Arg = 3,
+    /// `ElementOp`
+    Element = 4,
+    /// `PatchOp`
+    Patch = 5,
+    /// `TransactionError`
+    Transaction = 6,
+    /// `PathError`
+    Path = 7,
+    /// `MatchError`
+    Match = 8,
+    /// `TipError`
+    Tip = 9,
+    /// `MergeError`
+    Merge = 10,
+    /// `VersionError`
+    Version = 11,
+    /// `ReadOnly`
+    ReadOnly = 12,
+    /// `UserError`
+    User = 13,
+    /// `OtherError`
+    Other = 14,
+    /// `Locked`
+    Locked = 15,
+}
+impl ErrorCode {
+    /// Get the code of an error. A `ContextError` is transparent: the code of
+    /// the error it wraps is returned.
+    pub fn of(e: &(ErrorTrait + 'static)) -> ErrorCode {
+        if let Some(e) = e.downcast_ref::<ContextError>() {
+            ErrorCode::of(&*e.error)
+        } else if e.is::<io::Error>() {
+            ErrorCode::Io
+        } else if e.is::<ReadError>() {
+            ErrorCode::Read
+        } else if e.is::<ArgError>() {
+            ErrorCode::Arg
+        } else if e.is::<ElementOp>() {
+            ErrorCode::Element
+        } else if e.is::<PatchOp>() {
+            ErrorCode::Patch
+        } else if e.is::<TransactionError>() {
+            ErrorCode::Transaction
+        } else if e.is::<PathError>() {
+            ErrorCode::Path
+        } else if e.is::<MatchError>() {
+            ErrorCode::Match
+        } else if e.is::<TipError>() {
+            ErrorCode::Tip
+        } else if e.is::<MergeError>() {
+            ErrorCode::Merge
+        } else if e.is::<VersionError>() {
+            ErrorCode::Version
+        } else if e.is::<ReadOnly>() {
+            ErrorCode::ReadOnly
+        } else if e.is::<UserError>() {
+            ErrorCode::User
+        } else if e.is::<OtherError>() {
+            ErrorCode::Other
+        } else if e.is::<Locked>() {
+            ErrorCode::Locked
+        } else {
+            ErrorCode::Unknown
+        }
+    }
+    /// Get the numeric value
+    pub fn value(self) -> u32 {
+        self as u32
+    }
+}
+
+
+// —————  ContextError  —————
+/// Wraps another error with the partition and file it concerns. Errors
+/// reading files while opening or loading a partition are wrapped like this.
+/// 
+/// The wrapped error is available via `source()` or the `error` field.
+#[derive(Debug)]
+pub struct ContextError {
+    /// Name of the partition, if known
+    pub partition: Option<String>,
+    /// The file concerned, if any
+    pub file: Option<FileId>,
+    /// The wrapped error
+    pub error: Error,
+}
+impl ContextError {
+    /// Create, wrapping `error`
+    pub fn new(partition: Option<&str>, file: Option<FileId>, error: Error) -> ContextError {
+        ContextError { partition: partition.map(|s| s.to_string()), file: file, error: error }
+    }
+}
+impl ErrorTrait for ContextError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        Some(&*self.error)
+    }
+}
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        if let Some(ref name) = self.partition {
+            write!(f, "partition {}: ", name)?;
+        }
+        match self.file {
+            Some(FileId::Snapshot(ss)) => write!(f, "snapshot {}: ", ss)?,
+            Some(FileId::CommitLog(ss, cl)) => write!(f, "commit log {}-{}: ", ss, cl)?,
+            None => {},
+        }
+        self.error.fmt(f)
+    }
+}
+
+
 // —————  ReadError  —————
 
 /// This is a variant of the core `try!(...)` macro which adds position data
@@ -37,58 +170,112 @@ macro_rules! try_read {
 
 #[derive(Debug)]
 enum Wrapped {
-    Msg(&'static str),
+    Msg(Cow<'static, str>),
     ErrT(Error),
 }
 /// For read errors; adds a read position
+/// 
+/// Errors created by the library's readers also capture the bytes around the
+/// error (see `with_data`), which `dump()` displays.
 #[derive(Debug)]
 pub struct ReadError {
     detail: Wrapped,
     pos: usize,
     off_start: usize,
     off_end: usize,
+    // Captured bytes and their read position
+    data: Option<(usize, Vec<u8>)>,
 }
 impl ReadError {
+    /// Maximum number of bytes captured by `with_data`
+    pub const MAX_CAPTURE: usize = 64;
+    
     /// Create a "read" error with read position
     /// 
     /// `pos`: the start of the text to display (length is determined
     /// automatically from `offset`, rounded up to eight-byte blocks).
     /// 
     /// `offset`: the region of the displayed text to highlight.
-    pub fn new(msg: &'static str, pos: usize, offset: (usize, usize)) -> ReadError {
+    /// 
+    /// `msg` may be a static or formatted string.
+    pub fn new<M: Into<Cow<'static, str>>>(msg: M, pos: usize, offset: (usize, usize)) -> ReadError {
         let (o0, o1) = offset;
-        ReadError { detail: Wrapped::Msg(msg), pos: pos, off_start: o0, off_end: o1 }
+        ReadError { detail: Wrapped::Msg(msg.into()), pos: pos, off_start: o0, off_end: o1,
+                data: None }
     }
     /// New instance, wrapped with `Err` (see `new()`).
-    pub fn err<T>(msg: &'static str, pos: usize, offset: (usize, usize)) -> Result<T> {
+    pub fn err<T, M: Into<Cow<'static, str>>>(msg: M, pos: usize, offset: (usize, usize)) ->
+            Result<T>
+    {
         Err(Box::new(ReadError::new(msg, pos, offset)))
     }
+    /// New instance capturing bytes from `data`, wrapped with `Err` (see
+    /// `new()` and `with_data()`).
+    pub fn err_data<T, M: Into<Cow<'static, str>>>(msg: M, pos: usize, offset: (usize, usize),
+            data: &[u8]) -> Result<T>
+    {
+        Err(Box::new(ReadError::new(msg, pos, offset).with_data(data)))
+    }
     /// Create a "read" error wrapping another error
     pub fn new_wrap(e: Error, pos: usize, offset: (usize, usize)) -> ReadError {
         let (o0, o1) = offset;
-        ReadError { detail: Wrapped::ErrT(e), pos: pos, off_start: o0, off_end: o1 }
+        ReadError { detail: Wrapped::ErrT(e), pos: pos, off_start: o0, off_end: o1, data: None }
+    }
+    /// Capture the bytes around the highlighted region from `data`, the
+    /// buffer which the offset refers to (i.e. `data[0]` is at read position
+    /// `pos`). At most `MAX_CAPTURE` bytes are kept, in whole eight-byte
+    /// lines where available.
+    pub fn with_data(mut self, data: &[u8]) -> ReadError {
+        let start = min(8 * (self.off_start / 8), data.len());
+        let end = min(min(8 * ((self.off_end + 7) / 8), data.len()), start + ReadError::MAX_CAPTURE);
+        self.data = Some((self.pos + start, data[start..end].to_vec()));
+        self
+    }
+    /// Get the captured bytes (see `with_data`), if any, with the read
+    /// position of the first.
+    pub fn data(&self) -> Option<(usize, &[u8])> {
+        self.data.as_ref().map(|&(pos, ref data)| (pos, &data[..]))
     }
     /// Return an object which can be used in format expressions.
     /// 
+    /// `data` must be the whole of the data read (since position 0).
+    /// 
     /// Usage: `println!("{}", err.display(&buf));`
     pub fn display<'a>(&'a self, data: &'a [u8]) -> ReadErrorFormatter<'a> {
-        ReadErrorFormatter { err: self, data: data }
+        ReadErrorFormatter { err: self, data: data, base: 0 }
+    }
+    /// As `display`, but using the bytes captured by the error (see
+    /// `with_data`). This works where the data read is no longer available,
+    /// e.g. for errors returned by `Partition::open`.
+    /// 
+    /// Usage: `println!("{}", err.dump());`
+    pub fn dump(&self) -> ReadErrorFormatter {
+        match self.data {
+            Some((base, ref data)) => ReadErrorFormatter { err: self, data: data, base: base },
+            None => ReadErrorFormatter { err: self, data: &[], base: self.pos },
+        }
     }
 }
 impl ErrorTrait for ReadError {
     fn description(&self) -> &str {
         match self.detail {
-            Wrapped::Msg(msg) => msg,
+            Wrapped::Msg(ref msg) => msg,
             Wrapped::ErrT(ref e) => e.description(),
         }
     }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match self.detail {
+            Wrapped::Msg(_) => None,
+            Wrapped::ErrT(ref e) => Some(&**e),
+        }
+    }
 }
 impl fmt::Display for ReadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
         write!(f, "read error at position {}, offset ({}, {}): ", 
                 self.pos, self.off_start, self.off_end)?;
         match self.detail {
-            Wrapped::Msg(msg) => write!(f, "{}", msg),
+            Wrapped::Msg(ref msg) => write!(f, "{}", msg),
             Wrapped::ErrT(ref e) => e.fmt(f),
         }
     }
@@ -97,6 +284,8 @@ impl fmt::Display for ReadError {
 pub struct ReadErrorFormatter<'a> {
     err: &'a ReadError,
     data: &'a [u8],
+    // Read position of data[0]
+    base: usize,
 }
 impl<'a> fmt::Display for ReadErrorFormatter<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -106,7 +295,7 @@ impl<'a> fmt::Display for ReadErrorFormatter<'a> {
         write!(f, "read error (pos {}, offset ({}, {})): ", self.err.pos,
             self.err.off_start, self.err.off_end)?;
         match self.err.detail {
-            Wrapped::Msg(msg) => write!(f, "{}", msg),
+            Wrapped::Msg(ref msg) => write!(f, "{}", msg),
             Wrapped::ErrT(ref e) => e.fmt(f),
         }?;
         let start = self.err.pos + 8 * (self.err.off_start / 8);
@@ -115,11 +304,12 @@ impl<'a> fmt::Display for ReadErrorFormatter<'a> {
         // Rust issue #27741 is closed.
         let mut line_start = start;
         while line_start < end {
-            if line_start + 8 > self.data.len() {
+            if line_start < self.base || line_start + 8 > self.base + self.data.len() {
                 writeln!(f, "insufficient data to display!")?;
                 break;
             }
-            HexFormatter::line(&self.data[line_start..line_start+8]).fmt(f)?;
+            let i = line_start - self.base;
+            HexFormatter::line(&self.data[i..i+8]).fmt(f)?;
             let p0 = max(self.err.pos + self.err.off_start, line_start) - line_start;
             let p1 = min(self.err.pos + self.err.off_end, line_start + 8) - line_start;
             assert!(p0 <= p1 && p1 <= 8);
@@ -137,20 +327,20 @@ impl<'a> fmt::Display for ReadErrorFormatter<'a> {
 /// Any error where an invalid argument was supplied
 #[derive(PartialEq, Eq, Debug)]
 pub struct ArgError {
-    msg: &'static str
+    msg: Cow<'static, str>
 }
 impl ArgError {
-    /// Create an "invalid argument" error
-    pub fn new(msg: &'static str) -> ArgError {
-        ArgError{ msg: msg }
+    /// Create an "invalid argument" error (from a static or formatted string)
+    pub fn new<M: Into<Cow<'static, str>>>(msg: M) -> ArgError {
+        ArgError{ msg: msg.into() }
     }
     /// New instance, wrapped with `Err`
-    pub fn err<T>(msg: &'static str) -> Result<T> {
+    pub fn err<T, M: Into<Cow<'static, str>>>(msg: M) -> Result<T> {
         Err(Box::new(ArgError::new(msg)))
     }
 }
 impl ErrorTrait for ArgError {
-    fn description(&self) -> &str { self.msg }
+    fn description(&self) -> &str { &self.msg }
 }
 impl fmt::Display for ArgError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -170,6 +360,26 @@ pub enum ElementOp {
     /// Identifier already in use. An insertion failed since the given
     /// identifier is already in use.
     IdClash,
+    /// The element's data is longer than allowed (see `Quota::max_elt_bytes`)
+    EltTooLarge,
+    /// The state would have more elements than allowed (see
+    /// `Quota::max_elts`)
+    EltLimit,
+    /// The state's element data would be longer than allowed (see
+    /// `Quota::max_bytes`)
+    ByteLimit,
+    /// The element's data no longer matches its checksum (see
+    /// `PartState::verify_elements`)
+    EltCorrupt,
+}
+impl ElementOp {
+    /// True if this is a quota violation (see `Quota`)
+    pub fn is_quota(&self) -> bool {
+        match *self {
+            ElementOp::EltTooLarge | ElementOp::EltLimit | ElementOp::ByteLimit => true,
+            _ => false,
+        }
+    }
 }
 impl ErrorTrait for ElementOp {
     fn description(&self) -> &'static str {
@@ -177,6 +387,10 @@ impl ErrorTrait for ElementOp {
             ElementOp::EltNotFound => "element not found",
             ElementOp::IdGenFailure => "id generation failed to find a free identifier",
             ElementOp::IdClash => "identifier already in use",
+            ElementOp::EltTooLarge => "element exceeds size quota",
+            ElementOp::EltLimit => "state would exceed element count quota",
+            ElementOp::ByteLimit => "state would exceed data size quota",
+            ElementOp::EltCorrupt => "element data does not match its checksum",
         }
     }
 }
@@ -190,7 +404,8 @@ impl fmt::Display for ElementOp {
 // —————  PatchOp  —————
 /// Reason for a `push_commit` / `push_state` / commit patch operation failing.
 /// 
-/// Any `ElementOp` can automatically be converted to `PatchOp::PatchApply`.
+/// Any `ElementOp` can automatically be converted to `PatchOp::PatchApply`
+/// (`PatchOp::QuotaExceeded` for quota violations).
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum PatchOp {
     /// Parent state not found
@@ -199,6 +414,17 @@ pub enum PatchOp {
     WrongParent,
     /// Patch fails to apply cleanly
     PatchApply,
+    /// Commit number is not greater than that of its parent(s)
+    NumberOrder,
+    /// Parent is not the (only) tip, e.g. since another change was pushed
+    TipChanged,
+    /// Commit rejected by `Control::validate_commit`
+    Rejected,
+    /// The new state exceeds the partition's quota (see `Control::quota`)
+    QuotaExceeded,
+    /// The partition is sealed and may not be modified (see
+    /// `Partition::seal`)
+    Sealed,
 }
 impl ErrorTrait for PatchOp {
     fn description(&self) -> &'static str {
@@ -206,6 +432,11 @@ impl ErrorTrait for PatchOp {
             PatchOp::NoParent => "parent state of commit not found",
             PatchOp::WrongParent => "applying commit patch failed: wrong parent",
             PatchOp::PatchApply => "applying commit patch failed: data mismatch",
+            PatchOp::NumberOrder => "commit number not greater than parent's number",
+            PatchOp::TipChanged => "parent of new state is no longer the tip",
+            PatchOp::Rejected => "commit rejected by validation",
+            PatchOp::QuotaExceeded => "new state exceeds partition quota",
+            PatchOp::Sealed => "partition is sealed (read-only)",
         }
     }
 }
@@ -216,6 +447,9 @@ impl fmt::Display for PatchOp {
 }
 impl From<ElementOp> for PatchOp {
     fn from(e: ElementOp) -> PatchOp {
+        if e.is_quota() {
+            return PatchOp::QuotaExceeded;
+        }
         // Possibly WrongPartition, ClassifyFailure and NotLoaded shouldn't map
         // like this.
         trace!("casting ElementOp '{}' to PatchOp::PatchApply", e.description());
@@ -224,26 +458,71 @@ impl From<ElementOp> for PatchOp {
 }
 
 
+// —————  TransactionError  —————
+/// Reason for a transaction (see `Partition::transaction`) being rejected.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum TransactionError {
+    /// One or more operations conflict with the state the transaction was
+    /// started from; each is listed with the element identifier concerned
+    Conflicts(Vec<(EltId, ElementOp)>),
+    /// Pushing the resulting state failed
+    Patch(PatchOp),
+}
+impl ErrorTrait for TransactionError {
+    fn description(&self) -> &str {
+        match *self {
+            TransactionError::Conflicts(_) => "transaction rejected: conflicting operations",
+            TransactionError::Patch(ref e) => e.description(),
+        }
+    }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match *self {
+            TransactionError::Conflicts(_) => None,
+            TransactionError::Patch(ref e) => Some(e),
+        }
+    }
+}
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            TransactionError::Conflicts(ref conflicts) => {
+                write!(f, "transaction rejected:")?;
+                for &(id, ref e) in conflicts {
+                    write!(f, " {}: {};", id, e)?;
+                }
+                Ok(())
+            },
+            TransactionError::Patch(ref e) => write!(f, "transaction rejected: {}", e),
+        }
+    }
+}
+impl From<PatchOp> for TransactionError {
+    fn from(e: PatchOp) -> TransactionError {
+        TransactionError::Patch(e)
+    }
+}
+
+
 // —————  PathError  —————
 /// Error messages about some path on the file system
 #[derive(PartialEq, Eq, Debug)]
 pub struct PathError {
-    msg: &'static str,
+    msg: Cow<'static, str>,
     path: PathBuf,
 }
 impl PathError {
     /// Create a "path" error. Will be displayed as
     /// `println!("Error: {}: {}", msg, path.display());`.
-    pub fn new<P: Into<PathBuf>>(msg: &'static str, path: P) -> PathError {
-        PathError { msg: msg, path: path.into() }
+    pub fn new<M: Into<Cow<'static, str>>, P: Into<PathBuf>>(msg: M, path: P) -> PathError {
+        PathError { msg: msg.into(), path: path.into() }
     }
     /// New instance, wrapped with `Err`
-    pub fn err<T, P: Into<PathBuf>>(msg: &'static str, path: P) -> Result<T> {
+    pub fn err<T, M: Into<Cow<'static, str>>, P: Into<PathBuf>>(msg: M, path: P) -> Result<T> {
         Err(Box::new(PathError::new(msg, path)))
     }
 }
 impl ErrorTrait for PathError {
-    fn description(&self) -> &str { self.msg }
+    fn description(&self) -> &str { &self.msg }
 }
 impl fmt::Display for PathError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -253,19 +532,37 @@ impl fmt::Display for PathError {
 
 
 // —————  MatchError  —————
-/// Error messages about some path on the file system
+/// Error resolving a state from a string (see `Partition::state_from_string`)
 #[derive(PartialEq, Eq, Debug)]
 pub enum MatchError {
     /// No matching string found
     NoMatch,
     /// Multiple matching strings found; two examples follow
     MultiMatch(String, String),
+    /// The string could not be parsed; the string follows
+    Syntax(String),
+    /// `tip` was requested but there is not exactly one tip
+    Tip(TipError),
+    /// An ancestor was requested but the state found has no parent
+    NoParent,
+    /// An ancestor was requested but is not loaded; its sum follows
+    NotLoaded(String),
 }
 impl ErrorTrait for MatchError {
     fn description(&self) -> &str {
         match *self {
             MatchError::NoMatch => "no matching string",
             MatchError::MultiMatch(_,_) => "multiple matching strings",
+            MatchError::Syntax(_) => "unable to parse state reference",
+            MatchError::Tip(ref e) => e.description(),
+            MatchError::NoParent => "requested ancestor of initial state",
+            MatchError::NotLoaded(_) => "requested ancestor is not loaded",
+        }
+    }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match *self {
+            MatchError::Tip(ref e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -274,10 +571,20 @@ impl fmt::Display for MatchError {
         match *self {
             MatchError::NoMatch => write!(f, "no match found"),
             MatchError::MultiMatch(ref m1, ref m2) =>
-                write!(f, "multiple matches found ({}, {}, ...)", m1, m2)
+                write!(f, "multiple matches found ({}, {}, ...)", m1, m2),
+            MatchError::Syntax(ref s) => write!(f, "unable to parse state reference: {}", s),
+            MatchError::Tip(ref e) => write!(f, "unable to resolve tip: {}", e),
+            MatchError::NoParent => write!(f, "requested ancestor of initial state"),
+            MatchError::NotLoaded(ref s) =>
+                write!(f, "requested ancestor is not loaded (try loading more history): {}", s),
         }
     }
 }
+impl From<TipError> for MatchError {
+    fn from(e: TipError) -> MatchError {
+        MatchError::Tip(e)
+    }
+}
 
 
 // —————  TipError  —————
@@ -327,6 +634,12 @@ impl ErrorTrait for MergeError {
             MergeError::PatchOp(ref p) => p.description(),
         }
     }
+    fn source(&self) -> Option<&(ErrorTrait + 'static)> {
+        match *self {
+            MergeError::PatchOp(ref p) => Some(p),
+            _ => None,
+        }
+    }
 }
 impl fmt::Display for MergeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -340,6 +653,49 @@ impl From<PatchOp> for MergeError {
 }
 
 
+// —————  VersionError  —————
+/// A file uses a format version this library cannot read.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum VersionError {
+    /// File format is newer than the latest this library supports (a newer
+    /// version of the library is required); the file's version follows
+    Newer(u32),
+    /// File format is a legacy version which is no longer supported (these
+    /// use different checksums; snapshots may be converted with
+    /// `Partition::convert_legacy`); the file's version follows
+    Legacy(u32),
+}
+impl VersionError {
+    /// The format version of the file
+    pub fn file_version(&self) -> u32 {
+        match *self {
+            VersionError::Newer(v) | VersionError::Legacy(v) => v,
+        }
+    }
+}
+impl ErrorTrait for VersionError {
+    fn description(&self) -> &str {
+        match *self {
+            VersionError::Newer(_) => "file format version is newer than supported",
+            VersionError::Legacy(_) => "file format version is a no longer supported legacy version",
+        }
+    }
+}
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        let lib = ::LIB_VERSION;
+        match *self {
+            VersionError::Newer(v) => write!(f, "file format version {} is newer than \
+                supported (latest {}); a newer library version than {}.{}.{} is required",
+                v, CRC_VERSION, (lib >> 32) & 0xFFFF, (lib >> 16) & 0xFFFF, lib & 0xFFFF),
+            VersionError::Legacy(v) => write!(f, "file format version {} is a legacy \
+                version which is no longer supported (oldest supported: {}); the latest \
+                snapshot may be converted to a new partition", v, OLDEST_VERSION),
+        }
+    }
+}
+
+
 // —————  ReadOnly  —————
 /// Thing is not modifiable.
 #[derive(PartialEq, Eq, Debug)]
@@ -364,6 +720,27 @@ impl fmt::Display for ReadOnly {
 }
 
 
+// —————  Locked  —————
+/// An element is locked by another owner (see `lock::LockRegistry`)
+#[derive(PartialEq, Eq, Debug)]
+pub struct Locked {
+    /// Owner of the lock
+    pub owner: String,
+    /// Time at which the lock expires (UNIX time-stamp)
+    pub expires: i64,
+}
+impl ErrorTrait for Locked {
+    fn description(&self) -> &str {
+        "element is locked by another owner"
+    }
+}
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "element is locked by {} until {}", self.owner, self.expires)
+    }
+}
+
+
 // —————  UserError  —————
 /// An error the user may return
 #[derive(PartialEq, Eq, Debug)]
@@ -371,12 +748,12 @@ pub struct UserError {
     /// Arbitrary code a user may set
     pub code: u64,
     /// Message to display
-    pub msg: &'static str,
+    pub msg: Cow<'static, str>,
 }
 impl UserError {
-    /// Create
-    pub fn new(code: u64, msg: &'static str) -> UserError {
-        UserError { code: code, msg: msg }
+    /// Create (from a static or formatted message)
+    pub fn new<M: Into<Cow<'static, str>>>(code: u64, msg: M) -> UserError {
+        UserError { code: code, msg: msg.into() }
     }
 }
 impl fmt::Display for UserError {
@@ -385,7 +762,7 @@ impl fmt::Display for UserError {
     }
 }
 impl ErrorTrait for UserError {
-    fn description(&self) -> &str { self.msg }
+    fn description(&self) -> &str { &self.msg }
 }
 
 
@@ -393,15 +770,15 @@ impl ErrorTrait for UserError {
 /// Unclassified, generally not recoverable errors
 #[derive(PartialEq, Eq, Debug)]
 pub struct OtherError {
-    msg: &'static str,
+    msg: Cow<'static, str>,
 }
 impl OtherError {
-    /// Create with a message
-    pub fn new(msg: &'static str) -> OtherError {
-        OtherError { msg: msg }
+    /// Create with a message (a static or formatted string)
+    pub fn new<M: Into<Cow<'static, str>>>(msg: M) -> OtherError {
+        OtherError { msg: msg.into() }
     }
     /// New instance, wrapped with `Err`
-    pub fn err<T>(msg: &'static str) -> Result<T> {
+    pub fn err<T, M: Into<Cow<'static, str>>>(msg: M) -> Result<T> {
         Err(Box::new(OtherError::new(msg)))
     }
 }
@@ -411,11 +788,12 @@ impl fmt::Display for OtherError {
     }
 }
 impl ErrorTrait for OtherError {
-    fn description(&self) -> &str { self.msg }
+    fn description(&self) -> &str { &self.msg }
 }
 
-/// Use `io::error::new` to make an IO error
+/// Use `io::error::new` to make an IO error (from a static or formatted
+/// message)
 // #0011: replace all usages with Pippin-specific error types?
-pub fn make_io_err<T>(kind: io::ErrorKind, msg: &'static str) -> Result<T> {
-    Err(Box::new(io::Error::new(kind, msg)))
+pub fn make_io_err<T, M: Into<Cow<'static, str>>>(kind: io::ErrorKind, msg: M) -> Result<T> {
+    Err(Box::new(io::Error::new(kind, msg.into())))
 }
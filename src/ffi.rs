@@ -0,0 +1,417 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: C bindings.
+//! 
+//! This module is only available with the `ffi` feature. It exposes a
+//! partition over a C ABI, such that non-Rust applications (C, or Python via
+//! `ctypes`) can embed Pippin repositories. To get a shared library, build a
+//! `cdylib`, e.g. with
+//! `cargo rustc --release --features ffi --lib -- --crate-type cdylib`.
+//! 
+//! A partition is accessed via an opaque handle, `PippinPartition *`, created
+//! by `pippin_create_mem`, `pippin_create_file` or `pippin_open_file` and
+//! released with `pippin_free`. Elements are byte buffers, identified by
+//! 64-bit numbers. Changes (`pippin_insert`, `pippin_replace`,
+//! `pippin_remove`) are made to a pending state, started from the tip, which
+//! is committed by `pippin_commit` and saved by `pippin_write`.
+//! 
+//! Functions returning `int` return 0 (or a documented non-negative value)
+//! on success and -1 on failure, in which case `pippin_error_code` and
+//! `pippin_error_message` describe the error. Functions returning a handle
+//! return `NULL` on failure. Strings are NUL-terminated UTF-8. All functions
+//! dereference caller-provided pointers, thus are `unsafe` when called from
+//! Rust; see their `Safety` sections.
+//! 
+//! Corresponding C declarations:
+//! 
+//! ```c
+//! typedef struct PippinPartition PippinPartition;
+//! PippinPartition *pippin_create_mem(const char *name);
+//! PippinPartition *pippin_create_file(const char *prefix, const char *name);
+//! PippinPartition *pippin_open_file(const char *path);
+//! void pippin_free(PippinPartition *p);
+//! int pippin_load(PippinPartition *p, int all);
+//! int pippin_tip(PippinPartition *p, uint8_t sum_out[32]);
+//! int64_t pippin_get(PippinPartition *p, uint64_t id, uint8_t *buf, size_t buf_len);
+//! int pippin_insert(PippinPartition *p, const uint8_t *data, size_t len, uint64_t *id_out);
+//! int pippin_replace(PippinPartition *p, uint64_t id, const uint8_t *data, size_t len);
+//! int pippin_remove(PippinPartition *p, uint64_t id);
+//! int pippin_commit(PippinPartition *p);
+//! int pippin_write(PippinPartition *p);
+//! int pippin_error_code(const PippinPartition *p);
+//! const char *pippin_error_message(const PippinPartition *p);
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use control::DefaultControl;
+use elt::EltId;
+use error::{Result, ErrorCode, ArgError, OtherError};
+use io::RepoIO;
+#[cfg(feature = "fs")]
+use io::discover::part_from_path;
+#[cfg(feature = "fs")]
+use io::file::RepoFileIO;
+use io::mem::MemRepoIO;
+use part::Partition;
+use state::{MutPartState, StateRead, StateWrite};
+use sum::SUM_BYTES;
+
+type FfiControl = DefaultControl<Vec<u8>, Box<RepoIO>>;
+
+/// Opaque handle to a partition (see module documentation)
+pub struct PippinPartition {
+    part: Partition<FfiControl>,
+    // Changes not yet committed
+    state: Option<MutPartState<Vec<u8>>>,
+    // Last error, if any
+    error: Option<(ErrorCode, CString)>,
+}
+
+/// Create a new partition in memory (not saved anywhere; mostly useful for
+/// testing). Returns `NULL` on failure.
+/// 
+/// # Safety
+/// 
+/// `name` must be `NULL` or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_create_mem(name: *const c_char) -> *mut PippinPartition {
+    new_handle(|| {
+        let name = unsafe { to_str(name) }?;
+        Partition::create(DefaultControl::new(Box::new(MemRepoIO::new()) as Box<RepoIO>), name)
+    })
+}
+
+/// Create a new partition saved in files with path `prefix` (e.g.
+/// `dir/name` for files `dir/name-ss0.pip` etc.). Returns `NULL` on failure.
+/// Requires the `fs` feature.
+/// 
+/// # Safety
+/// 
+/// `prefix` and `name` must each be `NULL` or point to a NUL-terminated
+/// string.
+#[cfg(feature = "fs")]
+#[no_mangle]
+pub unsafe extern "C" fn pippin_create_file(prefix: *const c_char, name: *const c_char) ->
+        *mut PippinPartition
+{
+    new_handle(|| {
+        let io = RepoFileIO::new(unsafe { to_str(prefix) }?);
+        let name = unsafe { to_str(name) }?;
+        Partition::create(DefaultControl::new(Box::new(io) as Box<RepoIO>), name)
+    })
+}
+
+/// Open an existing partition from files, discovered from `path` (a
+/// directory or one of the partition's files). Data must be loaded with
+/// `pippin_load` before use. Returns `NULL` on failure. Requires the `fs`
+/// feature.
+/// 
+/// # Safety
+/// 
+/// `path` must be `NULL` or point to a NUL-terminated string.
+#[cfg(feature = "fs")]
+#[no_mangle]
+pub unsafe extern "C" fn pippin_open_file(path: *const c_char) -> *mut PippinPartition {
+    new_handle(|| {
+        let io = part_from_path(unsafe { to_str(path) }?)?;
+        Partition::open(DefaultControl::new(Box::new(io) as Box<RepoIO>), true)
+    })
+}
+
+/// Release a handle. Unsaved changes are lost. `p` may be `NULL`.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle, which is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_free(p: *mut PippinPartition) {
+    if !p.is_null() {
+        unsafe { drop(Box::from_raw(p)); }
+    }
+}
+
+/// Load data: the latest state only, or all history if `all` is non-zero.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_load(p: *mut PippinPartition, all: c_int) -> c_int {
+    call(p, -1, |h| {
+        if all != 0 { h.part.load_all()?; } else { h.part.load_latest()?; }
+        Ok(0)
+    })
+}
+
+/// Write the state sum of the tip to `sum_out`, which must have space for
+/// `SUM_BYTES` bytes.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle. `sum_out` must be `NULL` or point
+/// to at least `SUM_BYTES` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_tip(p: *mut PippinPartition, sum_out: *mut u8) -> c_int {
+    call(p, -1, |h| {
+        if sum_out.is_null() {
+            return ArgError::err("sum_out is null");
+        }
+        let mut out = unsafe { slice::from_raw_parts_mut(sum_out, SUM_BYTES) };
+        h.part.tip_key()?.write_to(&mut out)?;
+        Ok(0)
+    })
+}
+
+/// Get element `id` from the pending state if there are uncommitted
+/// changes, otherwise from the tip. The data is copied to `buf` if it fits
+/// (`buf_len` is at least the element's length). Returns the element's
+/// length, or -1 on failure.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle. `buf` must be `NULL` or point to at
+/// least `buf_len` writable bytes, not overlapping the partition's data.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_get(p: *mut PippinPartition, id: u64, buf: *mut u8,
+        buf_len: usize) -> i64
+{
+    call(p, -1, |h| {
+        let id = EltId::from(id);
+        let elt = match h.state {
+            Some(ref state) => state.get(id)?,
+            None => h.part.tip()?.get(id)?,
+        };
+        if elt.len() <= buf_len && !buf.is_null() {
+            unsafe { ptr::copy_nonoverlapping(elt.as_ptr(), buf, elt.len()); }
+        }
+        Ok(elt.len() as i64)
+    })
+}
+
+/// Insert a new element with a copy of `data`, writing its identifier to
+/// `id_out` (unless `NULL`).
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle. `data` must point to `len`
+/// readable bytes (it may be `NULL` if `len` is 0). `id_out` must be `NULL`
+/// or point to a writable `uint64_t`.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_insert(p: *mut PippinPartition, data: *const u8, len: usize,
+        id_out: *mut u64) -> c_int
+{
+    call(p, -1, |h| {
+        let elt = unsafe { to_vec(data, len) }?;
+        let id = pending(h)?.insert_new(elt)?;
+        if !id_out.is_null() {
+            unsafe { *id_out = id.into(); }
+        }
+        Ok(0)
+    })
+}
+
+/// Replace element `id` with a copy of `data`.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle. `data` must point to `len`
+/// readable bytes (it may be `NULL` if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn pippin_replace(p: *mut PippinPartition, id: u64, data: *const u8,
+        len: usize) -> c_int
+{
+    call(p, -1, |h| {
+        let elt = unsafe { to_vec(data, len) }?;
+        pending(h)?.replace(EltId::from(id), elt)?;
+        Ok(0)
+    })
+}
+
+/// Remove element `id`.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_remove(p: *mut PippinPartition, id: u64) -> c_int {
+    call(p, -1, |h| {
+        pending(h)?.remove(EltId::from(id))?;
+        Ok(0)
+    })
+}
+
+/// Commit pending changes. Returns 1 if a commit was made, 0 if there were
+/// no changes.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_commit(p: *mut PippinPartition) -> c_int {
+    call(p, -1, |h| {
+        match h.state.take() {
+            Some(state) => Ok(if h.part.push_state(state)? { 1 } else { 0 }),
+            None => Ok(0),
+        }
+    })
+}
+
+/// Write committed changes to storage (and a snapshot when due).
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_write(p: *mut PippinPartition) -> c_int {
+    call(p, -1, |h| {
+        h.part.write_full()?;
+        Ok(0)
+    })
+}
+
+/// The code of the last error (see `ErrorCode`), or -1 if no call on this
+/// handle has failed.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_error_code(p: *const PippinPartition) -> c_int {
+    match unsafe { p.as_ref() }.and_then(|h| h.error.as_ref()) {
+        Some(&(code, _)) => code as c_int,
+        None => -1,
+    }
+}
+
+/// A description of the last error, or `NULL` if no call on this handle has
+/// failed. The string is valid until the next call using the handle.
+/// 
+/// # Safety
+/// 
+/// `p` must be `NULL` or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn pippin_error_message(p: *const PippinPartition) -> *const c_char {
+    match unsafe { p.as_ref() }.and_then(|h| h.error.as_ref()) {
+        Some(&(_, ref msg)) => msg.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+// Run `f` to create a partition, returning a handle or null on failure
+fn new_handle<F: FnOnce() -> Result<Partition<FfiControl>>>(f: F) -> *mut PippinPartition {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(part)) => Box::into_raw(Box::new(PippinPartition {
+            part: part,
+            state: None,
+            error: None,
+        })),
+        Ok(Err(e)) => {
+            warn!("pippin ffi: {}", e);
+            ptr::null_mut()
+        },
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+// Run `f` on the handle `p`, recording any error (or panic) and returning
+// `fail` in that case. Unsafe: `p` must be null or a valid handle.
+unsafe fn call<T, F: FnOnce(&mut PippinPartition) -> Result<T>>(p: *mut PippinPartition,
+        fail: T, f: F) -> T
+{
+    let h = match unsafe { p.as_mut() } {
+        Some(h) => h,
+        None => return fail,
+    };
+    h.error = None;
+    let result = match catch_unwind(AssertUnwindSafe(|| f(&mut *h))) {
+        Ok(result) => result,
+        Err(_) => OtherError::err("panic in pippin"),
+    };
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = CString::new(e.to_string().replace('\0', " ")).expect("no NUL");
+            h.error = Some((ErrorCode::of(&*e), msg));
+            fail
+        },
+    }
+}
+
+// Get the pending state, starting one from the tip if necessary
+fn pending(h: &mut PippinPartition) -> Result<&mut MutPartState<Vec<u8>>> {
+    if h.state.is_none() {
+        h.state = Some(h.part.tip()?.clone_mut());
+    }
+    Ok(h.state.as_mut().expect("pending state"))
+}
+
+// Borrow a C string. Unsafe: `s` must be null or point to a NUL-terminated
+// string, valid for lifetime `'a`.
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return ArgError::err("string is null");
+    }
+    Ok(unsafe { CStr::from_ptr(s) }.to_str()?)
+}
+
+// Copy a buffer. Unsafe: `data` must be null or point to `len` readable bytes.
+unsafe fn to_vec(data: *const u8, len: usize) -> Result<Vec<u8>> {
+    if data.is_null() && len > 0 {
+        return ArgError::err("data is null");
+    }
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    Ok(unsafe { slice::from_raw_parts(data, len) }.to_vec())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+    use super::*;
+    
+    #[test]
+    fn c_calls() {
+        let name = CString::new("ffi").unwrap();
+        unsafe {
+            let p = pippin_create_mem(name.as_ptr());
+            assert!(!p.is_null());
+            
+            let data = b"an element";
+            let mut id = 0u64;
+            assert_eq!(pippin_insert(p, data.as_ptr(), data.len(), &mut id), 0);
+            assert_eq!(pippin_commit(p), 1);
+            assert_eq!(pippin_commit(p), 0);
+            assert_eq!(pippin_write(p), 0);
+            
+            let mut buf = [0u8; 32];
+            assert_eq!(pippin_get(p, id, ptr::null_mut(), 0), data.len() as i64);
+            assert_eq!(pippin_get(p, id, buf.as_mut_ptr(), buf.len()), data.len() as i64);
+            assert_eq!(&buf[..data.len()], data);
+            let mut sum = [0u8; SUM_BYTES];
+            assert_eq!(pippin_tip(p, sum.as_mut_ptr()), 0);
+            
+            assert_eq!(pippin_error_code(p), -1);
+            assert_eq!(pippin_remove(p, id + 1), -1);
+            assert_eq!(pippin_error_code(p), ErrorCode::Element as c_int);
+            let msg = CStr::from_ptr(pippin_error_message(p));
+            assert!(!msg.to_bytes().is_empty());
+            
+            assert_eq!(pippin_remove(p, id), 0);
+            assert_eq!(pippin_get(p, id, ptr::null_mut(), 0), -1);
+            assert_eq!(pippin_commit(p), 1);
+            pippin_free(p);
+            
+            assert!(pippin_create_mem(ptr::null()).is_null());
+            assert_eq!(pippin_load(ptr::null_mut(), 0), -1);
+        }
+    }
+}
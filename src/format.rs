@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: the low-level file format API.
+//! 
+//! This module collects what is needed to read and write Pippin files
+//! directly, without a `Partition` or `RepoIO`; for example in file viewers,
+//! converters or bindings for other languages. The format itself is
+//! described in `doc/file-format.md`.
+//! 
+//! Every file starts with a header (`read_head`, `write_head`) giving the
+//! file type and format version. The body is read with the function matching
+//! the file type, passing the version from the header, and the codec named
+//! by the header if any (see `select_codec`); `read_file` does all of this.
+//! Where the element type is not known, `Vec<u8>` may be used to get raw
+//! element data.
+//! 
+//! Items exported here are the supported interface to the file format; the
+//! modules under `rw` are implementation details and may be reorganised.
+//! 
+//! Example:
+//! 
+//! ```
+//! use pippin::format::{self, FileBody, ReadLimits};
+//! use pippin::pip::{Partition, DefaultControl, MemRepoIO};
+//! 
+//! let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+//! let part = Partition::create(control, "example").unwrap();
+//! let io = part.unwrap_control().unwrap_io();
+//! 
+//! let mut data = io.ss_data(0).unwrap();
+//! let (header, body) = format::read_file::<Vec<u8>>(&mut data,
+//!         &ReadLimits::default(), None).unwrap();
+//! assert_eq!(header.name, "example");
+//! match body {
+//!     FileBody::Snapshot(state) => assert_eq!(state.len(), 0),
+//!     _ => panic!("expected a snapshot"),
+//! }
+//! ```
+
+use std::io::Read;
+use std::rc::Rc;
+
+use commit::{Commit, Delta};
+use elt::Element;
+use error::Result;
+use state::PartState;
+
+pub use rw::{ReadLimits, LATEST_VERSION, CRC_VERSION, OLDEST_VERSION, DEFAULT_ALIGN,
+        is_supported_version, is_legacy_version, is_valid_align};
+pub use rw::header::{FileType, FileHeader, UserData, read_head, write_head, validate_repo_name};
+pub use rw::codec::{Codec, RawCodec, select as select_codec};
+pub use rw::snapshot::{EltVisitor, SnapshotInfo, read_snapshot, read_snapshot_with,
+        read_snapshot_unless, read_snapshot_meta, visit_snapshot, write_snapshot,
+        write_snapshot_ver, write_snapshot_with};
+pub use rw::commitlog::{CommitReceiver, CommitVisitor, Visit, CommitInfo, read_log, read_log_with,
+        read_log_meta, visit_log, start_log, write_commit, write_commit_ver, write_commit_with,
+        write_trailer};
+pub use rw::delta::{read_delta, write_delta};
+pub use rw::admin::{AdminOp, AdminRecord, read_admin_log, start_admin_log, write_record};
+pub use rw::logindex::{LogIndexEntry, read_log_index, write_log_index};
+
+/// The body of a file, as read by `read_file`
+#[derive(PartialEq, Debug)]
+pub enum FileBody<E: Element> {
+    /// A snapshot: a single state
+    Snapshot(PartState<E>),
+    /// A commit log: a sequence of commits (trailers are checked but not
+    /// returned)
+    CommitLog(Vec<Commit<E>>),
+    /// A delta between two states
+    Delta(Delta<E>),
+    /// An admin log: a sequence of records
+    AdminLog(Vec<AdminRecord>),
+}
+
+/// Read a whole file (header and body) from a stream.
+/// 
+/// Lengths read are checked against `limits`. If the header names a codec,
+/// `codec` must be this codec (see `select_codec`).
+pub fn read_file<E: Element>(reader: &mut Read, limits: &ReadLimits,
+        codec: Option<Rc<Codec>>) -> Result<(FileHeader, FileBody<E>)>
+{
+    let header = read_head(reader)?;
+    let codec = select_codec(&header, codec)?;
+    let ver = header.ftype.ver();
+    let align = header.align();
+    let body = match header.ftype {
+        FileType::Snapshot(_) => {
+            FileBody::Snapshot(read_snapshot_with(reader, ver, limits, &*codec, align)?)
+        },
+        FileType::CommitLog(_) => {
+            let mut commits = Vec::new();
+            read_log_with(reader, &mut commits, ver, limits, &*codec, align)?;
+            FileBody::CommitLog(commits)
+        },
+        FileType::Delta(_) => {
+            FileBody::Delta(read_delta(reader, ver, limits, &*codec, align)?)
+        },
+        FileType::AdminLog(_) => {
+            FileBody::AdminLog(read_admin_log(reader)?)
+        },
+    };
+    Ok((header, body))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rw::ReadLimits;
+    use testutil::{FixedMeta, random_history};
+    use rand::{SeedableRng, XorShiftRng};
+    
+    #[test]
+    fn read_files() {
+        let mut rng = XorShiftRng::from_seed([5, 31, 4, 1593]);
+        let (_, history) = random_history::<String, _>(&mut rng, 5, 10, &mut FixedMeta);
+        let (ref commit, ref state) = history[4];
+        
+        let mut header = FileHeader {
+            ftype: FileType::snapshot(),
+            name: "format".to_string(),
+            user: vec![],
+            codec: None,
+            align: None,
+            historical: false,
+            sealed: false,
+        };
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).expect("write_head");
+        write_snapshot(state, &mut buf).expect("write_snapshot");
+        let (head, body) = read_file::<String>(&mut &buf[..], &ReadLimits::default(), None)
+                .expect("read_file");
+        assert_eq!(head.name, "format");
+        assert_eq!(body, FileBody::Snapshot(state.clone_exact()));
+        
+        header.ftype = FileType::commit_log();
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).expect("write_head");
+        start_log(&mut buf).expect("start_log");
+        write_commit(commit, &mut buf).expect("write_commit");
+        let (_, body) = read_file::<String>(&mut &buf[..], &ReadLimits::default(), None)
+                .expect("read_file");
+        match body {
+            FileBody::CommitLog(commits) => assert!(commits.len() == 1 && commits[0] == *commit),
+            _ => panic!("expected a commit log"),
+        }
+        
+        // Raw element data may be read without knowing the element type:
+        let (_, body) = read_file::<Vec<u8>>(&mut &buf[..], &ReadLimits::default(), None)
+                .expect("read_file");
+        match body {
+            FileBody::CommitLog(commits) => assert_eq!(commits[0].statesum(), commit.statesum()),
+            _ => panic!("expected a commit log"),
+        }
+        
+        // A header naming an unknown codec is rejected:
+        header.codec = Some("unknown".to_string());
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).expect("write_head");
+        assert!(read_file::<String>(&mut &buf[..], &ReadLimits::default(), None).is_err());
+    }
+}
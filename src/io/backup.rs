@@ -0,0 +1,345 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: incremental backups between `RepoIO` providers.
+//! 
+//! `backup` copies snapshots and commit logs from a source to a destination,
+//! skipping files already backed up and extending commit logs which have been
+//! appended to since the last backup. A `Manifest` records the length and
+//! checksum of each file in the destination; it should be saved after each
+//! backup (`Manifest::write_to`) and passed to the next. `verify` checks a
+//! destination against a manifest.
+
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+
+use io::RepoIO;
+use error::{Result, OtherError, make_io_err};
+use rw::header::{FileType, read_head};
+use sum::Sum;
+
+const MANIFEST_HEAD: &'static str = "PIPPIN BACKUP MANIFEST";
+
+/// Identifies a snapshot or commit log by number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum FileId {
+    /// Snapshot, by snapshot number
+    Snapshot(usize),
+    /// Commit log, by snapshot number and log number
+    CommitLog(usize, usize),
+}
+
+/// Length and checksum of a file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileRecord {
+    /// Length in bytes
+    pub len: u64,
+    /// Checksum of the whole file
+    pub sum: Sum,
+}
+
+/// Record of files held in a backup.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Manifest {
+    files: BTreeMap<FileId, FileRecord>,
+}
+impl Manifest {
+    /// Create an empty manifest (for a new backup)
+    pub fn new() -> Manifest {
+        Manifest { files: BTreeMap::new() }
+    }
+    
+    /// Number of files recorded
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+    /// True if no files are recorded
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+    /// Get the record for a file, if present
+    pub fn get(&self, id: FileId) -> Option<&FileRecord> {
+        self.files.get(&id)
+    }
+    /// Iterate over all records, in order
+    pub fn iter(&self) -> Iter<FileId, FileRecord> {
+        self.files.iter()
+    }
+    
+    /// Write the manifest in a simple text format
+    pub fn write_to(&self, w: &mut Write) -> Result<()> {
+        writeln!(w, "{}", MANIFEST_HEAD)?;
+        for (id, rec) in &self.files {
+            match *id {
+                FileId::Snapshot(ss) => write!(w, "ss {}", ss)?,
+                FileId::CommitLog(ss, cl) => write!(w, "cl {} {}", ss, cl)?,
+            }
+            writeln!(w, " {} {}", rec.len, rec.sum.as_string(false))?;
+        }
+        Ok(())
+    }
+    
+    /// Read a manifest written by `write_to`
+    pub fn read_from(r: &mut Read) -> Result<Manifest> {
+        let mut lines = BufReader::new(r).lines();
+        match lines.next() {
+            Some(line) => if line?.trim_right() != MANIFEST_HEAD {
+                return OtherError::err("not a backup manifest");
+            },
+            None => return OtherError::err("not a backup manifest"),
+        }
+        let mut manifest = Manifest::new();
+        for line in lines {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() { continue; }
+            let (id, rest) = match (words[0], words.len()) {
+                ("ss", 4) => (parse_num(words[1]).map(FileId::Snapshot), &words[2..]),
+                ("cl", 5) => (parse_num(words[1]).and_then(|ss|
+                        parse_num(words[2]).map(|cl| FileId::CommitLog(ss, cl))), &words[3..]),
+                _ => (None, &words[..0]),
+            };
+            let len = rest.get(0).and_then(|s| s.parse::<u64>().ok());
+            let sum = rest.get(1).and_then(|s| Sum::from_hex(s));
+            match (id, len, sum) {
+                (Some(id), Some(len), Some(sum)) => {
+                    manifest.files.insert(id, FileRecord { len: len, sum: sum });
+                },
+                _ => return OtherError::err("invalid line in backup manifest"),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+fn parse_num(s: &str) -> Option<usize> {
+    s.parse::<usize>().ok()
+}
+
+/// Summary of actions taken by `backup`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BackupReport {
+    /// Files copied in full
+    pub copied: Vec<FileId>,
+    /// Commit logs extended with data appended to the source
+    pub appended: Vec<FileId>,
+    /// Number of files found to be up to date
+    pub unchanged: usize,
+}
+
+/// Back up all snapshots and commit logs from `src` to `dest`.
+/// 
+/// Files not present in `dest` are copied after checking that they start
+/// with a valid header of the expected type. Commit logs in `dest` which are
+/// a prefix of the source log (as logs are only ever appended to) are
+/// extended. Files whose length and checksum match the record in `manifest`
+/// are not read from `dest`; files in `dest` not recorded in `manifest` are
+/// read and checked. The written data is read back and checked, and
+/// `manifest` is updated.
+/// 
+/// Fails if a file in `dest` is neither identical to nor (for logs) a prefix
+/// of the source file; `manifest` is still updated for files processed
+/// before the failure, and should be saved.
+pub fn backup(src: &RepoIO, dest: &mut RepoIO, manifest: &mut Manifest) -> Result<BackupReport> {
+    let mut report = BackupReport::default();
+    for ss in src.list_ss() {
+        if src.has_ss(ss) {
+            backup_file(src, dest, manifest, FileId::Snapshot(ss), &mut report)?;
+        }
+        for cl in src.list_ss_cl(ss) {
+            backup_file(src, dest, manifest, FileId::CommitLog(ss, cl), &mut report)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Check all files recorded in `manifest` against `dest`.
+/// 
+/// Returns the list of files which are missing or whose length or checksum
+/// does not match (an empty list if the backup is good).
+pub fn verify(dest: &RepoIO, manifest: &Manifest) -> Result<Vec<FileId>> {
+    let mut bad = Vec::new();
+    for (id, rec) in manifest.iter() {
+        match read_file(dest, *id)? {
+            Some(mut r) => {
+                let (len, sum, _) = Sum::calculate_reader(&mut r, None)?;
+                if len != rec.len || sum != rec.sum {
+                    bad.push(*id);
+                }
+            },
+            None => bad.push(*id),
+        }
+    }
+    Ok(bad)
+}
+
+fn read_file<'a>(io: &'a RepoIO, id: FileId) -> Result<Option<Box<Read+'a>>> {
+    match id {
+        FileId::Snapshot(ss) => io.read_ss(ss),
+        FileId::CommitLog(ss, cl) => io.read_ss_cl(ss, cl),
+    }
+}
+
+fn backup_file(src: &RepoIO, dest: &mut RepoIO, manifest: &mut Manifest, id: FileId,
+        report: &mut BackupReport) -> Result<()>
+{
+    // What does dest currently hold?
+    let dest_rec = match manifest.get(id) {
+        Some(rec) => Some(rec.clone()),
+        None => match read_file(dest, id)? {
+            Some(mut r) => {
+                let (len, sum, _) = Sum::calculate_reader(&mut r, None)?;
+                Some(FileRecord { len: len, sum: sum })
+            },
+            None => None,
+        },
+    };
+    
+    let (len, sum, prefix) = match read_file(src, id)? {
+        Some(mut r) => Sum::calculate_reader(&mut r, dest_rec.as_ref().map(|rec| rec.len))?,
+        None => return Ok(()),   // log numbers may be sparse
+    };
+    let src_rec = FileRecord { len: len, sum: sum };
+    
+    match dest_rec {
+        Some(ref rec) if *rec == src_rec => {
+            report.unchanged += 1;
+        },
+        Some(ref rec) if prefix.as_ref() == Some(&rec.sum) => {
+            let skip = rec.len;
+            let mut r = match read_file(src, id)? {
+                Some(r) => r,
+                None => return make_io_err(io::ErrorKind::NotFound, "source file disappeared"),
+            };
+            io::copy(&mut (&mut r).take(skip), &mut io::sink())?;
+            let written = match id {
+                FileId::CommitLog(ss, cl) => copy_to(&mut r, dest.append_ss_cl(ss, cl)?)?,
+                FileId::Snapshot(_) => false,
+            };
+            if !written {
+                return OtherError::err("backup destination file differs from source");
+            }
+            check_copy(dest, id, &src_rec)?;
+            report.appended.push(id);
+        },
+        Some(_) => {
+            return OtherError::err("backup destination file differs from source");
+        },
+        None => {
+            let mut r = match read_file(src, id)? {
+                Some(r) => r,
+                None => return make_io_err(io::ErrorKind::NotFound, "source file disappeared"),
+            };
+            let ok = match (id, read_head(&mut r)?.ftype) {
+                (FileId::Snapshot(_), FileType::Snapshot(_)) => true,
+                (FileId::CommitLog(_, _), FileType::CommitLog(_)) => true,
+                _ => false,
+            };
+            if !ok {
+                return OtherError::err("file type in header does not match file name");
+            }
+            // Re-open to copy from the start:
+            let mut r = match read_file(src, id)? {
+                Some(r) => r,
+                None => return make_io_err(io::ErrorKind::NotFound, "source file disappeared"),
+            };
+            let written = match id {
+                FileId::Snapshot(ss) => copy_to(&mut r, dest.new_ss(ss)?)?,
+                FileId::CommitLog(ss, cl) => copy_to(&mut r, dest.new_ss_cl(ss, cl)?)?,
+            };
+            if !written {
+                return make_io_err(io::ErrorKind::AlreadyExists,
+                        "backup destination file already exists");
+            }
+            check_copy(dest, id, &src_rec)?;
+            report.copied.push(id);
+        },
+    }
+    manifest.files.insert(id, src_rec);
+    Ok(())
+}
+
+// Copy all data from `r` to `writer`, if not `None`. Returns true if copied.
+fn copy_to<'a>(r: &mut Read, writer: Option<Box<Write+'a>>) -> Result<bool> {
+    if let Some(mut w) = writer {
+        io::copy(r, &mut w)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+// Read back a file written to dest and check it matches.
+fn check_copy(dest: &RepoIO, id: FileId, expected: &FileRecord) -> Result<()> {
+    let (len, sum, _) = match read_file(dest, id)? {
+        Some(mut r) => Sum::calculate_reader(&mut r, None)?,
+        None => return OtherError::err("backup file not found after writing"),
+    };
+    if len != expected.len || sum != expected.sum {
+        return OtherError::err("backup file does not match source after writing");
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use control::DefaultControl;
+    use io::mem::MemRepoIO;
+    use part::Partition;
+    use state::StateWrite;
+    
+    type Part = Partition<DefaultControl<String, MemRepoIO>>;
+    
+    fn commit(part: &mut Part, text: &str) {
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new(text.to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+    }
+    
+    #[test]
+    fn incremental_backup() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "backup").expect("create");
+        commit(&mut part, "one");
+        let control = part.unwrap_control();
+        
+        let mut dest = MemRepoIO::new();
+        let mut manifest = Manifest::new();
+        let report = backup(control.io(), &mut dest, &mut manifest).expect("backup");
+        assert_eq!(report.copied, vec![FileId::Snapshot(0), FileId::CommitLog(0, 0)]);
+        assert_eq!(manifest.len(), 2);
+        
+        // Nothing new:
+        let report = backup(control.io(), &mut dest, &mut manifest).expect("backup");
+        assert_eq!(report.copied.len() + report.appended.len(), 0);
+        assert_eq!(report.unchanged, 2);
+        
+        // Extend the log in the source, then add a log and a snapshot:
+        let mut part = Partition::open(control, true).expect("open");
+        commit(&mut part, "two");
+        part.write_snapshot().expect("write_snapshot");
+        let mut control = part.unwrap_control();
+        control.io_mut().append_ss_cl(0, 0).expect("append").expect("log exists")
+            .write_all(b"appended").expect("write");
+        
+        let mut buf = Vec::new();
+        manifest.write_to(&mut buf).expect("write manifest");
+        let mut manifest = Manifest::read_from(&mut &buf[..]).expect("read manifest");
+        let report = backup(control.io(), &mut dest, &mut manifest).expect("backup");
+        assert_eq!(report.appended, vec![FileId::CommitLog(0, 0)]);
+        assert_eq!(report.copied, vec![FileId::CommitLog(0, 1), FileId::Snapshot(1)]);
+        assert_eq!(verify(&dest, &manifest).expect("verify"), vec![]);
+        assert_eq!(dest.ss_cl_data(0, 0), control.io().ss_cl_data(0, 0));
+        
+        // Corrupt the backup:
+        dest.append_ss_cl(0, 1).expect("append").expect("log exists")
+            .write_all(b"junk").expect("write");
+        assert_eq!(verify(&dest, &manifest).expect("verify"), vec![FileId::CommitLog(0, 1)]);
+    }
+}
@@ -0,0 +1,277 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: read-through caching of snapshots and commit logs.
+//! 
+//! `CachedRepoIO` wraps another `RepoIO`, keeping the content of recently
+//! read snapshots and commit logs in memory, up to a budget in bytes (the
+//! least recently used are dropped first). This speeds up repeated loading
+//! and verification (e.g. `Partition::verify_files`) on slow backends such as
+//! network object storage.
+//! 
+//! Files written through the wrapper are dropped from the cache. Files
+//! changed by other means (e.g. by another process, or via `io_mut`) are
+//! not detected: call `invalidate` or `clear` after such changes.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write, Cursor};
+use std::rc::Rc;
+
+use io::RepoIO;
+use io::backup::FileId;
+use error::Result;
+
+/// Caches data read from an inner `RepoIO` (see module documentation).
+#[derive(Debug)]
+pub struct CachedRepoIO<IO: RepoIO> {
+    io: IO,
+    budget: usize,
+    cache: RefCell<FileCache>,
+}
+impl<IO: RepoIO> CachedRepoIO<IO> {
+    /// Create, caching up to `budget` bytes of file content
+    pub fn new(io: IO, budget: usize) -> CachedRepoIO<IO> {
+        CachedRepoIO { io: io, budget: budget, cache: RefCell::new(FileCache::new()) }
+    }
+    /// Get a reference to the inner `RepoIO`
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+    /// Get a mutable reference to the inner `RepoIO`. Changes made via this
+    /// are not detected (see `invalidate`).
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+    /// Unwrap the inner `RepoIO`
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+    
+    /// Drop a file from the cache, e.g. after it was changed by another
+    /// process. Returns true if it was cached.
+    pub fn invalidate(&mut self, id: FileId) -> bool {
+        self.cache.borrow_mut().remove(id)
+    }
+    /// Drop all files from the cache
+    pub fn clear(&mut self) {
+        self.cache.borrow_mut().clear();
+    }
+    
+    /// Total length of cached content, in bytes
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.borrow().bytes
+    }
+    /// Number of reads served from the cache and from the inner `RepoIO`
+    /// (hits, misses), since creation
+    pub fn stats(&self) -> (usize, usize) {
+        let cache = self.cache.borrow();
+        (cache.hits, cache.misses)
+    }
+    
+    fn read_file<'a>(&'a self, id: FileId) -> Result<Option<Box<Read+'a>>> {
+        if let Some(data) = self.cache.borrow_mut().get(id) {
+            return Ok(Some(Box::new(Cursor::new(Shared(data)))));
+        }
+        let r = match id {
+            FileId::Snapshot(ss) => self.io.read_ss(ss)?,
+            FileId::CommitLog(ss, cl) => self.io.read_ss_cl(ss, cl)?,
+        };
+        let mut data = Vec::new();
+        match r {
+            Some(mut r) => { r.read_to_end(&mut data)?; },
+            None => return Ok(None),
+        }
+        let data = Rc::new(data);
+        self.cache.borrow_mut().insert(id, data.clone(), self.budget);
+        Ok(Some(Box::new(Cursor::new(Shared(data)))))
+    }
+}
+
+impl<IO: RepoIO> RepoIO for CachedRepoIO<IO> {
+    fn ss_len(&self) -> usize {
+        self.io.ss_len()
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.io.ss_cl_len(ss_num)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.io.has_ss(ss_num)
+    }
+    fn list_ss(&self) -> Vec<usize> {
+        self.io.list_ss()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        self.io.list_ss_cl(ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read_file(FileId::Snapshot(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read_file(FileId::CommitLog(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.invalidate(FileId::Snapshot(ss_num));
+        self.io.new_ss(ss_num)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        self.io.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.invalidate(FileId::CommitLog(ss_num, cl_num));
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.invalidate(FileId::CommitLog(ss_num, cl_num));
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.io.read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.write_ss_cl_index(ss_num, cl_num)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.write_gaps()
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_admin_log()
+    }
+}
+
+// Shared file content, readable via `Cursor`
+struct Shared(Rc<Vec<u8>>);
+impl AsRef<[u8]> for Shared {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// File content, dropping the least recently used when over budget
+#[derive(Debug)]
+struct FileCache {
+    files: HashMap<FileId, Rc<Vec<u8>>>,
+    // Keys, least recently used first
+    order: VecDeque<FileId>,
+    bytes: usize,
+    hits: usize,
+    misses: usize,
+}
+impl FileCache {
+    fn new() -> FileCache {
+        FileCache { files: HashMap::new(), order: VecDeque::new(), bytes: 0, hits: 0, misses: 0 }
+    }
+    // Get a file, marking it as most recently used
+    fn get(&mut self, id: FileId) -> Option<Rc<Vec<u8>>> {
+        match self.files.get(&id) {
+            Some(data) => {
+                self.order.retain(|k| *k != id);
+                self.order.push_back(id);
+                self.hits += 1;
+                Some(data.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+    // Insert a file, then drop others while over `budget`. Files larger than
+    // the budget are not cached.
+    fn insert(&mut self, id: FileId, data: Rc<Vec<u8>>, budget: usize) {
+        if data.len() > budget {
+            return;
+        }
+        self.remove(id);
+        self.bytes += data.len();
+        self.order.push_back(id);
+        self.files.insert(id, data);
+        while self.bytes > budget {
+            let id = self.order.pop_front().unwrap();
+            self.bytes -= self.files.remove(&id).unwrap().len();
+        }
+    }
+    fn remove(&mut self, id: FileId) -> bool {
+        match self.files.remove(&id) {
+            Some(data) => {
+                self.order.retain(|k| *k != id);
+                self.bytes -= data.len();
+                true
+            },
+            None => false,
+        }
+    }
+    fn clear(&mut self) {
+        self.files.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::conformance::check;
+    use io::mem::MemRepoIO;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::StateWrite;
+    
+    #[test]
+    fn conformance() {
+        check(|io| io.unwrap_or_else(|| CachedRepoIO::new(MemRepoIO::new(), 1 << 20)))
+                .expect("CachedRepoIO conformance");
+    }
+    
+    #[test]
+    fn cached_reads() {
+        let io = CachedRepoIO::new(MemRepoIO::new(), 1 << 20);
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io), "cache test")
+                .expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("element".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.stats(), (0, 0));
+        
+        // Loading twice reads each file from the inner `RepoIO` once
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        let io = part.unwrap_control().unwrap_io();
+        let (_, misses) = io.stats();
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        part.verify_files().expect("verify_files");
+        let mut io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.stats().1, misses);
+        assert!(io.stats().0 >= misses);
+        let len = io.io().ss_data(0).unwrap().len() + io.io().ss_cl_data(0, 0).unwrap().len();
+        assert_eq!(io.cached_bytes(), len);
+        
+        // Appending drops the log; invalidation and the budget drop others
+        io.append_ss_cl(0, 0).expect("append").expect("log exists");
+        assert_eq!(io.cached_bytes(), io.io().ss_data(0).unwrap().len());
+        assert!(io.invalidate(FileId::Snapshot(0)));
+        assert!(!io.invalidate(FileId::Snapshot(0)));
+        assert_eq!(io.cached_bytes(), 0);
+        let mut io = CachedRepoIO::new(io.unwrap_io(), len - 1);
+        io.read_ss(0).expect("read_ss");
+        io.read_ss_cl(0, 0).expect("read_ss_cl");
+        assert!(io.cached_bytes() < len);
+        io.clear();
+        assert_eq!(io.cached_bytes(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: conformance checks for `RepoIO` implementations.
+//! 
+//! Use `check` to test a custom `RepoIO` (e.g. a new storage backend) against
+//! the behaviour `Partition` expects.
+
+use std::io::{Read, Write};
+
+use io::RepoIO;
+use error::{Result, OtherError};
+
+/// Check a `RepoIO` implementation against the expected behaviour. This
+/// tests creation, reading, numbering and appending of snapshots and commit
+/// logs, that data is still available after "reopening" and that reserved
+/// snapshot numbers can be written.
+/// 
+/// `factory` is called with `None` to create an instance on new, empty
+/// storage. It is later called with `Some(io)`, where `io` is an instance
+/// previously returned; it should close `io` and return a new instance
+/// accessing the same storage (for an in-memory implementation it may simply
+/// return `io`).
+/// 
+/// Returns an `OtherError` describing the first failure found, or passes on
+/// any error returned by `io`.
+/// 
+/// Example:
+/// 
+/// ```
+/// use pippin::io::conformance;
+/// use pippin::pip::MemRepoIO;
+/// 
+/// conformance::check(|io| io.unwrap_or_else(MemRepoIO::new)).unwrap();
+/// ```
+pub fn check<IO: RepoIO, F: FnMut(Option<IO>) -> IO>(mut factory: F) -> Result<()> {
+    let ss0: &[u8] = b"snapshot zero";
+    let ss2: &[u8] = b"snapshot two";
+    let cl0: &[u8] = b"log zero";
+    let cl0_ext: &[u8] = b", extended";
+    let cl2: &[u8] = b"log two";
+    
+    let mut io = factory(None);
+    require(io.ss_len() == 0, "ss_len() of new storage must be 0")?;
+    require(!io.has_ss(0), "has_ss(0) on new storage must be false")?;
+    require(io.ss_cl_len(0) == 0, "ss_cl_len(0) on new storage must be 0")?;
+    require(io.read_ss(0)?.is_none(), "read_ss(0) on new storage must return None")?;
+    require(io.read_ss_cl(0, 0)?.is_none(),
+            "read_ss_cl(0, 0) on new storage must return None")?;
+    require(io.append_ss_cl(0, 0)?.is_none(),
+            "append_ss_cl(0, 0) on new storage must return None")?;
+    
+    // Snapshot creation and numbering
+    write_new(io.new_ss(0)?, ss0, "new_ss(0) on new storage must return a writer")?;
+    require(io.ss_len() == 1, "ss_len() must be 1 after new_ss(0)")?;
+    require(io.has_ss(0), "has_ss(0) must be true after new_ss(0)")?;
+    require(io.new_ss(0)?.is_none(), "new_ss(0) must return None when snapshot 0 exists")?;
+    require(read_all(io.read_ss(0)?)? == Some(ss0.to_vec()),
+            "read_ss(0) must return data written")?;
+    
+    // Log creation, numbering and appending
+    write_new(io.new_ss_cl(0, 0)?, cl0, "new_ss_cl(0, 0) must return a writer")?;
+    require(io.ss_cl_len(0) == 1, "ss_cl_len(0) must be 1 after new_ss_cl(0, 0)")?;
+    require(io.new_ss_cl(0, 0)?.is_none(),
+            "new_ss_cl(0, 0) must return None when log exists")?;
+    require(io.append_ss_cl(0, 1)?.is_none(),
+            "append_ss_cl(0, 1) must return None when log does not exist")?;
+    write_new(io.append_ss_cl(0, 0)?, cl0_ext,
+            "append_ss_cl(0, 0) must return a writer when log exists")?;
+    let expected = [cl0, cl0_ext].concat();
+    require(read_all(io.read_ss_cl(0, 0)?)? == Some(expected.clone()),
+            "read_ss_cl(0, 0) must return all data written and appended")?;
+    require(io.ss_len() == 1, "ss_len() must not be changed by writing logs")?;
+    
+    // Sparse numbering: ss_len() is one greater than the latest snapshot
+    write_new(io.new_ss(2)?, ss2, "new_ss(2) must return a writer")?;
+    require(io.ss_len() == 3, "ss_len() must be 3 after new_ss(2)")?;
+    require(!io.has_ss(1), "has_ss(1) must be false when only snapshots 0 and 2 exist")?;
+    require(io.read_ss(1)?.is_none(),
+            "read_ss(1) must return None when snapshot 1 does not exist")?;
+    require(io.ss_cl_len(1) == 0, "ss_cl_len(1) must be 0 when no logs exist")?;
+    write_new(io.new_ss_cl(2, 1)?, cl2, "new_ss_cl(2, 1) must return a writer")?;
+    require(io.ss_cl_len(2) == 2, "ss_cl_len(2) must be 2 after new_ss_cl(2, 1)")?;
+    require(io.read_ss_cl(2, 0)?.is_none(),
+            "read_ss_cl(2, 0) must return None when log does not exist")?;
+    
+    // Reopening must preserve everything
+    let mut io = factory(Some(io));
+    require(io.ss_len() == 3, "ss_len() must be preserved on reopening")?;
+    require(io.has_ss(0) && !io.has_ss(1) && io.has_ss(2),
+            "has_ss() must be preserved on reopening")?;
+    require(io.ss_cl_len(0) == 1 && io.ss_cl_len(2) == 2,
+            "ss_cl_len() must be preserved on reopening")?;
+    require(read_all(io.read_ss(0)?)? == Some(ss0.to_vec()),
+            "read_ss(0) data must be preserved on reopening")?;
+    require(read_all(io.read_ss(2)?)? == Some(ss2.to_vec()),
+            "read_ss(2) data must be preserved on reopening")?;
+    require(read_all(io.read_ss_cl(0, 0)?)? == Some(expected),
+            "read_ss_cl(0, 0) data must be preserved on reopening")?;
+    require(read_all(io.read_ss_cl(2, 1)?)? == Some(cl2.to_vec()),
+            "read_ss_cl(2, 1) data must be preserved on reopening")?;
+    
+    // Reservation (which need not reserve anything)
+    let num = io.reserve_ss(1)?;
+    require(num >= 1 && num != 2, "reserve_ss(1) must return a free number no less than 1")?;
+    write_new(io.new_ss(num)?, ss2, "new_ss() must return a writer for a reserved number")?;
+    require(io.has_ss(num), "has_ss() must be true after writing a reserved number")?;
+    
+    Ok(())
+}
+
+fn require(cond: bool, msg: &'static str) -> Result<()> {
+    if cond { Ok(()) } else { OtherError::err(msg) }
+}
+
+fn write_new<'a>(writer: Option<Box<Write+'a>>, data: &[u8], msg: &'static str) -> Result<()> {
+    if let Some(mut w) = writer {
+        w.write_all(data)?;
+        w.flush()?;
+        Ok(())
+    } else {
+        OtherError::err(msg)
+    }
+}
+
+fn read_all<'a>(reader: Option<Box<Read+'a>>) -> Result<Option<Vec<u8>>> {
+    if let Some(mut r) = reader {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        Ok(Some(buf))
+    } else {
+        Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use io::mem::MemRepoIO;
+    
+    #[test]
+    fn mem_repo_io() {
+        check(|io| io.unwrap_or_else(MemRepoIO::new)).expect("MemRepoIO conformance");
+    }
+    
+    #[cfg(feature = "fs")]
+    #[test]
+    fn repo_file_io() {
+        use std::env;
+        use std::fs;
+        use io::discover::part_from_path;
+        use io::file::RepoFileIO;
+        
+        let dir = env::temp_dir().join("pippin-conformance-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create_dir_all");
+        let prefix = dir.join("test");
+        // Reopen by discovering the files written from the directory
+        let result = check(|io: Option<RepoFileIO>| match io {
+            None => RepoFileIO::new(prefix.clone()),
+            Some(_) => part_from_path(&dir).expect("discovering files"),
+        });
+        let _ = fs::remove_dir_all(&dir);
+        result.expect("RepoFileIO conformance");
+    }
+    
+    #[cfg(feature = "kv")]
+    #[test]
+    fn kv_repo_io() {
+        use std::collections::BTreeMap;
+        use io::kv::KvRepoIO;
+        
+        // Reopen by saving entries to a store and restoring from it
+        let mut store = BTreeMap::new();
+        check(|io: Option<KvRepoIO>| match io {
+            None => KvRepoIO::new(),
+            Some(mut io) => {
+                for key in io.take_dirty() {
+                    store.insert(key.clone(), io.get(&key).unwrap().to_vec());
+                }
+                KvRepoIO::from_entries(store.clone()).expect("from_entries")
+            }
+        }).expect("KvRepoIO conformance");
+    }
+}
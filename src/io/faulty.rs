@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: fault injection, for testing.
+//! 
+//! `FaultyRepoIO` wraps another `RepoIO`, injecting scripted faults: failing
+//! writes, short reads, and truncated or corrupted files. Faults are
+//! deterministic, allowing repeatable tests of error handling and recovery
+//! (in this library, or of a custom `Control` or application).
+
+use std::cell::Cell;
+use std::io::{self, Read, Write, ErrorKind};
+
+use io::RepoIO;
+use io::backup::FileId;
+use error::Result;
+
+/// A fault to inject (see `FaultyRepoIO::inject`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail write operation number N (counting calls to `write` on all
+    /// streams opened for writing, from zero; see `FaultyRepoIO::writes`).
+    /// The failing operation writes nothing.
+    FailWrite(usize),
+    /// Fail write operation number N after writing only the given number of
+    /// bytes of it (a torn write, as when crashing part-way)
+    TornWrite(usize, usize),
+    /// Return at most this many bytes from each read operation (valid, but
+    /// exercises readers' handling of short reads)
+    ShortReads(usize),
+    /// Present the file as if truncated to the given length
+    Truncate(FileId, usize),
+    /// Corrupt the file by flipping the bits of the byte at this position
+    Corrupt(FileId, usize),
+}
+
+/// Injects faults into an inner `RepoIO` (see module documentation).
+/// 
+/// Faults affect streams opened after they are injected; the inner `RepoIO`
+/// is never modified except by (possibly torn) writes.
+#[derive(Debug)]
+pub struct FaultyRepoIO<IO: RepoIO> {
+    io: IO,
+    faults: Vec<Fault>,
+    writes: Cell<usize>,
+}
+impl<IO: RepoIO> FaultyRepoIO<IO> {
+    /// Create, with no faults
+    pub fn new(io: IO) -> FaultyRepoIO<IO> {
+        FaultyRepoIO { io: io, faults: Vec::new(), writes: Cell::new(0) }
+    }
+    /// Add a fault
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+    /// Remove all faults
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+    /// Get the faults injected
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+    /// Number of write operations so far, including failed ones
+    pub fn writes(&self) -> usize {
+        self.writes.get()
+    }
+    /// Get a reference to the inner `RepoIO`
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+    /// Get a mutable reference to the inner `RepoIO` (no faults are injected)
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+    /// Unwrap the inner `RepoIO`
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+    
+    fn reader<'a>(&'a self, id: FileId, r: Result<Option<Box<Read+'a>>>) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        let mut reader = FaultyRead { inner: None, pos: 0, max_read: None, len: None,
+                corrupt: vec![] };
+        for fault in &self.faults {
+            match *fault {
+                Fault::ShortReads(n) => reader.max_read = Some(n),
+                Fault::Truncate(f, len) if f == id => reader.len = Some(len),
+                Fault::Corrupt(f, pos) if f == id => reader.corrupt.push(pos),
+                _ => {},
+            }
+        }
+        Ok(r?.map(|r| {
+            reader.inner = Some(r);
+            Box::new(reader) as Box<Read+'a>
+        }))
+    }
+}
+
+// Wrap a write stream (not a method since the inner `RepoIO` is borrowed
+// mutably while the counter is borrowed immutably)
+fn writer<'a>(w: Result<Option<Box<Write+'a>>>, faults: &'a [Fault], writes: &'a Cell<usize>) ->
+        Result<Option<Box<Write+'a>>>
+{
+    Ok(w?.map(|w| Box::new(FaultyWrite { inner: w, faults: faults, writes: writes })
+            as Box<Write+'a>))
+}
+
+impl<IO: RepoIO> RepoIO for FaultyRepoIO<IO> {
+    fn ss_len(&self) -> usize {
+        self.io.ss_len()
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.io.ss_cl_len(ss_num)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.io.has_ss(ss_num)
+    }
+    fn list_ss(&self) -> Vec<usize> {
+        self.io.list_ss()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        self.io.list_ss_cl(ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.reader(FileId::Snapshot(ss_num), self.io.read_ss(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.reader(FileId::CommitLog(ss_num, cl_num), self.io.read_ss_cl(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        writer(self.io.new_ss(ss_num), &self.faults, &self.writes)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        self.io.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        writer(self.io.append_ss_cl(ss_num, cl_num), &self.faults, &self.writes)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        writer(self.io.new_ss_cl(ss_num, cl_num), &self.faults, &self.writes)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.io.read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.write_ss_cl_index(ss_num, cl_num)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.write_gaps()
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_admin_log()
+    }
+}
+
+struct FaultyRead<'a> {
+    // Always set once returned
+    inner: Option<Box<Read+'a>>,
+    pos: usize,
+    max_read: Option<usize>,
+    len: Option<usize>,
+    corrupt: Vec<usize>,
+}
+impl<'a> Read for FaultyRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = buf.len();
+        if let Some(max) = self.max_read {
+            n = n.min(max);
+        }
+        if let Some(len) = self.len {
+            n = n.min(len.saturating_sub(self.pos));
+        }
+        let n = self.inner.as_mut().expect("inner").read(&mut buf[..n])?;
+        for pos in &self.corrupt {
+            if *pos >= self.pos && *pos < self.pos + n {
+                buf[*pos - self.pos] ^= 0xFF;
+            }
+        }
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct FaultyWrite<'a> {
+    inner: Box<Write+'a>,
+    faults: &'a [Fault],
+    writes: &'a Cell<usize>,
+}
+impl<'a> Write for FaultyWrite<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num = self.writes.get();
+        self.writes.set(num + 1);
+        for fault in self.faults {
+            match *fault {
+                Fault::FailWrite(n) if n == num => {
+                    return Err(io::Error::new(ErrorKind::Other, "injected write failure"));
+                },
+                Fault::TornWrite(n, len) if n == num => {
+                    self.inner.write_all(&buf[..len.min(buf.len())])?;
+                    return Err(io::Error::new(ErrorKind::Other, "injected torn write"));
+                },
+                _ => {},
+            }
+        }
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::conformance::check;
+    use io::mem::MemRepoIO;
+    use control::DefaultControl;
+    use part::{Partition, LoadWarning};
+    use state::StateWrite;
+    
+    #[test]
+    fn conformance() {
+        check(|io| io.unwrap_or_else(|| FaultyRepoIO::new(MemRepoIO::new())))
+                .expect("FaultyRepoIO conformance");
+        check(|io| io.unwrap_or_else(|| {
+            let mut io = FaultyRepoIO::new(MemRepoIO::new());
+            io.inject(Fault::ShortReads(3));
+            io
+        })).expect("FaultyRepoIO conformance with short reads");
+    }
+    
+    #[test]
+    fn faults() {
+        let control = DefaultControl::<String, _>::new(FaultyRepoIO::new(MemRepoIO::new()));
+        let mut part = Partition::create(control, "faults").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let tip = part.tip_key().expect("tip").clone();
+        let mut io = part.unwrap_control().unwrap_io();
+        
+        // Short reads do not affect loading
+        io.inject(Fault::ShortReads(1));
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        let mem = part.unwrap_control().unwrap_io().unwrap_io();
+        
+        // Corruption is detected; truncation loses the commit with a warning
+        let len = mem.ss_cl_data(0, 0).expect("log").len();
+        let mut io = FaultyRepoIO::new(mem.clone());
+        io.inject(Fault::Corrupt(FileId::CommitLog(0, 0), len - 20));
+        assert!(Partition::open(DefaultControl::<String, _>::new(io), true).is_err());
+        let mut io = FaultyRepoIO::new(mem.clone());
+        io.inject(Fault::Truncate(FileId::CommitLog(0, 0), len - 20));
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert!(part.tip_key().expect("tip") != &tip);
+        assert_eq!(part.load_report().warnings, vec![LoadWarning::TruncatedLog(0, 0)]);
+        
+        // A failed write leaves the commit unsaved
+        let io = FaultyRepoIO::new(mem);
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("two".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        let writes = part.control().io().writes();
+        part.control_mut().io_mut().inject(Fault::FailWrite(writes));
+        assert!(part.write_fast().is_err());
+        assert_eq!(part.unsaved_len(), 1);
+        part.control_mut().io_mut().clear_faults();
+        part.write_fast().expect("write_fast");
+        assert_eq!(part.unsaved_len(), 0);
+        assert!(part.control().io().writes() > writes + 1);
+    }
+}
@@ -5,13 +5,15 @@
 //! Pippin: data access for repositories.
 
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions};
+use std::collections::BTreeSet;
+use std::io::{Read, Write, ErrorKind};
+use std::fs::{self, File, OpenOptions};
 use std::ops::Add;
 
 use vec_map::{VecMap, Entry};
 
 use io::RepoIO;
+use io::backup::FileId;
 use error::{Result, ReadOnly};
 
 
@@ -97,6 +99,9 @@ pub struct RepoFileIO {
     // Appended with snapshot/log number and extension to get a file path
     prefix: PathBuf,
     paths: PartPaths,
+    // Snapshot numbers reserved by `reserve_ss` (as empty files) and not yet
+    // written
+    reserved: BTreeSet<usize>,
 }
 
 impl RepoFileIO {
@@ -122,6 +127,7 @@ impl RepoFileIO {
             readonly: false,
             prefix: prefix,
             paths: paths,
+            reserved: BTreeSet::new(),
         }
     }
     
@@ -149,6 +155,68 @@ impl RepoFileIO {
     pub fn mut_paths(&mut self) -> &mut PartPaths {
         &mut self.paths
     }
+    /// Get the path of snapshot `ss_num` (the prefix appended with
+    /// `-ssS.pip`); this file may not exist
+    pub fn ss_path(&self, ss_num: usize) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(format!("-ss{}.pip", ss_num));
+        PathBuf::from(p)
+    }
+    /// Get the path of the index of commit log `cl_num` of snapshot `ss_num`
+    /// (the prefix appended with `-ssS-clL.pipidx`); this file may not exist
+    pub fn cl_index_path(&self, ss_num: usize, cl_num: usize) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(format!("-ss{}-cl{}.pipidx", ss_num, cl_num));
+        PathBuf::from(p)
+    }
+    /// Get the path of the gap index (the prefix appended with
+    /// `-gaps.pipidx`); this file may not exist
+    pub fn gaps_path(&self) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push("-gaps.pipidx");
+        PathBuf::from(p)
+    }
+    /// Get the path of the admin log (the prefix appended with
+    /// `-admin.pipadm`); this file may not exist
+    pub fn admin_log_path(&self) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push("-admin.pipadm");
+        PathBuf::from(p)
+    }
+    
+    /// Delete a snapshot or commit log file, e.g. after packing (see
+    /// `io::pack`), along with a commit log's index if any. Returns false if
+    /// the file is not known.
+    pub fn remove_file(&mut self, id: FileId) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let path = match (id, self.paths.paths.get_mut(id_ss(id))) {
+            (FileId::Snapshot(_), Some(&mut (ref mut ss, _))) => ss.take(),
+            (FileId::CommitLog(_, cl), Some(&mut (_, ref mut logs))) => logs.remove(cl),
+            (_, None) => None,
+        };
+        if let Some(path) = path {
+            trace!("Removing file: {}", path.display());
+            fs::remove_file(path)?;
+            if let FileId::CommitLog(ss, cl) = id {
+                let index = self.cl_index_path(ss, cl);
+                if index.exists() {
+                    trace!("Removing log index: {}", index.display());
+                    fs::remove_file(index)?;
+                }
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+fn id_ss(id: FileId) -> usize {
+    match id {
+        FileId::Snapshot(ss) | FileId::CommitLog(ss, _) => ss,
+    }
 }
 
 impl RepoIO for RepoFileIO {
@@ -192,15 +260,22 @@ impl RepoIO for RepoFileIO {
         if self.readonly {
             return ReadOnly::err();
         }
-        let mut p = self.prefix.as_os_str().to_os_string();
-        p.push(format!("-ss{}.pip", ss_num));
-        let p = PathBuf::from(p);
-        if self.paths.paths.get(ss_num).map_or(false, |&(ref p, _)| p.is_some()) || p.exists() {
-            // File already exists in internal map or on filesystem
+        let p = self.ss_path(ss_num);
+        let stream = if self.reserved.remove(&ss_num) {
+            trace!("Writing reserved snapshot file: {}", p.display());
+            File::create(&p)?
+        } else if self.has_ss(ss_num) {
+            // File already exists in internal map
             return Ok(None);
-        }
-        trace!("Creating snapshot file: {}", p.display());
-        let stream = File::create(&p)?;
+        } else {
+            trace!("Creating snapshot file: {}", p.display());
+            match OpenOptions::new().write(true).create_new(true).open(&p) {
+                Ok(stream) => stream,
+                // File already exists on filesystem
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => return Ok(None),
+                Err(e) => return Err(Box::new(e)),
+            }
+        };
         match self.paths.paths.entry(ss_num) {
             Entry::Occupied(mut entry) => { entry.get_mut().0 = Some(p); },
             Entry::Vacant(entry) => { entry.insert((Some(p), VecMap::new())); },
@@ -208,6 +283,30 @@ impl RepoIO for RepoFileIO {
         Ok(Some(Box::new(stream)))
     }
     
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        // Create an empty file, which no other writer can then create. There
+        // are only finitely many files, so this terminates.
+        let mut ss_num = ss_num;
+        loop {
+            if !self.has_ss(ss_num) && !self.reserved.contains(&ss_num) {
+                let p = self.ss_path(ss_num);
+                match OpenOptions::new().write(true).create_new(true).open(&p) {
+                    Ok(_) => {
+                        trace!("Reserved snapshot file: {}", p.display());
+                        self.reserved.insert(ss_num);
+                        return Ok(ss_num);
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {},
+                    Err(e) => return Err(Box::new(e)),
+                }
+            }
+            ss_num += 1;
+        }
+    }
+    
     fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
         if self.readonly {
             return ReadOnly::err();
@@ -237,4 +336,96 @@ impl RepoIO for RepoFileIO {
         logs.insert(cl_num, p);
         Ok(Some(Box::new(stream)))
     }
+    
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        let p = self.cl_index_path(ss_num, cl_num);
+        if !p.exists() {
+            return Ok(None);
+        }
+        trace!("Reading log index: {}", p.display());
+        Ok(Some(Box::new(File::open(p)?)))
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = self.cl_index_path(ss_num, cl_num);
+        trace!("Writing log index: {}", p.display());
+        Ok(Some(Box::new(File::create(p)?)))
+    }
+    
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let p = self.gaps_path();
+        if !p.exists() {
+            return Ok(None);
+        }
+        trace!("Reading gap index: {}", p.display());
+        Ok(Some(Box::new(File::open(p)?)))
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = self.gaps_path();
+        trace!("Writing gap index: {}", p.display());
+        Ok(Some(Box::new(File::create(p)?)))
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        let p = self.admin_log_path();
+        if !p.exists() {
+            return Ok(None);
+        }
+        trace!("Reading admin log: {}", p.display());
+        Ok(Some(Box::new(File::open(p)?)))
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let p = self.admin_log_path();
+        trace!("Appending to admin log: {}", p.display());
+        let stream = OpenOptions::new().create(true).write(true).append(true).open(&p)?;
+        Ok(Some(Box::new(stream)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::*;
+    
+    #[test]
+    fn reserve_ss() {
+        let dir = env::temp_dir().join("pippin-reserve-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create_dir_all");
+        let prefix = dir.join("test");
+        
+        // Two writers on the same files get distinct numbers
+        let mut a = RepoFileIO::new(prefix.clone());
+        let mut b = RepoFileIO::new(prefix.clone());
+        assert_eq!(a.reserve_ss(0).expect("reserve_ss"), 0);
+        assert_eq!(b.reserve_ss(0).expect("reserve_ss"), 1);
+        assert_eq!(a.reserve_ss(0).expect("reserve_ss"), 2);
+        assert!(b.new_ss(0).expect("new_ss").is_none());
+        a.new_ss(0).expect("new_ss").expect("writer").write_all(b"a").expect("write");
+        b.new_ss(1).expect("new_ss").expect("writer").write_all(b"b").expect("write");
+        assert!(a.has_ss(0) && !a.has_ss(1));
+        assert!(b.has_ss(1) && !b.has_ss(0));
+        
+        // Unreserved numbers are created only if not present on disk
+        assert!(b.new_ss(2).expect("new_ss").is_none());
+        assert!(b.new_ss(3).expect("new_ss").is_some());
+        
+        let mut ro = RepoFileIO::new(prefix);
+        ro.set_readonly(true);
+        assert!(ro.reserve_ss(0).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,409 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: data access via a key-value store, such as IndexedDB or
+//! localStorage in web browsers (requires the `kv` feature).
+//! 
+//! Browser storage APIs are asynchronous while `RepoIO` is synchronous, thus
+//! `KvRepoIO` works on an in-memory copy of the store's entries (held by a
+//! `MemRepoIO`): the application loads all entries for a partition before
+//! opening it, then after each write saves the entries modified back to the
+//! store. With a store implementing `AsyncStore` this is done by `load` and
+//! `save`; otherwise use `KvRepoIO::from_entries`, `take_dirty` and `get`
+//! directly.
+//! 
+//! Keys are `ssN` for snapshots, `ssN-clM` for commit logs, `ssN-clM-index`
+//! for commit log indexes, `gaps` for the gap index and `admin` for the
+//! admin log, matching file names (see `doc/repo-files.md`).
+//! 
+//! This module has no dependency on browser APIs; bindings (e.g. via
+//! `wasm-bindgen`) are left to the application.
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use io::RepoIO;
+use io::mem::MemRepoIO;
+use error::{Result, OtherError};
+
+type Data = Vec<u8>;
+
+/// Key of the gap index
+pub const GAPS_KEY: &'static str = "gaps";
+
+/// Key of the admin log
+pub const ADMIN_KEY: &'static str = "admin";
+
+/// Key of snapshot `ss_num`
+pub fn ss_key(ss_num: usize) -> String {
+    format!("ss{}", ss_num)
+}
+
+/// Key of commit log `cl_num` of snapshot `ss_num`
+pub fn cl_key(ss_num: usize, cl_num: usize) -> String {
+    format!("ss{}-cl{}", ss_num, cl_num)
+}
+
+/// Key of the index of commit log `cl_num` of snapshot `ss_num`
+pub fn index_key(ss_num: usize, cl_num: usize) -> String {
+    format!("ss{}-cl{}-index", ss_num, cl_num)
+}
+
+// Entry named by a key
+#[derive(Debug, PartialEq)]
+enum Key {
+    Ss(usize),
+    Cl(usize, usize),
+    Index(usize, usize),
+    Gaps,
+    Admin,
+}
+
+// Parse a key; None if not a key used by KvRepoIO
+fn parse_key(key: &str) -> Option<Key> {
+    if key == GAPS_KEY {
+        return Some(Key::Gaps);
+    }
+    if key == ADMIN_KEY {
+        return Some(Key::Admin);
+    }
+    if !key.starts_with("ss") {
+        return None;
+    }
+    let mut parts = key[2..].splitn(2, "-cl");
+    let ss = parts.next().and_then(|n| n.parse().ok());
+    match (ss, parts.next()) {
+        (Some(ss), None) => Some(Key::Ss(ss)),
+        (Some(ss), Some(cl)) => {
+            match cl.strip_suffix("-index") {
+                Some(cl) => cl.parse().ok().map(|cl| Key::Index(ss, cl)),
+                None => cl.parse().ok().map(|cl| Key::Cl(ss, cl)),
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Stores snapshots and commit logs as entries of a key-value store, via an
+/// in-memory copy (see module documentation).
+#[derive(Clone, Debug, Default)]
+pub struct KvRepoIO {
+    io: MemRepoIO,
+    // Keys of entries written since the last call to take_dirty()
+    dirty: BTreeSet<String>,
+}
+impl KvRepoIO {
+    /// Create an empty instance (for a new partition)
+    pub fn new() -> KvRepoIO {
+        KvRepoIO::default()
+    }
+    
+    /// Create from all the entries of a store. Fails on encountering a key
+    /// not used by this type.
+    pub fn from_entries<I: IntoIterator<Item = (String, Data)>>(entries: I) -> Result<KvRepoIO> {
+        let mut io = MemRepoIO::new();
+        for (key, data) in entries {
+            match parse_key(&key) {
+                Some(Key::Ss(ss)) => io.set_ss_data(ss, data),
+                Some(Key::Cl(ss, cl)) => io.set_ss_cl_data(ss, cl, data),
+                Some(Key::Index(ss, cl)) => io.set_ss_cl_index_data(ss, cl, data),
+                Some(Key::Gaps) => io.set_gaps_data(data),
+                Some(Key::Admin) => io.set_admin_data(data),
+                None => return OtherError::err(format!("unexpected key in store: {}", key)),
+            }
+        }
+        Ok(KvRepoIO { io: io, dirty: BTreeSet::new() })
+    }
+    
+    /// Get the contents of an entry, if present
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        match parse_key(key) {
+            Some(Key::Ss(ss)) => self.io.ss_data(ss),
+            Some(Key::Cl(ss, cl)) => self.io.ss_cl_data(ss, cl),
+            Some(Key::Index(ss, cl)) => self.io.ss_cl_index_data(ss, cl),
+            Some(Key::Gaps) => self.io.gaps_data(),
+            Some(Key::Admin) => self.io.admin_data(),
+            None => None,
+        }
+    }
+    
+    /// Get the keys of all entries present, in order
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = BTreeSet::new();
+        for ss in self.io.ss_nums() {
+            if self.io.has_ss(ss) {
+                keys.insert(ss_key(ss));
+            }
+            for cl in self.io.ss_cl_nums(ss) {
+                keys.insert(cl_key(ss, cl));
+            }
+        }
+        for (ss, cl) in self.io.ss_cl_index_nums() {
+            keys.insert(index_key(ss, cl));
+        }
+        if self.io.gaps_data().is_some() {
+            keys.insert(GAPS_KEY.to_string());
+        }
+        if self.io.admin_data().is_some() {
+            keys.insert(ADMIN_KEY.to_string());
+        }
+        keys.into_iter().collect()
+    }
+    
+    /// Get the keys of entries created or modified since the last call (or
+    /// since creation), in order. These should be saved to the store, along
+    /// with their contents (see `get`).
+    pub fn take_dirty(&mut self) -> Vec<String> {
+        let dirty = ::std::mem::replace(&mut self.dirty, BTreeSet::new());
+        dirty.into_iter().collect()
+    }
+    
+    /// Mark all entries as modified, so that the next `take_dirty` returns
+    /// all keys (e.g. after saving to the store failed).
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.extend(self.keys());
+    }
+    
+    // Take dirty entries, with their contents
+    fn take_dirty_entries(&mut self) -> Vec<(String, Data)> {
+        self.take_dirty().into_iter()
+            .filter_map(|key| {
+                let data = self.get(&key).map(|data| data.to_vec());
+                data.map(|data| (key, data))
+            })
+            .collect()
+    }
+}
+
+impl RepoIO for KvRepoIO {
+    fn ss_len(&self) -> usize {
+        self.io.ss_len()
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.io.ss_cl_len(ss_num)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.io.has_ss(ss_num)
+    }
+    // Keys may be sparse: list only the numbers present
+    fn list_ss(&self) -> Vec<usize> {
+        self.io.ss_nums()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        self.io.ss_cl_nums(ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let w = self.io.new_ss(ss_num)?;
+        if w.is_some() {
+            self.dirty.insert(ss_key(ss_num));
+        }
+        Ok(w)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        self.io.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let w = self.io.append_ss_cl(ss_num, cl_num)?;
+        if w.is_some() {
+            self.dirty.insert(cl_key(ss_num, cl_num));
+        }
+        Ok(w)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let w = self.io.new_ss_cl(ss_num, cl_num)?;
+        if w.is_some() {
+            self.dirty.insert(cl_key(ss_num, cl_num));
+        }
+        Ok(w)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.io.read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        let w = self.io.write_ss_cl_index(ss_num, cl_num)?;
+        if w.is_some() {
+            self.dirty.insert(index_key(ss_num, cl_num));
+        }
+        Ok(w)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        let w = self.io.write_gaps()?;
+        if w.is_some() {
+            self.dirty.insert(GAPS_KEY.to_string());
+        }
+        Ok(w)
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        let w = self.io.append_admin_log()?;
+        if w.is_some() {
+            self.dirty.insert(ADMIN_KEY.to_string());
+        }
+        Ok(w)
+    }
+}
+
+
+/// An asynchronous key-value store holding the entries of one partition,
+/// e.g. an IndexedDB object store via bindings supplied by the application.
+pub trait AsyncStore {
+    /// Future returned by `load_all`
+    type Load: Future<Output = Result<Vec<(String, Data)>>>;
+    /// Future returned by `save`
+    type Save: Future<Output = Result<()>>;
+    
+    /// Load all entries
+    fn load_all(&self) -> Self::Load;
+    
+    /// Save entries, replacing any with the same keys
+    fn save(&self, entries: Vec<(String, Data)>) -> Self::Save;
+}
+
+/// Future returned by `load`, resolving to a `KvRepoIO`
+pub struct Load<F> {
+    inner: Pin<Box<F>>,
+}
+impl<F: Future<Output = Result<Vec<(String, Data)>>>> Future for Load<F> {
+    type Output = Result<KvRepoIO>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<KvRepoIO>> {
+        match self.inner.as_mut().poll(cx) {
+            Poll::Ready(Ok(entries)) => Poll::Ready(KvRepoIO::from_entries(entries)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Load all entries of `store` into a new `KvRepoIO`.
+pub fn load<S: AsyncStore>(store: &S) -> Load<S::Load> {
+    Load { inner: Box::pin(store.load_all()) }
+}
+
+/// Save entries of `io` created or modified since the last save to `store`.
+/// 
+/// The entries are copied before this returns, thus `io` may continue to be
+/// used while saving. If the returned future fails, call
+/// `io.mark_all_dirty()` so that the next save writes all entries.
+pub fn save<S: AsyncStore>(store: &S, io: &mut KvRepoIO) -> S::Save {
+    store.save(io.take_dirty_entries())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::future::{ready, Ready};
+    use std::task::Waker;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::StateWrite;
+    
+    #[test]
+    fn save_and_restore() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(KvRepoIO::new()),
+                "kv store").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        // Save dirty entries to a "store", then restore from it
+        let mut io = part.unwrap_control().unwrap_io();
+        let dirty = io.take_dirty();
+        assert_eq!(dirty, vec!["ss0".to_string(), "ss0-cl0".to_string(), "ss0-cl0-index".to_string()]);
+        assert!(io.take_dirty().is_empty());
+        io.mark_all_dirty();
+        assert_eq!(io.take_dirty(), dirty);
+        let store: Vec<_> = dirty.into_iter()
+                .map(|key| { let data = io.get(&key).unwrap().to_vec(); (key, data) })
+                .collect();
+        
+        let io = KvRepoIO::from_entries(store).expect("from_entries");
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        
+        assert!(KvRepoIO::from_entries(vec![("other".to_string(), vec![])]).is_err());
+        assert_eq!(parse_key("ss12-cl3"), Some(Key::Cl(12, 3)));
+        assert_eq!(parse_key("ss12-cl3-index"), Some(Key::Index(12, 3)));
+        assert_eq!(parse_key("ss-cl3"), None);
+        assert_eq!(parse_key("ss1-cl-index"), None);
+    }
+    
+    #[test]
+    fn sparse_keys() {
+        // A huge snapshot number must not cause huge allocations or listings
+        let io = KvRepoIO::from_entries(vec![(ss_key(1 << 40), vec![])]).expect("from_entries");
+        assert_eq!(io.list_ss(), vec![1 << 40]);
+        assert_eq!(io.keys(), vec![ss_key(1 << 40)]);
+    }
+    
+    // A store whose futures are always ready
+    #[derive(Default)]
+    struct ReadyStore {
+        entries: RefCell<BTreeMap<String, Data>>,
+    }
+    impl AsyncStore for ReadyStore {
+        type Load = Ready<Result<Vec<(String, Data)>>>;
+        type Save = Ready<Result<()>>;
+        fn load_all(&self) -> Self::Load {
+            let entries = self.entries.borrow();
+            ready(Ok(entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect()))
+        }
+        fn save(&self, entries: Vec<(String, Data)>) -> Self::Save {
+            self.entries.borrow_mut().extend(entries);
+            ready(Ok(()))
+        }
+    }
+    
+    fn poll_ready<F: Future + Unpin>(mut f: F) -> F::Output {
+        let mut cx = Context::from_waker(Waker::noop());
+        match Pin::new(&mut f).poll(&mut cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+    
+    #[test]
+    fn async_store() {
+        let store = ReadyStore::default();
+        let io = poll_ready(load(&store)).expect("load");
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io),
+                "async kv store").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let tip = part.tip_key().expect("tip").clone();
+        poll_ready(save(&store, part.control_mut().io_mut())).expect("save");
+        assert_eq!(store.entries.borrow().keys().cloned().collect::<Vec<_>>(),
+                vec!["ss0".to_string(), "ss0-cl0".to_string(), "ss0-cl0-index".to_string()]);
+        
+        let io = poll_ready(load(&store)).expect("load");
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+    }
+}
@@ -0,0 +1,197 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: in-memory data access (mostly useful for testing).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write, ErrorKind};
+
+use io::RepoIO;
+use error::{Result, make_io_err};
+
+type Data = Vec<u8>;
+
+/// Stores snapshots and commit logs as in-memory buffers.
+/// 
+/// Data is lost when this is dropped, but may be recovered from a partition
+/// via `Partition::unwrap_control()` and re-used, e.g. to test that data
+/// written can be read back.
+#[derive(Clone, Debug, Default)]
+pub struct MemRepoIO {
+    // Map of snapshot-number to pair (snapshot, map of log number to log)
+    ss: BTreeMap<usize, (Option<Data>, BTreeMap<usize, Data>)>,
+    // Commit log indexes, by snapshot and log number
+    indexes: BTreeMap<(usize, usize), Data>,
+    // Gap index, if written
+    gaps: Option<Data>,
+    // Admin log, if written
+    admin: Option<Data>,
+    // Snapshot numbers reserved by `reserve_ss` and not yet written
+    reserved: BTreeSet<usize>,
+}
+impl MemRepoIO {
+    /// Create an empty instance
+    pub fn new() -> MemRepoIO {
+        MemRepoIO {
+            ss: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+            gaps: None,
+            admin: None,
+            reserved: BTreeSet::new(),
+        }
+    }
+    
+    /// Get the contents of a snapshot, if present
+    pub fn ss_data(&self, ss_num: usize) -> Option<&[u8]> {
+        self.ss.get(&ss_num).and_then(|&(ref ss, _)| ss.as_ref().map(|data| &data[..]))
+    }
+    /// Get the contents of a commit log, if present
+    pub fn ss_cl_data(&self, ss_num: usize, cl_num: usize) -> Option<&[u8]> {
+        self.ss.get(&ss_num)
+            .and_then(|&(_, ref logs)| logs.get(&cl_num))
+            .map(|data| &data[..])
+    }
+    /// Get the contents of a commit log index, if present
+    pub fn ss_cl_index_data(&self, ss_num: usize, cl_num: usize) -> Option<&[u8]> {
+        self.indexes.get(&(ss_num, cl_num)).map(|data| &data[..])
+    }
+    /// Get the contents of the gap index, if present
+    pub fn gaps_data(&self) -> Option<&[u8]> {
+        self.gaps.as_ref().map(|data| &data[..])
+    }
+    /// Get the contents of the admin log, if present
+    pub fn admin_data(&self) -> Option<&[u8]> {
+        self.admin.as_ref().map(|data| &data[..])
+    }
+    
+    /// List numbers of snapshots with an entry (the snapshot or its commit
+    /// logs), in increasing order
+    pub fn ss_nums(&self) -> Vec<usize> {
+        self.ss.keys().cloned().collect()
+    }
+    /// List numbers of commit logs present for snapshot `ss_num`, in
+    /// increasing order
+    pub fn ss_cl_nums(&self, ss_num: usize) -> Vec<usize> {
+        self.ss.get(&ss_num).map_or(vec![], |&(_, ref logs)| logs.keys().cloned().collect())
+    }
+    /// List pairs `(ss_num, cl_num)` of commit log indexes present
+    pub fn ss_cl_index_nums(&self) -> Vec<(usize, usize)> {
+        self.indexes.keys().cloned().collect()
+    }
+    
+    /// Set the contents of a snapshot, replacing any present (e.g. to restore
+    /// data saved elsewhere)
+    pub fn set_ss_data(&mut self, ss_num: usize, data: Data) {
+        self.ss.entry(ss_num).or_insert((None, BTreeMap::new())).0 = Some(data);
+    }
+    /// Set the contents of a commit log, replacing any present
+    pub fn set_ss_cl_data(&mut self, ss_num: usize, cl_num: usize, data: Data) {
+        self.ss.entry(ss_num).or_insert((None, BTreeMap::new())).1.insert(cl_num, data);
+    }
+    /// Set the contents of a commit log index, replacing any present
+    pub fn set_ss_cl_index_data(&mut self, ss_num: usize, cl_num: usize, data: Data) {
+        self.indexes.insert((ss_num, cl_num), data);
+    }
+    /// Set the contents of the gap index
+    pub fn set_gaps_data(&mut self, data: Data) {
+        self.gaps = Some(data);
+    }
+    /// Set the contents of the admin log
+    pub fn set_admin_data(&mut self, data: Data) {
+        self.admin = Some(data);
+    }
+    
+    /// Remove a snapshot and its commit logs (with their indexes), if present
+    /// (for testing handling of missing files)
+    pub fn remove_ss(&mut self, ss_num: usize) {
+        self.ss.remove(&ss_num);
+        let cl_nums: Vec<_> = self.indexes.keys()
+                .filter(|&&(ss, _)| ss == ss_num)
+                .map(|&(_, cl)| cl)
+                .collect();
+        for cl_num in cl_nums {
+            self.indexes.remove(&(ss_num, cl_num));
+        }
+    }
+}
+
+impl RepoIO for MemRepoIO {
+    fn ss_len(&self) -> usize {
+        self.ss.keys().next_back().map(|x| x+1).unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        match self.ss.get(&ss_num) {
+            Some(&(_, ref logs)) => logs.keys().next_back().map(|x| x+1).unwrap_or(0),
+            None => 0,
+        }
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.ss.get(&ss_num).map(|&(ref ss, _)| ss.is_some()).unwrap_or(false)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.ss_data(ss_num).map(|data| Box::new(data) as Box<Read+'a>))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.ss_cl_data(ss_num, cl_num).map(|data| Box::new(data) as Box<Read+'a>))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let pair = self.ss.entry(ss_num).or_insert((None, BTreeMap::new()));
+        if pair.0.is_some() {
+            return Ok(None);
+        }
+        self.reserved.remove(&ss_num);
+        pair.0 = Some(Vec::new());
+        Ok(pair.0.as_mut().map(|data| Box::new(data) as Box<Write+'a>))
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        let mut ss_num = ss_num;
+        while self.has_ss(ss_num) || self.reserved.contains(&ss_num) {
+            ss_num += 1;
+        }
+        self.reserved.insert(ss_num);
+        Ok(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        Ok(self.ss.get_mut(&ss_num)
+            .and_then(|&mut (_, ref mut logs)| logs.get_mut(&cl_num))
+            .map(|data| Box::new(data) as Box<Write+'a>))
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if let Some(&mut (_, ref mut logs)) = self.ss.get_mut(&ss_num) {
+            if logs.contains_key(&cl_num) {
+                return Ok(None);
+            }
+            let data = logs.entry(cl_num).or_insert_with(Vec::new);
+            Ok(Some(Box::new(data)))
+        } else {
+            make_io_err(ErrorKind::NotFound, "no snapshot corresponding to new commit log")
+        }
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        Ok(self.ss_cl_index_data(ss_num, cl_num).map(|data| Box::new(data) as Box<Read+'a>))
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        let data = self.indexes.entry((ss_num, cl_num)).or_insert_with(Vec::new);
+        data.clear();
+        Ok(Some(Box::new(data)))
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.gaps.as_ref().map(|data| Box::new(&data[..]) as Box<Read+'a>))
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.gaps = Some(Vec::new());
+        Ok(self.gaps.as_mut().map(|data| Box::new(data) as Box<Write+'a>))
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.admin.as_ref().map(|data| Box::new(&data[..]) as Box<Read+'a>))
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        let data = self.admin.get_or_insert_with(Vec::new);
+        Ok(Some(Box::new(data)))
+    }
+}
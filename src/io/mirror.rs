@@ -0,0 +1,324 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: mirrored (replicated) I/O.
+//! 
+//! `MirroredRepoIO` combines a primary and a secondary `RepoIO`: everything
+//! written goes to both, and reads come from the primary, falling back to
+//! the secondary where the primary fails or lacks a file. This gives simple
+//! synchronous replication, e.g. to a second disk or a remote store.
+//! 
+//! The number and existence of files are taken from the primary. Failure
+//! part-way through reading a file is not recovered from.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+
+use io::RepoIO;
+use error::{Result, Error, OtherError};
+
+/// How `MirroredRepoIO` treats failures to write to the secondary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorMode {
+    /// Writing fails if writing to either the primary or the secondary fails
+    FailOnEither,
+    /// Writing fails only if writing to the primary fails; failures of the
+    /// secondary are logged and counted (see
+    /// `MirroredRepoIO::secondary_failures`), after which the secondary is
+    /// out of date until re-synchronised (e.g. with `io::backup::backup`).
+    FailOnPrimary,
+}
+
+/// Mirrors writes to two `RepoIO`s (see module documentation).
+#[derive(Debug)]
+pub struct MirroredRepoIO<P: RepoIO, S: RepoIO> {
+    primary: P,
+    secondary: S,
+    mode: MirrorMode,
+    failures: Cell<usize>,
+}
+impl<P: RepoIO, S: RepoIO> MirroredRepoIO<P, S> {
+    /// Create. The two should have the same content initially.
+    pub fn new(primary: P, secondary: S, mode: MirrorMode) -> MirroredRepoIO<P, S> {
+        MirroredRepoIO {
+            primary: primary,
+            secondary: secondary,
+            mode: mode,
+            failures: Cell::new(0),
+        }
+    }
+    /// Get the mode
+    pub fn mode(&self) -> MirrorMode {
+        self.mode
+    }
+    /// Change the mode
+    pub fn set_mode(&mut self, mode: MirrorMode) {
+        self.mode = mode;
+    }
+    /// Number of failed writes to the secondary (including those causing an
+    /// error in mode `FailOnEither`), since creation or the last reset
+    pub fn secondary_failures(&self) -> usize {
+        self.failures.get()
+    }
+    /// Reset the count of failed writes to the secondary (e.g. after
+    /// re-synchronising it)
+    pub fn reset_failures(&mut self) {
+        self.failures.set(0);
+    }
+    /// Get a reference to the primary `RepoIO`
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+    /// Get a mutable reference to the primary `RepoIO` (not mirrored)
+    pub fn primary_mut(&mut self) -> &mut P {
+        &mut self.primary
+    }
+    /// Get a reference to the secondary `RepoIO`
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+    /// Get a mutable reference to the secondary `RepoIO`
+    pub fn secondary_mut(&mut self) -> &mut S {
+        &mut self.secondary
+    }
+    /// Unwrap, returning (primary, secondary)
+    pub fn unwrap_io(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+// Read from `primary`, falling back to `secondary` if it fails or is
+// missing the file. If both fail, the primary's error is returned.
+fn fallback<'a, F>(primary: Result<Option<Box<Read+'a>>>, secondary: F) ->
+        Result<Option<Box<Read+'a>>>
+        where F: FnOnce() -> Result<Option<Box<Read+'a>>>
+{
+    match primary {
+        Ok(Some(r)) => Ok(Some(r)),
+        Ok(None) => secondary(),
+        Err(e) => {
+            warn!("mirror: reading primary failed, trying secondary: {}", e);
+            match secondary() {
+                Ok(Some(r)) => Ok(Some(r)),
+                _ => Err(e),
+            }
+        },
+    }
+}
+
+// Combine writers. If the primary does not open a file (`None` or error),
+// the secondary is not used.
+fn mirror<'a>(primary: Result<Option<Box<Write+'a>>>, secondary: Result<Option<Box<Write+'a>>>,
+        mode: MirrorMode, failures: &'a Cell<usize>) -> Result<Option<Box<Write+'a>>>
+{
+    let primary = match primary? {
+        Some(w) => w,
+        None => return Ok(None),
+    };
+    let secondary = match secondary {
+        Ok(Some(w)) => Some(w),
+        Ok(None) => {
+            secondary_failed(mode, failures, OtherError::new("secondary: file exists").into())?;
+            None
+        },
+        Err(e) => {
+            secondary_failed(mode, failures, e)?;
+            None
+        },
+    };
+    Ok(Some(Box::new(MirrorWrite {
+        primary: primary,
+        secondary: secondary,
+        mode: mode,
+        failures: failures,
+    })))
+}
+
+// Count a failure of the secondary; return the error in mode `FailOnEither`
+fn secondary_failed(mode: MirrorMode, failures: &Cell<usize>, e: Error) -> Result<()> {
+    failures.set(failures.get() + 1);
+    match mode {
+        MirrorMode::FailOnEither => Err(e),
+        MirrorMode::FailOnPrimary => {
+            warn!("mirror: writing secondary failed (ignored): {}", e);
+            Ok(())
+        },
+    }
+}
+
+impl<P: RepoIO, S: RepoIO> RepoIO for MirroredRepoIO<P, S> {
+    fn ss_len(&self) -> usize {
+        self.primary.ss_len()
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.primary.ss_cl_len(ss_num)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.primary.has_ss(ss_num)
+    }
+    fn list_ss(&self) -> Vec<usize> {
+        self.primary.list_ss()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        self.primary.list_ss_cl(ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        fallback(self.primary.read_ss(ss_num), || self.secondary.read_ss(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        fallback(self.primary.read_ss_cl(ss_num, cl_num),
+                || self.secondary.read_ss_cl(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        mirror(self.primary.new_ss(ss_num), self.secondary.new_ss(ss_num),
+                self.mode, &self.failures)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        self.primary.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        mirror(self.primary.append_ss_cl(ss_num, cl_num),
+                self.secondary.append_ss_cl(ss_num, cl_num), self.mode, &self.failures)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        mirror(self.primary.new_ss_cl(ss_num, cl_num),
+                self.secondary.new_ss_cl(ss_num, cl_num), self.mode, &self.failures)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        fallback(self.primary.read_ss_cl_index(ss_num, cl_num),
+                || self.secondary.read_ss_cl_index(ss_num, cl_num))
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        mirror(self.primary.write_ss_cl_index(ss_num, cl_num),
+                self.secondary.write_ss_cl_index(ss_num, cl_num), self.mode, &self.failures)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        fallback(self.primary.read_gaps(), || self.secondary.read_gaps())
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        mirror(self.primary.write_gaps(), self.secondary.write_gaps(),
+                self.mode, &self.failures)
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        fallback(self.primary.read_admin_log(), || self.secondary.read_admin_log())
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        mirror(self.primary.append_admin_log(), self.secondary.append_admin_log(),
+                self.mode, &self.failures)
+    }
+}
+
+struct MirrorWrite<'a> {
+    primary: Box<Write+'a>,
+    // None once failed (in mode `FailOnPrimary`) or if not opened
+    secondary: Option<Box<Write+'a>>,
+    mode: MirrorMode,
+    failures: &'a Cell<usize>,
+}
+impl<'a> MirrorWrite<'a> {
+    // Handle the result of writing to the secondary (as `secondary_failed`)
+    fn check(&mut self, result: io::Result<()>) -> io::Result<()> {
+        if let Err(e) = result {
+            self.secondary = None;
+            self.failures.set(self.failures.get() + 1);
+            match self.mode {
+                MirrorMode::FailOnEither => return Err(e),
+                MirrorMode::FailOnPrimary => {
+                    warn!("mirror: writing secondary failed (ignored): {}", e);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+impl<'a> Write for MirrorWrite<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.primary.write(buf)?;
+        let result = match self.secondary {
+            Some(ref mut w) => w.write_all(&buf[..len]),
+            None => return Ok(len),
+        };
+        self.check(result)?;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()?;
+        let result = match self.secondary {
+            Some(ref mut w) => w.flush(),
+            None => return Ok(()),
+        };
+        self.check(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::conformance::check;
+    use io::faulty::{Fault, FaultyRepoIO};
+    use io::mem::MemRepoIO;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::StateWrite;
+    
+    #[test]
+    fn conformance() {
+        check(|io| io.unwrap_or_else(|| MirroredRepoIO::new(MemRepoIO::new(), MemRepoIO::new(),
+                MirrorMode::FailOnEither))).expect("MirroredRepoIO conformance");
+    }
+    
+    #[test]
+    fn mirrored() {
+        let io = MirroredRepoIO::new(MemRepoIO::new(), MemRepoIO::new(),
+                MirrorMode::FailOnEither);
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io), "mirror test")
+                .expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("element".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let tip = part.tip_key().expect("tip").clone();
+        let mut io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.primary().ss_data(0), io.secondary().ss_data(0));
+        assert_eq!(io.primary().ss_cl_data(0, 0), io.secondary().ss_cl_data(0, 0));
+        assert_eq!(io.secondary_failures(), 0);
+        
+        // Reads fall back to the secondary
+        io.primary_mut().remove_ss(0);
+        let mut buf = Vec::new();
+        io.read_ss(0).expect("read_ss").expect("present").read_to_end(&mut buf).expect("read");
+        assert_eq!(Some(&buf[..]), io.secondary().ss_data(0));
+        let (_, secondary) = io.unwrap_io();
+        let part = Partition::open(DefaultControl::<String, _>::new(secondary), true)
+                .expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+    }
+    
+    #[test]
+    fn secondary_failure() {
+        let mut secondary = FaultyRepoIO::new(MemRepoIO::new());
+        secondary.inject(Fault::FailWrite(1));
+        let mut io = MirroredRepoIO::new(MemRepoIO::new(), secondary, MirrorMode::FailOnEither);
+        assert!(io.new_ss(0).expect("new_ss").expect("new").write_all(b"a").is_ok());
+        assert!(io.new_ss(1).expect("new_ss").expect("new").write_all(b"b").is_err());
+        assert_eq!(io.secondary_failures(), 1);
+        
+        io.set_mode(MirrorMode::FailOnPrimary);
+        io.reset_failures();
+        io.secondary_mut().inject(Fault::FailWrite(2));
+        io.new_ss(2).expect("new_ss").expect("new").write_all(b"c").expect("write");
+        io.new_ss(3).expect("new_ss").expect("new").write_all(b"d").expect("write");
+        assert_eq!(io.secondary_failures(), 1);
+        assert_eq!(io.primary().ss_data(2), Some(&b"c"[..]));
+        assert!(io.secondary().io().ss_data(2) != Some(&b"c"[..]));
+        assert_eq!(io.secondary().io().ss_data(3), Some(&b"d"[..]));
+    }
+}
@@ -9,8 +9,21 @@ use std::fmt::Debug;
 
 use error::Result;
 
+pub mod backup;
+pub mod cache;
+pub mod conformance;
+#[cfg(feature = "fs")]
 pub mod discover;
+pub mod faulty;
+#[cfg(feature = "fs")]
 pub mod file;
+#[cfg(feature = "kv")]
+pub mod kv;
+pub mod mem;
+pub mod mirror;
+pub mod pack;
+#[cfg(feature = "clock")]
+pub mod throttle;
 
 
 /// An interface providing read and/or write access to a suitable location.
@@ -44,6 +57,24 @@ pub trait RepoIO: Debug {
     /// `read_ss(ss_num)` *should* succeed (assuming no I/O failure).
     fn has_ss(&self, ss_num: usize) -> bool;
     
+    /// List the numbers of snapshots, in increasing order. Snapshots are
+    /// found (e.g. when opening a partition) from this list; numbers listed
+    /// but not present are reported missing, unless recorded as pruned.
+    /// 
+    /// The default implementation lists all numbers less than `ss_len()`.
+    /// Backends with sparse or non-numeric keys may list only the snapshots
+    /// present (though `ss_len()` must still exceed each number listed).
+    fn list_ss(&self) -> Vec<usize> {
+        (0..self.ss_len()).collect()
+    }
+    
+    /// List the numbers of commit logs for snapshot `ss_num`, in increasing
+    /// order (see `list_ss`). The default implementation lists all numbers
+    /// less than `ss_cl_len(ss_num)`.
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        (0..self.ss_cl_len(ss_num)).collect()
+    }
+    
     /// Get a snapshot with the given number. If no snapshot is present or if
     /// ss_num is too large, None will be returned.
     /// 
@@ -73,6 +104,23 @@ pub trait RepoIO: Debug {
     /// This can fail due to IO operations failing.
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>>;
     
+    /// Reserve a number, no less than `ss_num`, for a new snapshot to be
+    /// written with `new_ss`.
+    /// 
+    /// Where several writers share a store, an implementation able to do so
+    /// should reserve the number atomically, such that no other writer gets
+    /// it. Otherwise `new_ss` may still find the number taken (returning
+    /// None). Either way, snapshots found with lower numbers (from `ss_num`)
+    /// or at a taken number are linked into the history, and another number
+    /// reserved as needed (see `Partition::write_snapshot`).
+    /// 
+    /// The default implementation reserves nothing, returning `ss_num`.
+    /// `RepoFileIO` reserves atomically by creating an empty file;
+    /// `MemRepoIO` and `PackedRepoIO` skip numbers present or reserved.
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        Ok(ss_num)
+    }
+    
     /// Open an append-write stream on an existing commit file. Writes may be
     /// atomic. Each commit should be written via a single write operation.
     /// 
@@ -100,6 +148,62 @@ pub trait RepoIO: Debug {
     /// This can fail due to IO operations failing.
     // #0012: verify atomicity of writes
     fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Read the index of a commit log: an optional file recording where each
+    /// commit starts (see `rw::logindex`).
+    /// 
+    /// Returns None if there is no such file. The default implementation
+    /// does not support indexes and always returns None.
+    fn read_ss_cl_index<'a>(&'a self, _ss_num: usize, _cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        Ok(None)
+    }
+    
+    /// Open a write stream replacing the index of a commit log (creating it
+    /// if necessary).
+    /// 
+    /// Returns None if indexes are not supported (the default).
+    fn write_ss_cl_index<'a>(&'a mut self, _ss_num: usize, _cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        Ok(None)
+    }
+    
+    /// Read the gap index: a small file recording snapshots removed
+    /// intentionally (see `Partition::record_pruned`).
+    /// 
+    /// Returns None if there is no such file. The default implementation
+    /// does not support a gap index and always returns None.
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(None)
+    }
+    
+    /// Open a write stream replacing the gap index (creating it if
+    /// necessary).
+    /// 
+    /// Returns None if a gap index is not supported (the default).
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        Ok(None)
+    }
+    
+    /// Read the admin log: an append-only file recording administrative
+    /// operations (see `Partition::admin_history`).
+    /// 
+    /// Returns None if there is no such file. The default implementation
+    /// does not support an admin log and always returns None.
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        Ok(None)
+    }
+    
+    /// Open an append-write stream on the admin log, creating it (empty) if
+    /// necessary. As with commit logs, each record should be written via a
+    /// single write operation.
+    /// 
+    /// Returns None if an admin log is not supported (the default).
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        Ok(None)
+    }
 }
 
 /// Doesn't provide any IO.
@@ -151,6 +255,8 @@ impl RepoIO for Box<RepoIO> {
     fn ss_len(&self) -> usize { (**self).ss_len() }
     fn ss_cl_len(&self, ss_num: usize) -> usize { (**self).ss_cl_len(ss_num) }
     fn has_ss(&self, ss_num: usize) -> bool { (**self).has_ss(ss_num) }
+    fn list_ss(&self) -> Vec<usize> { (**self).list_ss() }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> { (**self).list_ss_cl(ss_num) }
     fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
         (**self).read_ss(ss_num)
     }
@@ -160,6 +266,9 @@ impl RepoIO for Box<RepoIO> {
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
         (**self).new_ss(ss_num)
     }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        (**self).reserve_ss(ss_num)
+    }
     fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
             Result<Option<Box<Write+'a>>>
     {
@@ -169,4 +278,26 @@ impl RepoIO for Box<RepoIO> {
     {
         (**self).new_ss_cl(ss_num, cl_num)
     }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        (**self).read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        (**self).write_ss_cl_index(ss_num, cl_num)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        (**self).read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        (**self).write_gaps()
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        (**self).read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        (**self).append_admin_log()
+    }
 }
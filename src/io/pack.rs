@@ -0,0 +1,444 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: packing of old snapshots and commit logs into a single archive.
+//! 
+//! A partition accumulating thousands of commit log files is slow to list and
+//! open on many file systems. `pack` copies a set of snapshots and commit
+//! logs (unchanged) into one pack file, with a table of contents at the
+//! start (see `doc/repo-files.md`). `PackedRepoIO` reads transparently from
+//! packs and loose files; new files are always written as loose files.
+//! 
+//! Packing does not remove the loose files; once the pack has been written
+//! and checked this is left to the user (see `RepoFileIO::remove_file`).
+
+use std::io::{Read, Write};
+#[cfg(feature = "fs")]
+use std::io::{Seek, SeekFrom};
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+use std::cmp::max;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use io::RepoIO;
+use io::backup::FileId;
+use error::{Result, ReadError, OtherError};
+
+const PACK_HEAD: [u8; 16] = *b"PIPPINPK20161016";
+// Length of each table of contents entry
+const ENTRY_LEN: usize = 32;
+
+/// Select "old" files for packing: all snapshots and commit logs with
+/// snapshot number less than `ss_num`.
+pub fn old_files(io: &RepoIO, ss_num: usize) -> Vec<FileId> {
+    let mut files = Vec::new();
+    for ss in 0..ss_num {
+        if io.has_ss(ss) {
+            files.push(FileId::Snapshot(ss));
+        }
+        for cl in io.list_ss_cl(ss) {
+            // Logs not present are simply not packed; check by reading
+            if let Ok(Some(_)) = io.read_ss_cl(ss, cl) {
+                files.push(FileId::CommitLog(ss, cl));
+            }
+        }
+    }
+    files
+}
+
+/// Write a pack containing `files`, read from `src`, to `w`. Fails if any
+/// file listed is not found.
+/// 
+/// Files are read into memory in order to write the table of contents first.
+pub fn pack(src: &RepoIO, files: &[FileId], w: &mut Write) -> Result<()> {
+    let mut contents = Vec::with_capacity(files.len());
+    for id in files {
+        let reader = match *id {
+            FileId::Snapshot(ss) => src.read_ss(ss)?,
+            FileId::CommitLog(ss, cl) => src.read_ss_cl(ss, cl)?,
+        };
+        let mut data = Vec::new();
+        if let Some(mut r) = reader {
+            r.read_to_end(&mut data)?;
+        } else {
+            return OtherError::err(format!("file to pack not found: {:?}", id));
+        }
+        contents.push((*id, data));
+    }
+    
+    let mut buf = Vec::with_capacity(16 + 8 + ENTRY_LEN * files.len());
+    buf.extend_from_slice(&PACK_HEAD);
+    buf.write_u64::<BigEndian>(contents.len() as u64)?;
+    let mut offset = (16 + 8 + ENTRY_LEN * contents.len()) as u64;
+    for &(id, ref data) in &contents {
+        let (ss, cl) = match id {
+            FileId::Snapshot(ss) => (ss, 0),
+            FileId::CommitLog(ss, cl) => (ss, cl + 1),
+        };
+        buf.write_u64::<BigEndian>(ss as u64)?;
+        buf.write_u64::<BigEndian>(cl as u64)?;
+        buf.write_u64::<BigEndian>(offset)?;
+        buf.write_u64::<BigEndian>(data.len() as u64)?;
+        offset += data.len() as u64;
+    }
+    w.write_all(&buf)?;
+    for &(_, ref data) in &contents {
+        w.write_all(data)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum Source {
+    Mem(Vec<u8>),
+    #[cfg(feature = "fs")]
+    File(PathBuf),
+}
+
+/// A pack: the table of contents and access to the data.
+#[derive(Debug)]
+pub struct Pack {
+    // Map of file to (offset, length) within the pack
+    toc: BTreeMap<FileId, (u64, u64)>,
+    source: Source,
+}
+impl Pack {
+    /// Use a pack held in memory.
+    pub fn from_data(data: Vec<u8>) -> Result<Pack> {
+        let toc = read_toc(&mut &data[..], Some(data.len() as u64))?;
+        Ok(Pack { toc: toc, source: Source::Mem(data) })
+    }
+    
+    /// Open a pack file. Only the table of contents is read; files are read
+    /// from the pack when required.
+    #[cfg(feature = "fs")]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Pack> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let toc = read_toc(&mut file, Some(len))?;
+        Ok(Pack { toc: toc, source: Source::File(path.to_path_buf()) })
+    }
+    
+    /// Number of files held
+    pub fn len(&self) -> usize {
+        self.toc.len()
+    }
+    /// True if no files are held
+    pub fn is_empty(&self) -> bool {
+        self.toc.is_empty()
+    }
+    /// True if the given file is held
+    pub fn contains(&self, id: FileId) -> bool {
+        self.toc.contains_key(&id)
+    }
+    /// List files held (snapshots first, then commit logs)
+    pub fn files(&self) -> Vec<FileId> {
+        self.toc.keys().cloned().collect()
+    }
+    
+    fn ss_len(&self) -> usize {
+        self.toc.keys().map(|id| match *id {
+            FileId::Snapshot(ss) | FileId::CommitLog(ss, _) => ss + 1,
+        }).max().unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.toc.keys().filter_map(|id| match *id {
+            FileId::CommitLog(ss, cl) if ss == ss_num => Some(cl + 1),
+            _ => None,
+        }).max().unwrap_or(0)
+    }
+    
+    /// Open a read stream on a file held, if present.
+    pub fn read<'a>(&'a self, id: FileId) -> Result<Option<Box<Read+'a>>> {
+        let (offset, len) = match self.toc.get(&id) {
+            Some(&pos) => pos,
+            None => return Ok(None),
+        };
+        Ok(Some(match self.source {
+            Source::Mem(ref data) => {
+                Box::new(&data[offset as usize .. (offset + len) as usize])
+            },
+            #[cfg(feature = "fs")]
+            Source::File(ref path) => {
+                trace!("Reading {:?} from pack: {}", id, path.display());
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Box::new(file.take(len))
+            },
+        }))
+    }
+}
+
+// Read the table of contents, checking entries against the total length
+fn read_toc(r: &mut Read, total_len: Option<u64>) -> Result<BTreeMap<FileId, (u64, u64)>> {
+    let mut buf = vec![0; 24];
+    r.read_exact(&mut buf)?;
+    if buf[0..8] != PACK_HEAD[0..8] {
+        return ReadError::err("not a pack file", 0, (0, 8));
+    }
+    if buf[8..16] != PACK_HEAD[8..16] {
+        return ReadError::err("pack version not supported", 0, (8, 16));
+    }
+    let num = BigEndian::read_u64(&buf[16..24]);
+    if total_len.map_or(false, |total| num > total.saturating_sub(24) / ENTRY_LEN as u64) {
+        return ReadError::err("pack entry count exceeds file length", 16, (0, 8));
+    }
+    let start = match num.checked_mul(ENTRY_LEN as u64).and_then(|n| n.checked_add(24)) {
+        Some(start) => start,
+        None => return ReadError::err("pack entry count too large", 16, (0, 8)),
+    };
+    let mut toc = BTreeMap::new();
+    buf.resize(ENTRY_LEN, 0);
+    for i in 0..num {
+        let pos = (24 + ENTRY_LEN as u64 * i) as usize;
+        r.read_exact(&mut buf)?;
+        let ss = BigEndian::read_u64(&buf[0..8]) as usize;
+        let cl = BigEndian::read_u64(&buf[8..16]) as usize;
+        let offset = BigEndian::read_u64(&buf[16..24]);
+        let len = BigEndian::read_u64(&buf[24..32]);
+        let in_bounds = offset.checked_add(len)
+                .map_or(false, |end| total_len.map_or(true, |total| end <= total));
+        if offset < start || !in_bounds {
+            return ReadError::err("pack entry out of bounds", pos, (16, 32));
+        }
+        let id = if cl == 0 { FileId::Snapshot(ss) } else { FileId::CommitLog(ss, cl - 1) };
+        if toc.insert(id, (offset, len)).is_some() {
+            return ReadError::err("duplicate pack entry", pos, (0, 16));
+        }
+    }
+    Ok(toc)
+}
+
+/// Reads from packs and loose files, writing new files via an inner
+/// `RepoIO`.
+/// 
+/// Loose files take precedence over packed copies. Packed commit logs cannot
+/// be appended to.
+#[derive(Debug)]
+pub struct PackedRepoIO<IO: RepoIO> {
+    io: IO,
+    packs: Vec<Pack>,
+}
+impl<IO: RepoIO> PackedRepoIO<IO> {
+    /// Create, with no packs
+    pub fn new(io: IO) -> PackedRepoIO<IO> {
+        PackedRepoIO { io: io, packs: Vec::new() }
+    }
+    /// Add a pack
+    pub fn add_pack(&mut self, pack: Pack) {
+        self.packs.push(pack);
+    }
+    /// Get the packs used
+    pub fn packs(&self) -> &[Pack] {
+        &self.packs
+    }
+    /// Get a reference to the inner `RepoIO`
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+    /// Get a mutable reference to the inner `RepoIO`
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+    /// Unwrap the inner `RepoIO`
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+    
+    fn packed(&self, id: FileId) -> bool {
+        self.packs.iter().any(|pack| pack.contains(id))
+    }
+    fn read_packed<'a>(&'a self, id: FileId) -> Result<Option<Box<Read+'a>>> {
+        for pack in &self.packs {
+            if let Some(r) = pack.read(id)? {
+                return Ok(Some(r));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<IO: RepoIO> RepoIO for PackedRepoIO<IO> {
+    fn ss_len(&self) -> usize {
+        self.packs.iter().fold(self.io.ss_len(), |len, pack| max(len, pack.ss_len()))
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.packs.iter().fold(self.io.ss_cl_len(ss_num),
+                |len, pack| max(len, pack.ss_cl_len(ss_num)))
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.io.has_ss(ss_num) || self.packed(FileId::Snapshot(ss_num))
+    }
+    fn list_ss(&self) -> Vec<usize> {
+        let mut nums: BTreeSet<usize> = self.io.list_ss().into_iter().collect();
+        for pack in &self.packs {
+            nums.extend(pack.toc.keys().filter_map(|id| match *id {
+                FileId::Snapshot(ss) => Some(ss),
+                FileId::CommitLog(..) => None,
+            }));
+        }
+        nums.into_iter().collect()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        let mut nums: BTreeSet<usize> = self.io.list_ss_cl(ss_num).into_iter().collect();
+        for pack in &self.packs {
+            nums.extend(pack.toc.keys().filter_map(|id| match *id {
+                FileId::CommitLog(ss, cl) if ss == ss_num => Some(cl),
+                _ => None,
+            }));
+        }
+        nums.into_iter().collect()
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        if let Some(r) = self.io.read_ss(ss_num)? {
+            return Ok(Some(r));
+        }
+        self.read_packed(FileId::Snapshot(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        if let Some(r) = self.io.read_ss_cl(ss_num, cl_num)? {
+            return Ok(Some(r));
+        }
+        self.read_packed(FileId::CommitLog(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.packed(FileId::Snapshot(ss_num)) {
+            return Ok(None);
+        }
+        self.io.new_ss(ss_num)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        // New snapshots follow all packed files
+        let ss_num = self.packs.iter().fold(ss_num, |num, pack| max(num, pack.ss_len()));
+        self.io.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        if self.io.read_ss_cl(ss_num, cl_num)?.is_none() &&
+                self.packed(FileId::CommitLog(ss_num, cl_num)) {
+            return OtherError::err("cannot append to a packed commit log");
+        }
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.packed(FileId::CommitLog(ss_num, cl_num)) {
+            return Ok(None);
+        }
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.io.read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.write_ss_cl_index(ss_num, cl_num)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.write_gaps()
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_admin_log()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem::MemRepoIO;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::StateWrite;
+    
+    #[test]
+    fn pack_and_read() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()),
+                "pack test").expect("create");
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            part.write_fast().expect("write_fast");
+            part.write_snapshot().expect("write_snapshot");
+        }
+        let tip = part.tip_key().expect("tip").clone();
+        let mut io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.ss_len(), 4);
+        
+        let files = old_files(&io, 3);
+        assert_eq!(files, vec![FileId::Snapshot(0), FileId::CommitLog(0, 0),
+                FileId::Snapshot(1), FileId::CommitLog(1, 0), FileId::Snapshot(2),
+                FileId::CommitLog(2, 0)]);
+        let mut data = Vec::new();
+        pack(&io, &files, &mut data).expect("pack");
+        for ss in 0..3 {
+            io.remove_ss(ss);
+        }
+        
+        let pack = Pack::from_data(data).expect("from_data");
+        let mut sorted = files.clone();
+        sorted.sort();
+        assert_eq!(pack.files(), sorted);
+        let mut io = PackedRepoIO::new(io);
+        io.add_pack(pack);
+        assert_eq!(io.ss_len(), 4);
+        assert!(io.has_ss(1));
+        assert!(io.new_ss_cl(1, 0).expect("new_ss_cl").is_none());
+        assert!(io.append_ss_cl(1, 0).is_err());
+        assert_eq!(io.list_ss(), vec![0, 1, 2, 3]);
+        assert_eq!(io.list_ss_cl(1), vec![0]);
+        assert_eq!(io.reserve_ss(1).expect("reserve_ss"), 4);
+        
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        assert_eq!(part.states_len(), 4);
+        
+        assert!(Pack::from_data(b"PIPPINSS20160815".to_vec()).is_err());
+    }
+    
+    #[test]
+    fn bad_toc() {
+        use std::u64;
+        let toc = |num: u64, offset: u64, len: u64| {
+            let mut data = PACK_HEAD.to_vec();
+            data.write_u64::<BigEndian>(num).unwrap();
+            data.extend_from_slice(&[0; 16]);
+            data.write_u64::<BigEndian>(offset).unwrap();
+            data.write_u64::<BigEndian>(len).unwrap();
+            data
+        };
+        let data = toc(1, 56, 0);
+        assert_eq!(read_toc(&mut &data[..], Some(56)).expect("toc").len(), 1);
+        
+        // Entry counts must fit the file and not overflow
+        for &num in &[2, u64::MAX / ENTRY_LEN as u64, u64::MAX] {
+            let data = toc(num, 56, 0);
+            assert!(read_toc(&mut &data[..], Some(56)).is_err());
+        }
+        let data = toc(u64::MAX, 56, 0);
+        assert!(read_toc(&mut &data[..], None).is_err());
+        
+        // Entries must not overflow
+        let data = toc(1, u64::MAX - 4, 8);
+        assert!(read_toc(&mut &data[..], Some(56)).is_err());
+        assert!(read_toc(&mut &data[..], None).is_err());
+    }
+}
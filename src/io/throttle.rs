@@ -0,0 +1,246 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: rate-limited I/O.
+//! 
+//! `ThrottledRepoIO` wraps another `RepoIO`, limiting the bandwidth of reads
+//! and writes and the rate at which files are opened, such that background
+//! work (e.g. writing snapshots or `io::backup::backup`) does not starve a
+//! foreground application sharing the same disk. Limits are enforced by
+//! sleeping the calling thread. Requires the `clock` feature.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use io::RepoIO;
+use error::Result;
+
+/// Limits applied by `ThrottledRepoIO`. `None` means unlimited.
+/// 
+/// Can be constructed with `Default`, which sets no limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// Maximum bytes read per second
+    pub read_bytes: Option<u64>,
+    /// Maximum bytes written per second
+    pub write_bytes: Option<u64>,
+    /// Maximum files opened (for reading or writing) per second
+    pub ops: Option<u64>,
+}
+
+/// Limits the I/O rate of an inner `RepoIO` (see module documentation).
+/// 
+/// Operations are not delayed after a period of inactivity; thereafter
+/// each operation is delayed until those before it would have completed at
+/// the limited rate.
+#[derive(Debug)]
+pub struct ThrottledRepoIO<IO: RepoIO> {
+    io: IO,
+    read: RefCell<Pacer>,
+    write: RefCell<Pacer>,
+    ops: RefCell<Pacer>,
+}
+impl<IO: RepoIO> ThrottledRepoIO<IO> {
+    /// Create, with the given limits
+    pub fn new(io: IO, throttle: Throttle) -> ThrottledRepoIO<IO> {
+        ThrottledRepoIO {
+            io: io,
+            read: RefCell::new(Pacer::new(throttle.read_bytes)),
+            write: RefCell::new(Pacer::new(throttle.write_bytes)),
+            ops: RefCell::new(Pacer::new(throttle.ops)),
+        }
+    }
+    /// Change the limits (e.g. while the foreground application is idle)
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.read.borrow_mut().rate = throttle.read_bytes;
+        self.write.borrow_mut().rate = throttle.write_bytes;
+        self.ops.borrow_mut().rate = throttle.ops;
+    }
+    /// Get a reference to the inner `RepoIO`
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+    /// Get a mutable reference to the inner `RepoIO` (not throttled)
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+    /// Unwrap the inner `RepoIO`
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+    
+    fn reader<'a>(&'a self, r: Result<Option<Box<Read+'a>>>) -> Result<Option<Box<Read+'a>>> {
+        self.ops.borrow_mut().take(1);
+        Ok(r?.map(|r| Box::new(ThrottledRead { inner: r, pacer: &self.read }) as Box<Read+'a>))
+    }
+}
+
+// Wrap a write stream (not a method since the inner `RepoIO` is borrowed
+// mutably while the pacers are borrowed immutably)
+fn writer<'a>(w: Result<Option<Box<Write+'a>>>, pacer: &'a RefCell<Pacer>,
+        ops: &RefCell<Pacer>) -> Result<Option<Box<Write+'a>>>
+{
+    ops.borrow_mut().take(1);
+    Ok(w?.map(|w| Box::new(ThrottledWrite { inner: w, pacer: pacer }) as Box<Write+'a>))
+}
+
+impl<IO: RepoIO> RepoIO for ThrottledRepoIO<IO> {
+    fn ss_len(&self) -> usize {
+        self.io.ss_len()
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.io.ss_cl_len(ss_num)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.io.has_ss(ss_num)
+    }
+    fn list_ss(&self) -> Vec<usize> {
+        self.io.list_ss()
+    }
+    fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+        self.io.list_ss_cl(ss_num)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.reader(self.io.read_ss(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.reader(self.io.read_ss_cl(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        writer(self.io.new_ss(ss_num), &self.write, &self.ops)
+    }
+    fn reserve_ss(&mut self, ss_num: usize) -> Result<usize> {
+        self.io.reserve_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        writer(self.io.append_ss_cl(ss_num, cl_num), &self.write, &self.ops)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        writer(self.io.new_ss_cl(ss_num, cl_num), &self.write, &self.ops)
+    }
+    fn read_ss_cl_index<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Read+'a>>>
+    {
+        self.io.read_ss_cl_index(ss_num, cl_num)
+    }
+    fn write_ss_cl_index<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        self.io.write_ss_cl_index(ss_num, cl_num)
+    }
+    fn read_gaps<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_gaps()
+    }
+    fn write_gaps<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.write_gaps()
+    }
+    fn read_admin_log<'a>(&'a self) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_admin_log()
+    }
+    fn append_admin_log<'a>(&'a mut self) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_admin_log()
+    }
+}
+
+// Delays operations to keep to a rate (units per second)
+#[derive(Debug)]
+struct Pacer {
+    rate: Option<u64>,
+    // Time at which operations so far would complete at the limited rate
+    next: Instant,
+}
+impl Pacer {
+    fn new(rate: Option<u64>) -> Pacer {
+        Pacer { rate: rate, next: Instant::now() }
+    }
+    // Account for `n` units, first sleeping until previous units are within
+    // the rate
+    fn take(&mut self, n: u64) {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return,
+        };
+        let now = Instant::now();
+        if self.next < now {
+            self.next = now;
+        }
+        let wait = self.next - now;
+        self.next += Duration::new(n / rate, ((n % rate) * 1_000_000_000 / rate) as u32);
+        if wait > Duration::new(0, 0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+struct ThrottledRead<'a> {
+    inner: Box<Read+'a>,
+    pacer: &'a RefCell<Pacer>,
+}
+impl<'a> Read for ThrottledRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.pacer.borrow_mut().take(len as u64);
+        Ok(len)
+    }
+}
+
+struct ThrottledWrite<'a> {
+    inner: Box<Write+'a>,
+    pacer: &'a RefCell<Pacer>,
+}
+impl<'a> Write for ThrottledWrite<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.pacer.borrow_mut().take(len as u64);
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::conformance::check;
+    use io::mem::MemRepoIO;
+    
+    #[test]
+    fn conformance() {
+        check(|io| io.unwrap_or_else(|| ThrottledRepoIO::new(MemRepoIO::new(),
+                Throttle::default()))).expect("ThrottledRepoIO conformance");
+    }
+    
+    #[test]
+    fn throttled() {
+        let throttle = Throttle { write_bytes: Some(10_000), ops: Some(100),
+                .. Throttle::default() };
+        let mut io = ThrottledRepoIO::new(MemRepoIO::new(), throttle);
+        let start = Instant::now();
+        for ss in 0..3 {
+            let mut w = io.new_ss(ss).expect("new_ss").expect("new");
+            w.write_all(&[0; 200]).expect("write");
+        }
+        // Writes after the first wait for 400 bytes at 10kB/s
+        assert!(start.elapsed() >= Duration::new(0, 40_000_000));
+        
+        // Opening files waits for previous operations
+        io.set_throttle(Throttle { ops: Some(100), .. Throttle::default() });
+        let start = Instant::now();
+        for ss in 0..5 {
+            io.read_ss(ss % 3).expect("read_ss").expect("present");
+        }
+        assert!(start.elapsed() >= Duration::new(0, 40_000_000));
+        
+        io.set_throttle(Throttle::default());
+        let mut buf = Vec::new();
+        io.read_ss(0).expect("read_ss").expect("present").read_to_end(&mut buf).expect("read");
+        assert_eq!(buf, vec![0; 200]);
+    }
+}
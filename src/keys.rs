@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: stable external keys for elements.
+//! 
+//! Element identifiers are not stable across partitions: `Partition::absorb`
+//! may re-map identifiers which collide, and `Partition::split` places
+//! elements in new partitions. Elements may instead carry a user-supplied
+//! *external key* (see `Element::external_key`), stored as part of the
+//! element's data. A `KeyIndex` maps these keys to the current location
+//! (partition name and identifier) of each element, and is kept up to date
+//! by indexing states and applying the `Remap` reports returned by operations
+//! moving elements.
+
+use std::collections::HashMap;
+
+use elt::{Element, EltId};
+use state::PartState;
+
+/// Record of an element moved from one partition (and identifier) to
+/// another, as returned by `Partition::absorb`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remap {
+    /// External key of the element, if any (see `Element::external_key`)
+    pub key: Option<Vec<u8>>,
+    /// Name of the partition the element was copied from
+    pub from_part: String,
+    /// Identifier of the element in `from_part`
+    pub from: EltId,
+    /// Name of the partition the element was copied to
+    pub to_part: String,
+    /// Identifier of the element in `to_part`
+    pub to: EltId,
+}
+impl Remap {
+    /// True if the identifier changed (not only the partition)
+    pub fn id_changed(&self) -> bool {
+        self.from != self.to
+    }
+}
+
+/// An index from external keys (see `Element::external_key`) to the current
+/// location of each element: partition name and element identifier.
+/// 
+/// The index is held in memory and not persisted; build it by indexing the
+/// tip of each partition (`index_state`). Keys should be unique; where two
+/// elements share a key, the one indexed last is found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyIndex {
+    keys: HashMap<Vec<u8>, (String, EltId)>,
+}
+impl KeyIndex {
+    /// Create an empty index
+    pub fn new() -> KeyIndex {
+        KeyIndex { keys: HashMap::new() }
+    }
+    
+    /// Number of keys indexed
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+    
+    /// True if no keys are indexed
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+    
+    /// Index each element of `state` which has an external key, as located
+    /// in partition `part`, replacing previous locations of these keys (e.g.
+    /// indexing a partition created by `Partition::split`). Returns the
+    /// number of keys indexed.
+    pub fn index_state<E: Element>(&mut self, part: &str, state: &PartState<E>) -> usize {
+        let mut n = 0;
+        for (id, elt) in state.elts_iter() {
+            if let Some(key) = elt.external_key() {
+                self.keys.insert(key.to_vec(), (part.to_string(), id));
+                n += 1;
+            }
+        }
+        n
+    }
+    
+    /// Remove all keys located in partition `part`
+    pub fn remove_part(&mut self, part: &str) {
+        self.keys.retain(|_, loc| loc.0 != part);
+    }
+    
+    /// Update the index from a report of moved elements. Elements without an
+    /// external key are ignored.
+    pub fn apply(&mut self, remaps: &[Remap]) {
+        for remap in remaps {
+            if let Some(ref key) = remap.key {
+                self.keys.insert(key.clone(), (remap.to_part.clone(), remap.to));
+            }
+        }
+    }
+    
+    /// Find the location of the element with the given key: partition name
+    /// and element identifier
+    pub fn get(&self, key: &[u8]) -> Option<(&str, EltId)> {
+        self.keys.get(key).map(|loc| (&loc.0[..], loc.1))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use control::DefaultControl;
+    use error::Result;
+    use io::DummyRepoIO;
+    use part::Partition;
+    use state::{StateRead, StateWrite};
+    
+    // An element with an external key
+    #[derive(PartialEq, Eq, Debug)]
+    struct Keyed(String);
+    impl Element for Keyed {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_all(self.0.as_bytes())?;
+            Ok(())
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            Ok(Keyed(String::read_buf(buf)?))
+        }
+        fn external_key(&self) -> Option<&[u8]> {
+            Some(self.0.as_bytes())
+        }
+    }
+    
+    #[test]
+    fn key_index() {
+        let create = |name, keys: &[&str]| {
+            let control = DefaultControl::<Keyed, _>::new(DummyRepoIO::new());
+            let mut part = Partition::create(control, name).expect("create");
+            let mut state = part.tip().expect("tip").clone_mut();
+            for (i, key) in keys.iter().enumerate() {
+                state.insert(EltId::from(i as u64), Keyed(key.to_string())).expect("insert");
+            }
+            part.push_state(state).expect("push_state");
+            part
+        };
+        let mut a = create("a", &["apple", "apricot"]);
+        let b = create("b", &["banana", "blueberry"]);
+        
+        let mut index = KeyIndex::new();
+        assert_eq!(index.index_state("a", a.tip().expect("tip")), 2);
+        assert_eq!(index.index_state("b", b.tip().expect("tip")), 2);
+        assert_eq!(index.get(b"banana"), Some(("b", EltId::from(0))));
+        
+        let remaps = a.absorb(&b).expect("absorb");
+        assert_eq!(remaps.len(), 2);
+        assert!(remaps.iter().all(|r| r.id_changed() && r.to_part == "a"));
+        index.apply(&remaps);
+        let (part, id) = index.get(b"blueberry").expect("get");
+        assert_eq!(part, "a");
+        assert_eq!(a.tip().expect("tip").get(id).expect("get").0, "blueberry");
+        assert_eq!(index.get(b"apple"), Some(("a", EltId::from(0))));
+        
+        index.remove_part("a");
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin as a versioned key-value store.
+//! 
+//! `KvStore` is a thin facade over a `Partition` whose elements are
+//! key-value pairs (`KvEntry`), for users who just want an embedded store
+//! with history. Changes made with `put` and `delete` are visible to `get`
+//! immediately and saved as a single commit by `commit`. An index from keys
+//! to element identifiers is maintained internally.
+//! 
+//! ```
+//! use pippin::kvstore::KvStore;
+//! use pippin::pip::MemRepoIO;
+//! 
+//! let mut store = KvStore::create(MemRepoIO::new(), "example").unwrap();
+//! store.put(b"colour", b"blue").unwrap();
+//! store.commit("choose a colour").unwrap();
+//! store.put(b"colour", b"green").unwrap();
+//! store.commit("change my mind").unwrap();
+//! 
+//! assert_eq!(store.get(b"colour"), Some(&b"green"[..]));
+//! let history = store.history(b"colour");
+//! assert_eq!(history[1].value, Some(b"blue".to_vec()));
+//! assert_eq!(history[1].message, Some("choose a colour".to_string()));
+//! ```
+
+use std::collections::HashMap;
+use std::collections::hash_map::Keys;
+use std::io::Write;
+use std::mem;
+
+use commit::{MakeCommitMeta, CommitMeta, UserMeta};
+use control::{Control, SnapshotPolicy, DefaultSnapshot};
+use elt::{Element, EltId, write_value, read_value, check_consumed};
+use error::Result;
+use io::RepoIO;
+use part::Partition;
+use state::{PartState, MutPartState, StateRead, StateWrite};
+use sum::Sum;
+
+/// A key-value pair, the element type of a `KvStore`. The key is the
+/// element's external key (see `Element::external_key`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KvEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+impl KvEntry {
+    /// Create from a key and value
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> KvEntry {
+        KvEntry { key: key, value: value }
+    }
+    /// Get the key
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+    /// Get the value
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+impl Element for KvEntry {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        write_value(writer, &self.key)?;
+        write_value(writer, &self.value)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        let mut r = buf;
+        let key = read_value(&mut r)?;
+        let value = read_value(&mut r)?;
+        check_consumed(r)?;
+        Ok(KvEntry { key: key, value: value })
+    }
+    fn external_key(&self) -> Option<&[u8]> {
+        Some(&self.key)
+    }
+}
+
+/// The `Control` used by `KvStore`: default behaviour, plus commit messages
+/// stored as extra commit metadata (`UserMeta::Text`).
+#[derive(Debug)]
+pub struct KvControl<IO: RepoIO + 'static> {
+    io: IO,
+    ss_policy: DefaultSnapshot,
+    message: Option<String>,
+}
+impl<IO: RepoIO> KvControl<IO> {
+    /// Create, given I/O provider
+    pub fn new(io: IO) -> Self {
+        KvControl { io: io, ss_policy: DefaultSnapshot::default(), message: None }
+    }
+    /// Unwrap, returning the I/O provider
+    pub fn unwrap_io(self) -> IO {
+        self.io
+    }
+}
+impl<IO: RepoIO> MakeCommitMeta for KvControl<IO> {
+    fn make_commit_extra(&self, _number: u32, _parents: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+        self.message.clone().map_or(UserMeta::None, UserMeta::Text)
+    }
+}
+impl<IO: RepoIO> Control for KvControl<IO> {
+    type Element = KvEntry;
+    fn io(&self) -> &RepoIO {
+        &self.io
+    }
+    fn io_mut(&mut self) -> &mut RepoIO {
+        &mut self.io
+    }
+    fn snapshot_policy(&mut self) -> &mut SnapshotPolicy {
+        &mut self.ss_policy
+    }
+    fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+    fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+}
+
+/// A value of a key as of some commit (see `KvStore::history`)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KvVersion {
+    /// Sum of the state committed
+    pub statesum: Sum,
+    /// Commit number (see `CommitMeta::number`)
+    pub number: u32,
+    /// Commit timestamp (see `CommitMeta::timestamp`)
+    pub timestamp: i64,
+    /// Commit message, if any
+    pub message: Option<String>,
+    /// The value set by this commit, or `None` if the key was deleted
+    pub value: Option<Vec<u8>>,
+}
+
+/// A versioned key-value store (see module documentation).
+pub struct KvStore<IO: RepoIO + 'static> {
+    part: Partition<KvControl<IO>>,
+    // Tip plus uncommitted changes
+    state: MutPartState<KvEntry>,
+    // Keys of elements in `state`
+    index: HashMap<Vec<u8>, EltId>,
+}
+impl<IO: RepoIO> KvStore<IO> {
+    /// Create a new store, writing to `io`, which must be empty.
+    pub fn create(io: IO, name: &str) -> Result<KvStore<IO>> {
+        let part = Partition::create(KvControl::new(io), name)?;
+        KvStore::from_partition(part)
+    }
+    
+    /// Open an existing store, loading all history. Fails if a merge is
+    /// required (e.g. after concurrent writes; see `Partition::merge`).
+    pub fn open(io: IO) -> Result<KvStore<IO>> {
+        let mut part = Partition::open(KvControl::new(io), true)?;
+        part.load_all()?;
+        KvStore::from_partition(part)
+    }
+    
+    fn from_partition(part: Partition<KvControl<IO>>) -> Result<KvStore<IO>> {
+        let state = part.tip()?.clone_mut();
+        let index = state.elts_iter().map(|(id, elt)| (elt.key.clone(), id)).collect();
+        Ok(KvStore { part: part, state: state, index: index })
+    }
+    
+    /// Get the value of `key`, including uncommitted changes
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.index.get(key).and_then(|id| self.state.get(*id).ok()).map(|elt| elt.value())
+    }
+    
+    /// Set the value of `key` (uncommitted until `commit`)
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let elt = KvEntry::new(key.to_vec(), value.to_vec());
+        if let Some(id) = self.index.get(key) {
+            self.state.replace(*id, elt)?;
+            return Ok(());
+        }
+        let id = self.state.insert_new(elt)?;
+        self.index.insert(key.to_vec(), id);
+        Ok(())
+    }
+    
+    /// Remove `key` (uncommitted until `commit`). Returns true if the key
+    /// was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        match self.index.remove(key) {
+            Some(id) => {
+                self.state.remove(id)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+    
+    /// Iterate over all keys, including uncommitted changes
+    pub fn keys(&self) -> Keys<Vec<u8>, EltId> {
+        self.index.keys()
+    }
+    
+    /// Number of keys, including uncommitted changes
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    
+    /// True if there are no keys
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    
+    /// Save uncommitted changes as a single commit with the given message,
+    /// and write it to storage. Returns false if there were no changes.
+    /// 
+    /// If the commit cannot be made, uncommitted changes are discarded.
+    pub fn commit(&mut self, message: &str) -> Result<bool> {
+        let tip = self.part.tip()?.clone_mut();
+        let state = mem::replace(&mut self.state, tip);
+        self.part.control_mut().message = Some(message.to_string());
+        let result = self.part.push_state(state);
+        self.part.control_mut().message = None;
+        let changed = match result {
+            Ok(changed) => changed,
+            Err(e) => {
+                self.rollback()?;
+                return Err(Box::new(e));
+            },
+        };
+        self.part.write_full()?;
+        self.rollback()?;
+        Ok(changed)
+    }
+    
+    /// Discard uncommitted changes
+    pub fn rollback(&mut self) -> Result<()> {
+        self.state = self.part.tip()?.clone_mut();
+        self.index = self.state.elts_iter().map(|(id, elt)| (elt.key.clone(), id)).collect();
+        Ok(())
+    }
+    
+    /// Get the committed values of `key`, newest first: one entry per commit
+    /// changing or deleting the value, following the first parent of each
+    /// commit back through loaded history.
+    pub fn history(&self, key: &[u8]) -> Vec<KvVersion> {
+        let mut chain: Vec<(&PartState<KvEntry>, Option<&[u8]>)> = vec![];
+        let mut id = self.index.get(key).cloned();
+        let mut next = self.part.tip().ok();
+        while let Some(state) = next {
+            let value = find(state, key, &mut id);
+            chain.push((state, value));
+            next = state.parents().first().and_then(|sum| self.part.state(sum));
+        }
+        
+        let mut versions = vec![];
+        for (i, &(state, value)) in chain.iter().enumerate() {
+            let prev = chain.get(i + 1).and_then(|&(_, value)| value);
+            if value != prev {
+                let meta = state.meta();
+                versions.push(KvVersion {
+                    statesum: state.statesum().clone(),
+                    number: meta.number(),
+                    timestamp: meta.timestamp(),
+                    message: match *meta.extra() {
+                        UserMeta::Text(ref text) => Some(text.clone()),
+                        UserMeta::None => None,
+                    },
+                    value: value.map(|v| v.to_vec()),
+                });
+            }
+        }
+        versions
+    }
+    
+    /// Get the underlying partition (e.g. to inspect history)
+    pub fn partition(&self) -> &Partition<KvControl<IO>> {
+        &self.part
+    }
+    
+    /// Consume, returning the underlying partition. Uncommitted changes are
+    /// discarded.
+    pub fn into_partition(self) -> Partition<KvControl<IO>> {
+        self.part
+    }
+}
+
+// Find the value of `key` in `state`, trying identifier `id` first and
+// updating it if the key is found under another identifier.
+fn find<'a>(state: &'a PartState<KvEntry>, key: &[u8], id: &mut Option<EltId>) ->
+        Option<&'a [u8]>
+{
+    if let Some(elt) = id.and_then(|id| state.get(id).ok()) {
+        if elt.key() == key {
+            return Some(elt.value());
+        }
+    }
+    for (elt_id, elt) in state.elts_iter() {
+        if elt.key() == key {
+            *id = Some(elt_id);
+            return Some(elt.value());
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem::MemRepoIO;
+    
+    #[test]
+    fn kvstore() {
+        let mut store = KvStore::create(MemRepoIO::new(), "kvstore").expect("create");
+        store.put(b"a", b"1").expect("put");
+        store.put(b"b", b"2").expect("put");
+        assert_eq!(store.get(b"a"), Some(&b"1"[..]));
+        assert!(store.commit("first").expect("commit"));
+        assert!(!store.commit("nothing").expect("commit"));
+        
+        store.put(b"a", b"10").expect("put");
+        assert!(store.delete(b"b").expect("delete"));
+        assert!(!store.delete(b"c").expect("delete"));
+        assert!(store.commit("second").expect("commit"));
+        store.put(b"a", b"100").expect("put");
+        store.rollback().expect("rollback");
+        assert_eq!(store.get(b"a"), Some(&b"10"[..]));
+        assert_eq!(store.get(b"b"), None);
+        assert_eq!(store.len(), 1);
+        
+        // History survives re-opening
+        let io = store.into_partition().unwrap_control().unwrap_io();
+        let store = KvStore::open(io).expect("open");
+        let history: Vec<_> = store.history(b"a").into_iter()
+                .map(|v| (v.value, v.message)).collect();
+        assert_eq!(history, vec![(Some(b"10".to_vec()), Some("second".to_string())),
+                (Some(b"1".to_vec()), Some("first".to_string()))]);
+        let history: Vec<_> = store.history(b"b").into_iter().map(|v| v.value).collect();
+        assert_eq!(history, vec![None, Some(b"2".to_vec())]);
+        assert!(store.history(b"c").is_empty());
+    }
+}
@@ -45,7 +45,7 @@
 //! *   **commit log** — a set of commits applying on top of some snapshot;
 //!     a snapshot and all associated commit logs are combined to reproduce
 //!     the latest state
-//!
+//! 
 //! Usage should be via the `Repository` type. See `examples/hello.rs` for a
 //! simple example.
 //! 
@@ -74,27 +74,50 @@
 #![allow(unused_parens)]
 
 extern crate crypto;
+#[cfg(feature = "clock")]
 extern crate chrono;
 extern crate byteorder;
 extern crate hashindexed;
+#[cfg(feature = "fs")]
 extern crate regex;
 extern crate vec_map;
 extern crate rand;
+#[cfg(feature = "fs")]
 extern crate walkdir;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "derive")]
+extern crate pippin_derive;
+#[cfg(feature = "json")]
+extern crate serde_json;
+
+#[cfg(feature = "derive")]
+pub use pippin_derive::PippinElement;
 
 pub mod commit;
 pub mod control;
+#[cfg(feature = "crdt")]
+pub mod crdt;
+pub mod dag;
+pub mod dynelt;
 pub mod elt;
 pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
 pub mod io;
+pub mod keys;
+pub mod kvstore;
+pub mod lock;
 pub mod merge;
 pub mod part;
 pub mod pip;
 pub mod rw;
 pub mod state;
 pub mod sum;
+pub mod testutil;
+#[cfg(feature = "text-merge")]
+pub mod textmerge;
 pub mod util;
 
 
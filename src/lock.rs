@@ -0,0 +1,173 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: advisory element locks, for cooperative editing.
+//! 
+//! A `LockRegistry` records *leases*: locks on elements held by an owner
+//! (e.g. a user of a collaborative editor) until an expiry time. Locks are
+//! advisory: they do not prevent changes, but each partition keeps a
+//! registry (see `Partition::lock`) and reports commits changing an element
+//! locked by another owner to `Control::on_lock_conflict`. The owner of a
+//! commit is identified by `MakeCommitMeta::commit_source`; commits whose
+//! owner is unknown are not checked.
+//! 
+//! Times are UNIX time-stamps, as those of commits (see
+//! `MakeCommitMeta::make_commit_timestamp`). Registries are not stored in
+//! partition files; use `write` and `read` to keep one in a separate file.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use elt::EltId;
+use error::{Result, Locked, OtherError};
+
+/// A lock on an element, held by `owner` until `expires`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// Identifier of the owner
+    pub owner: String,
+    /// Time at which the lock expires (UNIX time-stamp)
+    pub expires: i64,
+}
+impl Lease {
+    /// True if expired at time `now`
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires <= now
+    }
+}
+
+/// A set of element locks (see module documentation).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockRegistry {
+    leases: HashMap<EltId, Lease>,
+}
+impl LockRegistry {
+    /// Create, empty
+    pub fn new() -> LockRegistry {
+        LockRegistry { leases: HashMap::new() }
+    }
+    
+    /// Lock element `id` for `owner`, for `ttl` seconds from `now`. Renews
+    /// the lock if already held by `owner`.
+    /// 
+    /// Fails if the element is locked by another owner (and not expired).
+    pub fn lock(&mut self, id: EltId, owner: &str, ttl: i64, now: i64) ->
+            Result<(), Locked>
+    {
+        if let Some(lease) = self.lease(id, now) {
+            if lease.owner != owner {
+                return Err(Locked { owner: lease.owner.clone(), expires: lease.expires });
+            }
+        }
+        self.leases.insert(id, Lease { owner: owner.to_string(), expires: now + ttl });
+        Ok(())
+    }
+    
+    /// Release the lock on element `id`, if held by `owner`. Returns true if
+    /// released.
+    pub fn unlock(&mut self, id: EltId, owner: &str) -> bool {
+        if self.leases.get(&id).map_or(false, |lease| lease.owner == owner) {
+            self.leases.remove(&id);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Get the lock on element `id`, if locked and not expired at `now`
+    pub fn lease(&self, id: EltId, now: i64) -> Option<&Lease> {
+        self.leases.get(&id).filter(|lease| !lease.is_expired(now))
+    }
+    
+    /// Find the elements of `ids` locked by owners other than `owner` at time
+    /// `now`, with their locks
+    pub fn conflicts<I>(&self, ids: I, owner: &str, now: i64) -> Vec<(EltId, &Lease)>
+            where I: IntoIterator<Item = EltId>
+    {
+        ids.into_iter().filter_map(|id| self.lease(id, now).map(|lease| (id, lease)))
+                .filter(|&(_, lease)| lease.owner != owner)
+                .collect()
+    }
+    
+    /// Drop locks expired at `now`. Returns the number dropped.
+    pub fn expire(&mut self, now: i64) -> usize {
+        let len = self.leases.len();
+        self.leases.retain(|_, lease| !lease.is_expired(now));
+        len - self.leases.len()
+    }
+    
+    /// Number of locks held (including expired locks not yet dropped)
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+    /// True if no locks are held
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+    
+    /// Write as text: one line per lock, holding element identifier, expiry
+    /// time and owner, ordered by identifier
+    pub fn write(&self, writer: &mut Write) -> Result<()> {
+        let mut ids: Vec<_> = self.leases.keys().cloned().collect();
+        ids.sort();
+        for id in ids {
+            let lease = &self.leases[&id];
+            let id: u64 = id.into();
+            writeln!(writer, "{} {} {}", id, lease.expires, lease.owner)?;
+        }
+        Ok(())
+    }
+    
+    /// Read the format written by `write`
+    pub fn read(reader: &mut Read) -> Result<LockRegistry> {
+        let mut reg = LockRegistry::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() { continue; }
+            let mut parts = line.splitn(3, ' ');
+            let id = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let expires = parts.next().and_then(|s| s.parse::<i64>().ok());
+            match (id, expires, parts.next()) {
+                (Some(id), Some(expires), Some(owner)) => {
+                    reg.leases.insert(id.into(), Lease { owner: owner.to_string(),
+                            expires: expires });
+                },
+                _ => return OtherError::err("invalid line in lock registry"),
+            }
+        }
+        Ok(reg)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn locks() {
+        let (a, b, c) = (EltId::from(1), EltId::from(2), EltId::from(3));
+        let mut reg = LockRegistry::new();
+        reg.lock(a, "ann", 10, 100).expect("lock");
+        reg.lock(b, "bob", 20, 100).expect("lock");
+        assert_eq!(reg.lock(a, "bob", 10, 105),
+                Err(Locked { owner: "ann".to_string(), expires: 110 }));
+        reg.lock(a, "ann", 10, 105).expect("renew");
+        assert_eq!(reg.conflicts(vec![a, b, c], "ann", 105).len(), 1);
+        assert_eq!(reg.conflicts(vec![a, b, c], "cat", 116).len(), 1);
+        assert!(!reg.unlock(b, "ann"));
+        
+        let mut buf = Vec::new();
+        reg.write(&mut buf).expect("write");
+        assert_eq!(buf, b"1 115 ann\n2 120 bob\n".to_vec());
+        let mut copy = LockRegistry::read(&mut &buf[..]).expect("read");
+        assert_eq!(copy, reg);
+        
+        assert!(copy.unlock(b, "bob"));
+        copy.lock(c, "cat", 10, 120).expect("lock");
+        assert_eq!(copy.expire(120), 1);
+        assert!(copy.lease(a, 110).is_none());
+        assert_eq!(copy.len(), 1);
+    }
+}
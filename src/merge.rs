@@ -58,6 +58,10 @@ pub struct TwoWayMerge<'a, E: Element+'a> {
 impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// Create an instance. `c` should be a common ancestor state of `a` and `b`.
     /// 
+    /// Where an element is present in both `a` and `b` but differs,
+    /// `Element::merge` is tried first; conflicts it solves are not passed to
+    /// solvers.
+    /// 
     /// Operation is `O(A + B + X)` where `A` and `B` are the numbers of
     /// elements in states `a` and `b` respectively and `X` are the number of
     /// conflicts.
@@ -71,7 +75,11 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
             if let Some(elt2) = map_b.remove(&id) {
                 // Have elt in states 1 and 2
                 if elt1 != elt2 {
-                    v.push((id, EltMerge::Fail));
+                    let result = match E::merge(c.get(id).ok(), elt1, elt2) {
+                        Some(elt) => EltMerge::Value(Rc::new(elt)),
+                        None => EltMerge::Fail,
+                    };
+                    v.push((id, result));
                 }
             } else {
                 // Have elt in state 1 but not 2
@@ -89,17 +97,18 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// of them.
     /// 
     /// Operation is `O(X)`.
-    pub fn solve<S>(&mut self, s: &S) where S: TwoWaySolver<E> {
+    pub fn solve<S>(&mut self, s: &S) where S: TwoWaySolver<E> + ?Sized {
         for &mut (id, ref mut result) in &mut self.v {
             if *result == EltMerge::Fail {
-                *result = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+                let ctx = SolveContext { id: id, a: self.a, b: self.b, c: self.c };
+                *result = s.solve_ctx(&ctx);
             }
         }
     }
     
     /// Run a solver. Same as `solve()` but consumes and returns self to allow
     /// chaining.
-    pub fn solve_inline<S>(mut self, s: &S) -> Self where S: TwoWaySolver<E> {
+    pub fn solve_inline<S>(mut self, s: &S) -> Self where S: TwoWaySolver<E> + ?Sized {
         self.solve(s);
         self
     }
@@ -124,9 +133,10 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// cases.
     /// 
     /// Operation is `O(1)`.
-    pub fn solve_one<S>(&mut self, i: usize, s: &S) where S: TwoWaySolver<E> {
+    pub fn solve_one<S>(&mut self, i: usize, s: &S) where S: TwoWaySolver<E> + ?Sized {
         let id = self.v[i].0;
-        self.v[i].1 = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+        let ctx = SolveContext { id: id, a: self.a, b: self.b, c: self.c };
+        self.v[i].1 = s.solve_ctx(&ctx);
     }
     
     /// Get the number of unsolved conflicts.
@@ -143,6 +153,24 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
         self.v.iter().all(|&(_, ref result)| *result != EltMerge::Fail)
     }
     
+    /// Create the state which a merge commit would yield, without consuming
+    /// the merge. This allows the result to be inspected (e.g. to check
+    /// application invariants) before deciding whether to call
+    /// `make_commit` or abandon the merge.
+    /// 
+    /// This succeeds if and only if `is_solved()` returns true. The state
+    /// matches that from `make_commit` except that metadata (e.g. the
+    /// timestamp) and identifiers of renamed elements may differ.
+    /// 
+    /// Operation is `O(X)` plus the cost of cloning one of the tip states.
+    pub fn preview(&self, mcm: &MakeCommitMeta) -> Option<PartState<E>> {
+        let merge = TwoWayMerge { a: self.a, b: self.b, c: self.c, v: self.v.clone() };
+        let commit = merge.make_commit(mcm)?;
+        let parent = if commit.first_parent() == self.a.statesum() { self.a } else { self.b };
+        Some(PartState::from_state_commit(parent, &commit)
+                .expect("merge commit applies to its first parent"))
+    }
+    
     /// Create a merge commit.
     /// 
     /// This succeeds if and only if `is_solved()` returns true.
@@ -402,6 +430,30 @@ pub enum EltMerge<E: Element> {
     /// Give up
     Fail,
 }
+impl<E: Element> Clone for EltMerge<E> {
+    fn clone(&self) -> Self {
+        match *self {
+            EltMerge::A => EltMerge::A,
+            EltMerge::B => EltMerge::B,
+            EltMerge::Value(ref elt) => EltMerge::Value(elt.clone()),
+            EltMerge::Delete => EltMerge::Delete,
+            EltMerge::Rename => EltMerge::Rename,
+            EltMerge::Fail => EltMerge::Fail,
+        }
+    }
+}
+
+/// Context of a single element merge, passed to `TwoWaySolver::solve_ctx`.
+pub struct SolveContext<'a, E: Element+'a> {
+    /// Identifier of the element being merged
+    pub id: EltId,
+    /// First tip state
+    pub a: &'a PartState<E>,
+    /// Second tip state
+    pub b: &'a PartState<E>,
+    /// Common ancestor state
+    pub c: &'a PartState<E>,
+}
 
 /// Implementations solve two-way merges on an element-by-element basis.
 pub trait TwoWaySolver<E: Element> {
@@ -410,6 +462,25 @@ pub trait TwoWaySolver<E: Element> {
     /// return an `EltMerge` object.
     fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
         c: Option<&'a Rc<E>>) -> EltMerge<E>;
+    
+    /// As `solve`, but given the element identifier and states being merged.
+    /// This is what `TwoWayMerge` calls; solvers needing more than the
+    /// elements (e.g. state metadata) can override it.
+    /// 
+    /// The default implementation calls `solve`.
+    fn solve_ctx(&self, ctx: &SolveContext<E>) -> EltMerge<E> {
+        self.solve(ctx.a.get_rc(ctx.id).ok(), ctx.b.get_rc(ctx.id).ok(),
+                ctx.c.get_rc(ctx.id).ok())
+    }
+    
+    /// Chain with another solver: `other` is used where `self` returns
+    /// `EltMerge::Fail`. Equivalent to `TwoWaySolverChain::new(self, other)`.
+    fn try_then<'a, T: TwoWaySolver<E>>(&'a self, other: &'a T) ->
+            TwoWaySolverChain<'a, E, Self, T>
+            where Self: Sized
+    {
+        TwoWaySolverChain::new(self, other)
+    }
 }
 
 /// Implementation of `TwoWaySolver` which always selects state A.
@@ -513,6 +584,48 @@ impl<'a, E: Element, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a> TwoWaySolver<
             self.t.solve(a, b, c)
         }
     }
+    fn solve_ctx(&self, ctx: &SolveContext<E>) -> EltMerge<E> {
+        let result = self.s.solve_ctx(ctx);
+        if result != EltMerge::Fail {
+            result
+        } else {
+            self.t.solve_ctx(ctx)
+        }
+    }
+}
+
+/// Selects a solver per element: uses the first solver for elements whose
+/// identifier matches a predicate, and the second for others.
+pub struct TwoWaySolverDispatch<'a, E: Element, F: Fn(EltId) -> bool,
+    S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
+{
+    f: F, s: &'a S, t: &'a T,
+    p: PhantomData<E>
+}
+impl<'a, E: Element, F: Fn(EltId) -> bool, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
+    TwoWaySolverDispatch<'a, E, F, S, T>
+{
+    /// Create an instance: `s` is used where `f(id)` is true, `t` otherwise
+    pub fn new(f: F, s: &'a S, t: &'a T) -> TwoWaySolverDispatch<'a, E, F, S, T> {
+        TwoWaySolverDispatch { f: f, s: s, t: t, p: PhantomData }
+    }
+}
+impl<'a, E: Element, F: Fn(EltId) -> bool, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
+    TwoWaySolver<E> for TwoWaySolverDispatch<'a, E, F, S, T>
+{
+    /// Without an identifier, this cannot dispatch and fails.
+    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
+        _: Option<&Rc<E>>) -> EltMerge<E>
+    {
+        EltMerge::Fail
+    }
+    fn solve_ctx(&self, ctx: &SolveContext<E>) -> EltMerge<E> {
+        if (self.f)(ctx.id) {
+            self.s.solve_ctx(ctx)
+        } else {
+            self.t.solve_ctx(ctx)
+        }
+    }
 }
 
 /// Solver which tries to make sensible choices by comparing to the common
@@ -548,6 +661,37 @@ impl<E: Element> TwoWaySolver<E> for AncestorSolver2W<E> {
     }
 }
 
+/// Solver which uses the element from whichever tip state has the newer
+/// commit timestamp, failing if timestamps are equal. Note that timestamps
+/// depend on the clocks of the machines making commits.
+pub struct NewestSolver2W<E: Element>{
+    p: PhantomData<E>
+}
+impl<E: Element> NewestSolver2W<E> {
+    /// Create an instance (requires no parameters)
+    pub fn new() -> Self {
+        NewestSolver2W { p: PhantomData }
+    }
+}
+impl<E: Element> TwoWaySolver<E> for NewestSolver2W<E> {
+    /// Without the states, this cannot compare timestamps and fails.
+    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
+        _: Option<&Rc<E>>) -> EltMerge<E>
+    {
+        EltMerge::Fail
+    }
+    fn solve_ctx(&self, ctx: &SolveContext<E>) -> EltMerge<E> {
+        let (ta, tb) = (ctx.a.meta().timestamp(), ctx.b.meta().timestamp());
+        if ta > tb {
+            EltMerge::A
+        } else if tb > ta {
+            EltMerge::B
+        } else {
+            EltMerge::Fail
+        }
+    }
+}
+
 /// Solver which handles the case where there is no common ancestor element by
 /// renaming (or in the case that either `a` or `b` is `None`, choosing the
 /// other).
@@ -571,3 +715,97 @@ impl<E: Element> TwoWaySolver<E> for RenamingSolver2W<E> {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use elt::EltId;
+    use error::{Result, OtherError};
+    use state::StateWrite;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    #[test]
+    fn solver_library() {
+        struct TimeMeta(i64);
+        impl MakeCommitMeta for TimeMeta {
+            fn make_commit_timestamp(&self) -> i64 { self.0 }
+        }
+        let (id1, id2) = (EltId::from(1), EltId::from(2));
+        let mut state = PartState::new(&mut TimeMeta(0)).clone_mut();
+        state.insert(id1, "one".to_string()).expect("insert");
+        state.insert(id2, "two".to_string()).expect("insert");
+        let c = PartState::from_mut(state, &mut TimeMeta(0));
+        
+        let mut state = c.clone_mut();
+        state.replace(id1, "one A".to_string()).expect("replace");
+        state.replace(id2, "two A".to_string()).expect("replace");
+        let a = PartState::from_mut(state, &mut TimeMeta(2));
+        let mut state = c.clone_mut();
+        state.replace(id1, "one B".to_string()).expect("replace");
+        state.replace(id2, "two B".to_string()).expect("replace");
+        let b = PartState::from_mut(state, &mut TimeMeta(1));
+        
+        // Solvers needing context fail without it:
+        let newest = NewestSolver2W::new();
+        assert!(newest.solve(None, None, None) == EltMerge::Fail);
+        
+        // Element 1 uses B; others use the newer state (A):
+        let (use_b, fail) = (TwoWaySolveUseB::new(), TwoWaySolveFail::new());
+        let dispatch = TwoWaySolverDispatch::new(|id| id == id1, &use_b, &newest);
+        // Chaining must pass on the context:
+        let solver = fail.try_then(&dispatch);
+        let merge = TwoWayMerge::new(&a, &b, &c).solve_inline(&solver);
+        assert!(merge.is_solved());
+        let preview = merge.preview(&TimeMeta(3)).expect("preview");
+        let commit = merge.make_commit(&TimeMeta(3)).expect("make_commit");
+        let parent = if commit.first_parent() == a.statesum() { &a } else { &b };
+        let merged = PartState::from_state_commit(parent, &commit).expect("apply merge");
+        assert_eq!(merged.get(id1), Ok(&"one B".to_string()));
+        assert_eq!(merged.get(id2), Ok(&"two A".to_string()));
+        assert_eq!(preview, merged);
+    }
+    
+    #[test]
+    fn element_merge_hook() {
+        // A counter: concurrent increments are added
+        #[derive(PartialEq, Eq, Debug)]
+        struct Counter(u64);
+        impl Element for Counter {
+            fn write_buf(&self, writer: &mut Write) -> Result<()> {
+                writer.write_all(self.0.to_string().as_bytes())?;
+                Ok(())
+            }
+            fn read_buf(buf: &[u8]) -> Result<Self> {
+                let s = String::read_buf(buf)?;
+                s.parse().map(Counter).or_else(|_| OtherError::err("bad counter"))
+            }
+            fn merge(ancestor: Option<&Self>, a: &Self, b: &Self) -> Option<Self> {
+                let base = ancestor.map_or(0, |c| c.0);
+                Some(Counter(a.0 + b.0 - base))
+            }
+        }
+        
+        let id = EltId::from(1);
+        let mut state = PartState::new(&mut MCM).clone_mut();
+        state.insert(id, Counter(5)).expect("insert");
+        let c = PartState::from_mut(state, &mut MCM);
+        let mut state = c.clone_mut();
+        state.replace(id, Counter(7)).expect("replace");
+        let a = PartState::from_mut(state, &mut MCM);
+        let mut state = c.clone_mut();
+        state.replace(id, Counter(6)).expect("replace");
+        let b = PartState::from_mut(state, &mut MCM);
+        
+        // No solver is needed:
+        let merge = TwoWayMerge::new(&a, &b, &c);
+        assert!(merge.is_solved());
+        let commit = merge.make_commit(&MCM).expect("make_commit");
+        let parent = if commit.first_parent() == a.statesum() { &a } else { &b };
+        let merged = PartState::from_state_commit(parent, &commit).expect("apply merge");
+        assert_eq!(merged.get(id), Ok(&Counter(8)));
+    }
+}
@@ -0,0 +1,111 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: export and import of deltas between states (see
+//! `Partition::write_delta`).
+
+use std::io::{Read, Write};
+
+use commit::Delta;
+use control::Control;
+use error::{Result, MatchError, OtherError};
+use rw::delta::{read_delta, write_delta};
+use rw::header::{FileType, read_head, write_head};
+use state::PartState;
+use sum::Sum;
+use super::{Partition, file_codec};
+
+// Methods exporting and importing deltas
+impl<C: Control> Partition<C> {
+    /// Write the differences between loaded states `base` and `target` as a
+    /// delta file, including a header, to `writer` (see `Delta`). This may
+    /// be much smaller than a snapshot of `target`; a receiver having `base`
+    /// can reconstruct `target` via `read_delta`.
+    /// 
+    /// `base` need not be an ancestor of `target`, e.g. they may be the
+    /// states of two snapshots. Fails with `MatchError::NoMatch` if either
+    /// state is not loaded, or if `target` is an initial state (with no
+    /// parents).
+    pub fn write_delta(&mut self, base: &Sum, target: &Sum, writer: &mut Write) -> Result<()> {
+        if !self.states.contains(base) || !self.states.contains(target) {
+            return Err(Box::new(MatchError::NoMatch));
+        }
+        let delta = match Delta::from_states(self.states.get(base).unwrap(),
+                self.states.get(target).unwrap())
+        {
+            Some(delta) => delta,
+            None => return OtherError::err("cannot write delta to an initial state"),
+        };
+        let header = self.make_header(FileType::delta())?;
+        let codec = file_codec(&self.control, &header)?;
+        debug!("Partition {}: writing delta from {} to {}", self.name, base, target);
+        write_head(&header, writer)?;
+        write_delta(&delta, writer, header.ftype.ver(), &*codec, header.align())?;
+        Ok(())
+    }
+    
+    /// Read a delta file (see `write_delta`) and apply it to its base state,
+    /// which must be loaded, returning the target state. The result is not
+    /// added to this partition.
+    /// 
+    /// Fails with `MatchError::NoMatch` if the base state is not loaded, or
+    /// if the delta does not reproduce its target state.
+    pub fn read_delta(&self, reader: &mut Read) -> Result<PartState<C::Element>> {
+        let header = read_head(reader)?;
+        match header.ftype {
+            FileType::Delta(_) => {},
+            _ => return OtherError::err("not a delta file"),
+        }
+        if self.name != header.name {
+            return OtherError::err("repository name does not match when reading delta");
+        }
+        let limits = self.control.read_limits();
+        let codec = file_codec(&self.control, &header)?;
+        let delta = read_delta(reader, header.ftype.ver(), &limits, &*codec, header.align())?;
+        let base = self.states.get(delta.base()).ok_or(MatchError::NoMatch)?;
+        Ok(PartState::from_state_delta(base, &delta)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use control::DefaultControl;
+    use elt::EltId;
+    use io::mem::MemRepoIO;
+    use state::StateWrite;
+    use sum::Sum;
+    use super::super::Partition;
+    
+    #[test]
+    fn deltas() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "deltas").expect("create");
+        let initial = part.tip_key().expect("tip").clone();
+        let mut base = None;
+        for i in 0..4 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            base = base.or_else(|| Some(part.tip_key().expect("tip").clone()));
+        }
+        let base = base.expect("base");
+        let target = part.tip_key().expect("tip").clone();
+        
+        let mut buf = Vec::new();
+        part.write_delta(&base, &target, &mut buf).expect("write_delta");
+        let state = part.read_delta(&mut &buf[..]).expect("read_delta");
+        assert_eq!(state, *part.tip().expect("tip"));
+        
+        // Deltas may go backwards; the base must be known to the reader
+        let mut buf = Vec::new();
+        part.write_delta(&target, &base, &mut buf).expect("write_delta");
+        assert_eq!(part.read_delta(&mut &buf[..]).expect("read_delta").statesum(), &base);
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let other = Partition::create(control, "deltas").expect("create");
+        assert!(other.read_delta(&mut &buf[..]).is_err());
+        assert!(part.write_delta(&base, &Sum::zero(), &mut Vec::new()).is_err());
+        assert!(part.write_delta(&base, &initial, &mut Vec::new()).is_err());
+    }
+}
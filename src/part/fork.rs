@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: forks and element locks, supporting speculative and concurrent
+//! changes to a partition (see `Partition::fork_tip` and `Partition::lock`).
+
+use std::result;
+
+use control::Control;
+use elt::EltId;
+use error::{TipError, PatchOp, Locked};
+use lock::LockRegistry;
+use state::MutPartState;
+use sum::Sum;
+use super::Partition;
+
+// Methods managing forks and element locks
+impl<C: Control> Partition<C> {
+    /// Clone the tip for speculative changes, as `tip()?.clone_mut()`, but
+    /// recording the fork under `label` (replacing any fork with the same
+    /// label). Multiple forks may be outstanding; each should later be
+    /// pushed with `push_fork` or dropped with `discard_fork`.
+    pub fn fork_tip(&mut self, label: &str) ->
+            result::Result<MutPartState<C::Element>, TipError>
+    {
+        let state = self.tip()?.clone_mut();
+        self.forks.insert(label.to_string(), state.parent().clone());
+        Ok(state)
+    }
+    
+    /// List outstanding forks (see `fork_tip`): labels and the states forked,
+    /// ordered by label.
+    pub fn forks(&self) -> Vec<(&str, &Sum)> {
+        let mut forks: Vec<_> = self.forks.iter().map(|(l, sum)| (l.as_str(), sum)).collect();
+        forks.sort();
+        forks
+    }
+    
+    /// Push a state made from the fork `label` as `push_state` does, and
+    /// remove the fork. If other states have been pushed since forking, this
+    /// creates a new tip (merge afterwards).
+    /// 
+    /// Fails with `PatchOp::WrongParent` if there is no fork `label` or
+    /// `state` was not forked from the same state. If pushing fails for
+    /// another reason, the fork remains outstanding.
+    pub fn push_fork(&mut self, label: &str, state: MutPartState<C::Element>) ->
+            Result<bool, PatchOp>
+    {
+        if self.forks.get(label) != Some(state.parent()) {
+            return Err(PatchOp::WrongParent);
+        }
+        let result = self.push_state(state)?;
+        self.forks.remove(label);
+        Ok(result)
+    }
+    
+    /// Forget the fork `label` (the state itself is simply dropped by the
+    /// caller). Returns true if the fork was outstanding.
+    pub fn discard_fork(&mut self, label: &str) -> bool {
+        self.forks.remove(label).is_some()
+    }
+    
+    /// Lock element `id` for `owner`, for `ttl` seconds from now (see
+    /// `lock::LockRegistry::lock`). The time is taken from
+    /// `MakeCommitMeta::make_commit_timestamp`.
+    /// 
+    /// Locks are advisory and are not saved: new commits by another owner
+    /// changing a locked element are allowed, but reported to
+    /// `Control::on_lock_conflict`.
+    pub fn lock(&mut self, id: EltId, owner: &str, ttl: i64) -> Result<(), Locked> {
+        let now = self.control.as_mcm_ref().make_commit_timestamp();
+        self.locks.lock(id, owner, ttl, now)
+    }
+    
+    /// Release the lock on element `id`, if held by `owner`. Returns true if
+    /// released.
+    pub fn unlock(&mut self, id: EltId, owner: &str) -> bool {
+        self.locks.unlock(id, owner)
+    }
+    
+    /// Get the lock registry (see `lock`)
+    pub fn locks(&self) -> &LockRegistry {
+        &self.locks
+    }
+    
+    /// Get the lock registry mutably, e.g. to replace it with one read from
+    /// a file
+    pub fn locks_mut(&mut self) -> &mut LockRegistry {
+        &mut self.locks
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use commit::{CommitMeta, MakeCommitMeta, UserMeta};
+    use control::{Control, DefaultControl, DefaultSnapshot, SnapshotPolicy};
+    use elt::EltId;
+    use error::PatchOp;
+    use io::{DummyRepoIO, RepoIO};
+    use lock::Lease;
+    use state::StateWrite;
+    use sum::Sum;
+    use super::super::Partition;
+    
+    #[test]
+    fn forks() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "forks").expect("create");
+        let base = part.tip_key().expect("tip").clone();
+        let mut a = part.fork_tip("a").expect("fork_tip");
+        let mut b = part.fork_tip("b").expect("fork_tip");
+        let c = part.fork_tip("c").expect("fork_tip");
+        assert_eq!(part.forks(), vec![("a", &base), ("b", &base), ("c", &base)]);
+        
+        a.insert_new("a".to_string()).expect("insert");
+        b.insert_new("b".to_string()).expect("insert");
+        assert!(part.push_fork("a", a).expect("push_fork"));
+        assert_eq!(part.tips_len(), 1);
+        part.fork_tip("c").expect("fork_tip");
+        assert_eq!(part.push_fork("c", c), Err(PatchOp::WrongParent));
+        assert!(part.push_fork("b", b).expect("push_fork"));
+        assert_eq!(part.tips_len(), 2);
+        assert_eq!(part.forks().len(), 1);
+        assert!(part.discard_fork("c"));
+        assert!(!part.discard_fork("c"));
+        assert!(part.forks().is_empty());
+        
+        // Forks do not outlive the data they were forked from
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "forks").expect("create");
+        part.fork_tip("d").expect("fork_tip");
+        assert!(part.unload(true));
+        assert!(part.forks().is_empty());
+    }
+    
+    // Attributes commits to `owner` and records lock conflicts
+    #[derive(Default)]
+    struct LockControl {
+        io: DummyRepoIO,
+        ss_policy: DefaultSnapshot,
+        owner: Option<&'static str>,
+        conflicts: Vec<(EltId, String)>,
+    }
+    impl MakeCommitMeta for LockControl {
+        fn make_commit_extra(&self, _: u32, _: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+            self.owner.map_or(UserMeta::None, |owner| UserMeta::Text(owner.to_string()))
+        }
+        fn commit_source(&self, meta: &CommitMeta) -> Option<String> {
+            match *meta.extra() {
+                UserMeta::Text(ref text) => Some(text.clone()),
+                _ => None,
+            }
+        }
+    }
+    impl Control for LockControl {
+        type Element = String;
+        fn io(&self) -> &RepoIO { &self.io }
+        fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+        fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+        fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+        fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+        fn on_lock_conflict(&mut self, id: EltId, lease: &Lease) {
+            self.conflicts.push((id, lease.owner.clone()));
+        }
+    }
+    
+    #[test]
+    fn lock_conflicts() {
+        let control = LockControl { owner: Some("ann"), .. LockControl::default() };
+        let mut part = Partition::create(control, "locks").expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        let a = state.insert_new("a".to_string()).expect("inserting elt");
+        let b = state.insert_new("b".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        
+        part.lock(a, "ann", 3600).expect("lock");
+        part.lock(b, "bob", 3600).expect("lock");
+        assert!(part.lock(b, "ann", 3600).is_err());
+        for id in vec![a, b] {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.replace(id, "changed".to_string()).expect("replacing elt");
+            part.push_state(state).expect("committing");
+        }
+        assert_eq!(part.control().conflicts, vec![(b, "bob".to_string())]);
+        
+        assert!(part.unlock(b, "bob"));
+        part.control_mut().owner = None;
+        part.lock(b, "bob", 3600).expect("lock");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.remove(b).expect("removing elt");
+        part.push_state(state).expect("committing");
+        assert_eq!(part.control().conflicts.len(), 1);
+    }
+}
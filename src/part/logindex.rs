@@ -0,0 +1,199 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: use of commit log indexes (see `rw::logindex`) to locate and read
+//! single commits without replaying logs.
+
+use std::io::{self, Read, Write};
+
+use commit::Commit;
+use control::Control;
+use elt::Element;
+use error::{Result, ArgError, OtherError};
+use io::backup::FileId;
+use rw::commitlog::{CommitReceiver, read_log_with};
+use rw::header::read_head;
+use rw::logindex::{LogIndexEntry, read_log_index, write_log_index};
+use sum::Sum;
+use super::{Partition, Provenance, ByteCounter, file_codec, file_error};
+
+// Methods reading and writing commit log indexes
+impl<C: Control> Partition<C> {
+    /// Read the index of commit log `cl` of snapshot `ss` (see
+    /// `rw::logindex`). Indexes are written along with each new log, if the
+    /// `RepoIO` supports them.
+    /// 
+    /// Returns `None` if the log has no index.
+    pub fn log_index(&self, ss: usize, cl: usize) -> Result<Option<Vec<LogIndexEntry>>> {
+        if let Some(mut r) = self.control.io().read_ss_cl_index(ss, cl)? {
+            Ok(Some(read_log_index(&mut r)?))
+        } else {
+            Ok(None)
+        }
+    }
+    
+    /// Find commits with number `n` via the indexes of all commit logs,
+    /// without reading the logs themselves (logs without an index are not
+    /// searched). Returns the sum of each state found and the position of its
+    /// commit, which may be read with `read_commit` or the state with
+    /// `fetch_state`.
+    pub fn locate_cnum(&self, n: u32) -> Result<Vec<(Sum, Provenance)>> {
+        let mut found = Vec::new();
+        for ss in self.control.io().list_ss() {
+            for cl in self.control.io().list_ss_cl(ss) {
+                let index = self.log_index(ss, cl)
+                        .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+                for entry in index.into_iter().flat_map(|index| index) {
+                    if entry.cnum == n {
+                        let at = Provenance { file: FileId::CommitLog(ss, cl),
+                                offset: entry.offset };
+                        found.push((entry.statesum, at));
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+    
+    /// Read the single commit at position `at` of a commit log (as given by
+    /// `locate_cnum` or `provenance`), skipping preceding commits without
+    /// decoding them.
+    /// 
+    /// Returns `None` if the log does not exist; fails if no commit starts
+    /// at this position.
+    pub fn read_commit(&self, at: &Provenance) -> Result<Option<Commit<C::Element>>> {
+        let (ss, cl) = match at.file {
+            FileId::CommitLog(ss, cl) => (ss, cl),
+            FileId::Snapshot(_) => return ArgError::err("position is not in a commit log"),
+        };
+        let result = self.read_commit_at(ss, cl, at.offset);
+        result.map_err(|e| file_error(Some(&self.name), at.file, e))
+    }
+    
+    // Read the commit at `offset` of log `ss`-`cl` (see `read_commit`)
+    fn read_commit_at(&self, ss: usize, cl: usize, offset: u64) ->
+            Result<Option<Commit<C::Element>>>
+    {
+        let r = match self.control.io().read_ss_cl(ss, cl)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut r = ByteCounter::new(r);
+        let header = read_head(&mut r)?;
+        let codec = file_codec(&self.control, &header)?;
+        // Keep the log identifier, skip to the commit, then read as a log
+        let mut ident = [0u8; 16];
+        r.read_exact(&mut ident)?;
+        if offset < r.bytes {
+            return ArgError::err("position is before the first commit");
+        }
+        let skip = offset - r.bytes;
+        if io::copy(&mut (&mut r).take(skip), &mut io::sink())? < skip {
+            return ArgError::err("position is beyond the end of the log");
+        }
+        let mut r = (&ident[..]).chain(r);
+        let mut first = FirstCommit(None);
+        read_log_with(&mut r, &mut first, header.ftype.ver(), &self.control.read_limits(),
+                &*codec, header.align())?;
+        match first.0 {
+            Some(commit) => Ok(Some(commit)),
+            None => OtherError::err("no commit found at position"),
+        }
+    }
+    
+    // Write the index of log `cl_num` (of the latest snapshot), if the
+    // `RepoIO` supports indexes. Since indexes are optional, failure is only
+    // logged: readers then replay the log instead.
+    pub(crate) fn write_log_index(&mut self, cl_num: usize, index: &[LogIndexEntry]) {
+        let ss = self.ss1 - 1;
+        let result = match self.control.io_mut().write_ss_cl_index(ss, cl_num) {
+            Ok(Some(mut w)) => write_log_index(index, &mut w).and_then(|_| Ok(w.flush()?)),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Partition {}: failed to write index of log {}-{}: {}",
+                    self.name, ss, cl_num, e);
+        }
+    }
+    
+    // True if logs `cls` of snapshot `ss` all have indexes and none lists
+    // state `key`. Indexes which cannot be read are treated as missing.
+    pub(crate) fn indexes_exclude(&self, ss: usize, cls: &[usize], key: &Sum) -> bool {
+        cls.iter().all(|&cl| match self.log_index(ss, cl) {
+            Ok(Some(index)) => index.iter().all(|entry| entry.statesum != *key),
+            _ => false,
+        })
+    }
+}
+
+// Receives the first commit read, then stops reading
+struct FirstCommit<E: Element>(Option<Commit<E>>);
+impl<E: Element> CommitReceiver<E> for FirstCommit<E> {
+    fn receive(&mut self, commit: Commit<E>) -> bool {
+        self.0 = Some(commit);
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use control::DefaultControl;
+    use io::backup::FileId;
+    use io::mem::MemRepoIO;
+    use state::StateWrite;
+    use super::super::{Partition, Provenance};
+    
+    #[test]
+    fn log_index() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "log index").expect("create");
+        let mut keys = vec![];
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            if i != 0 {
+                part.write_fast().expect("write_fast");
+            }
+        }
+        let cnums: Vec<_> = keys.iter()
+                .map(|key| part.state(key).expect("state").meta().number())
+                .collect();
+        assert!(part.control().io().ss_cl_index_data(0, 0).is_some());
+        let index = part.log_index(0, 0).expect("log_index").expect("index");
+        assert_eq!(index.iter().map(|entry| &entry.statesum).collect::<Vec<_>>(),
+                vec![&keys[0], &keys[1]]);
+        assert_eq!(index[1].cnum, cnums[1]);
+        assert_eq!(part.log_index(0, 2).expect("log_index"), None);
+        
+        // Commits are found and read without loading the logs
+        let part = Partition::open(part.unwrap_control(), false).expect("open");
+        let found = part.locate_cnum(cnums[2]).expect("locate_cnum");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, keys[2]);
+        assert_eq!(found[0].1.file, FileId::CommitLog(0, 1));
+        let commit = part.read_commit(&found[0].1).expect("read_commit").expect("commit");
+        assert_eq!(commit.statesum(), &keys[2]);
+        assert_eq!(commit.first_parent(), &keys[1]);
+        let at = Provenance { file: FileId::CommitLog(0, 0), offset: index[1].offset };
+        let commit = part.read_commit(&at).expect("read_commit").expect("commit");
+        assert_eq!(commit.statesum(), &keys[1]);
+        assert!(part.locate_cnum(1000).expect("locate_cnum").is_empty());
+        
+        let at = Provenance { file: FileId::CommitLog(0, 0), offset: index[1].offset + 1 };
+        assert!(part.read_commit(&at).is_err());
+        assert!(part.read_commit(&Provenance { file: FileId::Snapshot(0), offset: 0 }).is_err());
+        let at = Provenance { file: FileId::CommitLog(0, 5), offset: 0 };
+        assert!(part.read_commit(&at).expect("read_commit").is_none());
+        
+        // Offsets match those recorded on load
+        let mut part = part;
+        part.load_all().expect("load_all");
+        let at = Provenance { file: FileId::CommitLog(0, 0), offset: index[1].offset };
+        assert_eq!(part.provenance(&keys[1]), Some(&at));
+    }
+}
@@ -0,0 +1,4886 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: partition
+
+mod fork;
+mod provenance;
+mod delta;
+mod logindex;
+
+pub use self::provenance::Provenance;
+
+use std::io::{self, Read, Write, BufRead, BufReader, ErrorKind};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::hash_set as hs;
+use std::collections::vec_deque;
+use std::fmt;
+use std::result;
+use std::ops::{Deref, Range};
+use std::usize;
+use std::rc::Rc;
+use std::cmp::min;
+
+use hashindexed::{HashIndexed, Iter};
+
+use commit::{Commit, CommitMeta, ProvenanceMeta, UserMeta};
+use control::{Control, DefaultControl, LogLimits};
+use dag::StateDag;
+use elt::{Element, EltId};
+use error::{Result, Error, TipError, ElementOp, PatchOp, MatchError, MergeError,
+        TransactionError, ContextError, OtherError, ReadOnly, make_io_err};
+use io::RepoIO;
+use io::backup::FileId;
+use io::mem::MemRepoIO;
+use keys::Remap;
+use lock::{LockRegistry, Lease};
+use merge::{TwoWayMerge, TwoWaySolver};
+use rw::{ReadLimits, CRC_VERSION};
+use rw::header::{FileType, FileHeader, validate_repo_name, read_head, write_head};
+use rw::codec::{Codec, select};
+use rw::snapshot::{SnapshotInfo, read_snapshot_with, read_snapshot_unless, read_snapshot_meta,
+        write_snapshot_with};
+use rw::commitlog::{CommitReceiver, CommitInfo, read_log_with, read_log_meta, start_log,
+        write_commit_with, write_trailer};
+use rw::admin::{AdminOp, AdminRecord, read_admin_log, start_admin_log, write_record};
+use rw::legacy::read_legacy_snapshot;
+use rw::logindex::LogIndexEntry;
+use state::{PartState, MutPartState, StateWrite, StateHandle, Matcher, PartStateSumComparator};
+use sum::Sum;
+
+
+/// A *partition* is a sub-set of the entire set such that (a) each element is
+/// in exactly one partition, (b) a partition is small enough to be loaded into
+/// memory in its entirety, (c) there is some user control over the number of
+/// partitions and how elements are assigned partitions and (d) each partition
+/// can be managed independently of other partitions.
+/// 
+/// Partitions are the *only* method by which the entire set may grow beyond
+/// available memory, thus smart allocation of elements to partitions will be
+/// essential for some use-cases.
+/// 
+/// A partition is in one of three possible states: (1) unloaded, (2) loaded
+/// but requiring a merge (multiple tips), (3) ready for use.
+/// 
+/// Terminology: a *tip* (as in *point* or *peak*) is a state without a known
+/// successor. Normally there is exactly one tip, but see `is_ready`,
+/// `is_loaded` and `merge_required`.
+pub struct Partition<C: Control> {
+    // User control trait object
+    control: C,
+    // Repository name. Used to identify loaded files.
+    name: String,
+    // Number of first snapshot file loaded (equal to ss1 if nothing is loaded)
+    ss0: usize,
+    // Number of latest snapshot file loaded + 1; 0 if nothing loaded and never less than ss0
+    ss1: usize,
+    // Known committed states indexed by statesum 
+    states: HashIndexed<PartState<C::Element>, Sum, PartStateSumComparator>,
+    // All states not in `states` which are known to be superceded
+    ancestors: HashSet<Sum>,
+    // States dropped from `states` by `evict_history` (these are also in
+    // `ancestors`)
+    evicted: HashSet<Sum>,
+    // Ancestry of all states seen (loaded, evicted or unloaded)
+    dag: StateDag,
+    // States read from disk on demand by `fetch_state`
+    cache: StateCache<C::Element>,
+    // All states without a known successor
+    tips: HashSet<Sum>,
+    // Commits created but not yet saved to disk. First in at front; use as queue.
+    unsaved: VecDeque<Commit<C::Element>>,
+    // Commits read whose parents are not (yet) known; see `LoadOptions::stash_orphans`
+    orphans: Vec<Commit<C::Element>>,
+    // Outcome of replaying commits during the last load
+    report: LoadReport,
+    // True once sealed (see `seal`); no further changes may be made
+    sealed: bool,
+    // Initial state, until written as snapshot zero (see `create_in_memory`)
+    initial: Option<PartState<C::Element>>,
+    // Outstanding forks by label, with the state forked (see `fork_tip`)
+    forks: HashMap<String, Sum>,
+    // Advisory element locks (see `lock`)
+    locks: LockRegistry,
+    // File each loaded state was read from (see `provenance`)
+    provenance: HashMap<Sum, Provenance>,
+}
+
+// Methods creating a partition, loading its data or checking status
+impl<C: Control> Partition<C> {
+    /// Create a partition, assigning an IO provider (this can only be done at
+    /// time of creation). Create a blank state in the partition, write an
+    /// empty snapshot to the provided `RepoIO`, and mark self as *ready
+    /// for use*.
+    /// 
+    /// Example:
+    /// 
+    /// ```
+    /// use pippin::pip::{Partition, RepoIO, DefaultControl, DummyRepoIO};
+    /// 
+    /// let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    /// let partition = Partition::create(control, "example repo");
+    /// ```
+    pub fn create(control: C, name: &str) -> Result<Partition<C>> {
+        let mut part = Partition::create_in_memory(control, name)?;
+        part.write_initial()?;
+        Ok(part)
+    }
+    
+    /// Create a partition as `create` does, but without writing anything:
+    /// the initial snapshot is written by the first write operation
+    /// (`write_fast`, `write_full`, `flush_n` or `write_snapshot`). This
+    /// allows scratch partitions which are never saved, and creation with a
+    /// read-only `RepoIO`.
+    /// 
+    /// The partition is *ready for use*, though `clone_into` requires
+    /// `tip_only` until the initial snapshot is written.
+    pub fn create_in_memory(mut control: C, name: &str) -> Result<Partition<C>> {
+        validate_repo_name(name)?;
+        info!("Creating partiton {}", name);
+        
+        let state = PartState::new(control.as_mcm_ref_mut());
+        let mut part = Partition {
+            control: control,
+            name: name.into(),
+            ss0: 0,
+            ss1: 1,
+            states: HashIndexed::new(),
+            ancestors: HashSet::new(),
+            evicted: HashSet::new(),
+            dag: StateDag::new(),
+            cache: StateCache::new(),
+            tips: HashSet::new(),
+            unsaved: VecDeque::new(),
+            orphans: vec![],
+            report: LoadReport::default(),
+            sealed: false,
+            initial: Some(state.clone_exact()),
+            forks: HashMap::new(),
+            locks: LockRegistry::new(),
+            provenance: HashMap::new(),
+        };
+        part.tips.insert(state.statesum().clone());
+        part.dag.insert(state.statesum().clone(), state.parents().to_vec(), state.meta());
+        part.insert_state(state);
+        
+        Ok(part)
+    }
+    
+    /// Create a partition from a snapshot written in a legacy file format
+    /// version (see `rw::is_legacy_version`), read from `reader`.
+    /// 
+    /// Legacy files use other checksums, so history cannot be preserved: the
+    /// snapshot's elements become the new partition's initial state, which is
+    /// written as its first snapshot via `control`'s (empty) `RepoIO`. The
+    /// name is taken from the legacy header. Commit logs following the
+    /// snapshot are not converted, thus the latest snapshot should be used.
+    /// See `rw::legacy` for details.
+    pub fn convert_legacy(mut control: C, reader: &mut Read) -> Result<Partition<C>> {
+        let limits = control.read_limits();
+        let legacy = read_legacy_snapshot(reader, &limits, control.as_mcm_ref_mut())?;
+        validate_repo_name(&legacy.name)?;
+        info!("Creating partition {} from legacy snapshot", legacy.name);
+        Partition::create_from_state(control, legacy.name, legacy.state)
+    }
+    
+    /// Open a partition, assigning an IO provider (this can only be done at
+    /// time of creation).
+    /// 
+    /// If `read_data` is true, the latest state is read into memory immediately.
+    /// Otherwise, initialise the partition without reading data (currently requires reading a
+    /// snapshot header). In this case the partition will not be *ready to use* until data is
+    /// loaded with one of the load operations. Until then most operations will fail.
+    /// 
+    /// Example:
+    /// 
+    /// ```no_run
+    /// use std::path::Path;
+    /// use pippin::pip::{Partition, DefaultControl, part_from_path};
+    /// 
+    /// let path = Path::new("./my-partition");
+    /// let io = part_from_path(path).unwrap();
+    /// let control = DefaultControl::<String, _>::new(io);
+    /// let partition = Partition::open(control, true);
+    /// ```
+    pub fn open(mut control: C, read_data: bool) -> Result<Partition<C>> {
+        trace!("Opening partition");
+        // We need to read a header for classification purposes
+        
+        let ss_len = control.io().ss_len();
+        let pruned = read_gaps(control.io())?;
+        let mut missing = vec![];
+        for ss in control.io().list_ss().into_iter().rev() {
+            debug!("Partition: reading snapshot {}", ss);
+            let opt_head = read_ss_head(control.io(), ss)
+                    .map_err(|e| file_error(None, FileId::Snapshot(ss), e))?;
+            let result = if let Some(head) = opt_head {
+                trace!("Partition: name: {}", head.name);
+                if head.historical && has_older_ss(control.io(), ss) {
+                    debug!("Partition: snapshot {} is historical; using an older one", ss);
+                    continue;
+                }
+                
+                let state = if read_data {
+                    // Let the control read the header first (it may
+                    // configure limits), then re-open to read the state:
+                    control.read_header(&head)?;
+                    let limits = control.read_limits();
+                    match read_ss_file(&control, ss, &limits)
+                            .map_err(|e| file_error(Some(&head.name), FileId::Snapshot(ss), e))?
+                    {
+                        Some((_, state)) => Some(state),
+                        None => return make_io_err(ErrorKind::NotFound,
+                                format!("snapshot {} disappeared", ss)),
+                    }
+                } else {
+                    None
+                };
+                
+                Some((head.name, head.sealed, state))
+            } else {
+                if !pruned.contains(&ss) {
+                    warn!("Partition: missing snapshot {}", ss);
+                    missing.push(LoadWarning::MissingSnapshot(ss));
+                }
+                None
+            };
+            if let Some((name, sealed, opt_state)) = result {
+                let mut part = Partition {
+                    control,
+                    name,
+                    ss0: 0,
+                    ss1: 0,
+                    states: HashIndexed::new(),
+                    ancestors: HashSet::new(),
+                    evicted: HashSet::new(),
+                    dag: StateDag::new(),
+                    cache: StateCache::new(),
+                    tips: HashSet::new(),
+                    unsaved: VecDeque::new(),
+                    orphans: vec![],
+                    report: LoadReport::default(),
+                    sealed: false,
+                    initial: None,
+                    forks: HashMap::new(),
+                    locks: LockRegistry::new(),
+                    provenance: HashMap::new(),
+                };
+                part.report.warnings = missing;
+                part.sealed = sealed;
+                
+                if let Some(state) = opt_state {
+                    part.tips.insert(state.statesum().clone());
+                    for parent in state.parents() {
+                        part.ancestors.insert(parent.clone());
+                    }
+                    part.dag.insert(state.statesum().clone(), state.parents().to_vec(),
+                            state.meta());
+                    part.insert_state(state);
+                    part.control.snapshot_policy().reset();
+                    part.ss0 = ss;
+                    for ss2 in ss..ss_len {
+                        part.read_commits_for_ss(ss2, pruned.contains(&ss2))?;
+                    }
+                    part.check_orphans(false)?;
+                    part.ss1 = ss_len;
+                }
+                
+                return Ok(part);
+            }
+        }
+        OtherError::err("no snapshot found for first partition")
+    }
+    
+    /// Get the repo name, contained in each file's header.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// Load all history. Shortcut for `load_range(0, usize::MAX, control)`.
+    pub fn load_all(&mut self) -> Result<()> {
+        self.load_range(0, usize::MAX)
+    }
+    /// Load latest state from history (usually including some historical
+    /// data). Shortcut for `load_range(usize::MAX, usize::MAX, control)`.
+    pub fn load_latest(&mut self) -> Result<()> {
+        self.load_range(usize::MAX, usize::MAX)
+    }
+    
+    /// As `load_range(ss0, ss1)`, with options. If `options.auto_merge` is
+    /// set and loading leaves multiple tips (e.g. logs written concurrently
+    /// on two devices), these are merged with the given solver (see
+    /// `merge()`; more history is loaded if necessary), such that on success
+    /// `is_ready()` is true.
+    /// 
+    /// If `options.stash_orphans` is set, commits whose parent state is not
+    /// found are kept aside instead of causing failure (see `load_report()`).
+    pub fn load_with(&mut self, ss0: usize, ss1: usize, options: &LoadOptions<C::Element>) ->
+            Result<()>
+    {
+        self.load_impl(ss0, ss1, options.stash_orphans)?;
+        if let Some(solver) = options.auto_merge {
+            if self.merge_required() {
+                self.merge(solver, true)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Load snapshots `ss` where `ss0 <= ss < ss1`, and all log files for each
+    /// snapshot loaded. If `ss0` is beyond the latest snapshot found, it will
+    /// be reduced to the number of the last snapshot. `ss1` may be large. For
+    /// example, `(0, usize::MAX)` means load everything available and
+    /// `(usize::MAX, usize::MAX)` means load only the latest state.
+    /// 
+    /// Special behaviour: if some snapshots are already loaded and the range
+    /// does not overlap with this range, all snapshots in between will be
+    /// loaded.
+    /// 
+    /// Commits need not be ordered parent-before-child within or across the
+    /// logs loaded: a commit whose parent state is not found is set aside
+    /// until the parent appears. Loading fails if some parent is still not
+    /// found at the end; see `load_with` for an alternative. Afterwards,
+    /// `load_report()` lists the commits read from logs.
+    /// 
+    /// TODO: allow loading new & extended log files when snapshot is already loaded.
+    pub fn load_range(&mut self, ss0: usize, ss1: usize) -> Result<()> {
+        self.load_impl(ss0, ss1, false)
+    }
+    
+    // Implementation of `load_range`; if `stash` is true, orphaned commits
+    // are stashed instead of causing failure.
+    fn load_impl(&mut self, ss0: usize, ss1: usize, stash: bool) -> Result<()> {
+        // We have to consider several cases: nothing previously loaded, that
+        // we're loading data older than what was previously loaded, or newer,
+        // or even overlapping. The algorithm we use is:
+        // 
+        //  while ss0 > 0 and not has_ss(ss0), ss0 -= 1
+        //  if ss0 == 0 and not has_ss(0) nor pruned, assume initial state
+        //  for ss in ss0..ss1:
+        //      if this snapshot was already loaded, skip
+        //      load snapshot if found, skip if not
+        //      load all logs found and rebuild states, aborting if parents are missing
+        
+        // Input arguments may be greater than the available snapshot numbers. Clamp:
+        let ss_len = self.control.io().ss_len();
+        let mut ss0 = min(ss0, if ss_len > 0 { ss_len - 1 } else { ss_len });
+        let mut ss1 = min(ss1, ss_len);
+        // If data is already loaded, we must load snapshots between it and the new range too:
+        let was_loaded = self.ss1 > self.ss0;
+        if was_loaded {
+            if ss0 > self.ss1 { ss0 = self.ss1; }
+            if ss1 < self.ss0 { ss1 = self.ss0; }
+        }
+        self.report = LoadReport::default();
+        // If snapshot files are missing, we need to load older files:
+        let pruned = read_gaps(self.control.io())?;
+        // Historical snapshots (see `write_snapshot_of`) are skipped likewise.
+        while ss0 > 0 {
+            if !self.control.io().has_ss(ss0) {
+                if !pruned.contains(&ss0) {
+                    self.warn_load(LoadWarning::MissingSnapshot(ss0));
+                }
+            } else if !is_historical_ss(self.control.io(), ss0)? ||
+                    !has_older_ss(self.control.io(), ss0)
+            {
+                break;
+            }
+            ss0 -= 1;
+        }
+        
+        if ss0 == 0 && !self.control.io().has_ss(ss0) && !pruned.contains(&0) {
+            // No initial snapshot (and not pruned); assume a blank state
+            let state = PartState::new(self.control.as_mcm_ref_mut());
+            self.tips.insert(state.statesum().clone());
+            self.dag.insert(state.statesum().clone(), vec![], state.meta());
+            self.insert_state(state);
+        }
+        
+        let old_ss1 = self.ss1;
+        let limits = self.control.read_limits();
+        let mut require_ss = false;
+        for ss in ss0..ss1 {
+            // If already loaded, skip this snapshot:
+            if self.ss0 <= ss && ss < self.ss1 { continue; }
+            let at_tip = ss >= self.ss1;
+            
+            debug!("Partition {}: reading snapshot {}", self.name, ss);
+            let opt_result = {
+                let states = &self.states;
+                read_ss_file_unless(&self.control, ss, &limits, &mut |sum| states.contains(sum))
+                        .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?
+            };
+            
+            if let Some((header, opt_state)) = opt_result {
+                let historical = header.historical;
+                if at_tip && header.sealed {
+                    self.sealed = true;
+                }
+                self.verify_header(header)
+                        .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?;
+                
+                // States already loaded are not decoded again (None). A state
+                // already known (e.g. from a historical snapshot) keeps its
+                // status:
+                if let Some(state) = opt_state {
+                    if !self.ancestors.contains(state.statesum()) {
+                        self.tips.insert(state.statesum().clone());
+                    }
+                    for parent in state.parents() {
+                        if !self.states.contains(parent) {
+                            self.ancestors.insert(parent.clone());
+                        }
+                    }
+                    // TODO: check that classification in state equals that of this partition? (Already done in this case.)
+                    self.evicted.remove(state.statesum());
+                    self.dag.insert(state.statesum().clone(), state.parents().to_vec(),
+                            state.meta());
+                    self.provenance.entry(state.statesum().clone())
+                            .or_insert(Provenance { file: FileId::Snapshot(ss), offset: 0 });
+                    self.insert_state(state);
+                }
+                
+                require_ss = false;
+                if at_tip && !historical {
+                    self.control.snapshot_policy().reset();
+                }
+            } else {
+                if pruned.contains(&ss) {
+                    info!("Partition {}: snapshot {} was pruned", self.name, ss);
+                } else if !self.report.warnings.contains(&LoadWarning::MissingSnapshot(ss)) {
+                    self.warn_load(LoadWarning::MissingSnapshot(ss));
+                }
+                // Missing snapshot; if at head require a new one
+                require_ss = at_tip;
+            }
+            
+            self.read_commits_for_ss(ss, pruned.contains(&ss))?;
+            if at_tip {
+                self.ss1 = ss + 1;
+            }
+        }
+        if let Err(e) = self.check_orphans(stash) {
+            self.ss1 = old_ss1;
+            return Err(e.into());
+        }
+        
+        if ss0 < self.ss0 || !was_loaded {
+            // Older history was loaded (or nothing was loaded before). In this
+            // case we can only update ss0 once all older snapshots have been
+            // loaded. If there was a failure and retry, some snapshots could be
+            // reloaded unnecessarily.
+            self.ss0 = ss0;
+        }
+        assert!(self.ss0 <= ss1 && ss1 <= self.ss1);
+        
+        if require_ss {
+            self.control.snapshot_policy().force_snapshot();
+        }
+        Ok(())
+    }
+    
+    // Read commit logs for a snapshot and replay commits (see `replay`).
+    // If `pruned`, missing logs are expected.
+    fn read_commits_for_ss(&mut self, ss: usize, pruned: bool) -> Result<()> {
+        let mut queue = LogQueue::new();
+        // Commits of states already loaded or queued are not decoded again:
+        queue.known = Some(self.states.iter().map(|state| state.statesum().clone())
+                .chain(self.orphans.iter().map(|commit| commit.statesum().clone()))
+                .collect());
+        let mut trailers = vec![];
+        let limits = self.control.read_limits();
+        for cl in self.control.io().list_ss_cl(ss) {
+            debug!("Partition {}: reading commit log {}-{}", self.name, ss, cl);
+            let opt_header = read_cl_file(&self.control, ss, cl, &mut queue, &limits, true)
+                    .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+            trailers.extend(queue.trailers.drain(..).map(|(sum, n)| (cl, sum, n)));
+            if let Some((header, bytes, truncated)) = opt_header {
+                self.verify_header(header)
+                        .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+                self.control.snapshot_policy().count_bytes(bytes);
+                if truncated {
+                    self.warn_load(LoadWarning::TruncatedLog(ss, cl));
+                }
+            } else if !pruned {
+                self.warn_load(LoadWarning::MissingLog(ss, cl));
+            }
+        }
+        for sum in queue.skipped {
+            trace!("Partition {}: skipped duplicate commit {}", self.name, sum);
+            self.report.duplicates.push(sum);
+        }
+        for (commit, source) in queue.commits.into_iter().zip(queue.sources) {
+            self.provenance.entry(commit.statesum().clone()).or_insert(source);
+            self.replay(commit)?;
+        }
+        self.replay_orphans()?;
+        for (cl, sum, num_elts) in trailers {
+            self.check_trailer(sum, num_elts)
+                    .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+        }
+        Ok(())
+    }
+    
+    // Check that the state named by a log trailer was reached and has the
+    // expected number of elements. It may not be reached if commits were
+    // orphaned (in which case `check_orphans` reports the problem).
+    fn check_trailer(&mut self, sum: Sum, num_elts: usize) -> Result<()> {
+        if let Some(state) = self.states.get(&sum) {
+            if state.len() != num_elts {
+                return OtherError::err(format!("state {} has {} elements; log trailer expects {}",
+                        sum, state.len(), num_elts));
+            }
+        } else if self.orphans.is_empty() {
+            return OtherError::err(format!("state {} from log trailer not reached", sum));
+        } else {
+            self.warn_load(LoadWarning::TrailerNotReached(sum));
+            return Ok(());
+        }
+        self.report.verified.push(sum);
+        Ok(())
+    }
+    
+    // Log a warning and add it to the report
+    fn warn_load(&mut self, warning: LoadWarning) {
+        warn!("Partition {}: {}", self.name, warning);
+        self.report.warnings.push(warning);
+    }
+    
+    // Apply a commit read from a log, recording the outcome in the report.
+    // If its parent is unknown, it is stashed (see `check_orphans`).
+    fn replay(&mut self, commit: Commit<C::Element>) -> Result<(), PatchOp> {
+        let sum = commit.statesum().clone();
+        if self.states.contains(&sum) || self.orphans.iter().any(|c| *c.statesum() == sum) {
+            trace!("Partition {}: skipping duplicate commit {}", self.name, sum);
+            self.report.duplicates.push(sum);
+            return Ok(());
+        }
+        if !self.states.contains(commit.first_parent()) {
+            debug!("Partition {}: parent {} of commit {} not found (yet)", self.name,
+                    commit.first_parent(), sum);
+            self.report.orphaned.push(sum);
+            self.orphans.push(commit);
+            return Ok(());
+        }
+        self.add_commit(commit)?;
+        self.report.applied.push(sum);
+        Ok(())
+    }
+    
+    // Apply stashed orphans whose parents are now known (repeatedly, since
+    // one may be the parent of another).
+    fn replay_orphans(&mut self) -> Result<(), PatchOp> {
+        loop {
+            let pos = {
+                let states = &self.states;
+                self.orphans.iter().position(|c| states.contains(c.first_parent()))
+            };
+            let commit = match pos {
+                Some(i) => self.orphans.swap_remove(i),
+                None => return Ok(()),
+            };
+            let sum = commit.statesum().clone();
+            debug!("Partition {}: found parent of commit {}", self.name, sum);
+            self.report.orphaned.retain(|s| *s != sum);
+            self.add_commit(commit)?;
+            self.report.applied.push(sum);
+        }
+    }
+    
+    // At the end of a load, fail if commits read during the load are still
+    // orphaned, unless `stash` is true.
+    fn check_orphans(&self, stash: bool) -> Result<(), PatchOp> {
+        for sum in &self.report.orphaned {
+            warn!("Partition {}: parent of commit {} not found", self.name, sum);
+        }
+        if !stash && !self.report.orphaned.is_empty() {
+            return Err(PatchOp::NoParent);
+        }
+        Ok(())
+    }
+    
+    /// Record that snapshots `ss_nums` (along with their commit logs) have
+    /// been removed intentionally, e.g. by a retention policy. Loading then
+    /// treats these as pruned rather than warning that files are missing.
+    /// 
+    /// The record is kept in the `RepoIO`'s gap index, replacing any previous
+    /// version; this fails if the `RepoIO` does not support a gap index. The
+    /// operation is also noted in the admin log, if supported (see
+    /// `admin_history`).
+    pub fn record_pruned(&mut self, ss_nums: &[usize]) -> Result<()> {
+        let mut pruned = read_gaps(self.control.io())?;
+        pruned.extend(ss_nums.iter().cloned());
+        info!("Partition {}: recording pruned snapshots: {:?}", self.name, pruned);
+        if let Some(mut w) = self.control.io_mut().write_gaps()? {
+            for ss in pruned {
+                writeln!(w, "{}", ss)?;
+            }
+            w.flush()?;
+        } else {
+            return OtherError::err("gap index not supported by RepoIO");
+        }
+        self.append_admin(AdminOp::Prune, format!("snapshots {:?}", ss_nums))?;
+        Ok(())
+    }
+    
+    /// Record an administrative operation in the admin log, with a
+    /// description for operators, e.g. which files were removed after packing
+    /// (see `io::pack`) and why. `record_pruned` does this itself.
+    /// 
+    /// The log is append-only and kept by the `RepoIO`; this fails if the
+    /// `RepoIO` does not support an admin log.
+    pub fn record_admin(&mut self, op: AdminOp, detail: String) -> Result<()> {
+        if self.append_admin(op, detail)? {
+            Ok(())
+        } else {
+            OtherError::err("admin log not supported by RepoIO")
+        }
+    }
+    
+    /// Get the records of the admin log (see `record_admin`), oldest first.
+    /// This is empty if no operation has been recorded.
+    pub fn admin_history(&self) -> Result<Vec<AdminRecord>> {
+        if let Some(mut r) = self.control.io().read_admin_log()? {
+            let header = read_head(&mut r)?;
+            match header.ftype {
+                FileType::AdminLog(_) => {},
+                _ => return OtherError::err("not an admin log"),
+            }
+            read_admin_log(&mut r)
+        } else {
+            Ok(vec![])
+        }
+    }
+    
+    // Append a record to the admin log, starting the log if there is none.
+    // Returns false if the `RepoIO` does not support an admin log.
+    fn append_admin(&mut self, op: AdminOp, detail: String) -> Result<bool> {
+        let record = AdminRecord {
+            timestamp: self.control.as_mcm_ref().make_commit_timestamp(),
+            op: op,
+            detail: detail,
+        };
+        // Each record is written via a single write operation:
+        let mut buf = Vec::new();
+        if self.control.io().read_admin_log()?.is_none() {
+            let mut header = self.make_header(FileType::admin_log())?;
+            // The log holds no element data
+            header.codec = None;
+            header.align = None;
+            write_head(&header, &mut buf)?;
+            start_admin_log(&mut buf)?;
+        }
+        write_record(&record, &mut buf)?;
+        if let Some(mut w) = self.control.io_mut().append_admin_log()? {
+            info!("Partition {}: admin log: {} {}", self.name, record.op, record.detail);
+            w.write_all(&buf)?;
+            w.flush()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    
+    /// Get the snapshot numbers recorded as pruned (see `record_pruned`), in
+    /// order.
+    pub fn pruned(&self) -> Result<Vec<usize>> {
+        Ok(read_gaps(self.control.io())?.into_iter().collect())
+    }
+    
+    /// Get a report on the last load operation (or `open` with `read_data`):
+    /// commits read from logs and warnings about conditions which did not
+    /// cause failure but may need attention, such as missing files.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.report
+    }
+    
+    /// Number of stashed commits whose parents have not been found (see
+    /// `LoadOptions::stash_orphans`). These are applied automatically when
+    /// their parents are loaded.
+    pub fn orphans_len(&self) -> usize {
+        self.orphans.len()
+    }
+    
+    /// Read the metadata of all snapshots and commits available from the
+    /// `RepoIO`, without element data (see `read_snapshot_meta` and
+    /// `read_log_meta`). This is much faster than loading, allowing history
+    /// to be browsed and ancestry examined before deciding which states to
+    /// load; loaded data is not affected.
+    /// 
+    /// As when loading, a commit log ending part-way through a commit is
+    /// read up to that point.
+    pub fn load_meta(&self) -> Result<HistoryMeta> {
+        let limits = self.control.read_limits();
+        let io = self.control.io();
+        let mut history = HistoryMeta::default();
+        for ss in io.list_ss() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                debug!("Partition {}: reading metadata of snapshot {}", self.name, ss);
+                let info = self.check_head(&mut r).and_then(|header|
+                    read_snapshot_meta(&mut r, header.ftype.ver(), &limits, header.align())
+                ).map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?;
+                history.dag.insert(info.statesum.clone(), info.parents.clone(), &info.meta);
+                history.snapshots.push((ss, info));
+            }
+            for cl in io.list_ss_cl(ss) {
+                if let Some(r) = io.read_ss_cl(ss, cl)? {
+                    debug!("Partition {}: reading metadata of commit log {}-{}", self.name, ss, cl);
+                    let mut r = ByteCounter::new(r);
+                    let len = history.commits.len();
+                    let result = self.check_head(&mut r).and_then(|header| {
+                        let base = r.bytes;
+                        let result = read_log_meta(&mut r, &mut history.commits,
+                                header.ftype.ver(), &limits, header.align());
+                        history.commit_sources.extend(history.commits[len..].iter().map(|info|
+                                Provenance { file: FileId::CommitLog(ss, cl),
+                                        offset: base + info.offset }));
+                        result
+                    });
+                    match result {
+                        Err(ref e) if is_eof(e) => {},
+                        Err(e) => return Err(file_error(Some(&self.name),
+                                FileId::CommitLog(ss, cl), e)),
+                        Ok(()) => {},
+                    }
+                }
+            }
+        }
+        for info in &history.commits {
+            history.dag.insert(info.statesum.clone(), info.parents.clone(), &info.meta);
+        }
+        Ok(history)
+    }
+    
+    /// Find commits whose metadata matches `filter`, searching all commit
+    /// logs available (see `load_meta`) and unsaved commits. Returns the
+    /// statesums of matching commits in order (as in `HistoryMeta::commits`,
+    /// followed by unsaved commits), omitting repeats.
+    /// 
+    /// This reads metadata only, thus is fast compared to loading.
+    pub fn find_commits(&self, filter: &CommitFilter) -> Result<Vec<Sum>> {
+        let history = self.load_meta()?;
+        let saved = history.commits.iter()
+                .map(|info| (&info.statesum, &info.meta, info.num_changes));
+        let unsaved = self.unsaved.iter()
+                .map(|commit| (commit.statesum(), commit.meta(), commit.num_changes()));
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        for (sum, meta, num_changes) in saved.chain(unsaved) {
+            if seen.insert(sum.clone()) && filter.matches(meta, num_changes) {
+                found.push(sum.clone());
+            }
+        }
+        Ok(found)
+    }
+    
+    // Read a header, checking the partition name
+    fn check_head(&self, r: &mut Read) -> Result<FileHeader> {
+        let header = read_head(r)?;
+        if header.name != self.name {
+            return OtherError::err("repository name does not match when loading (wrong repo?)");
+        }
+        Ok(header)
+    }
+    
+    /// Read and fully verify all snapshots and commit logs available from the
+    /// `RepoIO`, whether or not loaded, ignoring `ReadLimits::quick_verify`.
+    /// This checks all file and element checksums (e.g. those skipped by
+    /// quick verification when loading); loaded data is not affected.
+    /// 
+    /// Returns the first error found (with the file concerned attached via
+    /// `ContextError`).
+    pub fn verify_files(&self) -> Result<()> {
+        let mut limits = self.control.read_limits();
+        limits.quick_verify = false;
+        let io = self.control.io();
+        for ss in io.list_ss() {
+            debug!("Partition {}: verifying snapshot {}", self.name, ss);
+            read_ss_file(&self.control, ss, &limits)
+                    .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?;
+            for cl in io.list_ss_cl(ss) {
+                debug!("Partition {}: verifying commit log {}-{}", self.name, ss, cl);
+                read_cl_file(&self.control, ss, cl, &mut LogQueue::new(), &limits, false)
+                        .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// The oldest snapshot number loaded
+    pub fn oldest_ss_loaded(&self) -> usize {
+        self.ss0
+    }
+    
+    /// Returns true when elements have been loaded (i.e. there is at least one
+    /// tip; see also `is_ready` and `merge_required`).
+    pub fn is_loaded(&self) -> bool {
+        !self.tips.is_empty()
+    }
+    
+    /// Returns true when ready for use (this is equivalent to
+    /// `self.is_loaded() && !self.merge_required()`, i.e. there is exactly
+    /// one tip).
+    pub fn is_ready(&self) -> bool {
+        self.tips.len() == 1
+    }
+    
+    /// Returns true while a merge is required (i.e. there is more than one
+    /// tip).
+    /// 
+    /// Returns false if not ready or no tip is found as well as when a single
+    /// tip is present and ready to use.
+    pub fn merge_required(&self) -> bool {
+        self.tips.len() > 1
+    }
+    
+    /// True if the partition is sealed, i.e. may not be modified (see
+    /// `seal`)
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+    
+    // Fail if sealed (see `seal`)
+    fn check_unsealed(&self) -> Result<()> {
+        if self.sealed {
+            warn!("Partition {}: sealed; refusing to write", self.name);
+            return ReadOnly::err();
+        }
+        Ok(())
+    }
+    
+    // Verify values in a header.
+    fn verify_header(&mut self, header: FileHeader) -> Result<()> {
+        if self.name != header.name {
+            return OtherError::err("repository name does not match when loading (wrong repo?)");
+        }
+        
+        self.control.read_header(&header)?;
+        
+        Ok(())
+    }
+    
+    /// Create a header
+    fn make_header(&mut self, file_type: FileType) -> Result<FileHeader> {
+        let file_type = if self.control.write_crc() { file_type.crc() } else { file_type };
+        let mut header = FileHeader {
+            codec: codec_name(&self.control, &file_type),
+            align: Some(self.control.element_align(&file_type)),
+            ftype: file_type,
+            name: self.name.clone(),
+            user: vec![],
+            historical: false,
+            sealed: false,
+        };
+        let user_fields = self.control.make_user_data(&header)?;
+        header.user = user_fields;
+        Ok(header)
+    }
+    
+    /// Unload data from memory. Note that unless `force == true` the operation
+    /// will fail if any changes have not yet been saved to disk.
+    /// 
+    /// Returns true if data was unloaded, false if not (implies `!force` and 
+    /// that unsaved changes exist).
+    /// 
+    /// Everything derived from the loaded states is dropped, including
+    /// outstanding forks, provenance and the load report, except the
+    /// ancestry (see `dag`).
+    pub fn unload(&mut self, force: bool) -> bool {
+        trace!("Unloading partition {} data", self.name);
+        if force || self.unsaved.is_empty() {
+            self.states.clear();
+            self.ancestors.clear();
+            self.evicted.clear();
+            self.cache.clear();
+            self.tips.clear();
+            self.orphans.clear();
+            self.forks.clear();
+            self.provenance.clear();
+            self.report = LoadReport::default();
+            // The ancestry is deliberately kept, allowing queries while unloaded
+            self.ss0 = 0;
+            self.ss1 = 0;
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Drop historical states from memory, keeping those selected by `keep`
+    /// as well as tips and the states of unsaved commits. The partition
+    /// stays loaded: evicted states can be read from disk again (see
+    /// `restore_history`), and their parent links are kept, thus finding a
+    /// common ancestor still works.
+    /// 
+    /// Evicted states are not found by `state()`, `states_iter()` and
+    /// similar until restored. `merge()` with `auto_load` restores them when
+    /// required.
+    /// 
+    /// Returns the number of states evicted.
+    pub fn evict_history(&mut self, keep: KeepCriteria) -> usize {
+        let depth = match keep {
+            KeepCriteria::Tips => 0,
+            KeepCriteria::Depth(depth) => depth,
+        };
+        let mut kept = HashSet::new();
+        let mut next: Vec<Sum> = self.tips.iter().cloned()
+                .chain(self.unsaved.iter().map(|commit| commit.statesum().clone()))
+                .collect();
+        let mut generation = 0;
+        while !next.is_empty() {
+            let mut parents = vec![];
+            for sum in next {
+                if let Some(state) = self.states.get(&sum) {
+                    if generation < depth {
+                        parents.extend(state.parents().iter().cloned());
+                    }
+                    kept.insert(sum);
+                }
+            }
+            next = parents;
+            generation += 1;
+        }
+        
+        let evict: Vec<Sum> = self.states.iter().map(|state| state.statesum())
+                .filter(|sum| !kept.contains(*sum)).cloned().collect();
+        for sum in &evict {
+            self.states.remove(sum);
+            self.ancestors.insert(sum.clone());
+            self.evicted.insert(sum.clone());
+        }
+        debug!("Partition {}: evicted {} states", self.name, evict.len());
+        evict.len()
+    }
+    
+    /// Find states held (loaded or evicted) which are not reachable from any
+    /// tip: states which are neither a tip, the state of an unsaved commit,
+    /// nor an ancestor of one of these. Such states may remain after merges
+    /// and partial loads, e.g. where a state is superseded by a commit
+    /// which is not loaded; they are not needed to merge or make commits.
+    /// 
+    /// Returns the statesums found, sorted.
+    pub fn unreachable_states(&self) -> Vec<Sum> {
+        let reachable = self.reachable_states();
+        let mut unreachable: Vec<Sum> = self.states.iter().map(|state| state.statesum())
+                .chain(self.evicted.iter())
+                .filter(|sum| !reachable.contains(*sum)).cloned().collect();
+        unreachable.sort();
+        unreachable
+    }
+    
+    /// Drop unreachable states (see `unreachable_states`) from memory. Unlike
+    /// `evict_history`, these states are forgotten: they are not restored
+    /// by `restore_history`. They are still recorded in the ancestry (see
+    /// `dag`), and may be loaded again with `load_range`.
+    /// 
+    /// Returns the number of states dropped and the memory reclaimed.
+    pub fn drop_unreachable(&mut self) -> Reclaimed {
+        let mut reclaimed = Reclaimed::default();
+        for sum in self.unreachable_states() {
+            if let Some(len) = self.states.get(&sum).map(|state| state.byte_len()) {
+                self.states.remove(&sum);
+                reclaimed.bytes += len;
+            }
+            self.evicted.remove(&sum);
+            // Keep marked as an ancestor, thus not becoming a tip if reloaded
+            self.ancestors.insert(sum);
+            reclaimed.states += 1;
+        }
+        debug!("Partition {}: dropped {} unreachable states ({} bytes)",
+                self.name, reclaimed.states, reclaimed.bytes);
+        reclaimed
+    }
+    
+    // Get tips, the states of unsaved commits and all their known ancestors,
+    // following parent links of loaded states and the ancestry (`dag`).
+    fn reachable_states(&self) -> HashSet<Sum> {
+        let mut reachable = HashSet::new();
+        let mut next: Vec<Sum> = self.tips.iter().cloned()
+                .chain(self.unsaved.iter().map(|commit| commit.statesum().clone()))
+                .collect();
+        while let Some(sum) = next.pop() {
+            if reachable.contains(&sum) {
+                continue;
+            }
+            if let Some(state) = self.states.get(&sum) {
+                next.extend(state.parents().iter().cloned());
+            }
+            if let Some(parents) = self.dag.parents(&sum) {
+                next.extend(parents.iter().cloned());
+            }
+            reachable.insert(sum);
+        }
+        reachable
+    }
+    
+    /// Number of states evicted by `evict_history` and not yet restored.
+    pub fn evicted_len(&self) -> usize {
+        self.evicted.len()
+    }
+    
+    /// Read evicted states (see `evict_history`) back from disk. Returns the
+    /// number of states restored; this may be less than `evicted_len()` if
+    /// files have since been removed.
+    pub fn restore_history(&mut self) -> Result<usize> {
+        if self.evicted.is_empty() {
+            return Ok(0);
+        }
+        let limits = self.control.read_limits();
+        let mut restored = 0;
+        let mut commits = vec![];
+        for ss in self.ss0..self.ss1 {
+            debug!("Partition {}: restoring states from snapshot {}", self.name, ss);
+            let opt_result = {
+                let evicted = &self.evicted;
+                read_ss_file_unless(&self.control, ss, &limits, &mut |sum| !evicted.contains(sum))
+                        .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?
+            };
+            if let Some((_, Some(state))) = opt_result {
+                if self.evicted.remove(state.statesum()) {
+                    self.insert_state(state);
+                    restored += 1;
+                }
+            }
+            let mut queue = LogQueue::new();
+            for cl in self.control.io().list_ss_cl(ss) {
+                read_cl_file(&self.control, ss, cl, &mut queue, &limits, true)
+                        .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+            }
+            let evicted = &self.evicted;
+            commits.extend(queue.commits.into_iter()
+                    .filter(|commit| evicted.contains(commit.statesum())));
+        }
+        
+        // Apply commits whose parents are available (repeatedly, since one
+        // may be the parent of another)
+        loop {
+            let pos = {
+                let states = &self.states;
+                commits.iter().position(|c| states.contains(c.first_parent()))
+            };
+            let commit = match pos {
+                Some(i) => commits.swap_remove(i),
+                None => break,
+            };
+            if !self.evicted.remove(commit.statesum()) {
+                continue;   // duplicate
+            }
+            let state = PartState::from_state_commit(
+                    self.states.get(commit.first_parent()).unwrap(), &commit)?;
+            self.insert_state(state);
+            restored += 1;
+        }
+        debug!("Partition {}: restored {} states", self.name, restored);
+        Ok(restored)
+    }
+    
+    /// Consume the `Partition` and return the held `RepoIO`.
+    /// 
+    /// This destroys all states held internally, but states may be cloned
+    /// before unwrapping. Since `Element`s are copy-on-write, cloning
+    /// shouldn't be too expensive.
+    pub fn unwrap_control(self) -> C {
+        self.control
+    }
+    
+    /// Get the `Control`
+    pub fn control(&self) -> &C {
+        &self.control
+    }
+    
+    /// Get the `Control` mutably, e.g. to adjust how commit metadata is made.
+    /// The I/O provider must not be replaced or its data altered.
+    pub fn control_mut(&mut self) -> &mut C {
+        &mut self.control
+    }
+}
+
+// Methods opening a partition without a `RepoIO`
+impl<E: Element> Partition<DefaultControl<E, MemRepoIO>> {
+    /// Open a partition from a single snapshot file, read from `reader` (e.g.
+    /// as received over a network or written by `export_state`), without a
+    /// directory layout.
+    /// 
+    /// The snapshot is copied into a `MemRepoIO` and loaded, after which the
+    /// partition is *ready for use*. Changes are only saved in memory; they
+    /// can be retrieved via `control().io()`.
+    pub fn from_snapshot_stream(reader: &mut Read) ->
+            Result<Partition<DefaultControl<E, MemRepoIO>>>
+    {
+        let mut io = MemRepoIO::new();
+        {
+            let mut writer = io.new_ss(0)?.expect("new snapshot in empty MemRepoIO");
+            io::copy(reader, &mut writer)?;
+        }
+        Partition::open(DefaultControl::new(io), true)
+    }
+}
+
+// Methods accessing or modifying a partition's data
+impl<C: Control> Partition<C> {
+    /// Get a reference to the PartState of the current tip. You can read
+    /// this directly or make a clone in order to make your modifications.
+    /// 
+    /// This operation will fail if no data has been loaded yet or if a merge
+    /// is required (i.e. it fails if the number of tips is not exactly one).
+    pub fn tip(&self) -> result::Result<&PartState<C::Element>, TipError> {
+        Ok(self.states.get(self.tip_key()?).unwrap())
+    }
+    
+    /// Get the state-sum (key) of the tip. Fails when `tip()` fails.
+    pub fn tip_key(&self) -> result::Result<&Sum, TipError> {
+        if self.tips.len() == 1 {
+            Ok(self.tips.iter().next().unwrap())
+        } else if self.tips.is_empty() {
+            Err(TipError::NotReady)
+        } else {
+            Err(TipError::MergeRequired)
+        }
+    }
+    
+    /// Get the number of tips.
+    pub fn tips_len(&self) -> usize {
+        self.tips.len()
+    }
+    
+    /// Get an iterator over tips.
+    pub fn tips_iter(&self) -> TipIter {
+        TipIter { iter: self.tips.iter() }
+    }
+    
+    /// Get the set of all tips. Is empty before loading and has more than one
+    /// entry when `merge_required()`. May be useful for merging.
+    pub fn tips(&self) -> &HashSet<Sum> {
+        &self.tips
+    }
+    
+    /// Get the number of states.
+    /// 
+    /// Tips are a subset of states, so `tips_len() <= states_len()`.
+    pub fn states_len(&self) -> usize {
+        self.states.len()
+    }
+    
+    /// Iterate over all states which have been loaded (see `load_...` functions).
+    /// 
+    /// Items are unordered (actually, they follow the order of an internal
+    /// hash map, which is randomised and usually different each time the
+    /// program is loaded).
+    pub fn states_iter(&self) -> StateIter<C::Element> {
+        StateIter { iter: self.states.iter(), tips: &self.tips, provenance: &self.provenance }
+    }
+    
+    /// Get a read-only reference to a state by its statesum, if found.
+    /// States loaded as well as those cached by `fetch_state` are found.
+    /// 
+    /// If you want to keep a copy, clone it.
+    pub fn state(&self, key: &Sum) -> Option<&PartState<C::Element>> {
+        self.states.get(key).or_else(|| self.cache.get(key))
+    }
+    
+    /// Get a shared, read-only copy of the tip, which is not tied to a borrow
+    /// of the partition and remains valid as new commits are pushed (see
+    /// `StateHandle`). This copies the map of elements (though not element
+    /// data); keep and clone the handle rather than calling repeatedly.
+    pub fn tip_handle(&self) -> result::Result<StateHandle<C::Element>, TipError> {
+        Ok(self.tip()?.clone_exact().into())
+    }
+    
+    /// Get a shared, read-only copy of a state by its statesum (see
+    /// `tip_handle` and `state`).
+    pub fn state_handle(&self, key: &Sum) -> Option<StateHandle<C::Element>> {
+        self.state(key).map(|state| state.clone_exact().into())
+    }
+    
+    /// Get a state by its statesum as `state()`, reading it from disk if
+    /// necessary: evicted states (see `evict_history`) are read from the
+    /// snapshots loaded, others from older snapshots. States read are kept in
+    /// a cache limited by `Control::state_cache_bytes()`, without affecting
+    /// tips or the states loaded.
+    /// 
+    /// Returns `None` if the state is not found.
+    pub fn fetch_state(&mut self, key: &Sum) -> Result<Option<&PartState<C::Element>>> {
+        if self.states.contains(key) {
+            return Ok(self.states.get(key));
+        }
+        if !self.cache.touch(key) {
+            let range = if self.evicted.contains(key) {
+                self.ss0..self.ss1
+            } else {
+                0..self.ss0
+            };
+            match self.read_state(key, range)? {
+                Some(mut state) => {
+                    if self.control.verify_on_get() {
+                        state.set_verify_on_get(true);
+                    }
+                    let budget = self.control.state_cache_bytes();
+                    self.cache.insert(state, budget);
+                },
+                None => return Ok(None),
+            }
+        }
+        Ok(self.cache.get(key))
+    }
+    
+    /// Get all elements of the tip state matching a search, ordered by
+    /// identifier (see `PartState::search`). Only loaded data is searched,
+    /// but since each state holds all its elements, loading the latest
+    /// snapshot suffices.
+    pub fn search(&self, matcher: &Matcher<C::Element>) ->
+            result::Result<Vec<(EltId, &Rc<C::Element>)>, TipError>
+    {
+        Ok(self.tip()?.search(matcher))
+    }
+    
+    /// Get a copy of a state with elements filtered through
+    /// `Control::view_element`, which may hide or redact elements. The copy
+    /// has its own state-sum and is intended for reading only: it should not
+    /// be used to make changes to this partition.
+    /// 
+    /// Fails with `MatchError::NoMatch` if the state is not loaded.
+    pub fn view_state(&self, key: &Sum) -> Result<PartState<C::Element>, MatchError> {
+        let state = self.states.get(key).ok_or(MatchError::NoMatch)?;
+        let control = &self.control;
+        Ok(state.filter(|id, elt| control.view_element(id, elt)))
+    }
+    
+    /// Try to find a state given a string reference. The following forms are
+    /// accepted:
+    /// 
+    /// *   `tip`: the current tip (fails if `tip()` would fail)
+    /// *   `#N`: the state with commit number `N` (see `state_by_cnum`)
+    /// *   a state-sum in hexadecimal; like git, we accept partial keys (so
+    ///     long as they uniquely resolve a key)
+    /// 
+    /// Any of the above may be followed by `~N` to select the `N`-th ancestor
+    /// (following first parents; `~` alone is equivalent to `~1`), e.g.
+    /// `tip~3`. Spaces are ignored.
+    /// 
+    /// Partitions do not store named references (tags or branches), so names
+    /// other than `tip` are not recognised.
+    pub fn state_from_string(&self, string: String) -> Result<&PartState<C::Element>, MatchError> {
+        let string = string.replace(" ", "");
+        let (base, n) = match string.find('~') {
+            Some(pos) => {
+                let n = if pos + 1 == string.len() { 1 } else {
+                    string[pos + 1..].parse::<usize>()
+                        .map_err(|_| MatchError::Syntax(string.clone()))?
+                };
+                (&string[..pos], n)
+            },
+            None => (&string[..], 0),
+        };
+        
+        let mut state = if base.eq_ignore_ascii_case("tip") {
+            self.tip()?
+        } else if base.starts_with('#') {
+            let cnum = base[1..].parse::<u32>()
+                .map_err(|_| MatchError::Syntax(string.clone()))?;
+            self.state_by_cnum(cnum)?
+        } else {
+            if base.is_empty() || !base.chars().all(|c| c.is_digit(16)) {
+                return Err(MatchError::Syntax(string.clone()));
+            }
+            self.state_from_partial_sum(&base.to_uppercase())?
+        };
+        
+        for _ in 0..n {
+            let parent = state.parents().first().ok_or(MatchError::NoParent)?;
+            state = self.states.get(parent)
+                .ok_or_else(|| MatchError::NotLoaded(parent.as_string(false)))?;
+        }
+        Ok(state)
+    }
+    
+    // Find a state given an upper-case hexadecimal (possibly partial) sum.
+    fn state_from_partial_sum(&self, string: &str) -> Result<&PartState<C::Element>, MatchError> {
+        let mut matching: Option<&Sum> = None;
+        for state in self.states.iter() {
+            if state.statesum().matches_string(string.as_bytes()) {
+                if let Some(prev) = matching {
+                    return Err(MatchError::MultiMatch(
+                        prev.as_string(false), state.statesum().as_string(false)));
+                } else {
+                    matching = Some(state.statesum());
+                }
+            }
+        }
+        if let Some(m) = matching {
+            Ok(self.states.get(m).unwrap())
+        } else {
+            Err(MatchError::NoMatch)
+        }
+    }
+    
+    /// Find a state by its commit number.
+    /// 
+    /// Commit numbers increase monotonically along each line of history (each
+    /// commit's number is greater than its parents'), but are not unique
+    /// where history branches. This fails with `MatchError::MultiMatch` if
+    /// more than one loaded state has number `n` (use `states_iter()` and
+    /// `StateItem::cnum()` in this case) and `MatchError::NoMatch` if none
+    /// does (possibly because history has not been loaded; see `load_all`).
+    /// States not loaded may be found via `locate_cnum`.
+    pub fn state_by_cnum(&self, n: u32) -> Result<&PartState<C::Element>, MatchError> {
+        let mut matching: Option<&PartState<C::Element>> = None;
+        for state in self.states.iter() {
+            if state.meta().number() == n {
+                if let Some(prev) = matching {
+                    // Sort so that the error is independent of hash-map order:
+                    let (a, b) = if prev.statesum() < state.statesum() {
+                        (prev.statesum(), state.statesum())
+                    } else {
+                        (state.statesum(), prev.statesum())
+                    };
+                    return Err(MatchError::MultiMatch(a.as_string(false), b.as_string(false)));
+                } else {
+                    matching = Some(state);
+                }
+            }
+        }
+        matching.ok_or(MatchError::NoMatch)
+    }
+    
+    /// Merge all latest states into a single tip.
+    /// This is a convenience wrapper around `merge_two(...)`.
+    /// 
+    /// Example:
+    /// 
+    /// ```no_run
+    /// use std::path::Path;
+    /// use pippin::pip::{Partition, DefaultControl, part_from_path,
+    ///         TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W};
+    /// 
+    /// let path = Path::new("./my-partition");
+    /// let io = part_from_path(path).unwrap();
+    /// let control = DefaultControl::<String, _>::new(io);
+    /// let mut partition = Partition::open(control, true)
+    ///         .expect("failed to open partition");
+    /// 
+    /// // Customise with your own solver:
+    /// let ancestor_solver = AncestorSolver2W::new();
+    /// let renaming_solver = RenamingSolver2W::new();
+    /// let solver = TwoWaySolverChain::new(&ancestor_solver, &renaming_solver);
+    /// 
+    /// partition.merge(&solver, true).expect("merge failed");
+    /// ```
+    /// 
+    /// This works through all 'tip' states in an order determined by a
+    /// `HashSet`'s random keying, thus the exact result may not be repeatable
+    /// if the program were run multiple times with the same initial state.
+    /// 
+    /// If `auto_load` is true, additional history will be loaded as necessary
+    /// to find a common ancestor.
+    pub fn merge<S: TwoWaySolver<C::Element> + ?Sized>(&mut self, solver: &S, auto_load: bool) -> Result<()> {
+        let mut start_ss = self.ss0;
+        while self.tips.len() > 1 {
+            if start_ss < self.ss0 {
+                let ss0 = self.ss0;
+                self.load_range(start_ss, ss0)?;
+            }
+            
+            let (tip1, tip2): (Sum, Sum) = {
+                // We sort tips in order to make the operation deterministic.
+                let mut tips: Vec<_> = self.tips.iter().collect();
+                tips.sort();
+                (tips[0].clone(), tips[1].clone())
+            };
+            trace!("Partition {}: attempting merge of tips {} and {}", self.name, &tip1, &tip2);
+            let c = match self.merge_two(&tip1, &tip2) {
+                Ok(merge) => merge.solve_inline(solver)
+                        .make_commit(&ProvenanceMeta::new(self.control.as_mcm_ref())),
+                Err(MergeError::NoCommonAncestor) if auto_load && self.ss0 > 0 => {
+                    // Iteratively load previous history and retry until success or error.
+                    start_ss = self.ss0 - 1;
+                    continue;
+                },
+                Err(MergeError::NoState) if auto_load && !self.evicted.is_empty() => {
+                    // A required state was evicted; restore and retry.
+                    if self.restore_history()? == 0 {
+                        return Err(Box::new(MergeError::NoState));
+                    }
+                    continue;
+                },
+                Err(MergeError::NoState) if auto_load && self.ss0 > 0 => {
+                    // The common ancestor is known (see `load_ancestry`) but
+                    // not loaded; load previous history and retry.
+                    start_ss = self.ss0 - 1;
+                    continue;
+                },
+                Err(e) => return Err(Box::new(e)),
+            };
+            if let Some(commit) = c {
+                trace!("Pushing merge commit: {} ({} changes)",
+                        commit.statesum(), commit.num_changes());
+                self.push_commit(commit)?;
+            } else {
+                return Err(Box::new(MergeError::NotSolved));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Creates a `TwoWayMerge` for two given states (presumably tip states,
+    /// but not required).
+    /// 
+    /// It is recommended to use `merge` instead unless you need control over merge order with more
+    /// than two tips. In order to use this function, you'll need code like:
+    /// 
+    /// ```no_compile
+    /// let commit = partition.merge_two(&tip1, &tip2)?
+    ///         .solve_inline(&solver)
+    ///         .make_commit(&mcm)
+    ///         .expect("merge failed");
+    /// partition.add_commit(commit)?;
+    /// ```
+    /// 
+    /// Note that this function can fail with `MergeError::NoCommonAncestor` if not enough history
+    /// is available. In this case you might try calling `part.load_all()?;` or
+    /// `let ss0 = part.oldest_ss_loaded(); part.load_range(ss0 - 1, ss0);`, then retrying.
+    pub fn merge_two(&self, tip1: &Sum, tip2: &Sum) -> Result<TwoWayMerge<C::Element>, MergeError> {
+        let common = match self.latest_common_ancestor(tip1, tip2) {
+            Ok(sum) => sum,
+            Err(e) => return Err(e),
+        };
+        let s1 = self.state(tip1).ok_or(MergeError::NoState)?;
+        let s2 = self.state(tip2).ok_or(MergeError::NoState)?;
+        let s3 = self.state(&common).ok_or(MergeError::NoState)?;
+        Ok(TwoWayMerge::new(s1, s2, s3))
+    }
+    
+    // #0003: allow getting a reference to other states listing snapshots,
+    // commits, getting non-current states and getting diffs.
+    
+    /// This adds a new commit to the list waiting to be written and updates
+    /// the states and 'tips' stored internally by creating a new state from
+    /// the commit.
+    /// 
+    /// Mutates the commit in the (very unlikely) case that its statesum
+    /// clashes with another commit whose data is different.
+    /// 
+    /// Fails if the commit's parent is not found or the patch cannot be
+    /// applied to it. In this case the commit is lost, but presumably either
+    /// there was a programmatic error or memory corruption for this to occur.
+    /// 
+    /// Fails with `PatchOp::NumberOrder` if the commit's number is not
+    /// greater than that of each of its parents, with `PatchOp::QuotaExceeded`
+    /// if the new state exceeds `Control::quota()`, and with
+    /// `PatchOp::Rejected` if `Control::validate_commit` rejects it.
+    /// 
+    /// Returns `Ok(true)` on success or `Ok(false)` if the commit matches an
+    /// already known state.
+    pub fn push_commit(&mut self, commit: Commit<C::Element>) -> Result<bool, PatchOp> {
+        if self.sealed { return Err(PatchOp::Sealed); }
+        self.check_number(&commit)?;
+        let state = {
+            let parent = self.states.get(commit.first_parent())
+                .ok_or(PatchOp::NoParent)?;
+            PartState::from_state_commit(parent, &commit)?
+        };  // end borrow on self (from parent)
+        self.validate(&commit, &state)?;
+        Ok(self.add_pair(commit, state))
+    }
+    
+    /// Add a new state, assumed to be derived from an existing known state.
+    /// 
+    /// This creates a commit from the given state, converts the `MutPartState`
+    /// to a `PartState` and adds it to the list of internal states, and
+    /// updates the tip. The commit is added to the internal list
+    /// waiting to be written to permanent storage (see `write()`).
+    /// 
+    /// Mutates the commit in the (very unlikely) case that its statesum
+    /// clashes with another commit whose data is different.
+    /// 
+    /// Fails with `PatchOp::QuotaExceeded` if the new state exceeds
+    /// `Control::quota()`, and with `PatchOp::Rejected` if
+    /// `Control::validate_commit` rejects the resulting commit.
+    /// 
+    /// Returns `Ok(true)` on success, or `Ok(false)` if the state matches its
+    /// parent (i.e. hasn't been changed) or another already known state.
+    pub fn push_state(&mut self, state: MutPartState<C::Element>) -> Result<bool, PatchOp> {
+        if self.sealed { return Err(PatchOp::Sealed); }
+        let parent_sum = state.parent().clone();
+        let new_state = PartState::from_mut(state, self.control.as_mcm_ref_mut());
+        
+        // #0019: Commit::from_diff compares old and new states and code be slow.
+        // #0019: Instead, we could record each alteration as it happens.
+        Ok(if let Some(commit) =
+                Commit::from_diff(
+                    self.states.get(&parent_sum).ok_or(PatchOp::NoParent)?,
+                    &new_state)
+            {
+                self.validate(&commit, &new_state)?;
+                self.add_pair(commit, new_state)
+            } else {
+                false
+            }
+        )
+    }
+    
+    /// As `push_state`, but only if the state's parent is still the tip
+    /// (compare-and-swap). Fails with `PatchOp::TipChanged` if another state
+    /// has been pushed or loaded since the state was cloned from the tip (or
+    /// if there is not a single tip), instead of creating a second tip which
+    /// would require a merge. The caller may then retry from the new tip.
+    pub fn push_state_if_tip(&mut self, state: MutPartState<C::Element>) ->
+            Result<bool, PatchOp>
+    {
+        if self.tip_key().ok() != Some(state.parent()) {
+            return Err(PatchOp::TipChanged);
+        }
+        self.push_state(state)
+    }
+    
+    /// Import all elements of the tip of `other` into a single new commit on
+    /// the tip of this partition: the inverse of `split`, e.g. to consolidate
+    /// small partitions. Elements keep their identifiers where free;
+    /// colliding identifiers are re-mapped to a free identifier nearby.
+    /// `other` is not modified.
+    /// 
+    /// Returns a report of each element imported, ordered by old identifier,
+    /// such that references to moved elements can be updated (see
+    /// `Remap::id_changed` and `KeyIndex::apply`). Requires a single tip in
+    /// each partition; fails if the new state cannot be pushed (see
+    /// `push_state`).
+    pub fn absorb<C2>(&mut self, other: &Partition<C2>) -> Result<Vec<Remap>>
+            where C2: Control<Element = C::Element>
+    {
+        let mut state = self.tip()?.clone_mut();
+        let mut remaps = vec![];
+        for (id, elt) in other.tip()?.elts_ordered() {
+            let new_id = state.free_id_near(id)?;
+            state.insert_rc(new_id, elt.clone())?;
+            remaps.push(Remap {
+                key: elt.external_key().map(|key| key.to_vec()),
+                from_part: other.name.clone(),
+                from: id,
+                to_part: self.name.clone(),
+                to: new_id,
+            });
+        }
+        info!("Partition {}: absorbing partition {} ({} identifiers re-mapped)", self.name,
+                other.name, remaps.iter().filter(|r| r.id_changed()).count());
+        self.push_state(state)?;
+        Ok(remaps)
+    }
+    
+    /// Start a transaction: a set of insertions, replacements and deletions
+    /// applied to the tip and committed as a single commit, or rejected as a
+    /// whole if any operation fails.
+    /// 
+    /// Example:
+    /// 
+    /// ```
+    /// use pippin::pip::{Partition, DefaultControl, DummyRepoIO, EltId};
+    /// 
+    /// let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+    /// let mut part = Partition::create(control, "example").unwrap();
+    /// let (one, two) = (EltId::from(1), EltId::from(2));
+    /// {
+    ///     let mut t = part.transaction().unwrap();
+    ///     t.insert(one, "one".to_string()).insert(two, "two".to_string());
+    ///     assert_eq!(t.commit(), Ok(true));
+    /// }
+    /// 
+    /// // Element 3 does not exist, so nothing is changed:
+    /// let mut t = part.transaction().unwrap();
+    /// t.replace(one, "uno".to_string()).remove(EltId::from(3));
+    /// assert!(t.commit().is_err());
+    /// ```
+    pub fn transaction(&mut self) -> Result<Transaction<C>, TipError> {
+        let mut state = self.tip()?.clone_mut();
+        state.set_quota(self.control.quota());
+        Ok(Transaction { part: self, state: state, conflicts: vec![] })
+    }
+    
+    /// The number of commits waiting to be written to permanent storage by
+    /// `write_fast()`, `write_full()` or `flush_n(...)`.
+    pub fn unsaved_len(&self) -> usize {
+        self.unsaved.len()
+    }
+    
+    /// Iterate over commits waiting to be written, oldest first (the order
+    /// in which they will be written).
+    pub fn unsaved_iter(&self) -> UnsavedIter<C::Element> {
+        UnsavedIter { iter: self.unsaved.iter() }
+    }
+    
+    /// Require that a snapshot be written the next time `write_full` is called.
+    /// (This property is not persisted across save/load.)
+    pub fn require_snapshot(&mut self) {
+        self.control.snapshot_policy().force_snapshot()
+    }
+    
+    /// This will write all unsaved commits to a log on the disk. Does nothing
+    /// if there are no queued changes. Commits are split over multiple logs
+    /// if required by `Control::log_limits()`.
+    /// 
+    /// Also see `write_full()`.
+    /// 
+    /// Returns true if any commits were written (i.e. unsaved commits
+    /// were found). Returns false if nothing needed doing.
+    /// 
+    /// Note that writing to disk can fail. In this case it may be worth trying
+    /// again: commits durably written before the failure are removed from
+    /// the unsaved queue and the rest are written to a new log on retry.
+    pub fn write_fast(&mut self) -> Result<bool> {
+        Ok(self.flush_n(usize::MAX)? > 0)
+    }
+    
+    /// Write at most `n` unsaved commits (the oldest) to the disk, as
+    /// `write_fast()` does for all; the rest remain queued. This allows
+    /// writing part of a large backlog at a time, e.g. under I/O pressure.
+    /// 
+    /// Returns the number of commits written (zero if `n` is zero or there
+    /// are no unsaved commits). On failure, commits durably written are
+    /// still removed from the queue (see `write_fast()`).
+    pub fn flush_n(&mut self, n: usize) -> Result<usize> {
+        self.write_initial()?;
+        let n = min(n, self.unsaved.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        self.check_unsealed()?;
+        
+        let header = self.make_header(FileType::commit_log())?;
+        
+        self.control.pre_write(n)?;
+        let mut written = Vec::with_capacity(n);
+        let result = self.write_unsaved(&header, n, &mut written);
+        if result.is_ok() {
+            self.control.post_write();
+        } else {
+            self.control.abort_write();
+        }
+        
+        // Commits durably written before a failure are still reported
+        for commit in &written {
+            self.control.on_commit(commit);
+        }
+        result.map(|_| written.len())
+    }
+    
+    /// This will write all unsaved commits to a log on the disk, then write a
+    /// snapshot if needed.
+    /// 
+    /// Returns true if any commits were written (i.e. unsaved commits
+    /// were found). Returns false if no unsaved commits were present. This
+    /// value implies nothing about whether a snapshot was made.
+    /// 
+    /// Note that writing to disk can fail. In this case it may be worth trying
+    /// again.
+    pub fn write_full(&mut self) -> Result<bool> {
+        let has_changes = self.write_fast()?;
+        
+        // Second step: maintenance operations
+        if self.is_ready() && self.control.snapshot_policy().want_snapshot() {
+            self.write_snapshot()?;
+        }
+        
+        Ok(has_changes)
+    }
+    
+    /// Write a new snapshot from the tip.
+    /// 
+    /// Normally you can just call `write_full()` and let the library figure out
+    /// when to write a new snapshot, though you can also call this directly.
+    /// 
+    /// Does nothing when `tip()` fails (returning `Ok(())`).
+    /// 
+    /// If another writer sharing the `RepoIO` takes the snapshot number (see
+    /// `RepoIO::reserve_ss`), its snapshot is added to the history, possibly
+    /// requiring a merge, and a higher number is used.
+    pub fn write_snapshot(&mut self) -> Result<()> {
+        // fail early if not ready:
+        let tip_key = self.tip_key()?.clone();
+        self.write_snapshot_impl(tip_key, false, false)
+    }
+    
+    /// Write a new snapshot of some state other than the tip, e.g. to
+    /// checkpoint a historical state before pruning the logs which produce
+    /// it. The state is read from disk if not loaded (see `fetch_state`).
+    /// 
+    /// The snapshot's header records that it is not the latest state (see
+    /// `FileHeader::historical`), thus loading the latest state starts from
+    /// an older snapshot; commit logs continue to be written after this
+    /// snapshot. If `key` is the (only) tip, this is equivalent to
+    /// `write_snapshot()`.
+    /// 
+    /// Fails if the state is not found.
+    pub fn write_snapshot_of(&mut self, key: &Sum) -> Result<()> {
+        if self.tip_key().ok() == Some(key) {
+            return self.write_snapshot();
+        }
+        if self.fetch_state(key)?.is_none() {
+            return OtherError::err("state to snapshot not found");
+        }
+        self.write_snapshot_impl(key.clone(), true, false)
+    }
+    
+    /// Seal the partition, e.g. to archive a completed data-set such as a
+    /// closed accounting period. Unsaved commits are written, then a final
+    /// snapshot of the tip, whose header marks the partition sealed (see
+    /// `FileHeader::sealed`).
+    /// 
+    /// Afterwards, and whenever the partition is opened again,
+    /// `push_commit` and `push_state` (thus also transactions and merges)
+    /// fail with `PatchOp::Sealed`, and writing commits or snapshots fails
+    /// with `ReadOnly`. Reading and administrative operations such as
+    /// pruning are not affected.
+    /// 
+    /// Fails if there is not a single tip (merge first). Does nothing if
+    /// already sealed.
+    pub fn seal(&mut self) -> Result<()> {
+        if self.sealed {
+            return Ok(());
+        }
+        let tip_key = self.tip_key()?.clone();
+        self.write_fast()?;
+        self.write_snapshot_impl(tip_key, false, true)?;
+        self.sealed = true;
+        info!("Partition {}: sealed", self.name);
+        Ok(())
+    }
+    
+    // Write a snapshot of a loaded or cached state
+    fn write_snapshot_impl(&mut self, key: Sum, historical: bool, sealed: bool) -> Result<()> {
+        self.check_unsealed()?;
+        self.write_initial()?;
+        let mut header = self.make_header(FileType::snapshot())?;
+        header.historical = historical;
+        header.sealed = sealed;
+        let codec = file_codec(&self.control, &header)?;
+        
+        let mut ss_num = self.ss1;
+        loop {
+            // Snapshots skipped by the reservation are from other writers:
+            let reserved = self.control.io_mut().reserve_ss(ss_num)?;
+            for n in ss_num..reserved {
+                self.link_ss(n)?;
+            }
+            ss_num = reserved;
+            
+            // Try to get a writer for this snapshot number:
+            let written = if let Some(mut writer) = self.control.io_mut().new_ss(ss_num)? {
+                debug!("Partition {}: writing snapshot {}: {}{}",
+                    self.name, ss_num, key, if historical { " (historical)" } else { "" });
+                
+                let cache = &self.cache;
+                let state = self.states.get(&key).or_else(|| cache.get(&key)).unwrap();
+                write_head(&header, &mut writer)?;
+                write_snapshot_with(state, &mut writer,
+                        header.ftype.ver(), &*codec, header.align())?;
+                writer.flush()?;
+                true
+            } else {
+                false
+            };
+            if !written {
+                // Taken by another writer: link its snapshot into our history
+                // and try the next number. Each number skipped must hold a
+                // snapshot (thus be less than `ss_len()`), so this terminates.
+                if !self.control.io().has_ss(ss_num) || !self.link_ss(ss_num)? {
+                    return OtherError::err(format!("unable to create snapshot: number {} \
+                            taken but no snapshot found", ss_num));
+                }
+                ss_num += 1;
+                continue;
+            }
+            
+            // After borrow on self.control expires:
+            self.ss1 = ss_num + 1;
+            if !historical {
+                self.control.snapshot_policy().reset();
+            }
+            self.control.on_snapshot(ss_num);
+            return Ok(())
+        }
+    }
+    
+    // Read snapshot `ss_num`, written by another writer, and add its state to
+    // the history (as a tip unless known). Returns false if not found.
+    fn link_ss(&mut self, ss_num: usize) -> Result<bool> {
+        let limits = self.control.read_limits();
+        let (header, state) = match read_ss_file(&self.control, ss_num, &limits)
+                .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss_num), e))? {
+            Some(result) => result,
+            None => return Ok(false),
+        };
+        let (historical, sealed) = (header.historical, header.sealed);
+        self.verify_header(header)
+                .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss_num), e))?;
+        info!("Partition {}: snapshot {} written by another writer: {}",
+                self.name, ss_num, state.statesum());
+        if sealed {
+            // Sealed by the other writer; refuse to write:
+            self.sealed = true;
+            self.check_unsealed()?;
+        }
+        let statesum = state.statesum().clone();
+        if !historical && !self.ancestors.contains(&statesum) &&
+                !self.states.contains(&statesum)
+        {
+            self.tips.insert(statesum.clone());
+            for parent in state.parents() {
+                if !self.states.contains(parent) {
+                    self.ancestors.insert(parent.clone());
+                }
+            }
+            self.evicted.remove(&statesum);
+            self.dag.insert(statesum.clone(), state.parents().to_vec(), state.meta());
+            self.provenance.insert(statesum.clone(),
+                    Provenance { file: FileId::Snapshot(ss_num), offset: 0 });
+            self.insert_state(state);
+        }
+        self.control.on_ss_collision(ss_num, &statesum);
+        Ok(true)
+    }
+    
+    // Write the initial state as snapshot zero, if not yet written (see
+    // `create_in_memory`)
+    fn write_initial(&mut self) -> Result<()> {
+        if self.initial.is_none() {
+            return Ok(());
+        }
+        debug!("Partition {}: writing snapshot 0", self.name);
+        let header = self.make_header(FileType::snapshot())?;
+        let codec = file_codec(&self.control, &header)?;
+        if let Some(mut writer) = self.control.io_mut().new_ss(0)? {
+            let state = self.initial.as_ref().unwrap();
+            write_head(&header, &mut writer)?;
+            write_snapshot_with(state, &mut writer, header.ftype.ver(), &*codec, header.align())?;
+            writer.flush()?;
+        } else {
+            return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
+        }
+        self.initial = None;
+        self.control.on_snapshot(0);
+        Ok(())
+    }
+}
+
+/// Options for `Partition::load_with`.
+/// 
+/// Can be constructed with `Default`, which sets no options.
+pub struct LoadOptions<'a, E: Element + 'a> {
+    /// If set, tips are merged after loading using this solver.
+    pub auto_merge: Option<&'a TwoWaySolver<E>>,
+    /// If true, commits whose parent state is not found are stashed instead
+    /// of causing loading to fail. Stashed commits are applied once their
+    /// parents are loaded (e.g. by loading older snapshots).
+    pub stash_orphans: bool,
+}
+impl<'a, E: Element + 'a> Default for LoadOptions<'a, E> {
+    fn default() -> Self {
+        LoadOptions { auto_merge: None, stash_orphans: false }
+    }
+}
+
+/// Outcome of loading: commits read from logs, by commit statesum, and
+/// warnings (see `Partition::load_report`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Commits applied, in order
+    pub applied: Vec<Sum>,
+    /// Commits skipped since their state is already known (e.g. since the
+    /// commit was written to more than one log)
+    pub duplicates: Vec<Sum>,
+    /// Commits stashed since their parent state is not known
+    pub orphaned: Vec<Sum>,
+    /// States matched against log trailers (written with `CRC_VERSION`)
+    pub verified: Vec<Sum>,
+    /// Non-fatal problems found, in order. Duplicate and orphaned commits
+    /// are listed above, not here.
+    pub warnings: Vec<LoadWarning>,
+}
+
+/// Metadata of a partition's history, without element data (see
+/// `Partition::load_meta`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryMeta {
+    /// Snapshots read (snapshot number and metadata), in order
+    pub snapshots: Vec<(usize, SnapshotInfo)>,
+    /// Commits read from logs, in order of snapshot number, log number and
+    /// position in the log. Commits written to more than one log are
+    /// repeated.
+    pub commits: Vec<CommitInfo>,
+    /// The log and position of each commit of `commits`, in the same order
+    pub commit_sources: Vec<Provenance>,
+    /// Ancestry of all states found
+    pub dag: StateDag,
+}
+
+/// Criteria on commit metadata for `Partition::find_commits`. A commit
+/// matches if it meets all criteria set.
+/// 
+/// Can be constructed with `Default`, which sets no criteria (matching all
+/// commits).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitFilter {
+    /// If set, match only commits with a timestamp in this range (see
+    /// `CommitMeta::timestamp`)
+    pub timestamp: Option<Range<i64>>,
+    /// If set, match only commits with a number in this range (see
+    /// `CommitMeta::number`)
+    pub number: Option<Range<u32>>,
+    /// If set, match only commits whose extra metadata is text containing
+    /// this string (see `CommitMeta::extra`)
+    pub extra_contains: Option<String>,
+    /// If set, match only commits with a number of element changes in this
+    /// range
+    pub num_changes: Option<Range<usize>>,
+}
+impl CommitFilter {
+    /// True if a commit with this metadata and number of changes matches
+    pub fn matches(&self, meta: &CommitMeta, num_changes: usize) -> bool {
+        if let Some(ref range) = self.timestamp {
+            if meta.timestamp() < range.start || meta.timestamp() >= range.end {
+                return false;
+            }
+        }
+        if let Some(ref range) = self.number {
+            if meta.number() < range.start || meta.number() >= range.end {
+                return false;
+            }
+        }
+        if let Some(ref pattern) = self.extra_contains {
+            match *meta.extra() {
+                UserMeta::Text(ref text) if text.contains(&pattern[..]) => {},
+                _ => return false,
+            }
+        }
+        if let Some(ref range) = self.num_changes {
+            if num_changes < range.start || num_changes >= range.end {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A condition found when loading which did not cause failure (see
+/// `LoadReport`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadWarning {
+    /// A snapshot was not found (and is not recorded as pruned; see
+    /// `Partition::record_pruned`); an older snapshot was used instead
+    MissingSnapshot(usize),
+    /// A commit log was not found (snapshot number, log number), though
+    /// logs with greater numbers exist
+    MissingLog(usize, usize),
+    /// The state named by a log trailer was not reached, since some commits
+    /// were orphaned
+    TrailerNotReached(Sum),
+    /// A commit log ends part-way through a commit (snapshot number, log
+    /// number), e.g. due to a failed write; commits before this were read
+    TruncatedLog(usize, usize),
+}
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            LoadWarning::MissingSnapshot(ss) => write!(f, "missing snapshot {}", ss),
+            LoadWarning::MissingLog(ss, cl) => write!(f, "missing commit log {}-{}", ss, cl),
+            LoadWarning::TrailerNotReached(ref sum) =>
+                write!(f, "state {} from log trailer not reached", sum),
+            LoadWarning::TruncatedLog(ss, cl) => write!(f, "commit log {}-{} is truncated", ss, cl),
+        }
+    }
+}
+
+/// Which states `Partition::evict_history` keeps in memory (besides tips and
+/// the states of unsaved commits, which are always kept).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeepCriteria {
+    /// Keep only tips
+    Tips,
+    /// Keep tips and their ancestors up to this many generations back
+    /// (following all parents)
+    Depth(usize),
+}
+
+/// Report of states dropped by `Partition::drop_unreachable`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reclaimed {
+    /// Number of states dropped
+    pub states: usize,
+    /// Total data length of dropped states which were loaded, in bytes (see
+    /// `PartState::byte_len`)
+    pub bytes: usize,
+}
+
+/// Options for `Partition::clone_into`.
+/// 
+/// Can be constructed with `Default`, which copies full history without
+/// renumbering.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CloneOptions {
+    /// If true, only the tip is copied (a "shallow clone"), written as
+    /// snapshot zero. If false, all snapshots and commit logs are copied.
+    pub tip_only: bool,
+    /// If true when copying full history, snapshots are renumbered from zero,
+    /// skipping numbers for which there is neither a snapshot nor any commit
+    /// log. Commit logs are likewise renumbered from zero. Has no effect when
+    /// `tip_only` is set.
+    pub renumber: bool,
+}
+
+// Methods exporting data
+impl<C: Control> Partition<C> {
+    /// Write any loaded state (not only the tip) as a standalone snapshot
+    /// file, including a header, to `writer`.
+    /// 
+    /// The output can be archived or used to start a new partition (e.g. by
+    /// saving it as snapshot zero); it does not include any history, though
+    /// the snapshot records the sums of the state's parents.
+    /// 
+    /// Fails with `MatchError::NoMatch` if the state is not loaded.
+    pub fn export_state(&mut self, key: &Sum, writer: &mut Write) -> Result<()> {
+        if !self.states.contains(key) {
+            return Err(Box::new(MatchError::NoMatch));
+        }
+        let header = self.make_header(FileType::snapshot())?;
+        let codec = file_codec(&self.control, &header)?;
+        debug!("Partition {}: exporting state {}", self.name, key);
+        write_head(&header, writer)?;
+        write_snapshot_with(self.states.get(key).unwrap(), writer, header.ftype.ver(), &*codec,
+                header.align())?;
+        Ok(())
+    }
+    
+    /// Write a loaded state as a standalone snapshot, as `export_state`, but
+    /// with elements filtered through `Control::view_element` (see
+    /// `view_state`).
+    /// 
+    /// Fails with `MatchError::NoMatch` if the state is not loaded.
+    pub fn export_view(&mut self, key: &Sum, writer: &mut Write) -> Result<()> {
+        let state = self.view_state(key)?;
+        let header = self.make_header(FileType::snapshot())?;
+        let codec = file_codec(&self.control, &header)?;
+        debug!("Partition {}: exporting view of state {}", self.name, key);
+        write_head(&header, writer)?;
+        write_snapshot_with(&state, writer, header.ftype.ver(), &*codec, header.align())?;
+        Ok(())
+    }
+    
+    /// Split the tip into two new partitions: elements for which `pred`
+    /// returns true are written to a partition named `name_a` using
+    /// `control_a`, the others to one named `name_b` using `control_b`. Each
+    /// new partition starts from a single snapshot (as with
+    /// `CloneOptions::tip_only`) whose state records the tip's parents and
+    /// metadata; element identifiers are kept (index the new partitions to
+    /// locate elements by external key; see `KeyIndex::index_state`). This
+    /// partition is not modified; remove moved elements or `seal` it as
+    /// required.
+    /// 
+    /// Requires a single tip (see `tip()`). Fails if either new control's
+    /// `RepoIO` already contains data.
+    pub fn split<F, C2>(&self, pred: F, control_a: C2, name_a: &str, control_b: C2,
+            name_b: &str) -> Result<(Partition<C2>, Partition<C2>)>
+            where F: Fn(EltId, &C::Element) -> bool, C2: Control<Element = C::Element>
+    {
+        validate_repo_name(name_a)?;
+        validate_repo_name(name_b)?;
+        if control_a.io().ss_len() > 0 || control_b.io().ss_len() > 0 {
+            return make_io_err(ErrorKind::AlreadyExists, "target of split is not empty");
+        }
+        let tip = self.tip()?;
+        info!("Partition {}: splitting tip {} into {} and {}", self.name, tip.statesum(),
+                name_a, name_b);
+        let in_a: HashSet<EltId> = tip.elts_iter()
+                .filter(|&(id, elt)| pred(id, elt))
+                .map(|(id, _)| id)
+                .collect();
+        let state_a = tip.filter(|id, elt|
+                if in_a.contains(&id) { Some(elt.clone()) } else { None });
+        let state_b = tip.filter(|id, elt|
+                if in_a.contains(&id) { None } else { Some(elt.clone()) });
+        let a = Partition::create_from_state(control_a, name_a.to_string(), state_a)?;
+        let b = Partition::create_from_state(control_b, name_b.to_string(), state_b)?;
+        Ok((a, b))
+    }
+    
+    // Create a partition whose only state is `state`, written as snapshot
+    // zero (see `clone_into` and `split`).
+    fn create_from_state(control: C, name: String, state: PartState<C::Element>) ->
+            Result<Partition<C>>
+    {
+        let mut part = Partition {
+            control: control,
+            name: name,
+            ss0: 0,
+            ss1: 1,
+            states: HashIndexed::new(),
+            ancestors: HashSet::new(),
+            evicted: HashSet::new(),
+            dag: StateDag::new(),
+            cache: StateCache::new(),
+            tips: HashSet::new(),
+            unsaved: VecDeque::new(),
+            orphans: vec![],
+            report: LoadReport::default(),
+            sealed: false,
+            initial: None,
+            forks: HashMap::new(),
+            locks: LockRegistry::new(),
+            provenance: HashMap::new(),
+        };
+        let header = part.make_header(FileType::snapshot())?;
+        let codec = file_codec(&part.control, &header)?;
+        if let Some(mut writer) = part.control.io_mut().new_ss(0)? {
+            write_head(&header, &mut writer)?;
+            write_snapshot_with(&state, &mut writer, header.ftype.ver(), &*codec,
+                    header.align())?;
+        } else {
+            return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
+        }
+        part.tips.insert(state.statesum().clone());
+        for parent in state.parents() {
+            part.ancestors.insert(parent.clone());
+        }
+        part.dag.insert(state.statesum().clone(), state.parents().to_vec(), state.meta());
+        part.insert_state(state);
+        part.control.snapshot_policy().reset();
+        Ok(part)
+    }
+    
+    /// Copy this partition into the (empty) I/O provider of a new `control`,
+    /// returning a partition using the copy. This may be used to back up or
+    /// duplicate a partition, or to start a new one from its latest state.
+    /// 
+    /// If `options.tip_only` is set, the tip is written as the only snapshot
+    /// of the copy (this requires a single tip; see `tip()`). Otherwise
+    /// all snapshots and commit logs available from this partition's
+    /// `RepoIO` are copied byte-for-byte (thus preserving header user data),
+    /// whether or not they are loaded, and any unsaved commits are written to
+    /// a new log in the copy (they remain unsaved in `self`). In this case the
+    /// returned partition is opened with `Partition::open(control, true)`.
+    /// 
+    /// Fails if the new control's `RepoIO` already contains data.
+    pub fn clone_into<C2>(&self, mut control: C2, options: &CloneOptions) ->
+            Result<Partition<C2>>
+            where C2: Control<Element = C::Element>
+    {
+        if control.io().ss_len() > 0 {
+            return make_io_err(ErrorKind::AlreadyExists, "target of clone is not empty");
+        }
+        
+        if options.tip_only {
+            let tip = self.tip()?.clone_exact();
+            info!("Partition {}: cloning tip {}", self.name, tip.statesum());
+            return Partition::create_from_state(control, self.name.clone(), tip);
+        }
+        
+        if self.initial.is_some() {
+            return OtherError::err("partition not yet written (clone with tip_only)");
+        }
+        info!("Partition {}: cloning full history", self.name);
+        let src = self.control.io();
+        let mut last_ss = None;
+        let mut next_ss = 0;
+        for ss in src.list_ss() {
+            let ss_num = if options.renumber { next_ss } else { ss };
+            let mut copied = false;
+            if let Some(mut reader) = src.read_ss(ss)? {
+                if let Some(mut writer) = control.io_mut().new_ss(ss_num)? {
+                    io::copy(&mut reader, &mut writer)?;
+                } else {
+                    return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
+                }
+                copied = true;
+            }
+            let mut next_cl = 0;
+            for cl in src.list_ss_cl(ss) {
+                let cl_num = if options.renumber { next_cl } else { cl };
+                if let Some(mut reader) = src.read_ss_cl(ss, cl)? {
+                    if let Some(mut writer) = control.io_mut().new_ss_cl(ss_num, cl_num)? {
+                        io::copy(&mut reader, &mut writer)?;
+                    } else {
+                        return make_io_err(ErrorKind::AlreadyExists, "commit log already exists");
+                    }
+                    // The log is copied unchanged, thus its index remains valid
+                    if let Some(mut reader) = src.read_ss_cl_index(ss, cl)? {
+                        let dest = control.io_mut();
+                        if let Some(mut writer) = dest.write_ss_cl_index(ss_num, cl_num)? {
+                            io::copy(&mut reader, &mut writer)?;
+                        }
+                    }
+                    copied = true;
+                    next_cl += 1;
+                }
+            }
+            if copied {
+                last_ss = Some(ss_num);
+                next_ss += 1;
+            }
+        }
+        
+        if !self.unsaved.is_empty() {
+            let ss_num = match last_ss {
+                Some(n) => n,
+                None => return OtherError::err("no snapshot found to clone"),
+            };
+            let ftype = FileType::commit_log();
+            let ftype = if control.write_crc() { ftype.crc() } else { ftype };
+            let mut header = FileHeader {
+                codec: codec_name(&control, &ftype),
+                align: Some(control.element_align(&ftype)),
+                ftype: ftype,
+                name: self.name.clone(),
+                user: vec![],
+                historical: false,
+                sealed: false,
+            };
+            header.user = control.make_user_data(&header)?;
+            let codec = file_codec(&control, &header)?;
+            let cl_num = control.io().ss_cl_len(ss_num);
+            debug!("Partition {}: writing {} unsaved commits to clone log {}-{}",
+                    self.name, self.unsaved.len(), ss_num, cl_num);
+            if let Some(mut writer) = control.io_mut().new_ss_cl(ss_num, cl_num)? {
+                write_head(&header, &mut writer)?;
+                start_log(&mut writer)?;
+                for commit in &self.unsaved {
+                    write_commit_with(commit, &mut writer, header.ftype.ver(), &*codec,
+                            header.align())?;
+                }
+                if header.ftype.ver() == CRC_VERSION {
+                    let last = self.unsaved.back().expect("unsaved commit");
+                    if let Some(state) = self.states.get(last.statesum()) {
+                        write_trailer(state, &mut writer)?;
+                    }
+                }
+            } else {
+                return make_io_err(ErrorKind::AlreadyExists, "commit log already exists");
+            }
+        }
+        
+        Partition::open(control, true)
+    }
+}
+
+// Methods tracking changes
+impl<C: Control> Partition<C> {
+    /// Get all loaded commits not in the history of the state `key` (i.e.
+    /// commits creating states which are neither `key` nor an ancestor of
+    /// `key`). Equivalent to `changes_since_cursor(&ChangeCursor::new(key))`.
+    pub fn changes_since(&self, key: &Sum) -> Result<Vec<Commit<C::Element>>, MatchError> {
+        self.changes_since_cursor(&ChangeCursor::new(key.clone()))
+    }
+    
+    /// Get all loaded commits not yet consumed by `cursor`.
+    /// 
+    /// Commits are recreated from loaded states, ordered by commit number
+    /// (thus each comes after its parents). Commits are included only where
+    /// the parent is loaded (so not for the initial state, nor for states read
+    /// from the oldest snapshot loaded). Since concurrent changes may be merged
+    /// from other commit logs, this may include commits older than those
+    /// already consumed.
+    /// 
+    /// Fails with `MatchError::NotLoaded` if a state recorded by the cursor is
+    /// not loaded.
+    pub fn changes_since_cursor(&self, cursor: &ChangeCursor) ->
+            Result<Vec<Commit<C::Element>>, MatchError>
+    {
+        let mut seen = HashSet::new();
+        let mut queue: Vec<&Sum> = Vec::new();
+        for key in &cursor.heads {
+            if !self.states.contains(key) {
+                return Err(MatchError::NotLoaded(key.as_string(false)));
+            }
+            queue.push(key);
+        }
+        while let Some(key) = queue.pop() {
+            if seen.insert(key) {
+                if let Some(state) = self.states.get(key) {
+                    queue.extend(state.parents());
+                }
+            }
+        }
+        
+        let mut states: Vec<&PartState<C::Element>> = self.states.iter().filter(|state|
+            !seen.contains(state.statesum()) &&
+                state.parents().first().map_or(false, |parent| self.states.contains(parent))
+        ).collect();
+        states.sort_by(|a, b| (a.meta().number(), a.statesum())
+                .cmp(&(b.meta().number(), b.statesum())));
+        Ok(states.into_iter().map(|state| {
+            let parent = self.states.get(&state.parents()[0]).unwrap();
+            Commit::from_state(parent, state)
+        }).collect())
+    }
+}
+
+// Internal support functions
+impl<C: Control> Partition<C> {
+    // Write the first `n` unsaved commits to new logs (more than one if
+    // required by `Control::log_limits()`), moving those written to
+    // `all_written` (initially empty).
+    // 
+    // Commits are only removed from `unsaved` once durably written: on
+    // failure, the log is re-read to find which were, so that a retry
+    // neither duplicates nor drops commits.
+    fn write_unsaved(&mut self, header: &FileHeader, n: usize,
+            all_written: &mut Vec<Commit<C::Element>>) -> Result<()>
+    {
+        // #0012: extend existing logs instead of always writing a new log file.
+        let mut cl_num = self.control.io().ss_cl_len(self.ss1 - 1);
+        let codec = file_codec(&self.control, header)?;
+        let limits = self.control.log_limits();
+        let mut buf = Vec::new();
+        while all_written.len() < n {
+            let remaining = n - all_written.len();
+            let mut index = Vec::new();
+            let result = if let Some(writer) = self.control.io_mut().new_ss_cl(self.ss1 - 1, cl_num)? {
+                debug!("Partition {}: writing up to {} commits to log {}-{}",
+                        self.name, remaining, self.ss1-1, cl_num);
+                let mut writer = ByteCounter::new(writer);
+                let commits = self.unsaved.iter().take(remaining);
+                let result = write_log(&mut writer, header, &*codec, &limits, commits,
+                        &self.states, &mut buf, &mut index);
+                Some(result.map(|num_written| (num_written, writer.bytes)))
+            } else {
+                None
+            };
+            
+            // After borrow on self.control expires:
+            match result {
+                Some(Ok((num_written, bytes))) => {
+                    self.control.snapshot_policy().count_bytes(bytes);
+                    all_written.extend(self.unsaved.drain(..num_written));
+                    self.write_log_index(cl_num, &index);
+                },
+                Some(Err(e)) => {
+                    let durable = self.count_durable(cl_num, remaining);
+                    warn!("Partition {}: write to log {}-{} failed after {} commits: {}",
+                            self.name, self.ss1-1, cl_num, durable, e);
+                    all_written.extend(self.unsaved.drain(..durable));
+                    return Err(e);
+                },
+                None => if cl_num >= self.control.io().ss_cl_len(self.ss1 - 1) {
+                    // The log exists, so must be counted by `ss_cl_len()`;
+                    // skipping only such logs, this terminates.
+                    return OtherError::err(format!("unable to create commit log: number {} \
+                            taken but not counted", cl_num));
+                },
+            }
+            // Try another number: either the log exists or we wrote it.
+            cl_num += 1;
+        }
+        Ok(())
+    }
+    
+    // After a failed write to log `cl_num` (of the latest snapshot), re-read
+    // it to count how many of the first `max` unsaved commits it holds
+    // intact. A truncated final commit (e.g. on disk full) is not counted.
+    fn count_durable(&self, cl_num: usize, max: usize) -> usize {
+        let mut queue = LogQueue::new();
+        let _ = read_cl_file(&self.control, self.ss1 - 1, cl_num, &mut queue,
+                &self.control.read_limits(), true);
+        queue.commits.iter().zip(self.unsaved.iter().take(max))
+                .take_while(|&(read, unsaved)| read.statesum() == unsaved.statesum())
+                .count()
+    }
+    
+    // Read state `key` from snapshots in `range` and their logs, newest
+    // first. Loaded and cached states may be the parents of commits read.
+    fn read_state(&self, key: &Sum, range: Range<usize>) -> Result<Option<PartState<C::Element>>> {
+        let limits = self.control.read_limits();
+        for ss in range.rev() {
+            debug!("Partition {}: searching snapshot {} for state {}", self.name, ss, key);
+            let mut known = HashMap::new();
+            let opt_result = read_ss_file(&self.control, ss, &limits)
+                    .map_err(|e| file_error(Some(&self.name), FileId::Snapshot(ss), e))?;
+            if let Some((_, state)) = opt_result {
+                if state.statesum() == key {
+                    return Ok(Some(state));
+                }
+                known.insert(state.statesum().clone(), state);
+            }
+            let cls = self.control.io().list_ss_cl(ss);
+            if self.indexes_exclude(ss, &cls, key) {
+                continue;   // no need to replay these logs
+            }
+            let mut queue = LogQueue::new();
+            for cl in cls {
+                read_cl_file(&self.control, ss, cl, &mut queue, &limits, true)
+                        .map_err(|e| file_error(Some(&self.name), FileId::CommitLog(ss, cl), e))?;
+            }
+            
+            // Replay commits whose parents are available until found
+            let mut commits = queue.commits;
+            loop {
+                let pos = commits.iter().position(|c| known.contains_key(c.first_parent()) ||
+                        self.state(c.first_parent()).is_some());
+                let commit = match pos {
+                    Some(i) => commits.swap_remove(i),
+                    None => break,
+                };
+                if known.contains_key(commit.statesum()) {
+                    continue;   // duplicate
+                }
+                let state = {
+                    let parent = known.get(commit.first_parent())
+                            .or_else(|| self.state(commit.first_parent())).unwrap();
+                    PartState::from_state_commit(parent, &commit)?
+                };
+                if state.statesum() == key {
+                    return Ok(Some(state));
+                }
+                known.insert(state.statesum().clone(), state);
+            }
+        }
+        Ok(None)
+    }
+    
+    // Take self and two sums. Return a copy of a key to avoid lifetime issues.
+    fn latest_common_ancestor(&self, k1: &Sum, k2: &Sum) -> Result<Sum, MergeError> {
+        self.dag.latest_common_ancestor(k1, k2).ok_or(MergeError::NoCommonAncestor)
+    }
+    
+    /// Add a state, assuming that this isn't a new one (i.e. it's been loaded
+    /// from a file and doesn't need to be saved).
+    /// 
+    /// Unlike add_pair, mutating isn't possible, so this just returns false
+    /// if the sum is known without checking whether the state is different.
+    /// 
+    /// `n_edits` is the number of changes in a commit. Normally the state is
+    /// created from a commit, and `n_edits = commit.num_changes()`; in the
+    /// case the state is a loaded snapshot the "snapshot policy" should be
+    /// reset afterwards, hence n_edits does not matter.
+    /// 
+    /// Returns true unless the given state's sum equals an existing one.
+    fn add_state(&mut self, state: PartState<C::Element>, n_edits: usize) {
+        trace!("Partition {}: add state {}", self.name, state.statesum());
+        if self.states.contains(state.statesum()) {
+            trace!("Partition {} already contains state {}", self.name, state.statesum());
+            return;
+        }
+        
+        for parent in state.parents() {
+            // Remove from 'tips' if it happened to be there:
+            self.tips.remove(parent);
+            // Add to 'ancestors' if not in 'states':
+            if !self.states.contains(parent) {
+                self.ancestors.insert(parent.clone());
+            }
+        }
+        // We know from above 'state' is not in 'self.states'; if it's not in
+        // 'self.ancestors' either then it must be a tip:
+        if !self.ancestors.contains(state.statesum()) {
+            self.control.snapshot_policy().count(1, n_edits);
+            self.tips.insert(state.statesum().clone());
+        }
+        // TODO: check that classification in state equals that of this partition?
+        self.evicted.remove(state.statesum());
+        self.dag.insert(state.statesum().clone(), state.parents().to_vec(), state.meta());
+        self.insert_state(state);
+    }
+    
+    // Hold a state, enabling sum checks on access if requested (see
+    // `Control::verify_on_get`).
+    fn insert_state(&mut self, mut state: PartState<C::Element>) {
+        if self.control.verify_on_get() {
+            state.set_verify_on_get(true);
+        }
+        self.states.insert(state);
+    }
+    
+    /// Creates a state from the commit and adds to self. Updates tip if this
+    /// state is new.
+    pub fn add_commit(&mut self, commit: Commit<C::Element>) -> Result<(), PatchOp> {
+        if self.states.contains(commit.statesum()) { return Ok(()); }
+        self.check_number(&commit)?;
+        
+        let state = {
+            let parent = self.states.get(commit.first_parent())
+                .ok_or(PatchOp::NoParent)?;
+            PartState::from_state_commit(parent, &commit)?
+        };  // end borrow on self (from parent)
+        self.add_state(state, commit.num_changes());
+        Ok(())
+    }
+    
+    // Check a new commit and its state via `Control::validate_commit`.
+    fn validate(&mut self, commit: &Commit<C::Element>, state: &PartState<C::Element>) ->
+            Result<(), PatchOp>
+    {
+        let parent = self.states.get(commit.first_parent()).ok_or(PatchOp::NoParent)?;
+        let quota = self.control.quota();
+        let result = quota.check_totals(state.len(), state.byte_len()).and_then(|_| {
+            if quota.max_elt_bytes == usize::MAX {
+                return Ok(());
+            }
+            commit.changes_iter().filter_map(|(_, change)| change.element())
+                    .map(|elt| quota.check_elt(elt.byte_len())).collect()
+        });
+        if let Err(e) = result {
+            warn!("Partition {}: commit {} exceeds quota: {}", self.name, commit.statesum(), e);
+            return Err(PatchOp::QuotaExceeded);
+        }
+        if let Err(e) = self.control.validate_commit(parent, commit, state) {
+            warn!("Partition {}: commit {} rejected: {}", self.name, commit.statesum(), e);
+            return Err(PatchOp::Rejected);
+        }
+        
+        if let Some(owner) = self.control.as_mcm_ref().commit_source(commit.meta()) {
+            let changed = commit.changes_iter().map(|(id, _)| *id);
+            let conflicts: Vec<(EltId, Lease)> = self.locks
+                    .conflicts(changed, &owner, commit.meta().timestamp())
+                    .into_iter().map(|(id, lease)| (id, lease.clone())).collect();
+            for (id, lease) in conflicts {
+                warn!("Partition {}: commit {} by {} changes element {} locked by {}",
+                        self.name, commit.statesum(), owner, id, lease.owner);
+                self.control.on_lock_conflict(id, &lease);
+            }
+        }
+        Ok(())
+    }
+    
+    // Check that the commit's number is greater than that of each known
+    // parent (allowing for saturation at `u32::MAX`).
+    fn check_number(&self, commit: &Commit<C::Element>) -> Result<(), PatchOp> {
+        for parent in commit.parents() {
+            if let Some(state) = self.states.get(parent) {
+                if commit.meta().number() < state.meta().next_number() {
+                    return Err(PatchOp::NumberOrder);
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Add a paired commit and state, asserting that the checksums match and
+    /// the parent state is present. Also add to the queue awaiting `write()`.
+    /// 
+    /// If an element with the states's statesum already exists and differs
+    /// from the state passed, the state and commit passed will be mutated to
+    /// achieve a unique statesum.
+    /// 
+    /// Returns true unless the given state (including metadata) equals a
+    /// stored one (in which case nothing happens and false is returned).
+    fn add_pair(&mut self, mut commit: Commit<C::Element>, mut state: PartState<C::Element>) -> bool {
+        trace!("Partition {}: add commit {}", self.name, commit.statesum());
+        assert_eq!(commit.parents(), state.parents());
+        assert_eq!(commit.statesum(), state.statesum());
+        assert!(self.states.contains(commit.first_parent()));
+        
+        while let Some(old_state) = self.states.get(state.statesum()) {
+            if state == *old_state {
+                trace!("Partition {} already contains commit {}", self.name, commit.statesum());
+                return false;
+            } else {
+                commit.mutate_meta(state.mutate_meta());
+                trace!("Partition {}: mutated commit to {}", self.name, commit.statesum());
+            }
+        }
+        
+        self.add_state(state, commit.num_changes());
+        self.unsaved.push_back(commit);
+        true
+    }
+}
+
+
+/// Iterator over unsaved commits (see `Partition::unsaved_iter()`)
+pub struct UnsavedIter<'a, E: Element + 'a> {
+    iter: vec_deque::Iter<'a, Commit<E>>
+}
+impl<'a, E: Element> Clone for UnsavedIter<'a, E> {
+    fn clone(&self) -> UnsavedIter<'a, E> {
+        UnsavedIter { iter: self.iter.clone() }
+    }
+}
+impl<'a, E: Element> Iterator for UnsavedIter<'a, E> {
+    type Item = &'a Commit<E>;
+    fn next(&mut self) -> Option<&'a Commit<E>> {
+        self.iter.next()
+    }
+}
+impl<'a, E: Element> ExactSizeIterator for UnsavedIter<'a, E> {
+    fn len(&self) -> usize {
+        self.iter.len()
+    }
+}
+
+/// Wrapper around underlying iterator structure
+pub struct TipIter<'a> {
+    iter: hs::Iter<'a, Sum>
+}
+impl<'a> Clone for TipIter<'a> {
+    fn clone(&self) -> TipIter<'a> {
+        TipIter { iter: self.iter.clone() }
+    }
+}
+impl<'a> Iterator for TipIter<'a> {
+    type Item = &'a Sum;
+    fn next(&mut self) -> Option<&'a Sum> {
+        self.iter.next()
+    }
+}
+impl<'a> ExactSizeIterator for TipIter<'a> {
+    fn len(&self) -> usize {
+        self.iter.len()
+    }
+}
+
+/// Wrapper around a `PartState<E>`. Dereferences to this type.
+pub struct StateItem<'a, E: Element+'a> {
+    state: &'a PartState<E>,
+    tips: &'a HashSet<Sum>,
+    provenance: &'a HashMap<Sum, Provenance>,
+}
+impl<'a, E: Element+'a> StateItem<'a, E> {
+    /// Returns true if and only if this state is a tip state (i.e. is not the
+    /// parent of any other state).
+    /// 
+    /// There is exactly one tip state unless a merge is required or no states
+    /// are loaded.
+    pub fn is_tip(&self) -> bool {
+        self.tips.contains(self.state.statesum())
+    }
+    
+    /// Get the state's commit number. This is greater than the number of
+    /// each parent state, thus sorting by `cnum()` orders states such that
+    /// ancestors come first (see `Partition::state_by_cnum`).
+    pub fn cnum(&self) -> u32 {
+        self.state.meta().number()
+    }
+    
+    /// Get the file this state was loaded from, if any (see
+    /// `Partition::provenance`).
+    pub fn provenance(&self) -> Option<&'a Provenance> {
+        self.provenance.get(self.state.statesum())
+    }
+}
+impl<'a, E: Element+'a> Deref for StateItem<'a, E> {
+    type Target = PartState<E>;
+    fn deref(&self) -> &Self::Target {
+        self.state
+    }
+}
+
+/// Iterator over a partition's (historical or current) states
+pub struct StateIter<'a, E: Element+'a> {
+    iter: Iter<'a, PartState<E>, Sum, PartStateSumComparator>,
+    tips: &'a HashSet<Sum>,
+    provenance: &'a HashMap<Sum, Provenance>,
+}
+impl<'a, E: Element+'a> Iterator for StateIter<'a, E> {
+    type Item = StateItem<'a, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|item|
+            StateItem {
+                state: item,
+                tips: self.tips,
+                provenance: self.provenance,
+            }
+        )
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+/// A set of changes to be committed atomically; see `Partition::transaction`.
+/// 
+/// Operations are validated against the tip (and earlier operations) as they
+/// are added; failures are recorded and cause `commit` to reject the whole
+/// transaction. Dropping a transaction without calling `commit` discards it.
+pub struct Transaction<'a, C: Control + 'a> {
+    part: &'a mut Partition<C>,
+    state: MutPartState<C::Element>,
+    conflicts: Vec<(EltId, ElementOp)>,
+}
+impl<'a, C: Control> Transaction<'a, C> {
+    /// Insert an element with the given identifier
+    pub fn insert(&mut self, id: EltId, elt: C::Element) -> &mut Self {
+        if let Err(e) = self.state.insert(id, elt) {
+            self.conflicts.push((id, e));
+        }
+        self
+    }
+    
+    /// Insert an element with a newly generated identifier, returning the
+    /// identifier. Failure (`ElementOp::IdGenFailure`) is returned directly
+    /// (not recorded as a conflict).
+    pub fn insert_new(&mut self, elt: C::Element) -> Result<EltId, ElementOp> {
+        self.state.insert_new(elt)
+    }
+    
+    /// Replace an existing element
+    pub fn replace(&mut self, id: EltId, elt: C::Element) -> &mut Self {
+        if let Err(e) = self.state.replace(id, elt) {
+            self.conflicts.push((id, e));
+        }
+        self
+    }
+    
+    /// Remove an existing element
+    pub fn remove(&mut self, id: EltId) -> &mut Self {
+        if let Err(e) = self.state.remove(id) {
+            self.conflicts.push((id, e));
+        }
+        self
+    }
+    
+    /// Get the list of conflicting operations so far
+    pub fn conflicts(&self) -> &[(EltId, ElementOp)] {
+        &self.conflicts
+    }
+    
+    /// Get the state resulting from operations so far
+    pub fn state(&self) -> &MutPartState<C::Element> {
+        &self.state
+    }
+    
+    /// Commit all changes as a single commit (see `Partition::push_state`),
+    /// or reject all if any operation conflicted.
+    /// 
+    /// Returns `Ok(true)` if committed, `Ok(false)` if there were no changes.
+    pub fn commit(self) -> Result<bool, TransactionError> {
+        if !self.conflicts.is_empty() {
+            return Err(TransactionError::Conflicts(self.conflicts));
+        }
+        Ok(self.part.push_state(self.state)?)
+    }
+}
+
+// Read the header of snapshot `ss`, if it exists
+fn read_ss_head(io: &RepoIO, ss: usize) -> Result<Option<FileHeader>> {
+    if let Some(mut r) = io.read_ss(ss)? {
+        Ok(Some(read_head(&mut r)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// True if snapshot `ss` exists and is historical (see `write_snapshot_of`)
+fn is_historical_ss(io: &RepoIO, ss: usize) -> Result<bool> {
+    Ok(read_ss_head(io, ss)
+            .map_err(|e| file_error(None, FileId::Snapshot(ss), e))?
+            .map_or(false, |head| head.historical))
+}
+
+// True if any snapshot before `ss` exists
+fn has_older_ss(io: &RepoIO, ss: usize) -> bool {
+    (0..ss).any(|ss| io.has_ss(ss))
+}
+
+// Name of the codec `control` uses to write files of type `ftype`, if not
+// the identity
+fn codec_name<C: Control>(control: &C, ftype: &FileType) -> Option<String> {
+    control.codec(ftype).and_then(|codec| if codec.is_identity() {
+        None
+    } else {
+        Some(codec.name().to_string())
+    })
+}
+
+// Select the codec for a file, given its header (see `codec::select`)
+fn file_codec<C: Control>(control: &C, header: &FileHeader) -> Result<Rc<Codec>> {
+    select(header, control.codec(&header.ftype))
+}
+
+// Write a new commit log to `writer`: header, then commits from `commits`
+// while within `limits` (at least one), then a trailer if the format has one.
+// Each commit written is recorded in `index`. Returns the number of commits
+// written.
+fn write_log<'a, E: Element + 'a, I, W: Write>(writer: &mut ByteCounter<W>,
+        header: &FileHeader, codec: &Codec, limits: &LogLimits, commits: I,
+        states: &HashIndexed<PartState<E>, Sum, PartStateSumComparator>, buf: &mut Vec<u8>,
+        index: &mut Vec<LogIndexEntry>) -> Result<usize>
+        where I: Iterator<Item = &'a Commit<E>>
+{
+    write_head(header, writer)?;
+    start_log(writer)?;
+    
+    let mut last = None;
+    let mut num_written = 0;
+    for commit in commits {
+        buf.clear();
+        write_commit_with(commit, buf, header.ftype.ver(), codec, header.align())?;
+        if num_written > 0 && (num_written >= limits.max_commits ||
+                writer.bytes + buf.len() as u64 > limits.max_bytes) {
+            break;
+        }
+        index.push(LogIndexEntry {
+            statesum: commit.statesum().clone(),
+            cnum: commit.meta().number(),
+            offset: writer.bytes,
+        });
+        writer.write_all(buf)?;
+        num_written += 1;
+        last = Some(commit);
+    }
+    if header.ftype.ver() == CRC_VERSION {
+        // Record the state reached, for checking when reading
+        if let Some(state) = last.and_then(|commit| states.get(commit.statesum())) {
+            write_trailer(state, writer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(num_written)
+}
+
+// Read snapshot `ss` with its header, if it exists
+fn read_ss_file<C: Control>(control: &C, ss: usize, limits: &ReadLimits) ->
+        Result<Option<(FileHeader, PartState<C::Element>)>>
+{
+    if let Some(mut r) = control.io().read_ss(ss)? {
+        let head = read_head(&mut r)?;
+        let codec = file_codec(control, &head)?;
+        let state = read_snapshot_with(&mut r, head.ftype.ver(), limits, &*codec,
+                head.align())?;
+        Ok(Some((head, state)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Read snapshot `ss` as `read_ss_file`, unless `known` returns true for its
+// state sum, in which case elements are not decoded and no state is returned
+fn read_ss_file_unless<C: Control>(control: &C, ss: usize, limits: &ReadLimits,
+        known: &mut FnMut(&Sum) -> bool) ->
+        Result<Option<(FileHeader, Option<PartState<C::Element>>)>>
+{
+    if let Some(mut r) = control.io().read_ss(ss)? {
+        let head = read_head(&mut r)?;
+        let codec = file_codec(control, &head)?;
+        let state = read_snapshot_unless(&mut r, head.ftype.ver(), limits, &*codec,
+                head.align(), known)?;
+        Ok(Some((head, state)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Read the gap index (one snapshot number per line), if any
+fn read_gaps(io: &RepoIO) -> Result<BTreeSet<usize>> {
+    let mut pruned = BTreeSet::new();
+    if let Some(r) = io.read_gaps()? {
+        for line in BufReader::new(r).lines() {
+            let line = line?;
+            if line.trim().is_empty() { continue; }
+            match line.trim().parse() {
+                Ok(ss) => { pruned.insert(ss); },
+                Err(_) => return OtherError::err("invalid snapshot number in gap index"),
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+// States read on demand (see `Partition::fetch_state`), dropping the least
+// recently used when over a budget of element data bytes
+struct StateCache<E: Element> {
+    states: HashMap<Sum, (PartState<E>, usize)>,
+    // Keys, least recently used first
+    order: VecDeque<Sum>,
+    bytes: usize,
+}
+impl<E: Element> StateCache<E> {
+    fn new() -> Self {
+        StateCache { states: HashMap::new(), order: VecDeque::new(), bytes: 0 }
+    }
+    fn get(&self, key: &Sum) -> Option<&PartState<E>> {
+        self.states.get(key).map(|&(ref state, _)| state)
+    }
+    // Mark `key` as most recently used. Returns false if not present.
+    fn touch(&mut self, key: &Sum) -> bool {
+        if !self.states.contains_key(key) {
+            return false;
+        }
+        self.order.retain(|k| k != key);
+        self.order.push_back(key.clone());
+        true
+    }
+    // Insert a state, then drop others while over `budget`
+    fn insert(&mut self, state: PartState<E>, budget: usize) {
+        let len = state.elts_iter().map(|(_, elt)| elt.byte_len()).sum();
+        let key = state.statesum().clone();
+        self.bytes += len;
+        self.order.push_back(key.clone());
+        self.states.insert(key, (state, len));
+        while self.bytes > budget && self.order.len() > 1 {
+            let key = self.order.pop_front().unwrap();
+            let (_, len) = self.states.remove(&key).unwrap();
+            self.bytes -= len;
+        }
+    }
+    fn clear(&mut self) {
+        self.states.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+// Commits and trailers read from commit logs
+struct LogQueue<E: Element> {
+    commits: Vec<Commit<E>>,
+    // Where each commit was read (see `read_cl_file`)
+    sources: Vec<Provenance>,
+    trailers: Vec<(Sum, usize)>,
+    // Current log and length of its header
+    file: FileId,
+    base: u64,
+    // If set, commits of these states (and those queued) are skipped without
+    // decoding, and their sums recorded in `skipped`
+    known: Option<HashSet<Sum>>,
+    skipped: Vec<Sum>,
+}
+impl<E: Element> LogQueue<E> {
+    fn new() -> Self {
+        LogQueue { commits: vec![], sources: vec![], trailers: vec![],
+                file: FileId::CommitLog(0, 0), base: 0, known: None, skipped: vec![] }
+    }
+}
+impl<E: Element> CommitReceiver<E> for LogQueue<E> {
+    fn receive(&mut self, commit: Commit<E>) -> bool {
+        self.receive_at(commit, 0)
+    }
+    fn receive_at(&mut self, commit: Commit<E>, offset: u64) -> bool {
+        self.commits.push(commit);
+        self.sources.push(Provenance { file: self.file, offset: self.base + offset });
+        true
+    }
+    fn receive_trailer(&mut self, statesum: Sum, num_elts: usize) -> bool {
+        self.trailers.push((statesum, num_elts));
+        true
+    }
+    fn skip(&mut self, statesum: &Sum) -> bool {
+        let skip = self.known.as_mut().map_or(false, |known| !known.insert(statesum.clone()));
+        if skip {
+            self.skipped.push(statesum.clone());
+        }
+        skip
+    }
+}
+
+// Read commit log `ss`-`cl` into `queue`, if it exists. Returns the header,
+// number of bytes read and whether the log ends part-way through a commit
+// (as left by a failed write); this is an error unless `allow_truncated`.
+fn read_cl_file<C: Control>(control: &C, ss: usize, cl: usize,
+        queue: &mut LogQueue<C::Element>, limits: &ReadLimits, allow_truncated: bool) ->
+        Result<Option<(FileHeader, u64, bool)>>
+{
+    if let Some(r) = control.io().read_ss_cl(ss, cl)? {
+        let mut r = ByteCounter::new(r);
+        let header = read_head(&mut r)?;
+        let codec = file_codec(control, &header)?;
+        queue.file = FileId::CommitLog(ss, cl);
+        queue.base = r.bytes;
+        let result = read_log_with(&mut r, queue, header.ftype.ver(), limits, &*codec,
+                header.align());
+        let truncated = match result {
+            Ok(()) => false,
+            Err(ref e) if allow_truncated && is_eof(e) => true,
+            Err(e) => return Err(e),
+        };
+        Ok(Some((header, r.bytes, truncated)))
+    } else {
+        Ok(None)
+    }
+}
+
+// True if `e` is an I/O error due to unexpected end of file
+fn is_eof(e: &Error) -> bool {
+    e.downcast_ref::<io::Error>().map_or(false, |e| e.kind() == ErrorKind::UnexpectedEof)
+}
+
+// Attach partition name and file to an error
+fn file_error(partition: Option<&str>, file: FileId, e: Error) -> Error {
+    Box::new(ContextError::new(partition, Some(file), e))
+}
+
+// Wraps a reader or writer, counting bytes read or written
+struct ByteCounter<T> {
+    inner: T,
+    bytes: u64,
+}
+impl<T> ByteCounter<T> {
+    fn new(inner: T) -> ByteCounter<T> {
+        ByteCounter { inner: inner, bytes: 0 }
+    }
+}
+impl<R: Read> Read for ByteCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+}
+impl<W: Write> Write for ByteCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Records which commits have been consumed, for use with
+/// `Partition::changes_since_cursor`. Can be saved and restored, allowing
+/// an external consumer (e.g. an indexer) to see each commit exactly once
+/// across restarts.
+/// 
+/// A new cursor (from `Default`) has consumed nothing. The cursor tracks
+/// the latest states consumed; these and their ancestors are considered
+/// consumed.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ChangeCursor {
+    heads: HashSet<Sum>,
+}
+impl ChangeCursor {
+    /// Create, with `key` and its ancestors considered consumed
+    pub fn new(key: Sum) -> ChangeCursor {
+        let mut heads = HashSet::new();
+        heads.insert(key);
+        ChangeCursor { heads: heads }
+    }
+    
+    /// Get the latest consumed states
+    pub fn heads(&self) -> &HashSet<Sum> {
+        &self.heads
+    }
+    
+    /// Mark a commit as consumed. Commits should be consumed in the order
+    /// given by `Partition::changes_since_cursor`.
+    pub fn consume<E: Element>(&mut self, commit: &Commit<E>) {
+        for parent in commit.parents() {
+            self.heads.remove(parent);
+        }
+        self.heads.insert(commit.statesum().clone());
+    }
+    
+    /// Write, as text (one hexadecimal sum per line)
+    pub fn write_to(&self, w: &mut Write) -> Result<()> {
+        let mut heads: Vec<&Sum> = self.heads.iter().collect();
+        heads.sort();
+        for key in heads {
+            writeln!(w, "{}", key.as_string(false))?;
+        }
+        Ok(())
+    }
+    
+    /// Read a cursor saved by `write_to`
+    pub fn read_from(r: &mut Read) -> Result<ChangeCursor> {
+        let mut heads = HashSet::new();
+        for line in BufReader::new(r).lines() {
+            let line = line?;
+            if line.trim().is_empty() { continue; }
+            match Sum::from_hex(line.trim()) {
+                Some(key) => { heads.insert(key); },
+                None => return OtherError::err("invalid sum in change cursor"),
+            }
+        }
+        Ok(ChangeCursor { heads: heads })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::u64;
+    use super::*;
+    use elt::EltId;
+    use commit::{Commit, CommitMeta, MetaFlags, UserMeta, MakeCommitMeta,
+            provenance};
+    use control::{Control, DefaultControl, DefaultSnapshot, SnapshotPolicy, LogLimits};
+    use io::{DummyRepoIO, RepoIO};
+    use io::mem::MemRepoIO;
+    use rw::snapshot::{read_snapshot, write_snapshot};
+    use rw::commitlog::write_commit;
+    use rw::DEFAULT_ALIGN;
+    use merge::AncestorSolver2W;
+    use state::*;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    #[test]
+    fn commit_creation_and_replay(){
+        let mut queue = vec![];
+        let mut mcm = MCM;
+        
+        let insert = |state: &mut MutPartState<_>, num, string: &str| -> Result<_, _> {
+            state.insert(EltId::from(num), string.to_string())
+        };
+        
+        let mut state = PartState::new(&mut mcm).clone_mut();
+        insert(&mut state, 1, "one").unwrap();
+        insert(&mut state, 2, "two").unwrap();
+        let state_a = PartState::from_mut(state, &mut mcm);
+        
+        let mut state = state_a.clone_mut();
+        insert(&mut state, 3, "three").unwrap();
+        insert(&mut state, 4, "four").unwrap();
+        insert(&mut state, 5, "five").unwrap();
+        let state_b = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_a, &state_b).unwrap();
+        queue.push(commit);
+        
+        let mut state = state_b.clone_mut();
+        insert(&mut state, 6, "six").unwrap();
+        insert(&mut state, 7, "seven").unwrap();
+        state.remove(EltId::from(4)).unwrap();
+        state.replace(EltId::from(3), "half six".to_string()).unwrap();
+        let state_c = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_b, &state_c).unwrap();
+        queue.push(commit);
+        
+        let mut state = state_c.clone_mut();
+        insert(&mut state, 8, "eight").unwrap();
+        insert(&mut state, 4, "half eight").unwrap();
+        let state_d = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&state_c, &state_d).unwrap();
+        queue.push(commit);
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "replay part").unwrap();
+        part.add_state(state_a, 0);
+        for commit in queue {
+            part.push_commit(commit).unwrap();
+        }
+        
+        assert_eq!(part.tips.len(), 1);
+        let replayed_state = part.tip().unwrap();
+        assert_eq!(*replayed_state, state_d);
+    }
+    
+    #[test]
+    fn convert_legacy() {
+        use rw::legacy::legacy_file;
+        let file = legacy_file(2016_02_27, &[(1, b"one"), (2, b"two")]);
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let part = Partition::convert_legacy(control, &mut &file[..]).unwrap();
+        assert_eq!(part.name(), "legacy");
+        assert_eq!(part.tip().unwrap().len(), 2);
+        assert!(part.tip().unwrap().parents().is_empty());
+        
+        // The converted state is saved as the first snapshot:
+        let statesum = part.tip_key().unwrap().clone();
+        let control = part.unwrap_control();
+        let part2 = Partition::open(control, true).unwrap();
+        assert_eq!(part2.tip_key().unwrap(), &statesum);
+        assert_eq!(**part2.tip().unwrap().get_rc(2.into()).unwrap(), "two");
+        
+        // Files of current versions are not converted:
+        let mut buf = Vec::new();
+        part2.control().io().read_ss(0).unwrap().unwrap().read_to_end(&mut buf).unwrap();
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        assert!(Partition::convert_legacy(control, &mut &buf[..]).is_err());
+    }
+    
+    #[test]
+    fn on_new_partition() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "on_new_partition")
+                .expect("partition creation");
+        assert_eq!(part.tips.len(), 1);
+        
+        let state = part.tip().expect("getting tip").clone_mut();
+        assert_eq!(part.push_state(state).expect("committing"), false);
+        
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        assert!(!state.any_avail());
+        
+        let elt1 = "This is element one.".to_string();
+        let elt2 = "Element two data.".to_string();
+        let e1id = state.insert_new(elt1).expect("inserting elt");
+        let e2id = state.insert_new(elt2).expect("inserting elt");
+        
+        assert_eq!(part.push_state(state).expect("comitting"), true);
+        assert_eq!(part.unsaved.len(), 1);
+        assert_eq!(part.states.len(), 2);
+        let key = part.tip().expect("tip").statesum().clone();
+        {
+            let state = part.state(&key).expect("getting state by key");
+            assert!(state.is_avail(e1id));
+            assert_eq!(state.get(e2id), Ok(&"Element two data.".to_string()));
+        }   // `state` goes out of scope
+        assert_eq!(part.tips.len(), 1);
+        let state = part.tip().expect("getting tip").clone_mut();
+        assert_eq!(*state.parent(), key);
+        
+        assert_eq!(part.push_state(state).expect("committing"), false);
+    }
+    
+    #[test]
+    fn commit_numbers() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "commit_numbers")
+                .expect("partition creation");
+        for i in 0..3 {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("inserting elt");
+            assert!(part.push_state(state).expect("committing"));
+        }
+        
+        let key = part.tip_key().expect("tip_key").clone();
+        assert_eq!(part.state_by_cnum(3).expect("state 3").statesum(), &key);
+        assert_eq!(part.state_by_cnum(4), Err(MatchError::NoMatch));
+        let mut cnums: Vec<u32> = part.states_iter().map(|s| s.cnum()).collect();
+        cnums.sort();
+        assert_eq!(cnums, vec![0, 1, 2, 3]);
+        
+        // A commit whose number does not follow its parent's is rejected:
+        let parent = part.state_by_cnum(2).expect("state 2").clone_exact();
+        let mut state = parent.clone_mut();
+        state.insert_new("another".to_string()).expect("inserting elt");
+        let new_state = PartState::from_mut(state, &mut MCM);
+        let mut commit = Commit::from_diff(&parent, &new_state).expect("commit");
+        *commit.meta_mut() = CommitMeta::new_explicit(2, 0, MetaFlags::zero(),
+                vec![], UserMeta::None).expect("meta");
+        assert_eq!(part.push_commit(commit), Err(PatchOp::NumberOrder));
+    }
+    
+    #[test]
+    fn state_refs() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "state_refs")
+                .expect("partition creation");
+        let initial = part.tip_key().expect("tip_key").clone();
+        for i in 0..2 {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("inserting elt");
+            assert!(part.push_state(state).expect("committing"));
+        }
+        let tip = part.tip_key().expect("tip_key").clone();
+        let find = |s: &str| part.state_from_string(s.to_string()).map(|s| s.statesum().clone());
+        
+        assert_eq!(find("tip"), Ok(tip.clone()));
+        assert_eq!(find("TIP~2"), Ok(initial.clone()));
+        assert_eq!(find("tip~"), find("#1"));
+        assert_eq!(find(&tip.as_string(false).to_lowercase()), Ok(tip.clone()));
+        assert_eq!(find(&format!("{}~1", &tip.as_string(false)[0..12])), find("#1"));
+        assert_eq!(find("tip~3"), Err(MatchError::NoParent));
+        assert_eq!(find("tip~x"), Err(MatchError::Syntax("tip~x".to_string())));
+        assert_eq!(find("master"), Err(MatchError::Syntax("master".to_string())));
+    }
+    
+    #[test]
+    fn export_state() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "export_state")
+                .expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        let old = part.tip().expect("tip").clone_exact();
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        
+        let mut buf = Vec::new();
+        part.export_state(old.statesum(), &mut buf).expect("export_state");
+        let mut r = &buf[..];
+        let head = read_head(&mut r).expect("read_head");
+        assert_eq!(head.name, "export_state");
+        let state = read_snapshot(&mut r, head.ftype.ver(), &Default::default()).expect("read_snapshot");
+        assert_eq!(state, old);
+        
+        assert!(part.export_state(&Sum::zero(), &mut Vec::new()).is_err());
+    }
+    
+    #[test]
+    fn from_snapshot_stream() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "from stream").expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        let tip = part.tip().expect("tip").clone_exact();
+        let mut buf = Vec::new();
+        part.export_state(tip.statesum(), &mut buf).expect("export_state");
+        
+        let mut part = Partition::<DefaultControl<String, _>>::from_snapshot_stream(&mut &buf[..])
+                .expect("from_snapshot_stream");
+        assert_eq!(part.name(), "from stream");
+        assert_eq!(*part.tip().expect("tip"), tip);
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        part.write_fast().expect("write_fast");
+        assert_eq!(part.control().io().ss_cl_len(0), 1);
+        
+        buf.truncate(buf.len() - 1);
+        assert!(Partition::<DefaultControl<String, _>>::from_snapshot_stream(&mut &buf[..])
+                .is_err());
+    }
+    
+    #[test]
+    fn create_in_memory() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create_in_memory(control, "in memory").expect("create");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        assert_eq!(part.control().io().ss_len(), 0);
+        let opts = CloneOptions { tip_only: false, renumber: false };
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        assert!(part.clone_into(control, &opts).is_err());
+        
+        // The first write writes snapshot zero, then the commit
+        assert!(part.write_fast().expect("write_fast"));
+        let tip = part.tip().expect("tip").clone_exact();
+        let io = part.unwrap_control().unwrap_io();
+        assert_eq!((io.ss_len(), io.ss_cl_len(0)), (1, 1));
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(*part.tip().expect("tip"), tip);
+        assert_eq!(part.states_len(), 2);
+    }
+    
+    #[test]
+    fn clone_into() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "clone_into")
+                .expect("partition creation");
+        for i in 0..4 {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("inserting elt");
+            part.push_state(state).expect("committing");
+            if i == 1 {
+                part.write_fast().expect("write_fast");
+                part.write_snapshot().expect("write_snapshot");
+            }
+        }
+        let tip = part.tip().expect("tip").clone_exact();
+        assert_eq!(part.unsaved_len(), 2);
+        
+        let opts = CloneOptions { tip_only: false, renumber: false };
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut clone = part.clone_into(control, &opts).expect("clone_into");
+        assert_eq!(*clone.tip().expect("tip of clone"), tip);
+        assert_eq!(clone.unsaved_len(), 0);
+        clone.load_all().expect("load_all");
+        assert_eq!(clone.states_len(), 5);
+        assert_eq!(part.unsaved_len(), 2);
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        assert!(part.clone_into(clone.unwrap_control(), &opts).is_err());
+        
+        let opts = CloneOptions { tip_only: true, renumber: false };
+        let control = part.clone_into(control, &opts).expect("clone_into").unwrap_control();
+        assert_eq!(control.io().ss_len(), 1);
+        let clone = Partition::open(control, true).expect("open");
+        assert_eq!(clone.name(), "clone_into");
+        assert_eq!(*clone.tip().expect("tip of clone"), tip);
+        assert_eq!(clone.states_len(), 1);
+    }
+    
+    #[test]
+    fn changes_since() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "changes_since")
+                .expect("partition creation");
+        let initial = part.tip_key().expect("tip_key").clone();
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("one".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        
+        let mut cursor = ChangeCursor::default();
+        let commits = part.changes_since_cursor(&cursor).expect("changes");
+        assert_eq!(commits.len(), 1);
+        assert_eq!(part.changes_since(&initial).expect("changes"), commits);
+        for commit in &commits {
+            cursor.consume(commit);
+        }
+        assert_eq!(part.changes_since_cursor(&cursor).expect("changes"), vec![]);
+        
+        // Save and restore the cursor; branch from the initial state:
+        let mut buf = Vec::new();
+        cursor.write_to(&mut buf).expect("write cursor");
+        let mut cursor = ChangeCursor::read_from(&mut &buf[..]).expect("read cursor");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        let mut state = part.state(&initial).expect("initial").clone_mut();
+        state.insert_new("three".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        assert_eq!(part.tips_len(), 2);
+        
+        let commits = part.changes_since_cursor(&cursor).expect("changes");
+        assert_eq!(commits.len(), 2);
+        assert!(commits.iter().any(|c| c.parents() == &[initial.clone()]));
+        for commit in &commits {
+            cursor.consume(commit);
+        }
+        assert_eq!(cursor.heads(), part.tips());
+        assert_eq!(part.changes_since(&Sum::zero()),
+                Err(MatchError::NotLoaded(Sum::zero().as_string(false))));
+    }
+    
+    // Records commits, snapshots and write notifications
+    #[derive(Default)]
+    struct WriteNotify {
+        io: MemRepoIO,
+        ss_policy: DefaultSnapshot,
+        commits: Vec<Sum>,
+        snapshots: Vec<usize>,
+        writes: Vec<&'static str>,
+        refuse_write: bool,
+    }
+    impl MakeCommitMeta for WriteNotify {}
+    impl Control for WriteNotify {
+        type Element = String;
+        fn io(&self) -> &RepoIO { &self.io }
+        fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+        fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+        fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+        fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+        fn pre_write(&mut self, _num_commits: usize) -> Result<()> {
+            self.writes.push("pre");
+            if self.refuse_write { OtherError::err("refused") } else { Ok(()) }
+        }
+        fn post_write(&mut self) {
+            self.writes.push("post");
+        }
+        fn abort_write(&mut self) {
+            self.writes.push("abort");
+        }
+        fn on_commit(&mut self, commit: &Commit<String>) {
+            self.commits.push(commit.statesum().clone());
+        }
+        fn on_snapshot(&mut self, ss_num: usize) {
+            self.snapshots.push(ss_num);
+        }
+    }
+    
+    #[test]
+    fn write_notifications() {
+        let mut part = Partition::create(WriteNotify::default(), "notifications")
+                .expect("partition creation");
+        let mut keys = vec![];
+        for i in 0..2 {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("inserting elt");
+            part.push_state(state).expect("committing");
+            keys.push(part.tip_key().expect("tip_key").clone());
+        }
+        assert_eq!(part.control.commits.len(), 0);
+        part.control.refuse_write = true;
+        assert!(part.write_fast().is_err());
+        assert_eq!(part.unsaved_len(), 2);
+        part.control.refuse_write = false;
+        part.write_fast().expect("write_fast");
+        part.write_snapshot().expect("write_snapshot");
+        assert!(!part.write_fast().expect("write_fast"));
+        
+        let control = part.unwrap_control();
+        assert_eq!(control.commits, keys);
+        assert_eq!(control.snapshots, vec![0, 1]);
+        assert_eq!(control.writes, vec!["pre", "pre", "post"]);
+    }
+    
+    #[test]
+    fn ss_collision() {
+        #[derive(Default)]
+        struct Collisions {
+            io: MemRepoIO,
+            ss_policy: DefaultSnapshot,
+            snapshots: Vec<usize>,
+            ss_collisions: Vec<(usize, Sum)>,
+        }
+        impl MakeCommitMeta for Collisions {}
+        impl Control for Collisions {
+            type Element = String;
+            fn io(&self) -> &RepoIO { &self.io }
+            fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+            fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+            fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+            fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+            fn on_snapshot(&mut self, ss_num: usize) {
+                self.snapshots.push(ss_num);
+            }
+            fn on_ss_collision(&mut self, ss_num: usize, statesum: &Sum) {
+                self.ss_collisions.push((ss_num, statesum.clone()));
+            }
+        }
+        
+        let mut a = Partition::create(Collisions::default(), "collision")
+                .expect("partition creation");
+        let control = Collisions { io: a.control().io.clone(), .. Collisions::default() };
+        let mut b = Partition::open(control, true).expect("opening");
+        for (part, name) in vec![(&mut a, "a"), (&mut b, "b")] {
+            let mut state = part.tip().expect("getting tip").clone_mut();
+            state.insert_new(name.to_string()).expect("inserting elt");
+            part.push_state(state).expect("committing");
+        }
+        a.write_snapshot().expect("writing snapshot");
+        let a_tip = a.tip_key().expect("tip_key").clone();
+        
+        // Both chose number 1; the second writer finds it taken
+        {
+            let data = a.control().io.ss_data(1).expect("snapshot 1");
+            let mut w = b.control_mut().io.new_ss(1).expect("new_ss").expect("writer");
+            w.write_all(data).expect("writing");
+        }
+        b.write_snapshot().expect("writing snapshot");
+        assert_eq!(b.control().ss_collisions, vec![(1, a_tip.clone())]);
+        assert_eq!(b.control().snapshots, vec![2]);
+        assert!(b.merge_required());
+        assert!(b.tips().contains(&a_tip));
+        assert!(b.control().io.has_ss(2));
+    }
+    
+    #[test]
+    fn ss_collisions_checked() {
+        // Every snapshot number appears taken, by a copy of snapshot 0, but
+        // only snapshot 0 is present
+        #[derive(Debug)]
+        struct TakenIO(MemRepoIO);
+        impl RepoIO for TakenIO {
+            fn ss_len(&self) -> usize { self.0.ss_len() }
+            fn ss_cl_len(&self, ss_num: usize) -> usize { self.0.ss_cl_len(ss_num) }
+            fn has_ss(&self, ss_num: usize) -> bool { self.0.has_ss(ss_num) }
+            fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+                self.0.read_ss(if self.0.has_ss(ss_num) { ss_num } else { 0 })
+            }
+            fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Read+'a>>>
+            {
+                self.0.read_ss_cl(ss_num, cl_num)
+            }
+            fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+                if self.0.has_ss(0) { Ok(None) } else { self.0.new_ss(ss_num) }
+            }
+            fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Write+'a>>>
+            {
+                self.0.append_ss_cl(ss_num, cl_num)
+            }
+            fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Write+'a>>>
+            {
+                self.0.new_ss_cl(ss_num, cl_num)
+            }
+        }
+        
+        let control = DefaultControl::<String, _>::new(TakenIO(MemRepoIO::new()));
+        let mut part = Partition::create(control, "taken").expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("elt".to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        let e = part.write_snapshot().expect_err("writing snapshot");
+        assert!(e.to_string().contains("no snapshot found"));
+    }
+    
+    #[test]
+    fn validate_commit() {
+        // Rejects states with more than one element
+        #[derive(Default)]
+        struct MaxOne {
+            io: MemRepoIO,
+            ss_policy: DefaultSnapshot,
+        }
+        impl MakeCommitMeta for MaxOne {}
+        impl Control for MaxOne {
+            type Element = String;
+            fn io(&self) -> &RepoIO { &self.io }
+            fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+            fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+            fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+            fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+            fn validate_commit(&mut self, _parent: &PartState<String>,
+                    _commit: &Commit<String>, state: &PartState<String>) -> Result<()>
+            {
+                if state.len() > 1 { OtherError::err("too many elements") } else { Ok(()) }
+            }
+        }
+        
+        let mut part = Partition::create(MaxOne::default(), "validation")
+                .expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("one".to_string()).expect("inserting elt");
+        assert_eq!(part.push_state(state), Ok(true));
+        let key = part.tip_key().expect("tip_key").clone();
+        
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        let new_state = PartState::from_mut(state, &mut MCM);
+        let commit = Commit::from_diff(part.tip().expect("getting tip"), &new_state)
+                .expect("from_diff");
+        assert_eq!(part.push_commit(commit), Err(PatchOp::Rejected));
+        let mut state = part.tip().expect("getting tip").clone_mut();
+        state.insert_new("two".to_string()).expect("inserting elt");
+        assert_eq!(part.push_state(state), Err(PatchOp::Rejected));
+        assert_eq!(part.tip_key(), Ok(&key));
+        assert_eq!(part.unsaved_len(), 1);
+    }
+    
+    #[test]
+    fn verify_on_get() {
+        use std::cell::Cell;
+        use std::io::Write;
+        use elt::Element;
+        use error::ElementOp;
+        
+        // An element which can (wrongly) be mutated in place
+        #[derive(PartialEq, Eq, Debug)]
+        struct Mutable(Cell<u8>);
+        impl Element for Mutable {
+            fn write_buf(&self, writer: &mut Write) -> Result<()> {
+                writer.write_all(&[self.0.get()])?;
+                Ok(())
+            }
+            fn read_buf(buf: &[u8]) -> Result<Self> {
+                Ok(Mutable(Cell::new(buf[0])))
+            }
+        }
+        
+        let mut control = DefaultControl::<Mutable, _>::new(DummyRepoIO::new());
+        control.set_verify_on_get(true);
+        let mut part = Partition::create(control, "verify").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let id = state.insert_new(Mutable(Cell::new(1))).expect("insert");
+        assert_eq!(part.push_state(state), Ok(true));
+        
+        let tip = part.tip().expect("tip");
+        assert!(tip.verify_on_get());
+        assert_eq!(tip.get(id).expect("get").0.get(), 1);
+        assert_eq!(tip.verify_elements(), Ok(()));
+        
+        tip.get(id).expect("get").0.set(2);
+        assert_eq!(tip.get(id), Err(ElementOp::EltCorrupt));
+        assert_eq!(tip.verify_elements(), Err(ElementOp::EltCorrupt));
+        
+        // Without recorded sums, corruption is still found via the state sum
+        let mut copy = tip.clone_exact();
+        copy.set_verify_on_get(false);
+        assert!(copy.get(id).is_ok());
+        assert_eq!(copy.verify_elements(), Err(ElementOp::EltCorrupt));
+    }
+    
+    #[test]
+    fn split() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "split").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for i in 0..6 {
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+        }
+        part.push_state(state).expect("push_state");
+        
+        let (a, b) = part.split(|id, _| Into::<u64>::into(id) % 2 == 0,
+                DefaultControl::new(MemRepoIO::new()), "split-even",
+                DefaultControl::new(MemRepoIO::new()), "split-odd").expect("split");
+        assert_eq!(a.name(), "split-even");
+        let (tip_a, tip_b) = (a.tip().expect("tip a"), b.tip().expect("tip b"));
+        assert_eq!((tip_a.len(), tip_b.len()), (3, 3));
+        assert_eq!(tip_a.get(EltId::from(2)).map(|s| s.as_str()), Ok("element 2"));
+        assert!(tip_b.get(EltId::from(2)).is_err());
+        assert_eq!(tip_b.parents(), part.tip().expect("tip").parents());
+        assert_eq!(part.tip().expect("tip").len(), 6);
+        
+        // Each child is a complete partition:
+        let io = b.unwrap_control().unwrap_io();
+        let b = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(b.name(), "split-odd");
+        assert_eq!(b.tip().expect("tip").get(EltId::from(5)).map(|s| s.as_str()),
+                Ok("element 5"));
+        
+        let mut used = MemRepoIO::new();
+        used.new_ss(0).expect("new_ss");
+        assert!(part.split(|_, _| true, DefaultControl::new(MemRepoIO::new()), "a",
+                DefaultControl::new(used), "b").is_err());
+    }
+    
+    #[test]
+    fn absorb() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "absorb").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).expect("insert");
+        state.insert(EltId::from(2), "two".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut other = Partition::create(control, "other").expect("create");
+        let mut state = other.tip().expect("tip").clone_mut();
+        state.insert(EltId::from(2), "deux".to_string()).expect("insert");
+        state.insert(EltId::from(7), "sept".to_string()).expect("insert");
+        other.push_state(state).expect("push_state");
+        
+        let remaps = part.absorb(&other).expect("absorb");
+        let moved: Vec<_> = remaps.iter().map(|r| (r.from, r.to)).collect();
+        assert_eq!(moved, vec![(EltId::from(2), EltId::from(3)),
+                (EltId::from(7), EltId::from(7))]);
+        assert!(remaps.iter().all(|r| r.from_part == "other" && r.key.is_none()));
+        let tip = part.tip().expect("tip");
+        assert_eq!(tip.len(), 4);
+        assert_eq!(tip.get(EltId::from(2)).map(|s| s.as_str()), Ok("two"));
+        assert_eq!(tip.get(EltId::from(3)).map(|s| s.as_str()), Ok("deux"));
+        assert_eq!(tip.get(EltId::from(7)).map(|s| s.as_str()), Ok("sept"));
+        assert_eq!(other.tip().expect("tip").len(), 2);
+    }
+    
+    #[test]
+    fn tip_handle() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "tip_handle").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let id = state.insert_new("old".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        
+        let handle = part.tip_handle().expect("tip_handle");
+        let copy = handle.clone();
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.replace(id, "new".to_string()).expect("replace");
+        part.push_state(state).expect("push_state");
+        
+        assert_eq!(copy.get(id).map(|s| s.as_str()), Ok("old"));
+        assert_eq!(part.tip().expect("tip").get(id).map(|s| s.as_str()), Ok("new"));
+        assert_eq!(*handle, *part.state(handle.statesum()).expect("state"));
+        assert!(part.state_handle(&Sum::zero()).is_none());
+    }
+    
+    #[test]
+    fn snapshot_isolation() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "isolation").expect("create");
+        let id = EltId::from(1);
+        let mut reader1 = part.tip_handle().expect("tip_handle");
+        let mut reader2 = reader1.clone();
+        
+        // The writer advances the tip; readers still see the old state
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(id, "first".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        assert!(!reader1.is_tip(&part));
+        assert!(reader1.get(id).is_err() && reader2.get(id).is_err());
+        
+        // Refreshing one reader does not affect the other
+        assert_eq!(reader1.refresh(&part), Ok(true));
+        assert_eq!(reader1.refresh(&part), Ok(false));
+        assert_eq!(reader1.get(id).map(|s| s.as_str()), Ok("first"));
+        assert!(reader2.get(id).is_err());
+        
+        // Writing from a stale handle fails; after refreshing it succeeds
+        let mut state = reader2.clone_mut();
+        state.insert(id, "second".to_string()).expect("insert");
+        assert_eq!(part.push_state_if_tip(state), Err(PatchOp::TipChanged));
+        assert_eq!(reader2.refresh(&part), Ok(true));
+        let mut state = reader2.clone_mut();
+        state.replace(id, "second".to_string()).expect("replace");
+        assert_eq!(part.push_state_if_tip(state), Ok(true));
+        assert_eq!(reader1.get(id).map(|s| s.as_str()), Ok("first"));
+    }
+    
+    #[test]
+    fn seal() {
+        use error::ReadOnly;
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "seal").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let id = state.insert_new("closed".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        assert!(!part.is_sealed());
+        part.seal().expect("seal");
+        assert!(part.is_sealed());
+        assert_eq!(part.unsaved_len(), 0);
+        
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.remove(id).expect("remove");
+        assert_eq!(part.push_state(state), Err(PatchOp::Sealed));
+        assert!(part.write_snapshot().expect_err("write_snapshot").is::<ReadOnly>());
+        
+        // The seal is persistent:
+        let io = part.unwrap_control().unwrap_io();
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("open");
+        assert!(part.is_sealed());
+        assert_eq!(part.tip().expect("tip").get(id).map(|s| s.as_str()), Ok("closed"));
+        let state = part.tip().expect("tip").clone_mut();
+        assert_eq!(part.push_state(state), Err(PatchOp::Sealed));
+    }
+    
+    #[test]
+    fn quota() {
+        use error::ElementOp;
+        use state::Quota;
+        
+        let mut control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        control.set_quota(Quota { max_elts: 2, max_bytes: 8, max_elt_bytes: 6 });
+        let mut part = Partition::create(control, "quota").expect("create");
+        let (one, two, three) = (EltId::from(1), EltId::from(2), EltId::from(3));
+        {
+            let mut t = part.transaction().expect("transaction");
+            t.insert(one, "one".to_string()).insert(two, "two".to_string());
+            assert_eq!(t.commit(), Ok(true));
+        }
+        {
+            let mut t = part.transaction().expect("transaction");
+            t.insert(three, "three".to_string());
+            assert_eq!(t.conflicts(), &[(three, ElementOp::EltLimit)]);
+            t.replace(one, "one hundred".to_string());
+            t.replace(two, "twenty".to_string());
+            assert_eq!(t.conflicts()[1..], [(one, ElementOp::EltTooLarge),
+                    (two, ElementOp::ByteLimit)]);
+        }
+        
+        // States built without the quota are checked when pushed:
+        let key = part.tip_key().expect("tip_key").clone();
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(three, "three".to_string()).expect("insert");
+        assert_eq!(part.push_state(state), Err(PatchOp::QuotaExceeded));
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.replace(one, "one hundred".to_string()).expect("replace");
+        let new_state = PartState::from_mut(state, &mut MCM);
+        let commit = Commit::from_diff(part.tip().expect("tip"), &new_state)
+                .expect("from_diff");
+        assert_eq!(part.push_commit(commit), Err(PatchOp::QuotaExceeded));
+        assert_eq!(part.tip_key(), Ok(&key));
+        
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.replace(one, "uno".to_string()).expect("replace");
+        assert_eq!(part.push_state(state), Ok(true));
+    }
+    
+    #[test]
+    fn view_state() {
+        use rw::ReadLimits;
+        use rw::header::read_head;
+        use rw::snapshot::read_snapshot;
+        
+        // Hides "secret" elements and redacts "private" ones
+        #[derive(Default)]
+        struct Redact {
+            io: MemRepoIO,
+            ss_policy: DefaultSnapshot,
+        }
+        impl MakeCommitMeta for Redact {}
+        impl Control for Redact {
+            type Element = String;
+            fn io(&self) -> &RepoIO { &self.io }
+            fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+            fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+            fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+            fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+            fn view_element(&self, _id: EltId, elt: &Rc<String>) -> Option<Rc<String>> {
+                if elt.starts_with("secret") {
+                    None
+                } else if elt.starts_with("private") {
+                    Some(Rc::new("[redacted]".to_string()))
+                } else {
+                    Some(elt.clone())
+                }
+            }
+        }
+        
+        let mut part = Partition::create(Redact::default(), "view")
+                .expect("partition creation");
+        let ids = {
+            let mut t = part.transaction().expect("transaction");
+            let ids = ["public", "private", "secret"].iter()
+                    .map(|s| t.insert_new(s.to_string()).expect("insert_new"))
+                    .collect::<Vec<_>>();
+            assert_eq!(t.commit(), Ok(true));
+            ids
+        };
+        let key = part.tip_key().expect("tip_key").clone();
+        
+        let view = part.view_state(&key).expect("view_state");
+        assert_eq!(view.num_avail(), 2);
+        assert_eq!(view.get(ids[0]), Ok(&"public".to_string()));
+        assert_eq!(view.get(ids[1]), Ok(&"[redacted]".to_string()));
+        assert!(!view.is_avail(ids[2]));
+        assert!(*view.statesum() != key);
+        assert_eq!(part.tip().expect("tip").num_avail(), 3);
+        
+        let mut buf = Vec::new();
+        part.export_view(&key, &mut buf).expect("export_view");
+        let mut r = &buf[..];
+        let head = read_head(&mut r).expect("read_head");
+        let state: PartState<String> = read_snapshot(&mut r, head.ftype.ver(),
+                &ReadLimits::default()).expect("read_snapshot");
+        assert_eq!(state, view);
+        assert_eq!(part.view_state(&Sum::zero()), Err(MatchError::NoMatch));
+    }
+    
+    #[test]
+    fn load_error_context() {
+        use error::{ContextError, ErrorCode, ReadError};
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let part = Partition::create(control, "context").expect("partition creation");
+        let mut control = part.unwrap_control();
+        {
+            let mut w = control.io_mut().new_ss_cl(0, 1).expect("new_ss_cl").expect("writer");
+            w.write_all(b"this is not a Pippin commit log").expect("write_all");
+        }
+        
+        let e = Partition::open(control, true).err().expect("open must fail");
+        assert_eq!(ErrorCode::of(&*e), ErrorCode::Read);
+        {
+            let ctx = e.downcast_ref::<ContextError>().expect("ContextError");
+            assert_eq!(ctx.partition, Some("context".to_string()));
+            assert_eq!(ctx.file, Some(FileId::CommitLog(0, 1)));
+        }
+        {
+            let read = e.source().expect("source").downcast_ref::<ReadError>().expect("ReadError");
+            assert_eq!(read.data(), Some((0, &b"this is not a Pi"[..])));
+            assert!(!read.dump().to_string().contains("insufficient data"));
+        }
+        assert!(e.to_string().starts_with("partition context: commit log 0-1: "));
+    }
+    
+    #[test]
+    fn load_report() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "replay").expect("create");
+        let mut commits = vec![];
+        for i in 1..4 {
+            let state = {
+                let mut state = part.tip().expect("tip").clone_mut();
+                state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+                PartState::from_mut(state, &mut MCM)
+            };
+            let commit = Commit::from_diff(part.tip().expect("tip"), &state).expect("commit");
+            commits.push(Commit::from_diff(part.tip().expect("tip"), &state).expect("commit"));
+            assert!(part.push_commit(commit).expect("push_commit"));
+        }
+        let state1 = part.state(commits[0].statesum()).expect("state 1").clone_exact();
+        let ss_header = part.make_header(FileType::snapshot()).expect("header");
+        let cl_header = part.make_header(FileType::commit_log()).expect("header");
+        part.unsaved.pop_back();    // write only commits 1 and 2 to log 0-0
+        part.write_fast().expect("write_fast");
+        
+        // Snapshot 1 holds state 1; log 1-0 holds commit 3, whose parent is in log 0-0
+        let mut control = part.unwrap_control();
+        {
+            let mut w = control.io_mut().new_ss(1).expect("new_ss").expect("writer");
+            write_head(&ss_header, &mut w).expect("write_head");
+            write_snapshot(&state1, &mut w).expect("write_snapshot");
+        }
+        {
+            let mut w = control.io_mut().new_ss_cl(1, 0).expect("new_ss_cl").expect("writer");
+            write_head(&cl_header, &mut w).expect("write_head");
+            start_log(&mut w).expect("start_log");
+            write_commit(&commits[2], &mut w).expect("write_commit");
+        }
+        
+        let mut part = Partition::open(control, false).expect("open");
+        assert!(part.load_latest().is_err());
+        assert!(part.unload(true));
+        
+        let options = LoadOptions { stash_orphans: true, ..Default::default() };
+        part.load_with(usize::MAX, usize::MAX, &options).expect("load latest");
+        assert_eq!(part.orphans_len(), 1);
+        assert_eq!(*part.load_report(), LoadReport {
+            applied: vec![],
+            duplicates: vec![],
+            orphaned: vec![commits[2].statesum().clone()],
+            verified: vec![],
+            warnings: vec![],
+        });
+        
+        part.load_with(0, usize::MAX, &options).expect("load all");
+        assert_eq!(part.orphans_len(), 0);
+        assert_eq!(*part.load_report(), LoadReport {
+            applied: vec![commits[1].statesum().clone(), commits[2].statesum().clone()],
+            duplicates: vec![commits[0].statesum().clone()],
+            orphaned: vec![],
+            verified: vec![],
+            warnings: vec![],
+        });
+        assert_eq!(part.tip_key().expect("tip"), commits[2].statesum());
+        
+        // The report describes loaded data only
+        assert!(part.unload(false));
+        assert_eq!(*part.load_report(), LoadReport::default());
+    }
+    
+    #[test]
+    fn out_of_order_log() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "out of order").expect("create");
+        let mut commits = vec![];
+        let mut state = part.tip().expect("tip").clone_exact();
+        for i in 1..3 {
+            let mut mut_state = state.clone_mut();
+            mut_state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            let next = PartState::from_mut(mut_state, &mut MCM);
+            commits.push(Commit::from_diff(&state, &next).expect("commit"));
+            state = next;
+        }
+        let header = part.make_header(FileType::commit_log()).expect("header");
+        let mut control = part.unwrap_control();
+        {
+            let mut w = control.io_mut().new_ss_cl(0, 0).expect("new_ss_cl").expect("writer");
+            write_head(&header, &mut w).expect("write_head");
+            start_log(&mut w).expect("start_log");
+            write_commit(&commits[1], &mut w).expect("write_commit");
+            write_commit(&commits[0], &mut w).expect("write_commit");
+        }
+        
+        let part = Partition::open(control, true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), state.statesum());
+        assert_eq!(part.load_report().applied,
+                vec![commits[0].statesum().clone(), commits[1].statesum().clone()]);
+        assert!(part.load_report().orphaned.is_empty());
+    }
+    
+    #[test]
+    fn crc_files() {
+        use control::PartConfig;
+        use rw::CRC_VERSION;
+        
+        let mut config = PartConfig::default();
+        config.crc = true;
+        config.read_limits.quick_verify = true;
+        let control = DefaultControl::<String, _>::with_config(MemRepoIO::new(), config);
+        let mut part = Partition::create(control, "crc").expect("create");
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+        }
+        part.write_fast().expect("write_fast");
+        let tip = part.tip_key().expect("tip").clone();
+        let io = part.unwrap_control().unwrap_io();
+        assert_eq!(&io.ss_data(0).expect("ss")[8..16], format!("{}", CRC_VERSION).as_bytes());
+        
+        // Copy with a byte of log 0-0 changed, at `pos` from the end
+        let corrupt = |pos: usize| {
+            let mut log = io.ss_cl_data(0, 0).expect("log").to_vec();
+            let i = log.len() - pos;
+            log[i] ^= 0x10;
+            let mut io2 = MemRepoIO::new();
+            io2.new_ss(0).expect("new_ss").expect("writer")
+                    .write_all(io.ss_data(0).expect("ss")).expect("write");
+            io2.new_ss_cl(0, 0).expect("new_ss_cl").expect("writer")
+                    .write_all(&log).expect("write");
+            DefaultControl::<String, _>::new(io2)
+        };
+        
+        let part = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        assert_eq!(part.load_report().verified, vec![tip.clone()]);
+        part.verify_files().expect("verify_files");
+        
+        // Data changed: caught by the CRC
+        let e = Partition::open(corrupt(200), true).err().expect("open must fail");
+        assert!(e.to_string().contains("CRC mismatch"));
+        // Only the final checksum changed: not checked by quick verification
+        let part = Partition::open(corrupt(1), true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        let e = part.verify_files().err().expect("verify_files must fail");
+        assert!(e.to_string().contains("checksum invalid"));
+    }
+    
+    #[test]
+    fn pruned_snapshots() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "pruned").expect("create");
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            part.write_fast().expect("write_fast");
+            part.write_snapshot().expect("write_snapshot");
+        }
+        let tip = part.tip_key().expect("tip").clone();
+        let mut io = part.unwrap_control().unwrap_io();
+        io.remove_ss(0);
+        io.remove_ss(1);
+        
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        assert_eq!(part.pruned().expect("pruned"), Vec::<usize>::new());
+        part.load_all().expect("load_all");
+        assert_eq!(part.load_report().warnings, vec![LoadWarning::MissingSnapshot(0),
+                LoadWarning::MissingSnapshot(1)]);
+        part.record_pruned(&[1, 0]).expect("record_pruned");
+        part.record_pruned(&[1]).expect("record_pruned");
+        assert_eq!(part.pruned().expect("pruned"), vec![0, 1]);
+        
+        let io = part.unwrap_control().unwrap_io();
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(part.pruned().expect("pruned"), vec![0, 1]);
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        assert_eq!(part.load_report().warnings, vec![]);
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "no gaps").expect("create");
+        assert!(part.record_pruned(&[0]).is_err());
+    }
+    
+    #[test]
+    fn admin_history() {
+        use rw::admin::AdminOp;
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "admin").expect("create");
+        assert_eq!(part.admin_history().expect("admin_history"), vec![]);
+        part.record_pruned(&[0]).expect("record_pruned");
+        part.record_admin(AdminOp::Pack, "snapshots 0-1 into pack 1".to_string())
+                .expect("record_admin");
+        
+        let io = part.unwrap_control().unwrap_io();
+        let mut part = Partition::open(DefaultControl::<String, _>::new(io), false)
+                .expect("open");
+        part.record_admin(AdminOp::Other("archive".to_string()), String::new())
+                .expect("record_admin");
+        let history = part.admin_history().expect("admin_history");
+        let ops: Vec<_> = history.iter().map(|r| (r.op.clone(), &r.detail[..])).collect();
+        assert_eq!(ops, vec![(AdminOp::Prune, "snapshots [0]"),
+                (AdminOp::Pack, "snapshots 0-1 into pack 1"),
+                (AdminOp::Other("archive".to_string()), "")]);
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "no admin").expect("create");
+        assert!(part.record_admin(AdminOp::Rewrite, String::new()).is_err());
+    }
+    
+    #[test]
+    fn search() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "search").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for (i, s) in ["apple", "banana", "avocado", "cherry"].iter().enumerate() {
+            state.insert(EltId::from(i as u64 + 1), s.to_string()).expect("insert");
+        }
+        part.push_state(state).expect("push_state");
+        
+        let found = part.search(&|_, elt: &String| elt.starts_with('a')).expect("search");
+        let ids: Vec<u64> = found.iter().map(|&(id, _)| id.into()).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(**found[1].1, "avocado");
+        assert!(part.search(&|_, elt: &String| elt.is_empty()).expect("search").is_empty());
+    }
+    
+    struct Xor;
+    impl Codec for Xor {
+        fn name(&self) -> &str { "xor" }
+        fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.extend(data.iter().map(|b| b ^ 0x5A));
+            Ok(())
+        }
+        fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            self.encode(data, out)
+        }
+    }
+    
+    // Uses `codec` for all files
+    #[derive(Default)]
+    struct CodecControl {
+        io: MemRepoIO,
+        ss_policy: DefaultSnapshot,
+        codec: Option<Rc<Codec>>,
+    }
+    impl MakeCommitMeta for CodecControl {}
+    impl Control for CodecControl {
+        type Element = String;
+        fn io(&self) -> &RepoIO { &self.io }
+        fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+        fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+        fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+        fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+        fn codec(&self, _ftype: &FileType) -> Option<Rc<Codec>> {
+            self.codec.clone()
+        }
+    }
+    
+    #[test]
+    fn codecs() {
+        let mut control = CodecControl::default();
+        control.codec = Some(Rc::new(Xor));
+        let mut part = Partition::create(control, "codecs").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("plain text".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        part.write_snapshot().expect("write_snapshot");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let control = part.unwrap_control();
+        for data in vec![control.io.ss_data(1), control.io.ss_cl_data(0, 0)] {
+            let data = data.expect("file written");
+            assert!(!data.windows(10).any(|w| w == b"plain text"));
+            let header = read_head(&mut &data[..]).expect("read_head");
+            assert_eq!(header.codec, Some("xor".to_string()));
+        }
+        let mut part = Partition::open(control, true).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        part.verify_files().expect("verify_files");
+        
+        // Files cannot be read without the codec
+        let mut control = part.unwrap_control();
+        control.codec = None;
+        assert!(Partition::open(control, true).is_err());
+    }
+    
+    #[test]
+    fn load_dedup() {
+        use std::cell::Cell;
+        
+        // Counts elements decoded
+        struct Counting(Cell<usize>);
+        impl Codec for Counting {
+            fn name(&self) -> &str { "counting" }
+            fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+                out.extend_from_slice(data);
+                Ok(())
+            }
+            fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+                self.0.set(self.0.get() + 1);
+                self.encode(data, out)
+            }
+        }
+        
+        let codec = Rc::new(Counting(Cell::new(0)));
+        let mut control = CodecControl::default();
+        control.codec = Some(codec.clone());
+        let mut part = Partition::create(control, "dedup").expect("create");
+        for i in 0..2 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+        }
+        part.write_fast().expect("write_fast");
+        part.write_snapshot().expect("write_snapshot");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        // Copy the log, as if both commits were written by two writers
+        let mut control = part.unwrap_control();
+        let log = control.io.ss_cl_data(0, 0).expect("log").to_vec();
+        control.io.new_ss_cl(0, 1).expect("new_ss_cl").expect("writer")
+                .write_all(&log).expect("write");
+        
+        codec.0.set(0);
+        let mut part = Partition::open(control, false).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        // Each commit's element is decoded once; snapshot 1 is not decoded
+        assert_eq!(codec.0.get(), 2);
+        assert_eq!(part.load_report().duplicates.len(), 2);
+    }
+    
+    #[test]
+    fn element_align() {
+        #[derive(Default)]
+        struct Aligned {
+            io: MemRepoIO,
+            ss_policy: DefaultSnapshot,
+            align: Option<usize>,
+        }
+        impl MakeCommitMeta for Aligned {}
+        impl Control for Aligned {
+            type Element = String;
+            fn io(&self) -> &RepoIO { &self.io }
+            fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+            fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+            fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+            fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+            fn element_align(&self, _ftype: &FileType) -> usize {
+                self.align.unwrap_or(DEFAULT_ALIGN)
+            }
+        }
+        
+        let write = |align| {
+            let control = Aligned { align: align, .. Aligned::default() };
+            let mut part = Partition::create(control, "align").expect("create");
+            let mut state = part.tip().expect("tip").clone_mut();
+            for i in 0..20 {
+                state.insert_new(format!("e{}", i)).expect("insert");
+            }
+            part.push_state(state).expect("push_state");
+            part.write_fast().expect("write_fast");
+            part.write_snapshot().expect("write_snapshot");
+            part
+        };
+        let padded = write(None).unwrap_control();
+        let part = write(Some(1));
+        let tip = part.tip_key().expect("tip").clone();
+        let control = part.unwrap_control();
+        for &(a, b) in &[(padded.io.ss_data(1), control.io.ss_data(1)),
+                (padded.io.ss_cl_data(0, 0), control.io.ss_cl_data(0, 0))] {
+            let (a, b) = (a.expect("file written"), b.expect("file written"));
+            assert!(b.len() + 200 < a.len());
+            assert_eq!(read_head(&mut &b[..]).expect("read_head").align, Some(1));
+        }
+        let mut part = Partition::open(control, true).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        part.verify_files().expect("verify_files");
+    }
+    
+    #[test]
+    fn log_limits() {
+        let write = |limits| {
+            let mut control = DefaultControl::<String, _>::new(MemRepoIO::new());
+            control.set_log_limits(limits);
+            let mut part = Partition::create(control, "log limits").expect("create");
+            for i in 0..5 {
+                let mut state = part.tip().expect("tip").clone_mut();
+                state.insert_new(format!("element {}", i)).expect("insert");
+                part.push_state(state).expect("push_state");
+            }
+            assert!(part.write_fast().expect("write_fast"));
+            assert_eq!(part.unsaved_len(), 0);
+            let tip = part.tip_key().expect("tip").clone();
+            let mut part = Partition::open(part.unwrap_control(), true).expect("open");
+            part.load_all().expect("load_all");
+            assert_eq!(part.tip_key().expect("tip"), &tip);
+            // The initial state and all five commits were written
+            assert_eq!(part.states_len(), 6);
+            part.unwrap_control().unwrap_io()
+        };
+        
+        let io = write(LogLimits::default());
+        assert_eq!(io.ss_cl_len(0), 1);
+        let io = write(LogLimits { max_commits: 2, max_bytes: u64::MAX });
+        assert_eq!(io.ss_cl_len(0), 3);
+        // Each log holds at least one commit
+        let io = write(LogLimits { max_commits: usize::MAX, max_bytes: 1 });
+        assert_eq!(io.ss_cl_len(0), 5);
+        let io = write(LogLimits { max_commits: 4, max_bytes: u64::MAX });
+        assert_eq!(io.ss_cl_len(0), 2);
+    }
+    
+    #[test]
+    fn flush_n() {
+        let mut part = Partition::create(WriteNotify::default(), "flush n").expect("create");
+        let mut keys = vec![];
+        for i in 0..5 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+        }
+        assert_eq!(part.unsaved_len(), 5);
+        assert_eq!(part.unsaved_iter().len(), 5);
+        let unsaved: Vec<_> = part.unsaved_iter().map(|c| c.statesum().clone()).collect();
+        assert_eq!(unsaved, keys);
+        
+        assert_eq!(part.flush_n(0).expect("flush_n"), 0);
+        assert_eq!(part.flush_n(2).expect("flush_n"), 2);
+        assert_eq!(part.unsaved_len(), 3);
+        assert_eq!(part.unsaved_iter().next().map(|c| c.statesum()), Some(&keys[2]));
+        assert_eq!(part.control.commits, &keys[0..2]);
+        assert_eq!(part.flush_n(10).expect("flush_n"), 3);
+        assert_eq!(part.unsaved_len(), 0);
+        assert_eq!(part.flush_n(1).expect("flush_n"), 0);
+        assert_eq!(part.control.commits, keys);
+        assert_eq!(part.control.writes, vec!["pre", "post", "pre", "post"]);
+    }
+    
+    #[test]
+    fn evict_history() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()), "evict").expect("create");
+        let initial = part.tip_key().expect("tip").clone();
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        let base = part.tip_key().expect("tip").clone();
+        for name in &["two", "three"] {
+            let mut state = part.state(&base).expect("base").clone_mut();
+            state.insert_new(name.to_string()).expect("insert");
+            part.push_state(state).expect("push_state");
+        }
+        assert_eq!(part.tips_len(), 2);
+        assert_eq!(part.states_len(), 4);
+        
+        // The initial state is in a snapshot; unsaved states are kept
+        assert_eq!(part.evict_history(KeepCriteria::Tips), 1);
+        assert!(part.state(&initial).is_none());
+        assert!(part.state(&base).is_some());
+        part.write_fast().expect("write_fast");
+        assert_eq!(part.evict_history(KeepCriteria::Depth(1)), 0);
+        assert_eq!(part.evict_history(KeepCriteria::Tips), 1);
+        assert_eq!(part.states_len(), 2);
+        assert_eq!(part.evicted_len(), 2);
+        assert!(part.is_loaded());
+        
+        // The common ancestor is found from parent links, then restored
+        assert_eq!(part.latest_common_ancestor(part.tips_iter().next().unwrap(),
+                part.tips_iter().nth(1).unwrap()), Ok(base.clone()));
+        part.merge(&AncestorSolver2W::new(), true).expect("merge");
+        assert!(part.is_ready());
+        assert_eq!(part.evicted_len(), 0);
+        assert!(part.state(&initial).is_some());
+        assert_eq!(part.tip().expect("tip").len(), 3);
+        
+        part.evict_history(KeepCriteria::Tips);
+        assert_eq!(part.restore_history().expect("restore_history"), 4);
+        assert_eq!(part.states_len(), 5);
+    }
+    
+    #[test]
+    fn drop_unreachable() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()), "gc").expect("create");
+        let initial = part.tip_key().expect("tip").clone();
+        let mut keys = vec![];
+        for name in &["one", "two"] {
+            let mut state = part.state(&initial).expect("initial").clone_mut();
+            state.insert_new(name.to_string()).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tips_iter().find(|k| !keys.contains(*k)).unwrap().clone());
+        }
+        part.merge(&AncestorSolver2W::new(), false).expect("merge");
+        part.write_fast().expect("write_fast");
+        let merged = part.tip_key().expect("tip").clone();
+        assert!(part.unreachable_states().is_empty());
+        
+        // A branch superseded by a commit which is not loaded
+        let mut state = part.state(&keys[0]).expect("one").clone_mut();
+        state.insert_new("three".to_string()).expect("insert");
+        part.push_state(state).expect("push_state");
+        part.write_fast().expect("write_fast");
+        let branch = part.tips_iter().find(|k| **k != merged).unwrap().clone();
+        part.tips.remove(&branch);
+        part.ancestors.insert(branch.clone());
+        assert_eq!(part.unreachable_states(), vec![branch.clone()]);
+        
+        let bytes = part.state(&branch).expect("branch").byte_len();
+        assert_eq!(part.drop_unreachable(), Reclaimed { states: 1, bytes: bytes });
+        assert!(part.state(&branch).is_none());
+        assert!(part.unreachable_states().is_empty());
+        assert_eq!(part.tips_len(), 1);
+        assert_eq!(part.states_len(), 4);
+    }
+    
+    #[test]
+    fn load_meta() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()), "meta").expect("create");
+        let initial = part.tip_key().expect("tip").clone();
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            state.insert_new(format!("element {}b", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            part.write_fast().expect("write_fast");
+            if i == 1 {
+                part.write_snapshot().expect("write_snapshot");
+            }
+        }
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let part = Partition::open(part.unwrap_control(), false).expect("open");
+        let history = part.load_meta().expect("load_meta");
+        assert!(!part.is_loaded());
+        assert_eq!(history.snapshots.iter().map(|&(ss, ref info)| (ss, info.num_elts))
+                .collect::<Vec<_>>(), vec![(0, 0), (1, 4)]);
+        assert_eq!(history.commits.len(), 3);
+        assert!(history.commits.iter().all(|info| info.num_changes == 2));
+        assert_eq!(history.commits[2].statesum, tip);
+        assert_eq!(history.snapshots[1].1.statesum, history.commits[1].statesum);
+        assert_eq!(history.dag.len(), 4);
+        assert_eq!(history.dag.parents(&history.commits[0].statesum),
+                Some(&[initial.clone()][..]));
+        assert_eq!(history.dag.tips(), vec![tip.clone()].into_iter().collect());
+        assert_eq!(history.dag.ancestors(&tip).len(), 3);
+        assert!(history.dag.ancestors(&tip).contains(&initial));
+    }
+    
+    // Tags commits with the device making them (see `commit::provenance`)
+    #[derive(Default)]
+    struct DeviceControl {
+        io: MemRepoIO,
+        ss_policy: DefaultSnapshot,
+        device: Option<&'static str>,
+    }
+    impl MakeCommitMeta for DeviceControl {
+        fn make_commit_extra(&self, _: u32, _: Vec<(&Sum, &CommitMeta)>) -> UserMeta {
+            self.device.map_or(UserMeta::None,
+                    |device| UserMeta::Text(format!("device {}", device)))
+        }
+        fn commit_source(&self, meta: &CommitMeta) -> Option<String> {
+            match *meta.extra() {
+                UserMeta::Text(ref text) if text.starts_with("device ") =>
+                    text.lines().next().map(|line| line[7..].to_string()),
+                _ => None,
+            }
+        }
+    }
+    impl Control for DeviceControl {
+        type Element = String;
+        fn io(&self) -> &RepoIO { &self.io }
+        fn io_mut(&mut self) -> &mut RepoIO { &mut self.io }
+        fn snapshot_policy(&mut self) -> &mut SnapshotPolicy { &mut self.ss_policy }
+        fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
+        fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
+    }
+    
+    #[test]
+    fn find_commits() {
+        let mut part = Partition::create(DeviceControl::default(), "find").expect("create");
+        let mut keys = vec![];
+        for i in 0..4 {
+            part.control.device = if i % 2 == 0 { Some("bulk-import") } else { None };
+            let mut state = part.tip().expect("tip").clone_mut();
+            for j in 0..(i + 1) {
+                state.insert_new(format!("element {}-{}", i, j)).expect("insert");
+            }
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            if i < 3 {
+                part.write_fast().expect("write_fast");
+            }
+        }
+        
+        // The last commit is unsaved
+        assert_eq!(part.unsaved_len(), 1);
+        assert_eq!(part.find_commits(&CommitFilter::default()).expect("find"), keys);
+        let filter = CommitFilter {
+            extra_contains: Some("bulk-import".to_string()),
+            .. CommitFilter::default()
+        };
+        assert_eq!(part.find_commits(&filter).expect("find"), vec![keys[0].clone(),
+                keys[2].clone()]);
+        let filter = CommitFilter {
+            number: Some(2..4),
+            num_changes: Some(3..10),
+            .. CommitFilter::default()
+        };
+        assert_eq!(part.find_commits(&filter).expect("find"), vec![keys[2].clone()]);
+        let ts = part.tip().expect("tip").meta().timestamp();
+        let filter = CommitFilter { timestamp: Some(ts + 1..ts + 2), .. CommitFilter::default() };
+        assert!(part.find_commits(&filter).expect("find").is_empty());
+    }
+    
+    #[test]
+    fn write_snapshot_of() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()), "historic").expect("create");
+        let mut keys = vec![part.tip_key().expect("tip").clone()];
+        for i in 0..3 {
+            if i == 2 {
+                part.write_snapshot_of(&keys[1]).expect("write_snapshot_of");
+                assert!(part.write_snapshot_of(&Sum::load(&[7; 32])).is_err());
+            }
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            part.write_fast().expect("write_fast");
+        }
+        {
+            let io = part.control.io();
+            assert!(read_ss_head(io, 1).expect("read").expect("snapshot 1").historical);
+            assert_eq!(io.ss_cl_len(1), 1);
+        }
+        
+        // Loading the latest state starts from snapshot 0, not the historical one
+        let mut part = Partition::open(part.unwrap_control(), true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &keys[3]);
+        assert_eq!(part.states_len(), 4);
+        part.load_latest().expect("load_latest");
+        assert!(part.is_ready());
+        let mut part = Partition::open(part.unwrap_control(), false).expect("open");
+        part.load_latest().expect("load_latest");
+        assert_eq!(part.tip_key().expect("tip"), &keys[3]);
+    }
+    
+    #[test]
+    fn merge_provenance() {
+        let control = DeviceControl { device: Some("laptop"), .. DeviceControl::default() };
+        let mut part = Partition::create(control, "provenance").expect("create");
+        let base = part.tip_key().expect("tip").clone();
+        let mut tips = vec![];
+        for &(device, name) in &[("laptop", "one"), ("phone", "two")] {
+            part.control.device = Some(device);
+            let mut state = part.state(&base).expect("base").clone_mut();
+            state.insert_new(name.to_string()).expect("insert");
+            part.push_state(state).expect("push_state");
+            tips.push(part.tips_iter().find(|&sum| !tips.contains(sum)).unwrap().clone());
+        }
+        
+        part.merge(&AncestorSolver2W::new(), false).expect("merge");
+        let meta = part.tip().expect("tip").meta().clone();
+        // Parent order depends on the sums; the merge's own extra data comes first
+        let line = format!("merge-parent {} laptop", tips[0].as_string(false));
+        match *meta.extra() {
+            UserMeta::Text(ref text) => {
+                assert!(text.starts_with("device phone\nmerge-parent "));
+                assert!(text.lines().any(|l| l == line));
+            },
+            UserMeta::None => panic!("no extra metadata"),
+        }
+        let mut found = provenance(&meta);
+        found.sort();
+        let mut expected = vec![(tips[0].clone(), "laptop".to_string()),
+                (tips[1].clone(), "phone".to_string())];
+        expected.sort();
+        assert_eq!(found, expected);
+        
+        // Without known sources nothing is recorded
+        let parent = CommitMeta::new_explicit(1, 0, MetaFlags::zero(), vec![], UserMeta::None)
+                .expect("meta");
+        let meta = CommitMeta::new_parents(vec![(&tips[0], &parent), (&tips[1], &parent)],
+                &ProvenanceMeta::new(&MCM));
+        assert!(provenance(&meta).is_empty());
+        assert_eq!(meta.extra(), &UserMeta::None);
+    }
+    
+    #[test]
+    fn fetch_state() {
+        let mut part = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()), "fetch").expect("create");
+        let mut keys = vec![part.tip_key().expect("tip").clone()];
+        for i in 0..4 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            if i == 1 {
+                part.write_fast().expect("write_fast");
+                part.write_snapshot().expect("write_snapshot");
+            }
+        }
+        part.write_fast().expect("write_fast");
+        
+        // Open with only the latest snapshot; evict all but the tip
+        let control = part.unwrap_control();
+        let mut part = Partition::open(control, true).expect("open");
+        assert_eq!(part.oldest_ss_loaded(), 1);
+        assert_eq!(part.evict_history(KeepCriteria::Tips), 2);
+        for key in &keys[0..4] {
+            assert!(part.state(key).is_none());
+        }
+        
+        // Older and evicted states are read from disk; only the last is kept
+        assert_eq!(part.fetch_state(&keys[1]).expect("fetch").map(|s| s.len()), Some(1));
+        assert_eq!(part.fetch_state(&keys[2]).expect("fetch").map(|s| s.len()), Some(2));
+        assert!(part.state(&keys[1]).is_none());
+        assert!(part.state(&keys[2]).is_some());
+        assert_eq!(part.fetch_state(&keys[3]).expect("fetch").map(|s| s.len()), Some(3));
+        assert_eq!(part.fetch_state(&keys[4]).expect("fetch").map(|s| s.len()), Some(4));
+        assert!(part.fetch_state(&Sum::zero()).expect("fetch").is_none());
+        assert_eq!(part.states_len(), 1);
+        
+        // With a budget, several are kept, least recently used dropped first
+        part.control.set_state_cache_bytes(5 * "element 0".len());
+        part.fetch_state(&keys[1]).expect("fetch");
+        part.fetch_state(&keys[0]).expect("fetch");
+        assert!(part.state(&keys[1]).is_some() && part.state(&keys[3]).is_some());
+        part.fetch_state(&keys[3]).expect("fetch");
+        part.fetch_state(&keys[2]).expect("fetch");
+        assert!(part.state(&keys[1]).is_none() && part.state(&keys[3]).is_some());
+    }
+    
+    // Wraps MemRepoIO, failing writes to commit logs after `budget` bytes
+    #[derive(Debug)]
+    struct FailingIO {
+        io: MemRepoIO,
+        budget: usize,
+    }
+    struct FailingWriter<'a> {
+        inner: Box<Write + 'a>,
+        budget: &'a mut usize,
+    }
+    impl<'a> Write for FailingWriter<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = min(buf.len(), *self.budget);
+            let len = self.inner.write(&buf[..len])?;
+            *self.budget -= len;
+            Ok(len)     // zero when out of budget: write_all fails
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+    impl FailingIO {
+        fn wrap<'a>(w: Option<Box<Write + 'a>>, budget: &'a mut usize) ->
+                Option<Box<Write + 'a>>
+        {
+            w.map(move |inner| Box::new(FailingWriter { inner: inner, budget: budget }) as Box<Write>)
+        }
+    }
+    impl RepoIO for FailingIO {
+        fn ss_len(&self) -> usize { self.io.ss_len() }
+        fn ss_cl_len(&self, ss: usize) -> usize { self.io.ss_cl_len(ss) }
+        fn has_ss(&self, ss: usize) -> bool { self.io.has_ss(ss) }
+        fn read_ss<'a>(&'a self, ss: usize) -> Result<Option<Box<Read+'a>>> {
+            self.io.read_ss(ss)
+        }
+        fn read_ss_cl<'a>(&'a self, ss: usize, cl: usize) -> Result<Option<Box<Read+'a>>> {
+            self.io.read_ss_cl(ss, cl)
+        }
+        fn new_ss<'a>(&'a mut self, ss: usize) -> Result<Option<Box<Write+'a>>> {
+            self.io.new_ss(ss)
+        }
+        fn append_ss_cl<'a>(&'a mut self, ss: usize, cl: usize) -> Result<Option<Box<Write+'a>>> {
+            Ok(FailingIO::wrap(self.io.append_ss_cl(ss, cl)?, &mut self.budget))
+        }
+        fn new_ss_cl<'a>(&'a mut self, ss: usize, cl: usize) -> Result<Option<Box<Write+'a>>> {
+            Ok(FailingIO::wrap(self.io.new_ss_cl(ss, cl)?, &mut self.budget))
+        }
+    }
+    
+    #[test]
+    fn resume_write() {
+        let io = FailingIO { io: MemRepoIO::new(), budget: usize::MAX };
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io), "resume")
+                .expect("create");
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+        }
+        let tip = part.tip_key().expect("tip").clone();
+        
+        // Allow the header, the first commit and part of the second
+        let header = part.make_header(FileType::commit_log()).expect("make_header");
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).expect("write_head");
+        start_log(&mut buf).expect("start_log");
+        let codec = file_codec(&part.control, &header).expect("codec");
+        write_commit_with(part.unsaved_iter().next().unwrap(), &mut buf, header.ftype.ver(),
+                &*codec, header.align()).expect("write_commit");
+        part.control.io_mut().budget = buf.len() + 20;
+        
+        assert!(part.write_fast().is_err());
+        assert_eq!(part.unsaved_len(), 2);
+        part.control.io_mut().budget = usize::MAX;
+        assert!(part.write_fast().expect("write_fast"));
+        assert_eq!(part.unsaved_len(), 0);
+        
+        // All commits are found on reload, once each
+        let io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.ss_cl_len(0), 2);
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        assert_eq!(part.states_len(), 4);
+        assert_eq!(part.load_report().warnings, vec![LoadWarning::TruncatedLog(0, 0)]);
+        assert!(part.verify_files().is_err());
+    }
+    
+    #[test]
+    fn transaction() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "transaction")
+                .expect("partition creation");
+        let (one, two) = (EltId::from(1), EltId::from(2));
+        {
+            let mut t = part.transaction().expect("transaction");
+            t.insert(one, "one".to_string()).insert(two, "two".to_string());
+            t.replace(two, "deux".to_string());
+            assert_eq!(t.commit(), Ok(true));
+        }
+        assert_eq!(part.unsaved_len(), 1);
+        
+        let key = part.tip_key().expect("tip_key").clone();
+        {
+            let mut t = part.transaction().expect("transaction");
+            t.remove(one).insert(two, "zwei".to_string()).remove(EltId::from(3));
+            assert_eq!(t.conflicts().len(), 2);
+            assert_eq!(t.commit(), Err(TransactionError::Conflicts(vec![
+                    (two, ElementOp::IdClash), (EltId::from(3), ElementOp::EltNotFound)])));
+        }
+        assert_eq!(part.tip_key(), Ok(&key));
+        assert_eq!(part.tip().expect("tip").get(two), Ok(&"deux".to_string()));
+        
+        assert_eq!(part.transaction().expect("transaction").commit(), Ok(false));
+    }
+    
+    #[test]
+    fn push_state_if_tip() {
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "push_if_tip")
+                .expect("partition creation");
+        let mut state1 = part.tip().expect("getting tip").clone_mut();
+        let mut state2 = part.tip().expect("getting tip").clone_mut();
+        state1.insert_new("one".to_string()).expect("inserting elt");
+        state2.insert_new("two".to_string()).expect("inserting elt");
+        assert_eq!(part.push_state_if_tip(state1), Ok(true));
+        assert_eq!(part.push_state_if_tip(state2), Err(PatchOp::TipChanged));
+        assert_eq!(part.tips_len(), 1);
+    }
+    
+    #[test]
+    fn load_auto_merge() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "auto_merge")
+                .expect("partition creation");
+        // Two concurrent changes on the initial state:
+        for i in 0..2 {
+            let mut state = part.state_by_cnum(0).expect("initial").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("inserting elt");
+            part.push_state(state).expect("committing");
+        }
+        assert_eq!(part.tips_len(), 2);
+        part.write_fast().expect("write_fast");
+        
+        let control = part.unwrap_control();
+        let mut part = Partition::open(control, false).expect("open");
+        let solver = AncestorSolver2W::new();
+        let options = LoadOptions { auto_merge: Some(&solver), ..Default::default() };
+        part.load_with(0, usize::MAX, &options).expect("load_with");
+        assert!(part.is_ready());
+        assert_eq!(part.tip().expect("tip").num_avail(), 2);
+    }
+    
+    #[test]
+    fn sparse_snapshots() {
+        use std::io::{Read, Write};
+        
+        // Snapshot 1 has been removed; numbers not listed cannot be read
+        #[derive(Debug)]
+        struct SparseRepoIO(MemRepoIO);
+        impl RepoIO for SparseRepoIO {
+            fn ss_len(&self) -> usize { self.0.ss_len() }
+            fn ss_cl_len(&self, ss_num: usize) -> usize { self.0.ss_cl_len(ss_num) }
+            fn has_ss(&self, ss_num: usize) -> bool { self.0.has_ss(ss_num) }
+            fn list_ss(&self) -> Vec<usize> {
+                (0..self.0.ss_len()).filter(|ss| self.0.has_ss(*ss)).collect()
+            }
+            fn list_ss_cl(&self, ss_num: usize) -> Vec<usize> {
+                (0..self.0.ss_cl_len(ss_num))
+                        .filter(|cl| self.0.ss_cl_data(ss_num, *cl).is_some()).collect()
+            }
+            fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+                if !self.list_ss().contains(&ss_num) && ss_num < self.0.ss_len() {
+                    return OtherError::err("not listed");
+                }
+                self.0.read_ss(ss_num)
+            }
+            fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Read+'a>>>
+            {
+                if !self.list_ss_cl(ss_num).contains(&cl_num) {
+                    return OtherError::err("not listed");
+                }
+                self.0.read_ss_cl(ss_num, cl_num)
+            }
+            fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+                self.0.new_ss(ss_num)
+            }
+            fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Write+'a>>>
+            {
+                self.0.append_ss_cl(ss_num, cl_num)
+            }
+            fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+                    Result<Option<Box<Write+'a>>>
+            {
+                self.0.new_ss_cl(ss_num, cl_num)
+            }
+        }
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "sparse").expect("create");
+        for n in 0..2 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("elt {}", n)).expect("insert");
+            part.push_state(state).expect("push_state");
+            part.write_snapshot().expect("write_snapshot");
+        }
+        let tip = part.tip_key().expect("tip").clone();
+        let mut io = part.unwrap_control().unwrap_io();
+        assert_eq!(io.ss_len(), 3);
+        io.remove_ss(1);
+        
+        let io = SparseRepoIO(io);
+        assert_eq!(io.list_ss(), vec![0, 2]);
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(part.tip_key().expect("tip"), &tip);
+        part.verify_files().expect("verify_files");
+        let history = part.load_meta().expect("load_meta");
+        assert_eq!(history.snapshots.iter().map(|s| s.0).collect::<Vec<_>>(), vec![0, 2]);
+    }
+}
@@ -0,0 +1,152 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: where loaded states were read from (see `Provenance`), and the
+//! ancestry of all states seen (see `Partition::dag`).
+
+use control::Control;
+use dag::StateDag;
+use error::Result;
+use io::backup::FileId;
+use sum::Sum;
+use super::Partition;
+
+/// The file a state or commit was read from (see `Partition::provenance`),
+/// e.g. to point verification reports or support tools at concrete files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Provenance {
+    /// The snapshot or commit log
+    pub file: FileId,
+    /// Position in the file, in bytes: zero for a snapshot, or the start of
+    /// the commit in a log
+    pub offset: u64,
+}
+
+// Methods tracking provenance and ancestry
+impl<C: Control> Partition<C> {
+    /// Get the file a state was loaded from: the snapshot, or the commit log
+    /// and position of the commit reaching it (see `Provenance`). If found in
+    /// more than one file, that read first is given.
+    /// 
+    /// Returns None for states not loaded from a file (e.g. those of new
+    /// commits, even once written).
+    pub fn provenance(&self, key: &Sum) -> Option<&Provenance> {
+        self.provenance.get(key)
+    }
+    
+    /// Get the ancestry of all states seen: those loaded, evicted (see
+    /// `evict_history`) or since unloaded, and any added by `load_ancestry`.
+    /// This allows ancestry queries without element data.
+    pub fn dag(&self) -> &StateDag {
+        &self.dag
+    }
+    
+    /// Read metadata of all history (see `load_meta`) and add it to the
+    /// ancestry known (see `dag`), without loading states. Returns the number
+    /// of states added.
+    /// 
+    /// This allows `merge()` to find a common ancestor beyond loaded history;
+    /// with `auto_load` the history needed is then loaded.
+    pub fn load_ancestry(&mut self) -> Result<usize> {
+        let history = self.load_meta()?;
+        let len = self.dag.len();
+        for info in &history.snapshots {
+            let info = &info.1;
+            self.dag.insert(info.statesum.clone(), info.parents.clone(), &info.meta);
+        }
+        for info in &history.commits {
+            self.dag.insert(info.statesum.clone(), info.parents.clone(), &info.meta);
+        }
+        Ok(self.dag.len() - len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use control::DefaultControl;
+    use io::backup::FileId;
+    use io::mem::MemRepoIO;
+    use state::StateWrite;
+    use super::*;
+    
+    #[test]
+    fn file_provenance() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "provenance").expect("create");
+        let initial = part.tip_key().expect("tip").clone();
+        let mut keys = vec![];
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            if i == 1 {
+                part.write_fast().expect("write_fast");
+                part.write_snapshot().expect("write_snapshot");
+            }
+        }
+        part.write_fast().expect("write_fast");
+        assert_eq!(part.provenance(&keys[2]), None);
+        
+        let mut part = Partition::open(part.unwrap_control(), false).expect("open");
+        part.load_all().expect("load_all");
+        assert_eq!(part.provenance(&initial),
+                Some(&Provenance { file: FileId::Snapshot(0), offset: 0 }));
+        let p0 = *part.provenance(&keys[0]).expect("provenance");
+        let p1 = *part.provenance(&keys[1]).expect("provenance");
+        let p2 = *part.provenance(&keys[2]).expect("provenance");
+        assert_eq!((p0.file, p1.file, p2.file), (FileId::CommitLog(0, 0),
+                FileId::CommitLog(0, 0), FileId::CommitLog(1, 0)));
+        assert!(p0.offset < p1.offset);
+        for p in vec![p0, p1, p2] {
+            let (ss, cl) = match p.file { FileId::CommitLog(ss, cl) => (ss, cl), _ => panic!() };
+            let data = part.control().io().ss_cl_data(ss, cl).expect("log");
+            assert_eq!(&data[p.offset as usize..p.offset as usize + 6], b"COMMIT");
+        }
+        assert!(part.states_iter().all(|state| state.provenance().is_some()));
+        
+        let history = part.load_meta().expect("load_meta");
+        assert_eq!(history.commit_sources, vec![p0, p1, p2]);
+        
+        // Unloading drops provenance, but not ancestry
+        assert!(part.unload(false));
+        assert_eq!(part.provenance(&keys[0]), None);
+        assert!(part.dag().is_ancestor(&initial, &keys[2]));
+        part.load_all().expect("load_all");
+        assert_eq!(part.provenance(&keys[0]), Some(&p0));
+    }
+    
+    #[test]
+    fn ancestry() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "ancestry").expect("create");
+        let mut keys = vec![part.tip_key().expect("tip").clone()];
+        for i in 0..2 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert_new(format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push_state");
+            keys.push(part.tip_key().expect("tip").clone());
+            part.write_fast().expect("write_fast");
+            if i == 0 {
+                part.write_snapshot().expect("write_snapshot");
+            }
+        }
+        
+        // Ancestry is kept when unloaded
+        assert!(part.unload(false));
+        assert_eq!(part.dag().len(), 3);
+        assert!(part.dag().is_ancestor(&keys[0], &keys[2]));
+        assert_eq!(part.dag().distance(&keys[0], &keys[2]), Some(2));
+        assert_eq!(part.dag().latest_common_ancestor(&keys[1], &keys[2]), Some(keys[1].clone()));
+        
+        // Reopening loads from the latest snapshot; metadata supplies the rest
+        let mut part = Partition::open(part.unwrap_control(), true).expect("open");
+        assert_eq!(part.dag().len(), 2);
+        assert!(!part.dag().contains(&keys[0]));
+        assert_eq!(part.load_ancestry().expect("load_ancestry"), 1);
+        assert_eq!(part.dag().distance(&keys[0], &keys[2]), Some(2));
+        assert_eq!(part.states_len(), 2);
+    }
+}
@@ -8,19 +8,55 @@
 
 pub use ::LIB_VERSION;
 
-pub use commit::{UserMeta, CommitMeta, CommitMetaPartial, Commit, MakeCommitMeta, EltChange};
-pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot};
-pub use elt::{EltId, Element};
-pub use error::{Result, Error, ReadError, ReadErrorFormatter, ArgError, ElementOp, PatchOp,
-        PathError, MatchError, TipError, MergeError, ReadOnly, UserError,
-        OtherError, make_io_err};
+pub use commit::{UserMeta, CommitMeta, CommitMetaPartial, Commit, MakeCommitMeta, EltChange, Delta,
+        ProvenanceMeta, VectorClock, SumOnly, provenance};
+pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot, SizeSnapshot,
+        PartConfig, SnapshotConfig, LogLimits};
+#[cfg(feature = "clock")]
+pub use control::TimedSnapshot;
+pub use dag::{StateDag, DagNode};
+pub use dynelt::{DynElement, DynRegistry, DynValue};
+pub use elt::{EltId, Element, Blob, BlobLimit};
+pub use error::{Result, Error, ErrorCode, ContextError, ReadError, ReadErrorFormatter, ArgError,
+        ElementOp, PatchOp, TransactionError, PathError, MatchError, TipError, MergeError,
+        ReadOnly, UserError, VersionError, OtherError, Locked, make_io_err};
 pub use io::{DummyRepoIO, RepoIO};
+pub use io::cache::CachedRepoIO;
+#[cfg(feature = "fs")]
 pub use io::discover::{part_from_path, discover_basename};
+pub use io::faulty::{Fault, FaultyRepoIO};
+#[cfg(feature = "fs")]
 pub use io::file::{PartPaths, RepoFileIO};
-pub use merge::{TwoWayMerge, EltMerge, TwoWaySolver, TwoWaySolveUseA, TwoWaySolveUseB,
-        TwoWaySolveUseC, TwoWaySolveFail, TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W};
-pub use part::{Partition, TipIter, StateItem, StateIter};
+#[cfg(feature = "kv")]
+pub use io::kv::KvRepoIO;
+pub use io::mem::MemRepoIO;
+pub use io::mirror::{MirrorMode, MirroredRepoIO};
+pub use keys::{KeyIndex, Remap};
+pub use kvstore::KvStore;
+pub use lock::{LockRegistry, Lease};
+pub use io::pack::{Pack, PackedRepoIO};
+#[cfg(feature = "clock")]
+pub use io::throttle::{Throttle, ThrottledRepoIO};
+pub use merge::{TwoWayMerge, EltMerge, TwoWaySolver, SolveContext, TwoWaySolveUseA,
+        TwoWaySolveUseB, TwoWaySolveUseC, TwoWaySolveFail, TwoWaySolverChain,
+        TwoWaySolverDispatch, AncestorSolver2W, NewestSolver2W, RenamingSolver2W};
+#[cfg(feature = "text-merge")]
+pub use textmerge::TextSolver2W;
+#[cfg(feature = "crdt")]
+pub use crdt::{Crdt, GSet, LwwRegister, OrSet, CrdtSolver2W};
+pub use part::{Partition, LoadOptions, LoadReport, LoadWarning, CloneOptions, ChangeCursor,
+        Transaction, TipIter, UnsavedIter, StateItem, StateIter, KeepCriteria, Reclaimed,
+        HistoryMeta, CommitFilter, Provenance};
+pub use rw::{ReadLimits, LATEST_VERSION, CRC_VERSION, OLDEST_VERSION, is_supported_version,
+        is_legacy_version};
 pub use rw::header::{FileType, UserData, FileHeader, validate_repo_name};
-pub use state::{PartState, MutPartState, StateRead, StateWrite, EltIter};
+pub use rw::codec::{Codec, RawCodec};
+pub use rw::sum::{HashReader, HashWriter};
+pub use rw::admin::{AdminOp, AdminRecord};
+pub use rw::logindex::LogIndexEntry;
+pub use rw::snapshot::{EltVisitor, visit_snapshot};
+pub use rw::commitlog::{CommitVisitor, Visit, visit_log};
+pub use state::{PartState, MutPartState, StateRead, StateWrite, EltIter, Matcher, Quota,
+        StateHandle};
 pub use sum::{Sum, SUM_BYTES};
 pub use util::{rtrim, ByteFormatter, HexFormatter};
@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for reading and writing the admin log: an append-only record of
+//! administrative operations which alter a partition's files, such as pruning
+//! (see `Partition::record_admin` and `Partition::admin_history`).
+
+use std::io::{Read, Write};
+use std::fmt;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use rw::sum;
+use sum::SUM_BYTES;
+use error::{Result, ArgError, ReadError};
+
+// Maximum length of an operation name
+const MAX_OP_LEN: usize = 8;
+// Maximum length of a record's detail text
+const MAX_DETAIL_LEN: u64 = 1 << 20;
+
+/// An administrative operation
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AdminOp {
+    /// Snapshots and their commit logs were removed (see
+    /// `Partition::record_pruned`)
+    Prune,
+    /// Files were copied into a pack, and possibly removed (see `io::pack`)
+    Pack,
+    /// Commits were combined, discarding intermediate states
+    Squash,
+    /// Files were rewritten, e.g. with another codec
+    Rewrite,
+    /// Keys used to encode files were replaced
+    RotateKeys,
+    /// Another operation, named by 1-8 ASCII letters, digits or `-`
+    Other(String),
+}
+impl AdminOp {
+    /// The name written to the log
+    pub fn name(&self) -> &str {
+        match *self {
+            AdminOp::Prune => "prune",
+            AdminOp::Pack => "pack",
+            AdminOp::Squash => "squash",
+            AdminOp::Rewrite => "rewrite",
+            AdminOp::RotateKeys => "rekey",
+            AdminOp::Other(ref name) => name,
+        }
+    }
+    
+    /// Get the operation with the given name (`Other` if not known)
+    pub fn from_name(name: &str) -> AdminOp {
+        match name {
+            "prune" => AdminOp::Prune,
+            "pack" => AdminOp::Pack,
+            "squash" => AdminOp::Squash,
+            "rewrite" => AdminOp::Rewrite,
+            "rekey" => AdminOp::RotateKeys,
+            _ => AdminOp::Other(name.to_string()),
+        }
+    }
+}
+impl fmt::Display for AdminOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// One entry of the admin log
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AdminRecord {
+    /// Time of the operation (as for commit timestamps, see
+    /// `MakeCommitMeta::make_commit_timestamp`)
+    pub timestamp: i64,
+    /// The operation performed
+    pub op: AdminOp,
+    /// Free-form description, e.g. which files were affected and why
+    pub detail: String,
+}
+
+/// Write the section identifier at the start of an admin log (after the
+/// header)
+pub fn start_admin_log(writer: &mut Write) -> Result<()> {
+    writer.write_all(b"ADMIN LOG\x00\x00\x00\x00\x00\x00\x00")?;
+    Ok(())
+}
+
+/// Write a single record to a stream
+pub fn write_record(record: &AdminRecord, writer: &mut Write) -> Result<()> {
+    let name = record.op.name().as_bytes();
+    if name.is_empty() || name.len() > MAX_OP_LEN ||
+        !name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'-')
+    {
+        return ArgError::err("admin operation name must be 1-8 ASCII letters, digits or '-'");
+    }
+    let detail = record.detail.as_bytes();
+    if detail.len() as u64 > MAX_DETAIL_LEN {
+        return ArgError::err("admin record detail too long");
+    }
+    trace!("Writing admin record: {} {}", record.op, record.detail);
+    
+    // Records are written via a single write operation (see `RepoIO`):
+    let mut buf = Vec::with_capacity(32 + detail.len() + 15 + SUM_BYTES);
+    {
+        let mut w = sum::HashWriter::new(&mut buf);
+        w.write_all(b"ADMIN\x00\x00\x00")?;
+        w.write_i64::<BigEndian>(record.timestamp)?;
+        w.write_all(name)?;
+        w.write_all(&[0u8; MAX_OP_LEN][name.len()..])?;
+        w.write_u64::<BigEndian>(detail.len() as u64)?;
+        w.write_all(detail)?;
+        let pad_len = (16 - detail.len() % 16) % 16;
+        w.write_all(&[0u8; 15][0..pad_len])?;
+        let sum = w.sum();
+        sum.write_to(&mut w.into_inner())?;
+    }
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read all records from an admin log (after the header)
+pub fn read_admin_log(mut reader: &mut Read) -> Result<Vec<AdminRecord>> {
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    
+    reader.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != *b"ADMIN LOG\x00\x00\x00\x00\x00\x00\x00" {
+        return ReadError::err_data("unexpected contents (expected \
+            ADMIN LOG\\x00\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16), &buf);
+    }
+    pos += 16;
+    
+    let mut records = Vec::new();
+    // As with commit logs, records are appended, thus EOF marks the end.
+    loop {
+        let mut r = sum::HashReader::new(reader);
+        
+        let l = r.read(&mut buf[0..32])?;
+        if l == 0 { break; /*end of file (EOF)*/ }
+        if l < 32 { r.read_exact(&mut buf[l..32])?; }
+        
+        if buf[0..8] != *b"ADMIN\x00\x00\x00" {
+            return ReadError::err_data("unexpected contents (expected ADMIN)",
+                    pos, (0, 8), &buf);
+        }
+        let timestamp = BigEndian::read_i64(&buf[8..16]);
+        let name_len = buf[16..24].iter().position(|c| *c == 0).unwrap_or(MAX_OP_LEN);
+        let op = match String::from_utf8(buf[16..16 + name_len].to_vec()) {
+            Ok(name) => AdminOp::from_name(&name),
+            Err(_) => return ReadError::err_data("admin operation name not valid UTF-8",
+                    pos, (16, 24), &buf),
+        };
+        let len = BigEndian::read_u64(&buf[24..32]);
+        if len > MAX_DETAIL_LEN {
+            return ReadError::err_data("admin record detail too long", pos, (24, 32), &buf);
+        }
+        pos += 32;
+        
+        let len = len as usize;
+        let padded_len = len + (16 - len % 16) % 16;
+        let mut data = vec![0; padded_len];
+        r.read_exact(&mut data)?;
+        data.truncate(len);
+        let detail = match String::from_utf8(data) {
+            Ok(detail) => detail,
+            Err(_) => return ReadError::err("admin record detail not valid UTF-8",
+                    pos, (0, len)),
+        };
+        pos += padded_len;
+        
+        let sum = r.sum();
+        reader = r.into_inner();
+        reader.read_exact(&mut buf[0..SUM_BYTES])?;
+        if sum != buf[0..SUM_BYTES] {
+            return ReadError::err_data("admin record checksum invalid", pos, (0, SUM_BYTES),
+                    &buf);
+        }
+        pos += SUM_BYTES;
+        
+        records.push(AdminRecord { timestamp: timestamp, op: op, detail: detail });
+    }
+    Ok(records)
+}
+
+#[test]
+fn admin_write_read() {
+    let records = vec![
+        AdminRecord { timestamp: 1476000000, op: AdminOp::Prune,
+                detail: "snapshots 0, 1 by retention policy".to_string() },
+        AdminRecord { timestamp: -5, op: AdminOp::Other("archive".to_string()),
+                detail: String::new() },
+        AdminRecord { timestamp: 0, op: AdminOp::RotateKeys,
+                detail: "sixteen bytes!!!".to_string() },
+    ];
+    let mut obj = Vec::new();
+    start_admin_log(&mut obj).unwrap();
+    for record in &records {
+        write_record(record, &mut obj).unwrap();
+    }
+    assert_eq!(read_admin_log(&mut &obj[..]).unwrap(), records);
+    
+    let bad = AdminRecord { timestamp: 0, op: AdminOp::Other("bad name".to_string()),
+            detail: String::new() };
+    assert!(write_record(&bad, &mut obj).is_err());
+    
+    // Corrupt detail of the first record
+    obj[16 + 32] ^= 1;
+    assert!(read_admin_log(&mut &obj[..]).is_err());
+}
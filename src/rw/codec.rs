@@ -0,0 +1,94 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pluggable encodings of element data within files.
+//! 
+//! The readers and writers in `rw` handle framing of snapshots, commit logs
+//! and deltas (section markers, lengths, padding and checksums); a `Codec`
+//! only transforms the serialised data of each element as stored, for
+//! example to compress or encrypt it. Element and state sums are calculated
+//! on decoded data, thus do not depend on the codec used.
+//! 
+//! A file written with a codec other than `RawCodec` names the codec in its
+//! header (see `FileHeader::codec`); this is an essential header block, thus
+//! readers not supporting codecs reject such files.
+
+use std::rc::Rc;
+
+use error::{Result, ArgError, OtherError};
+use rw::header::FileHeader;
+
+/// Maximum length of a codec name (in bytes), such that the name fits in a
+/// single header block.
+pub const MAX_NAME_LEN: usize = 9;
+
+/// An encoding of element data.
+/// 
+/// Implementations must be deterministic in the sense that `decode` always
+/// reproduces the data passed to `encode`.
+pub trait Codec {
+    /// Name identifying the codec in file headers. This must be between 1
+    /// and `MAX_NAME_LEN` bytes of printable ASCII.
+    fn name(&self) -> &str;
+    
+    /// Encode `data`, appending the result to `out`.
+    fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()>;
+    
+    /// Decode `data` (as produced by `encode`), appending the result to
+    /// `out`.
+    fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()>;
+    
+    /// True if `encode` and `decode` leave data unchanged, in which case
+    /// readers and writers skip them. The default implementation returns
+    /// false.
+    fn is_identity(&self) -> bool {
+        false
+    }
+}
+
+/// The identity codec: element data is stored as serialised. This is used
+/// for files whose header does not name a codec.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RawCodec;
+impl Codec for RawCodec {
+    fn name(&self) -> &str {
+        "raw"
+    }
+    fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(data);
+        Ok(())
+    }
+    fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(data);
+        Ok(())
+    }
+    fn is_identity(&self) -> bool {
+        true
+    }
+}
+
+/// Check that a codec name may be written to a header.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return ArgError::err(format!("codec name must have length 1-{}", MAX_NAME_LEN));
+    }
+    if !name.bytes().all(|c| c > b' ' && c <= b'~') {
+        return ArgError::err("codec name must be printable ASCII");
+    }
+    Ok(())
+}
+
+/// Select the codec for reading or writing a file with the given header.
+/// 
+/// If the header names no codec, this is `RawCodec`. Otherwise `codec` must
+/// be given and have the name stored in the header, else this fails (the
+/// file cannot be decoded).
+pub fn select(header: &FileHeader, codec: Option<Rc<Codec>>) -> Result<Rc<Codec>> {
+    match (header.codec.as_ref(), codec) {
+        (None, _) => Ok(Rc::new(RawCodec)),
+        (Some(name), Some(codec)) if codec.name() == name => Ok(codec),
+        (Some(name), _) => OtherError::err(format!("file uses codec '{}'; \
+                program not configured for this", name)),
+    }
+}
@@ -6,18 +6,21 @@
 
 //! Support for reading and writing Rust snapshots
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::u32;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use rw::{sum, read_meta, write_meta};
-use commit::{Commit, EltChange};
-use elt::Element;
+use rw::{sum, read_meta, write_meta, check_len, read_crc, write_crc, encode_elt, check_elt,
+        pad_len, skip, ReadLimits, LATEST_VERSION, CRC_VERSION, DEFAULT_ALIGN};
+use rw::codec::{Codec, RawCodec};
+use commit::{Commit, CommitMeta, EltChange};
+use elt::{Element, EltId};
+use state::PartState;
 use sum::{Sum, SUM_BYTES};
-use error::{Result, ReadError};
+use error::{Result, ReadError, OtherError};
 
 /// Implement this to use `read_log()`.
 /// 
@@ -27,6 +30,29 @@ pub trait CommitReceiver<E: Element> {
     /// Implement to receive a commit once it has been read. Return true to
     /// continue reading or false to stop reading more commits.
     fn receive(&mut self, commit: Commit<E>) -> bool;
+    
+    /// Receive a commit along with its position in the log: the number of
+    /// bytes preceding it, from the start of the log (following the file
+    /// header). The default implementation calls `receive`.
+    fn receive_at(&mut self, commit: Commit<E>, _offset: u64) -> bool {
+        self.receive(commit)
+    }
+    
+    /// Called once a commit's state sum is read, before its element data is
+    /// decoded. Return true to skip the commit (e.g. since its state is
+    /// already known): it is then neither decoded nor passed to `receive`,
+    /// and its element checksums are not verified. The default
+    /// implementation returns false.
+    fn skip(&mut self, _statesum: &Sum) -> bool {
+        false
+    }
+    
+    /// Receive a log trailer (see `write_trailer`): the state sum and number
+    /// of elements of the state reached by the preceding commits. The default
+    /// implementation ignores this and continues reading.
+    fn receive_trailer(&mut self, _statesum: Sum, _num_elts: usize) -> bool {
+        true
+    }
 }
 impl<E: Element> CommitReceiver<E> for Vec<Commit<E>> {
     /// Implement function required by `read_log`.
@@ -39,17 +65,126 @@ impl<E: Element> CommitReceiver<E> for Vec<Commit<E>> {
 
 /// Read a commit log from a stream
 /// 
-/// `format_ver` is the decimalised file format version
-pub fn read_log<E: Element>(mut reader: &mut Read,
-        receiver: &mut CommitReceiver<E>, format_ver: u32) -> Result<()>
+/// `format_ver` is the decimalised file format version. Lengths read are
+/// checked against `limits` before any allocation. If `limits.quick_verify`
+/// is set, versions with a CRC are only verified via this.
+/// 
+/// Element data is assumed not to be encoded; see `read_log_with`.
+pub fn read_log<E: Element>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, limits: &ReadLimits) -> Result<()>
+{
+    read_log_with(reader, receiver, format_ver, limits, &RawCodec, DEFAULT_ALIGN)
+}
+
+/// Read a commit log as `read_log`, decoding element data with `codec`
+/// (select this from the file header with `codec::select`) and expecting
+/// element data aligned to `align` bytes (see `FileHeader::align()`).
+pub fn read_log_with<E: Element>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, limits: &ReadLimits,
+        codec: &Codec, align: usize) -> Result<()>
 {
+    read_log_impl(reader, LogSink::Commits(receiver, codec), format_ver, limits, align)
+}
+
+/// What to do with a commit, as chosen by `CommitVisitor::visit_meta`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Visit {
+    /// Decode the commit's element data and pass the commit to
+    /// `CommitVisitor::visit_commit`
+    Decode,
+    /// Skip the commit without decoding element data, and continue
+    Skip,
+    /// Stop reading more commits
+    Stop,
+}
+
+/// Implement this to use `visit_log()`: a richer alternative to
+/// `CommitReceiver`, deciding per commit (from its metadata) whether element
+/// data is needed.
+pub trait CommitVisitor<E: Element> {
+    /// Receive a commit's metadata, once the commit has been read and its
+    /// checksum verified, and choose whether to decode it.
+    fn visit_meta(&mut self, info: &CommitInfo) -> Visit;
+    
+    /// Receive a commit for which `visit_meta` returned `Visit::Decode`.
+    /// Return true to continue or false to stop reading more commits.
+    fn visit_commit(&mut self, commit: Commit<E>) -> bool;
+    
+    /// Receive a log trailer, as `CommitReceiver::receive_trailer`. The
+    /// default implementation ignores this and continues reading.
+    fn visit_trailer(&mut self, _statesum: Sum, _num_elts: usize) -> bool {
+        true
+    }
+}
+
+/// Read a commit log, passing each commit's metadata to `visitor`, which
+/// chooses whether the commit is decoded (see `CommitVisitor`). Commits
+/// skipped are verified as for `read_log_meta`; element data is read but
+/// neither decoded nor turned into elements. This suits fast metadata scans
+/// which extract only some commits.
+/// 
+/// Arguments are as for `read_log_with`.
+pub fn visit_log<E: Element>(reader: &mut Read,
+        visitor: &mut CommitVisitor<E>, format_ver: u32, limits: &ReadLimits,
+        codec: &Codec, align: usize) -> Result<()>
+{
+    read_log_impl(reader, LogSink::Visit(visitor, codec), format_ver, limits, align)
+}
+
+/// Metadata of a commit, as read by `read_log_meta`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitInfo {
+    /// Sum of the state reached
+    pub statesum: Sum,
+    /// Parents (the first is the state the changes apply to)
+    pub parents: Vec<Sum>,
+    /// Commit metadata
+    pub meta: CommitMeta,
+    /// Number of element changes
+    pub num_changes: usize,
+    /// Position of the commit in the log, in bytes from the start of the log
+    /// (following the file header)
+    pub offset: u64,
+}
+
+/// Read the metadata of each commit in a log into `infos`, skipping element
+/// data. This is much faster than `read_log` since element data is neither
+/// decoded nor allocated. Checksums covering whole commits are verified as
+/// for `read_log`, thus no codec is needed. Log trailers are skipped.
+pub fn read_log_meta(reader: &mut Read, infos: &mut Vec<CommitInfo>, format_ver: u32,
+        limits: &ReadLimits, align: usize) -> Result<()>
+{
+    // The element type is irrelevant since no elements are created
+    read_log_impl::<Vec<u8>>(reader, LogSink::Meta(infos), format_ver, limits, align)
+}
+
+// Receives what `read_log_impl` reads
+enum LogSink<'a, E: Element + 'a> {
+    // Commits, with element data decoded by the codec
+    Commits(&'a mut CommitReceiver<E>, &'a Codec),
+    // Metadata, then commits decoded as chosen by the visitor
+    Visit(&'a mut CommitVisitor<E>, &'a Codec),
+    // Metadata only
+    Meta(&'a mut Vec<CommitInfo>),
+}
+
+fn read_log_impl<E: Element>(mut reader: &mut Read, mut sink: LogSink<E>, format_ver: u32,
+        limits: &ReadLimits, align: usize) -> Result<()>
+{
+    let codec = match sink {
+        LogSink::Commits(_, codec) | LogSink::Visit(_, codec) => Some(codec),
+        LogSink::Meta(_) => None,
+    };
+    let crc = format_ver >= CRC_VERSION;
+    let quick = crc && limits.quick_verify;
     let mut pos: usize = 0;
     let mut buf = vec![0; 32];
+    let mut decode_buf = Vec::new();
     
     reader.read_exact(&mut buf[0..16])?;
     if buf[0..16] != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
-        return ReadError::err("unexpected contents (expected \
-            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16));
+        return ReadError::err_data("unexpected contents (expected \
+            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16), &buf);
     }
     pos += 16;
     
@@ -57,26 +192,65 @@ pub fn read_log<E: Element>(mut reader: &mut Read,
     // file, we only know we're at the end if we hit EOF. This is the only
     // condition where encountering EOF is not an error.
     loop {
-        // A reader which calculates the checksum of what was read:
-        let mut r = sum::HashReader::new(reader);
+        // A reader which calculates the checksum and/or CRC of what was read:
+        let mut r = if crc {
+            sum::HashReader::with_crc(reader, !quick)
+        } else {
+            sum::HashReader::new(reader)
+        };
         
+        let start = pos;
         let l = r.read(&mut buf[0..16])?;
         if l == 0 { break; /*end of file (EOF)*/ }
         if l < 16 { r.read_exact(&mut buf[l..16])?; /*not EOF, buf haven't filled buffer*/ }
         
+        if crc && buf[0..8] == *b"TRAILER\x00" {
+            // The trailer records the size of a state, as a snapshot would
+            let num_elts = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_elts,
+                    "number of elements exceeds limit", pos, (8, 16), &buf)?;
+            pos += 16;
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            let statesum = Sum::load(&buf[0..SUM_BYTES]);
+            pos += SUM_BYTES;
+            read_crc(&mut r, &mut buf, &mut pos)?;
+            
+            let sum = if quick { None } else { Some(r.sum()) };
+            reader = r.into_inner();
+            reader.read_exact(&mut buf[0..SUM_BYTES])?;
+            if sum.map_or(false, |sum| sum != buf[0..SUM_BYTES]) {
+                return ReadError::err_data(format!("checksum invalid for trailer {}", statesum),
+                        pos, (0, SUM_BYTES), &buf);
+            }
+            pos += SUM_BYTES;
+            
+            trace!("Read log trailer ({} elements): {}", num_elts, statesum);
+            let cont = match sink {
+                LogSink::Commits(ref mut receiver, _) =>
+                        receiver.receive_trailer(statesum, num_elts),
+                LogSink::Visit(ref mut visitor, _) => visitor.visit_trailer(statesum, num_elts),
+                LogSink::Meta(_) => true,
+            };
+            if !cont { break; }
+            continue;
+        }
+        
         let n_parents = if buf[0..6] == *b"COMMIT" {
             1
         } else if buf[0..5] == *b"MERGE" {
             let n: u8 = buf[5];
-            if n < 2 { return ReadError::err("bad number of parents", pos, (5, 6)); }
+            if n < 2 {
+                return ReadError::err_data(format!("bad number of parents ({})", n),
+                        pos, (5, 6), &buf);
+            }
             n as usize
         } else {
-            return ReadError::err("unexpected contents (expected COMMIT or MERGE)", pos, (0, 6));
+            return ReadError::err_data("unexpected contents (expected COMMIT or MERGE)",
+                    pos, (0, 6), &buf);
         };
         if buf[6..8] != *b"\x00U" {
-            return ReadError::err("unexpected contents (expected \\x00U)", pos, (6, 8));
+            return ReadError::err_data("unexpected contents (expected \\x00U)", pos, (6, 8), &buf);
         }
-        let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
+        let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver, limits)?;
         
         let mut parents = Vec::with_capacity(n_parents);
         for _ in 0..n_parents {
@@ -87,17 +261,22 @@ pub fn read_log<E: Element>(mut reader: &mut Read,
         
         r.read_exact(&mut buf[0..16])?;
         if buf[0..8] != *b"ELEMENTS" {
-            return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+            return ReadError::err_data("unexpected contents (expected ELEMENTS)",
+                    pos, (0, 8), &buf);
         }
-        let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+        let num_elts = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_changes,
+                "number of changes exceeds limit", pos, (8, 16), &buf)?;
         pos += 16;
         
         let mut changes = HashMap::new();
+        // Element data is decoded once the commit is known not to be skipped
+        let mut stored = Vec::new();
         
         for _ in 0..num_elts {
             r.read_exact(&mut buf[0..16])?;
             if buf[0..4] != *b"ELT " {
-                return ReadError::err("unexpected contents (expected ELT\\x20)", pos, (0, 4));
+                return ReadError::err_data("unexpected contents (expected ELT\\x20)",
+                        pos, (0, 4), &buf);
             }
             let elt_id = BigEndian::read_u64(&buf[8..16]).into();
             let change_t = match &buf[4..8] {
@@ -105,63 +284,111 @@ pub fn read_log<E: Element>(mut reader: &mut Read,
                 b"INS\x00" => { Change::Insert },
                 b"REPL" => { Change::Replace },
                 _ => {
-                    return ReadError::err("unexpected contents (expected one \
-                        of DEL\\x00, INS\\x00, REPL)", pos, (4, 8));
+                    return ReadError::err_data("unexpected contents (expected one \
+                        of DEL\\x00, INS\\x00, REPL)", pos, (4, 8), &buf);
                 }
             };
             pos += 16;
             
-            let change = match change_t {
-                Change::Delete => EltChange::deletion(),
+            match change_t {
+                Change::Delete => { changes.insert(elt_id, EltChange::deletion()); },
                 Change::Insert | Change::Replace => {
                     r.read_exact(&mut buf[0..16])?;
                     if buf[0..8] != *b"ELT DATA" {
-                        return ReadError::err("unexpected contents (expected ELT DATA)", pos, (0, 8));
+                        return ReadError::err_data("unexpected contents (expected ELT DATA)",
+                                pos, (0, 8), &buf);
                     }
-                    let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+                    let data_len = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_elt_len,
+                            "element length exceeds limit", pos, (8, 16), &buf)?;
                     pos += 16;
                     
+                    let pad_len = pad_len(data_len, align);
+                    if codec.is_none() {
+                        // Skip data and sum; only the number of changes is needed
+                        skip(&mut r, data_len + pad_len + SUM_BYTES)?;
+                        pos += data_len + pad_len + SUM_BYTES;
+                        continue;
+                    }
+                    
                     let mut data = vec![0; data_len];
                     r.read_exact(&mut data)?;
                     pos += data_len;
                     
-                    let pad_len = 16 * ((data_len + 15) / 16) - data_len;
                     if pad_len > 0 {
                         r.read_exact(&mut buf[0..pad_len])?;
                         pos += pad_len;
                     }
                     
-                    let elt_sum = Sum::elt_sum(elt_id, &data);
                     r.read_exact(&mut buf[0..SUM_BYTES])?;
-                    if elt_sum != buf[0..SUM_BYTES] {
-                        return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-                    }
+                    stored.push((elt_id, change_t, data, Sum::load(&buf[0..SUM_BYTES]), pos));
                     pos += SUM_BYTES;
-                    
-                    let elt = Rc::new(E::from_vec_sum(data, elt_sum)?);
-                    match change_t {
-                        Change::Insert => EltChange::insertion(elt),
-                        Change::Replace => EltChange::replacement(elt),
-                        _ => panic!()
-                    }
                 },
-            };
-            changes.insert(elt_id, change);
+            }
         }
         
         r.read_exact(&mut buf[0..SUM_BYTES])?;
         let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
         pos += SUM_BYTES;
         
-        let sum = r.sum();
+        if crc {
+            read_crc(&mut r, &mut buf, &mut pos)?;
+        }
+        
+        let sum = if quick { None } else { Some(r.sum()) };
         reader = r.into_inner();
         reader.read_exact(&mut buf[0..SUM_BYTES])?;
-        if sum != buf[0..SUM_BYTES] {
-            return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+        if sum.map_or(false, |sum| sum != buf[0..SUM_BYTES]) {
+            return ReadError::err_data(format!("checksum invalid for commit {}", commit_sum),
+                    pos, (0, SUM_BYTES), &buf);
         }
+        pos += SUM_BYTES;
         
-        trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
-        let cont = receiver.receive(Commit::new_explicit(commit_sum, parents, changes, meta));
+        trace!("Read commit ({} changes): {}; first parent: {}", num_elts, commit_sum, parents[0]);
+        // Decode stored element data into `changes`
+        let decode = |codec: &Codec, changes: &mut HashMap<EltId, EltChange<E>>| {
+            for (elt_id, change_t, mut data, elt_sum, pos) in stored {
+                check_elt(codec, elt_id, &mut data, &mut decode_buf, &elt_sum, quick,
+                        limits.max_elt_len, pos)?;
+                let elt = Rc::new(E::from_vec_sum(data, elt_sum)?);
+                changes.insert(elt_id, match change_t {
+                    Change::Insert => EltChange::insertion(elt),
+                    Change::Replace => EltChange::replacement(elt),
+                    Change::Delete => panic!(),
+                });
+            }
+            Ok(()) as Result<()>
+        };
+        let cont = match sink {
+            LogSink::Commits(ref mut receiver, ref codec) => if receiver.skip(&commit_sum) {
+                trace!("Skipped commit: {}", commit_sum);
+                true
+            } else {
+                decode(*codec, &mut changes)?;
+                receiver.receive_at(Commit::new_explicit(commit_sum, parents, changes, meta),
+                        start as u64)
+            },
+            LogSink::Visit(ref mut visitor, ref codec) => {
+                let info = CommitInfo { statesum: commit_sum, parents: parents, meta: meta,
+                        num_changes: num_elts, offset: start as u64 };
+                match visitor.visit_meta(&info) {
+                    Visit::Decode => {
+                        decode(*codec, &mut changes)?;
+                        let CommitInfo { statesum, parents, meta, .. } = info;
+                        visitor.visit_commit(Commit::new_explicit(statesum, parents, changes, meta))
+                    },
+                    Visit::Skip => {
+                        trace!("Skipped commit: {}", info.statesum);
+                        true
+                    },
+                    Visit::Stop => false,
+                }
+            },
+            LogSink::Meta(ref mut infos) => {
+                infos.push(CommitInfo { statesum: commit_sum, parents: parents, meta: meta,
+                        num_changes: num_elts, offset: start as u64 });
+                true
+            },
+        };
         if !cont { break; }
     }
     
@@ -182,11 +409,34 @@ pub fn start_log(writer: &mut Write) -> Result<()> {
 
 /// Write a single commit to a stream
 pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Result<()> {
+    write_commit_ver(commit, writer, LATEST_VERSION)
+}
+
+/// Write a single commit in the given file format version, which must be
+/// `LATEST_VERSION` or `CRC_VERSION` (matching the log's header).
+pub fn write_commit_ver<E: Element>(commit: &Commit<E>, writer: &mut Write,
+        format_ver: u32) -> Result<()>
+{
+    write_commit_with(commit, writer, format_ver, &RawCodec, DEFAULT_ALIGN)
+}
+
+/// Write a single commit as `write_commit_ver`, encoding element data with
+/// `codec` (which must be named in the log's header, unless `RawCodec`) and
+/// aligning it to `align` bytes (which must match the log's header).
+pub fn write_commit_with<E: Element>(commit: &Commit<E>, writer: &mut Write,
+        format_ver: u32, codec: &Codec, align: usize) -> Result<()>
+{
     trace!("Writing commit ({} changes): {}",
         commit.num_changes(), commit.statesum());
+    assert!(format_ver == LATEST_VERSION || format_ver == CRC_VERSION);
+    let crc = format_ver == CRC_VERSION;
     
-    // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    // A writer which calculates the checksum (and maybe CRC) of what was written:
+    let mut w = if crc {
+        sum::HashWriter::with_crc(writer)
+    } else {
+        sum::HashWriter::new(writer)
+    };
     
     if commit.parents().len() == 1 {
         w.write_all(b"COMMIT\x00U")?;
@@ -209,6 +459,7 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
     w.write_u64::<BigEndian>(commit.num_changes() as u64)?;       // #0015
     
     let mut elt_buf = Vec::new();
+    let mut encode_buf = Vec::new();
     
     let mut keys: Vec<_> = commit.changes_iter().map(|(k,_)| k).collect();
     keys.sort();
@@ -223,23 +474,83 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
         w.write_u64::<BigEndian>((*elt_id).into())?;
         if let Some(elt) = change.element() {
             w.write_all(b"ELT DATA")?;
-            elt_buf.clear();
-            elt.write_buf(&mut &mut elt_buf)?;
-            w.write_u64::<BigEndian>(elt_buf.len() as u64)?;      // #0015
+            let (len, elt_sum) = if elt.is_streamed() && codec.is_identity() {
+                let len = elt.byte_len();
+                w.write_u64::<BigEndian>(len as u64)?;      // #0015
+                (len, write_elt_stream(&**elt, *elt_id, len, &mut w)?)
+            } else {
+                elt_buf.clear();
+                elt.write_buf(&mut &mut elt_buf)?;
+                let stored = encode_elt(codec, &elt_buf, &mut encode_buf)?;
+                w.write_u64::<BigEndian>(stored.len() as u64)?;      // #0015
+                w.write_all(stored)?;
+                (stored.len(), elt.sum(*elt_id))
+            };
             
-            w.write_all(&elt_buf)?;
-            let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
+            let pad_len = pad_len(len, align);
             if pad_len > 0 {
                 let padding = [0u8; 15];
                 w.write_all(&padding[0..pad_len])?;
             }
             
-            elt.sum(*elt_id).write_to(&mut w)?;
+            elt_sum.write_to(&mut w)?;
         }
     }
     
     commit.statesum().write_to(&mut w)?;
     
+    if crc {
+        write_crc(&mut w)?;
+    }
+    
+    let sum = w.sum();
+    sum.write_to(&mut w.into_inner())?;
+    
+    Ok(())
+}
+
+// Write element data via `Element::write_stream`, checking that `len` bytes
+// are written. Returns the element sum, calculated from the data written.
+fn write_elt_stream<E: Element>(elt: &E, elt_id: EltId, len: usize, w: &mut Write) ->
+        Result<Sum>
+{
+    let mut w = sum::HashWriter::for_elt(CountWriter { inner: w, len: 0 }, elt_id);
+    elt.write_stream(&mut w)?;
+    if w.inner().len != len {
+        return OtherError::err("streamed element length does not match byte_len");
+    }
+    Ok(w.sum())
+}
+
+// A writer counting the bytes written
+struct CountWriter<'a> {
+    inner: &'a mut Write,
+    len: usize,
+}
+impl<'a> Write for CountWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.len += len;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a log trailer, recording the state sum and number of elements of
+/// `state`, which should be the state reached by the commits written before
+/// it. Trailers are only supported by `CRC_VERSION`; readers use them to check
+/// that replaying the log reaches the expected state.
+pub fn write_trailer<E: Element>(state: &PartState<E>, writer: &mut Write) -> Result<()> {
+    trace!("Writing log trailer ({} elements): {}", state.len(), state.statesum());
+    let mut w = sum::HashWriter::with_crc(writer);
+    
+    w.write_all(b"TRAILER\x00")?;
+    w.write_u64::<BigEndian>(state.len() as u64)?;
+    state.statesum().write_to(&mut w)?;
+    write_crc(&mut w)?;
+    
     let sum = w.sum();
     sum.write_to(&mut w.into_inner())?;
     
@@ -248,7 +559,6 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
 
 #[test]
 fn commit_write_read(){
-    use rw::HEAD_VERSIONS;
     use elt::EltId;
     use commit::{CommitMeta, UserMeta, MetaFlags};
     
@@ -284,7 +594,8 @@ fn commit_write_read(){
     assert!(write_commit(&commit_2, &mut obj).is_ok());
     
     let mut commits = Vec::new();
-    match read_log(&mut &obj[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]) {
+    match read_log(&mut &obj[..], &mut commits, LATEST_VERSION,
+            &ReadLimits::default()) {
         Ok(()) => {},
         Err(e) => {
 //             // specialisation for a ReadError:
@@ -297,3 +608,229 @@ fn commit_write_read(){
     assert_eq!(commits[0], commit_1);
     assert_eq!(commits[1], commit_2);
 }
+
+#[test]
+fn vector_clock() {
+    use commit::MakeCommitMeta;
+    use state::{PartState, StateWrite};
+    
+    struct Device(u32);
+    impl MakeCommitMeta for Device {
+        fn device_id(&self) -> Option<u32> { Some(self.0) }
+    }
+    let base = PartState::new(&mut Device(1));
+    let mut state = base.clone_mut();
+    state.insert_new("a".to_string()).expect("insert");
+    let a = PartState::from_mut(state, &mut Device(1));
+    let mut state = base.clone_mut();
+    state.insert_new("b".to_string()).expect("insert");
+    let b = PartState::from_mut(state, &mut Device(2));
+    let mut state = a.clone_mut();
+    state.insert_new("c".to_string()).expect("insert");
+    let c = PartState::from_mut(state, &mut Device(3));
+    
+    assert_eq!(a.meta().clock().iter().collect::<Vec<_>>(), vec![(&1, &2)]);
+    assert_eq!(c.meta().clock().len(), 2);
+    assert!(a.meta().happens_before(c.meta()));
+    assert!(!a.meta().happens_before(b.meta()) && !b.meta().happens_before(a.meta()));
+    assert!(!c.meta().happens_before(a.meta()));
+    assert!(a.meta().ext_flags().has_clock());
+    
+    // Clocks survive writing and reading (one and two devices: with and
+    // without padding)
+    let commits = vec![Commit::from_diff(&base, &a).unwrap(), Commit::from_diff(&a, &c).unwrap()];
+    let mut obj = Vec::new();
+    start_log(&mut obj).expect("start_log");
+    for commit in &commits {
+        write_commit(commit, &mut obj).expect("write_commit");
+    }
+    let mut read = Vec::new();
+    read_log(&mut &obj[..], &mut read, LATEST_VERSION, &ReadLimits::default()).expect("read_log");
+    assert_eq!(read, commits);
+    assert_eq!(read[1].meta().clock(), c.meta().clock());
+}
+
+#[test]
+fn log_trailer() {
+    use commit::MakeCommitMeta;
+    use elt::EltId;
+    use state::StateWrite;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    struct Receiver {
+        commits: Vec<Commit<String>>,
+        trailers: Vec<(Sum, usize)>,
+    }
+    impl CommitReceiver<String> for Receiver {
+        fn receive(&mut self, commit: Commit<String>) -> bool {
+            self.commits.push(commit);
+            true
+        }
+        fn receive_trailer(&mut self, statesum: Sum, num_elts: usize) -> bool {
+            self.trailers.push((statesum, num_elts));
+            true
+        }
+    }
+    
+    let state0 = PartState::<String>::new(&mut MCM);
+    let mut state = state0.clone_mut();
+    state.insert(EltId::from(1), "one".to_string()).unwrap();
+    state.insert(EltId::from(2), "two".to_string()).unwrap();
+    let state1 = PartState::from_mut(state, &mut MCM);
+    let commit = Commit::from_diff(&state0, &state1).unwrap();
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).unwrap();
+    write_commit_ver(&commit, &mut obj, CRC_VERSION).unwrap();
+    write_trailer(&state1, &mut obj).unwrap();
+    
+    for quick in vec![false, true] {
+        let limits = ReadLimits { quick_verify: quick, ..ReadLimits::default() };
+        let mut receiver = Receiver { commits: vec![], trailers: vec![] };
+        read_log(&mut &obj[..], &mut receiver, CRC_VERSION, &limits).unwrap();
+        assert_eq!(receiver.commits, vec![Commit::from_diff(&state0, &state1).unwrap()]);
+        assert_eq!(receiver.trailers, vec![(state1.statesum().clone(), 2)]);
+        
+        // Receivers ignoring trailers work unchanged
+        let mut commits: Vec<Commit<String>> = Vec::new();
+        read_log(&mut &obj[..], &mut commits, CRC_VERSION, &limits).unwrap();
+        assert_eq!(commits.len(), 1);
+    }
+    
+    // The trailer's element count is subject to limits
+    let limits = ReadLimits { max_elts: 1, ..ReadLimits::default() };
+    let mut commits: Vec<Commit<String>> = Vec::new();
+    assert!(read_log(&mut &obj[..], &mut commits, CRC_VERSION, &limits).is_err());
+    
+    // Trailers are covered by the CRC
+    let l = obj.len();
+    obj[l - SUM_BYTES - 20] ^= 1;
+    let mut commits: Vec<Commit<String>> = Vec::new();
+    assert!(read_log(&mut &obj[..], &mut commits, CRC_VERSION, &ReadLimits::default()).is_err());
+}
+
+#[test]
+fn streamed_element() {
+    use commit::{MetaFlags, UserMeta};
+    
+    // Streams data in small pieces; `len` is reported by `byte_len`
+    #[derive(PartialEq, Eq, Debug)]
+    struct Streamed { data: Vec<u8>, len: usize }
+    impl Element for Streamed {
+        fn write_buf(&self, _: &mut Write) -> Result<()> {
+            panic!("not buffered")
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            Ok(Streamed { data: buf.to_vec(), len: buf.len() })
+        }
+        fn sum(&self, id: EltId) -> Sum {
+            Sum::elt_sum(id, &self.data)
+        }
+        fn byte_len(&self) -> usize {
+            self.len
+        }
+        fn is_streamed(&self) -> bool {
+            true
+        }
+        fn write_stream(&self, writer: &mut Write) -> Result<()> {
+            for chunk in self.data.chunks(3) {
+                writer.write_all(chunk)?;
+            }
+            Ok(())
+        }
+    }
+    let make_commit = |elt: Streamed| {
+        let mut changes = HashMap::new();
+        changes.insert(EltId::from(1), EltChange::insertion(Rc::new(elt)));
+        let meta = CommitMeta::new_explicit(1, 0, MetaFlags::zero(), vec![], UserMeta::None)
+                .expect("new meta");
+        Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta)
+    };
+    
+    let data = b"streamed element data".to_vec();
+    let commit = make_commit(Streamed { data: data.clone(), len: data.len() });
+    let mut obj = Vec::new();
+    start_log(&mut obj).expect("start_log");
+    write_commit(&commit, &mut obj).expect("write_commit");
+    let mut commits = Vec::new();
+    read_log(&mut &obj[..], &mut commits, LATEST_VERSION, &ReadLimits::default())
+            .expect("read_log");
+    assert_eq!(commits, vec![commit]);
+    
+    // The same data as written via a buffer
+    let mut changes = HashMap::new();
+    changes.insert(EltId::from(1), EltChange::insertion(Rc::new(data.clone())));
+    let meta = CommitMeta::new_explicit(1, 0, MetaFlags::zero(), vec![], UserMeta::None)
+            .expect("new meta");
+    let mut buffered = Vec::new();
+    start_log(&mut buffered).expect("start_log");
+    write_commit(&Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta),
+            &mut buffered).expect("write_commit");
+    assert_eq!(obj, buffered);
+    
+    // The length must be exact
+    let commit = make_commit(Streamed { data: data.clone(), len: data.len() + 1 });
+    assert!(write_commit(&commit, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn visit_log_skip() {
+    use commit::MakeCommitMeta;
+    use state::StateWrite;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    // Decodes commits with more than one change; stops after `max` commits
+    struct Visitor {
+        infos: Vec<CommitInfo>,
+        commits: Vec<Commit<String>>,
+        max: usize,
+    }
+    impl CommitVisitor<String> for Visitor {
+        fn visit_meta(&mut self, info: &CommitInfo) -> Visit {
+            if self.infos.len() >= self.max {
+                return Visit::Stop;
+            }
+            self.infos.push(info.clone());
+            if info.num_changes > 1 { Visit::Decode } else { Visit::Skip }
+        }
+        fn visit_commit(&mut self, commit: Commit<String>) -> bool {
+            self.commits.push(commit);
+            true
+        }
+    }
+    
+    let mut states = vec![PartState::<String>::new(&mut MCM)];
+    for n in 1..4 {
+        let mut state = states.last().unwrap().clone_mut();
+        for i in 0..(if n == 2 { 3 } else { 1 }) {
+            state.insert_new(format!("{}-{}", n, i)).unwrap();
+        }
+        states.push(PartState::from_mut(state, &mut MCM));
+    }
+    let commits: Vec<_> = states.windows(2)
+            .map(|pair| Commit::from_diff(&pair[0], &pair[1]).unwrap())
+            .collect();
+    let mut obj = Vec::new();
+    start_log(&mut obj).unwrap();
+    for commit in &commits {
+        write_commit(commit, &mut obj).unwrap();
+    }
+    
+    let mut visitor = Visitor { infos: vec![], commits: vec![], max: 10 };
+    visit_log(&mut &obj[..], &mut visitor, LATEST_VERSION, &ReadLimits::default(), &RawCodec,
+            DEFAULT_ALIGN).unwrap();
+    let mut infos = Vec::new();
+    read_log_meta(&mut &obj[..], &mut infos, LATEST_VERSION, &ReadLimits::default(),
+            DEFAULT_ALIGN).unwrap();
+    assert_eq!(visitor.infos, infos);
+    assert_eq!(visitor.commits.len(), 1);
+    assert_eq!(visitor.commits[0], commits[1]);
+    
+    let mut visitor = Visitor { infos: vec![], commits: vec![], max: 1 };
+    visit_log(&mut &obj[..], &mut visitor, LATEST_VERSION, &ReadLimits::default(), &RawCodec,
+            DEFAULT_ALIGN).unwrap();
+    assert_eq!(visitor.infos.len(), 1);
+    assert!(visitor.commits.is_empty());
+}
@@ -0,0 +1,100 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for reading and writing deltas: the differences between two
+//! states, e.g. two snapshots (see `Delta`).
+//! 
+//! A delta is stored as the sum of the base state followed by a commit log
+//! holding a single commit, thus is framed and checked as a commit is.
+
+use std::io::{Read, Write};
+
+use rw::ReadLimits;
+use rw::codec::Codec;
+use rw::commitlog::{read_log_with, start_log, write_commit_with};
+use commit::{Commit, Delta};
+use elt::Element;
+use sum::{Sum, SUM_BYTES};
+use error::{Result, ReadError};
+
+/// Read a delta from a stream (after the header)
+/// 
+/// `format_ver` is the decimalised file format version. Lengths read are
+/// checked against `limits` and element data decoded with `codec`, with
+/// alignment `align` (see `read_log_with`).
+pub fn read_delta<E: Element>(reader: &mut Read, format_ver: u32, limits: &ReadLimits,
+        codec: &Codec, align: usize) -> Result<Delta<E>>
+{
+    let mut buf = vec![0; 16 + SUM_BYTES];
+    reader.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != *b"DELTA BASE\x00\x00\x00\x00\x00\x00" {
+        return ReadError::err_data("unexpected contents (expected \
+            DELTA BASE\\x00\\x00\\x00\\x00\\x00\\x00)", 0, (0, 16), &buf);
+    }
+    reader.read_exact(&mut buf[16..16 + SUM_BYTES])?;
+    let base = Sum::load(&buf[16..16 + SUM_BYTES]);
+    
+    let mut commits: Vec<Commit<E>> = Vec::new();
+    read_log_with(reader, &mut commits, format_ver, limits, codec, align)?;
+    if commits.len() != 1 {
+        return ReadError::err(format!("expected one commit in delta (found {})", commits.len()),
+                16 + SUM_BYTES, (0, 0));
+    }
+    let commit = commits.pop().expect("one commit");
+    trace!("Read delta ({} changes): {} to {}", commit.num_changes(), base, commit.statesum());
+    Ok(Delta::new_explicit(base, commit))
+}
+
+/// Write a delta to a stream (after the header), in the given file format
+/// version (`LATEST_VERSION` or `CRC_VERSION`, matching the header),
+/// encoding element data with `codec` and aligning it to `align` bytes.
+pub fn write_delta<E: Element>(delta: &Delta<E>, writer: &mut Write, format_ver: u32,
+        codec: &Codec, align: usize) -> Result<()>
+{
+    trace!("Writing delta ({} changes): {} to {}",
+            delta.commit().num_changes(), delta.base(), delta.statesum());
+    writer.write_all(b"DELTA BASE\x00\x00\x00\x00\x00\x00")?;
+    delta.base().write_to(writer)?;
+    start_log(writer)?;
+    write_commit_with(delta.commit(), writer, format_ver, codec, align)?;
+    Ok(())
+}
+
+#[test]
+fn delta_write_read() {
+    use commit::MakeCommitMeta;
+    use elt::EltId;
+    use rw::LATEST_VERSION;
+    use rw::codec::RawCodec;
+    use state::{PartState, StateWrite};
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    for i in 1..5 {
+        state.insert(EltId::from(i), format!("element {}", i)).unwrap();
+    }
+    let base = PartState::from_mut(state, &mut MCM);
+    let mut state = base.clone_mut();
+    state.remove(EltId::from(1)).unwrap();
+    state.replace(EltId::from(2), "two".to_string()).unwrap();
+    let mid = PartState::from_mut(state, &mut MCM);
+    let mut state = mid.clone_mut();
+    state.insert(EltId::from(5), "five".to_string()).unwrap();
+    let target = PartState::from_mut(state, &mut MCM);
+    
+    // The base is not the target's parent, thus the delta skips `mid`
+    let delta = Delta::from_states(&base, &target).unwrap();
+    assert_eq!(delta.commit().num_changes(), 3);
+    let mut obj = Vec::new();
+    write_delta(&delta, &mut obj, LATEST_VERSION, &RawCodec, 16).unwrap();
+    let delta2 = read_delta(&mut &obj[..], LATEST_VERSION, &ReadLimits::default(), &RawCodec, 16)
+            .unwrap();
+    assert_eq!(delta, delta2);
+    
+    assert_eq!(PartState::from_state_delta(&base, &delta2).unwrap(), target);
+    assert!(PartState::from_state_delta(&mid, &delta2).is_err());
+    assert!(Delta::from_states(&target, &PartState::new(&mut MCM)).is_none());
+}
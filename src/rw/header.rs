@@ -8,39 +8,83 @@ use std::io::{Read, Write, ErrorKind};
 use std::cmp::min;
 use std::result::Result as stdResult;
 
-use error::{Result, ArgError, ReadError, make_io_err};
-use rw::{HEAD_VERSIONS, sum};
+use error::{Result, ArgError, ReadError, VersionError, make_io_err};
+use rw::{LATEST_VERSION, CRC_VERSION, DEFAULT_ALIGN, is_supported_version, is_legacy_version,
+        is_valid_align, sum};
+use rw::codec::validate_name;
 use sum::SUM_BYTES;
 use util::rtrim;
 
-// Snapshot header. This is the latest version.
-const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160815";
-// Commit log header. This is the latest version.
-const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160815";
+// Snapshot header. Version (last 8 bytes) is written separately.
+const HEAD_SNAPSHOT : [u8; 8] = *b"PIPPINSS";
+// Commit log header. Version (last 8 bytes) is written separately.
+const HEAD_COMMITLOG : [u8; 8] = *b"PIPPINCL";
+// Delta header. Version (last 8 bytes) is written separately.
+const HEAD_DELTA : [u8; 8] = *b"PIPPINDL";
+// Admin log header. Version (last 8 bytes) is written separately.
+const HEAD_ADMINLOG : [u8; 8] = *b"PIPPINAL";
 
 const SUM_SHA256 : [u8; 16] = *b"HSUM SHA-2 256\x00\x00";
 const SUM_BLAKE2_16 : [u8; 16] = *b"HSUM BLAKE2 16\x00\x00";
 const PARTID : [u8; 8] = *b"HPARTID ";
 const CLASS_RANGE : [u8; 4] = *b"HCSF";
+const CODEC : [u8; 7] = *b"HCODEC ";
+const ALIGN : [u8; 7] = *b"HALIGN ";
+const HISTORICAL : [u8; 16] = *b"Hhistorical\x00\x00\x00\x00\x00";
+const SEALED : [u8; 16] = *b"Hsealed\x00\x00\x00\x00\x00\x00\x00\x00\x00";
 
 /// File type and version.
 /// 
 /// Version is encoded as an integer; see `HEAD_VERSIONS` constant.
 /// 
-/// The version is set when a header is read but ignored when the header is
-/// written. When creating an instance you can normally just use version 0.
+/// The version is set when a header is read. When writing, the version must
+/// be `LATEST_VERSION` or 0, which is treated as `LATEST_VERSION` (see
+/// `FileType::snapshot()`, `FileType::commit_log()`, `FileType::delta()` and
+/// `FileType::admin_log()`), or `CRC_VERSION` (see `FileType::crc()`).
 pub enum FileType {
     /// File is a snapshot
     Snapshot(u32),
     /// File is a commit log
     CommitLog(u32),
+    /// File is a delta between two states (see `rw::delta`)
+    Delta(u32),
+    /// File is an admin log (see `rw::admin`)
+    AdminLog(u32),
 }
 impl FileType {
+    /// A snapshot of the latest version
+    pub fn snapshot() -> FileType {
+        FileType::Snapshot(LATEST_VERSION)
+    }
+    /// A commit log of the latest version
+    pub fn commit_log() -> FileType {
+        FileType::CommitLog(LATEST_VERSION)
+    }
+    /// A delta of the latest version
+    pub fn delta() -> FileType {
+        FileType::Delta(LATEST_VERSION)
+    }
+    /// An admin log of the latest version
+    pub fn admin_log() -> FileType {
+        FileType::AdminLog(LATEST_VERSION)
+    }
+    
+    /// The same file type, of version `CRC_VERSION`
+    pub fn crc(self) -> FileType {
+        match self {
+            FileType::Snapshot(_) => FileType::Snapshot(CRC_VERSION),
+            FileType::CommitLog(_) => FileType::CommitLog(CRC_VERSION),
+            FileType::Delta(_) => FileType::Delta(CRC_VERSION),
+            FileType::AdminLog(_) => FileType::AdminLog(CRC_VERSION),
+        }
+    }
+    
     /// Extract the version number regardless of file type (should be one of
     /// the HEAD_VERSIONS numbers or zero).
     pub fn ver(&self) -> u32 {
         match *self {
-            FileType::Snapshot(v) | FileType::CommitLog(v) => v,
+            FileType::Snapshot(v) | FileType::CommitLog(v) | FileType::Delta(v) |
+                    FileType::AdminLog(v) => v,
         }
     }
 }
@@ -65,11 +109,31 @@ pub struct FileHeader {
     pub name: String,
     /// User data fields, remarks, etc.
     pub user: Vec<UserData>,
+    /// Name of the codec used to encode element data (see `rw::codec`), or
+    /// `None` if data is not encoded.
+    pub codec: Option<String>,
+    /// Alignment of element data in bytes (see `rw::is_valid_align`), or
+    /// `None` for the default, `rw::DEFAULT_ALIGN`. Smaller alignment wastes
+    /// less space on padding when elements are small.
+    pub align: Option<usize>,
+    /// True for a snapshot of a state other than the latest (see
+    /// `Partition::write_snapshot_of`). Such a snapshot is not used as the
+    /// starting point when loading the latest state.
+    pub historical: bool,
+    /// True for the final snapshot of a sealed partition (see
+    /// `Partition::seal`), which may not be modified further.
+    pub sealed: bool,
+}
+impl FileHeader {
+    /// The alignment of element data, as passed to readers and writers.
+    pub fn align(&self) -> usize {
+        self.align.unwrap_or(DEFAULT_ALIGN)
+    }
 }
 
 // Decodes from a string to the format used in HEAD_VERSIONS. Returns zero on
 // error.
-fn read_head_version(s: &[u8]) -> u32 {
+pub(crate) fn read_head_version(s: &[u8]) -> u32 {
     let mut v = 0;
     for c in s {
         if *c < b'0' || *c > b'9' { return 0; }
@@ -99,27 +163,44 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
     let mut buf = vec![0; 32];
     
     r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != HEAD_SNAPSHOT && buf[0..8] != HEAD_COMMITLOG && buf[0..8] != HEAD_DELTA &&
+        buf[0..8] != HEAD_ADMINLOG
+    {
+        return ReadError::err_data("not a known Pippin file format", pos, (0, 16), &buf);
+    }
     let head_version = read_head_version(&buf[8..16]);
-    if !HEAD_VERSIONS.contains(&head_version) {
-        return ReadError::err("Pippin file of incompatible version", pos, (0, 16));
+    if head_version > CRC_VERSION {
+        return Err(Box::new(VersionError::Newer(head_version)));
+    }
+    if is_legacy_version(head_version) {
+        return Err(Box::new(VersionError::Legacy(head_version)));
     }
-    let ftype = if buf[0..8] == HEAD_SNAPSHOT[0..8] {
+    if !is_supported_version(head_version) {
+        return ReadError::err_data("Pippin file of incompatible version", pos, (0, 16), &buf);
+    }
+    let ftype = if buf[0..8] == HEAD_SNAPSHOT {
         FileType::Snapshot(head_version)
-    } else if buf[0..8] == HEAD_COMMITLOG[0..8] {
+    } else if buf[0..8] == HEAD_COMMITLOG {
         FileType::CommitLog(head_version)
+    } else if buf[0..8] == HEAD_DELTA {
+        FileType::Delta(head_version)
     } else {
-        return ReadError::err("not a known Pippin file format", pos, (0, 16));
+        FileType::AdminLog(head_version)
     };
     pos += 16;
     
     r.read_exact(&mut buf[0..16])?;
     let repo_name = match String::from_utf8(rtrim(&buf, 0).to_vec()) {
         Ok(name) => name,
-        Err(_) => return ReadError::err("repo name not valid UTF-8", pos, (0, 16))
+        Err(_) => return ReadError::err_data("repo name not valid UTF-8", pos, (0, 16), &buf)
     };
     pos += 16;
     
     let mut user_fields = Vec::new();
+    let mut codec = None;
+    let mut align = None;
+    let mut historical = false;
+    let mut sealed = false;
     loop {
         r.read_exact(&mut buf[0..16])?;
         let (block, off): (&[u8], usize) = if buf[0] == b'H' {
@@ -129,7 +210,8 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             let x: usize = match buf[1] {
                 b'1' ... b'9' => buf[1] - b'0',
                 b'A' ... b'Z' => buf[1] + 10 - b'A',
-                _ => return ReadError::err("header section Qx... has invalid length specification 'x'", pos, (0, 2))
+                _ => return ReadError::err_data("header section Qx... has invalid length specification 'x'",
+                        pos, (0, 2), &buf)
             } as usize;
             let len = x * 16;
             if buf.len() < len { buf.resize(len, 0); }
@@ -146,7 +228,7 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             pos += 4;
             (&buf[4..len], 4)
         } else {
-            return ReadError::err("unexpected header contents", pos, (0, 1));
+            return ReadError::err_data("unexpected header contents", pos, (0, 1), &buf);
         };
         
         if block[0..3] == *b"SUM" {
@@ -154,16 +236,29 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
                 /* we don't support any other checksum at run-time, so don't need
                  * to configure anything here */
             } else if rtrim(&block[3..], 0) == &SUM_SHA256[4..14] {
-                return ReadError::err("file uses SHA256 checksum; program not configured for this",
-                    pos, (3+off, 13+off))
+                return ReadError::err_data("file uses SHA256 checksum; program not configured for this",
+                    pos, (3+off, 13+off), &buf)
             }else {
-                return ReadError::err("unknown checksum format", pos, (3+off, 13+off))
+                return ReadError::err_data("unknown checksum format", pos, (3+off, 13+off), &buf)
             };
             break;      // "HSUM" must be last item of header before final checksum
         } else if block[0..7] == PARTID[1..] {
             // ignore; feature removed
         } else if block[0..3] == CLASS_RANGE[1..] {
             // ignore; feature removed
+        } else if block[0..6] == CODEC[1..] {
+            codec = Some(String::from_utf8(rtrim(&block[6..], 0).to_vec())?);
+        } else if block[0..6] == ALIGN[1..] {
+            let value = read_head_version(rtrim(&block[6..], 0)) as usize;
+            if !is_valid_align(value) {
+                return ReadError::err_data("invalid element data alignment", pos,
+                        (6+off, off+block.len()), &buf);
+            }
+            align = Some(value);
+        } else if rtrim(block, 0) == rtrim(&HISTORICAL[1..], 0) {
+            historical = true;
+        } else if rtrim(block, 0) == rtrim(&SEALED[1..], 0) {
+            sealed = true;
         } else if block[0] == b'R' {
             user_fields.push(UserData::Text(String::from_utf8(rtrim(&block[1..], 0).to_vec())?));
         } else if block[0] == b'U' {
@@ -173,14 +268,15 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             // Note: we *could* go ahead and read file with caution, but how
             // should we proceed when we know we missed something important?
             error!("Unknown essential header block: {}", String::from_utf8_lossy(block));
-            return ReadError::err("unknown essential header block", pos, (off, off+block.len()));
+            return ReadError::err_data("unknown essential header block", pos,
+                    (off, off+block.len()), &buf);
         } else if block[0] >= b'a' && block[0] <= b'z' {
             // Match unknown inessential extensions here
             trace!("Ignoring unknown inessential header block: {}", String::from_utf8_lossy(block));
         } else {
             // Match any other block rules here.
             error!("Invalid header block: {}", String::from_utf8_lossy(block));
-            return ReadError::err("invalid header block", pos, (off, off+block.len()));
+            return ReadError::err_data("invalid header block", pos, (off, off+block.len()), &buf);
         }
         pos += block.len();
     }
@@ -190,13 +286,17 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
     let mut r = r.into_inner();
     r.read_exact(&mut buf[0..SUM_BYTES])?;
     if sum != buf[0..SUM_BYTES] {
-        return ReadError::err("header checksum invalid", pos, (0, SUM_BYTES));
+        return ReadError::err_data("header checksum invalid", pos, (0, SUM_BYTES), &buf);
     }
     
     Ok(FileHeader{
         ftype: ftype,
         name: repo_name,
         user: user_fields,
+        codec: codec,
+        align: align,
+        historical: historical,
+        sealed: sealed,
     })
 }
 
@@ -205,15 +305,29 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
     // A writer which calculates the checksum of what was written:
     let mut w = sum::HashWriter::new(writer);
     
+    // Note: we always write in the latest (or CRC) version, even if we read from an old one
+    let ver = match header.ftype.ver() {
+        0 => LATEST_VERSION,
+        ver => ver,
+    };
+    if ver != LATEST_VERSION && ver != CRC_VERSION {
+        return ArgError::err("can only write files in the latest or CRC format version");
+    }
     match header.ftype {
-        // Note: we always write in the latest version, even if we read from an old one
         FileType::Snapshot(_) => {
             w.write_all(&HEAD_SNAPSHOT)?;
         },
         FileType::CommitLog(_) => {
             w.write_all(&HEAD_COMMITLOG)?;
         },
+        FileType::Delta(_) => {
+            w.write_all(&HEAD_DELTA)?;
+        },
+        FileType::AdminLog(_) => {
+            w.write_all(&HEAD_ADMINLOG)?;
+        },
     };
+    write!(w, "{:08}", ver)?;
     validate_repo_name(&header.name)?;
     w.write_all(header.name.as_bytes())?;
     pad(&mut w, 16 - header.name.len())?;
@@ -250,6 +364,31 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         }
     }
     
+    if let Some(ref name) = header.codec {
+        validate_name(name)?;
+        w.write_all(&CODEC)?;
+        w.write_all(name.as_bytes())?;
+        pad(&mut w, 16 - CODEC.len() - name.len())?;
+    }
+    
+    match header.align {
+        None | Some(DEFAULT_ALIGN) => {},
+        Some(align) if is_valid_align(align) => {
+            let value = align.to_string();
+            w.write_all(&ALIGN)?;
+            w.write_all(value.as_bytes())?;
+            pad(&mut w, 16 - ALIGN.len() - value.len())?;
+        },
+        Some(_) => return ArgError::err("invalid element data alignment"),
+    }
+    
+    if header.historical {
+        w.write_all(&HISTORICAL)?;
+    }
+    if header.sealed {
+        w.write_all(&SEALED)?;
+    }
+    
     w.write_all(&SUM_BLAKE2_16)?;
     
     // Write the checksum of everything above:
@@ -307,7 +446,7 @@ fn read_header() {
 #[test]
 fn write_header() {
     let header = FileHeader {
-        ftype: FileType::Snapshot(0 /*version should be ignored*/),
+        ftype: FileType::Snapshot(0 /*equivalent to latest version*/),
         name: "Ähnliche Unsinn".to_string(),
         user: vec![
             UserData::Text("Remark ω".to_string()),
@@ -315,6 +454,10 @@ fn write_header() {
             UserData::Data(b"0123456789abcdefghijklmnopqrs".to_vec()),
             UserData::Data(b" rsei noasr auyv 10()% xovn".to_vec()),
         ],
+        codec: None,
+        align: None,
+        historical: false,
+        sealed: false,
     };
     let mut buf = Vec::new();
     write_head(&header, &mut buf).unwrap();
@@ -339,3 +482,114 @@ fn write_header() {
         assert!(false);
     }
 }
+
+#[test]
+fn header_versions() {
+    let header = FileHeader {
+        ftype: FileType::commit_log(),
+        name: "versions".to_string(),
+        user: vec![],
+        codec: None,
+        align: None,
+        historical: false,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[0..16], *b"PIPPINCL20160815");
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.ftype.ver(), LATEST_VERSION);
+    
+    let old = FileHeader {
+        ftype: FileType::Snapshot(2016_03_10),
+        name: "versions".to_string(),
+        user: vec![],
+        codec: None,
+        align: None,
+        historical: false,
+        sealed: false,
+    };
+    assert!(write_head(&old, &mut Vec::new()).is_err());
+    
+    // A file from the future should be rejected with a VersionError:
+    buf[8..16].copy_from_slice(b"20991231");
+    let err = read_head(&mut &buf[..]).err().expect("read_head of newer version");
+    assert_eq!(err.downcast_ref::<VersionError>(), Some(&VersionError::Newer(2099_12_31)));
+    
+    buf[8..16].copy_from_slice(b"20150929");
+    let err = read_head(&mut &buf[..]).err().expect("read_head of legacy version");
+    assert_eq!(err.downcast_ref::<VersionError>(), Some(&VersionError::Legacy(2015_09_29)));
+}
+
+#[test]
+fn header_codec() {
+    let mut header = FileHeader {
+        ftype: FileType::snapshot(),
+        name: "codec".to_string(),
+        user: vec![],
+        codec: Some("zz".to_string()),
+        align: None,
+        historical: false,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[32..48], *b"HCODEC zz\x00\x00\x00\x00\x00\x00\x00");
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.codec, Some("zz".to_string()));
+    
+    header.codec = Some("much too long".to_string());
+    assert!(write_head(&header, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn header_align() {
+    let mut header = FileHeader {
+        ftype: FileType::commit_log(),
+        name: "align".to_string(),
+        user: vec![],
+        codec: None,
+        align: Some(4),
+        historical: false,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[32..48], *b"HALIGN 4\x00\x00\x00\x00\x00\x00\x00\x00");
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.align(), 4);
+    
+    // The default is not written
+    header.align = Some(16);
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(read_head(&mut &buf[..]).unwrap().align, None);
+    
+    header.align = Some(3);
+    assert!(write_head(&header, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn header_historical() {
+    let mut header = FileHeader {
+        ftype: FileType::snapshot(),
+        name: "historical".to_string(),
+        user: vec![],
+        codec: None,
+        align: None,
+        historical: true,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[32..48], *b"Hhistorical\x00\x00\x00\x00\x00");
+    assert!(read_head(&mut &buf[..]).unwrap().historical);
+    
+    header.historical = false;
+    header.sealed = true;
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[32..48], *b"Hsealed\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+    let head = read_head(&mut &buf[..]).unwrap();
+    assert!(!head.historical && head.sealed);
+}
@@ -0,0 +1,354 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Conversion of snapshots written in legacy file format versions (see
+//! `is_legacy_version`).
+//! 
+//! Legacy files use SHA-2 256 checksums and a different method of combining
+//! element sums into state sums, so their states cannot be reproduced. Only
+//! element data is converted: the header and whole-file checksums are
+//! verified with SHA-2 256, old element and state sums are ignored, and the
+//! elements are returned as a new initial state (without history).
+//! 
+//! Layout of legacy snapshots, as read here (all versions use 16-byte
+//! alignment):
+//! 
+//! *   header: as in current versions, but ending `HSUM SHA-2 256` and
+//!     possibly including `HPARTID` (ignored)
+//! *   from 2016_02_22, `SNAPSHOT` (no parents) or `SNAPSH` + number of
+//!     parents + `U` (from 2016_02_27), then metadata in the `CNUM` form and
+//!     parent sums (all ignored)
+//! *   `ELEMENTS` + count, then per element `ELEMENT\0` + identifier,
+//!     `BYTES\0\0\0` + length, padded data and an (ignored) element sum
+//! *   optionally (from 2016_02_01) `ELTMOVES` + count and 16 bytes per move
+//!     (ignored, since moved elements are not retained)
+//! *   `STATESUM` + count, an (ignored) state sum and the file sum, which is
+//!     verified
+//! 
+//! Element identifiers are kept as stored (in versions with a partition
+//! identifier this occupies their high bits).
+
+use std::collections::hash_map::{HashMap, Entry};
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, BigEndian};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use commit::{CommitMeta, MakeCommitMeta};
+use elt::{Element, EltId};
+use error::{Result, ReadError, ElementOp};
+use rw::{read_meta, check_len, pad_len, skip, is_legacy_version, ReadLimits, DEFAULT_ALIGN};
+use rw::header::read_head_version;
+use state::PartState;
+use sum::{Sum, SUM_BYTES};
+
+// Legacy versions adding features to snapshots (see LEGACY_VERSIONS)
+const VER_ELTMOVES: u32 = 2016_02_01;
+const VER_SS_META: u32 = 2016_02_22;
+
+// Legacy checksum algorithm; the output length equals SUM_BYTES.
+struct Sha256Reader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+impl<R: Read> Sha256Reader<R> {
+    fn new(r: R) -> Self {
+        Sha256Reader { inner: r, hasher: Sha256::new() }
+    }
+    fn sum(&mut self) -> Vec<u8> {
+        let mut buf = vec![0; self.hasher.output_bytes()];
+        self.hasher.result(&mut buf);
+        self.hasher.reset();
+        buf
+    }
+}
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.input(&buf[0..len]);
+        Ok(len)
+    }
+}
+
+/// A legacy snapshot converted by `read_legacy_snapshot`.
+#[derive(Debug)]
+pub struct LegacySnapshot<E: Element> {
+    /// Repository name, from the header
+    pub name: String,
+    /// File format version read
+    pub version: u32,
+    /// A new initial state holding the snapshot's elements
+    pub state: PartState<E>,
+}
+
+/// Read a legacy snapshot (header and body) from a stream, converting its
+/// elements into a new initial state with metadata from `mcm`.
+/// 
+/// Fails with a `ReadError` if the file is not a snapshot of a legacy version
+/// (use `header::read_head` and `snapshot::read_snapshot` for supported
+/// versions) or if checksums do not match. Lengths are checked against
+/// `limits`. Element data is not decoded (legacy versions have no codecs).
+pub fn read_legacy_snapshot<E: Element>(reader: &mut Read, limits: &ReadLimits,
+        mcm: &mut MakeCommitMeta) -> Result<LegacySnapshot<E>>
+{
+    let mut r = Sha256Reader::new(reader);
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    assert!(buf.len() >= SUM_BYTES);
+    
+    // Header
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..6] != *b"PIPPIN" {
+        return ReadError::err_data("not a known Pippin file format", pos, (0, 16), &buf);
+    }
+    let version = read_head_version(&buf[8..16]);
+    if !is_legacy_version(version) {
+        return ReadError::err_data("not a legacy file format version", pos, (8, 16), &buf);
+    }
+    if buf[6..8] != *b"SS" {
+        return ReadError::err_data("only legacy snapshots can be converted", pos, (6, 8), &buf);
+    }
+    pos += 16;
+    
+    r.read_exact(&mut buf[0..16])?;
+    let end = buf[0..16].iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let name = match String::from_utf8(buf[0..end].to_vec()) {
+        Ok(name) => name,
+        Err(_) => return ReadError::err_data("repo name not valid UTF-8", pos, (0, 16), &buf)
+    };
+    pos += 16;
+    
+    loop {
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0] == b'Q' {
+            let x: usize = match buf[1] {
+                b'1' ... b'9' => buf[1] - b'0',
+                b'A' ... b'Z' => buf[1] + 10 - b'A',
+                _ => return ReadError::err_data("header section Qx... has invalid length specification 'x'",
+                        pos, (0, 2), &buf)
+            } as usize;
+            skip(&mut r, x * 16 - 16)?;
+            pos += x * 16;
+        } else if buf[0] == b'H' {
+            if buf[1..4] == *b"SUM" {
+                if buf[4..16] != *b" SHA-2 256\x00\x00" {
+                    return ReadError::err_data("legacy file with unknown checksum format",
+                            pos, (4, 16), &buf);
+                }
+                pos += 16;
+                break;      // "HSUM" must be last item of header before final checksum
+            }
+            // other blocks (e.g. PARTID and remarks) are ignored
+            pos += 16;
+        } else {
+            return ReadError::err_data("unexpected header contents", pos, (0, 1), &buf);
+        }
+    }
+    let sum = r.sum();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    r.sum();    // restart sum for the body
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err_data("header checksum invalid", pos, (0, SUM_BYTES), &buf);
+    }
+    pos += SUM_BYTES;
+    
+    // Snapshot metadata and parents (ignored)
+    r.read_exact(&mut buf[0..16])?;
+    if version >= VER_SS_META {
+        let num_parents = if buf[0..8] == *b"SNAPSHOT" {
+            0
+        } else if buf[0..6] == *b"SNAPSH" && buf[7] == b'U' {
+            buf[6] as usize
+        } else {
+            return ReadError::err_data("unexpected contents (expected SNAPSHOT or SNAPSH_U)",
+                    pos, (0, 8), &buf);
+        };
+        read_meta(&mut r, &mut buf, &mut pos, version, limits)?;
+        skip(&mut r, num_parents * SUM_BYTES)?;
+        pos += num_parents * SUM_BYTES;
+        r.read_exact(&mut buf[0..16])?;
+    }
+    
+    if buf[0..8] != *b"ELEMENTS" {
+        return ReadError::err_data("unexpected contents (expected ELEMENTS)", pos, (0, 8), &buf);
+    }
+    let num_elts = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_elts,
+            "number of elements exceeds limit", pos, (8, 16), &buf)?;
+    pos += 16;
+    
+    let mut elts = HashMap::new();
+    let mut elt_sum = Sum::zero();
+    for _ in 0..num_elts {
+        r.read_exact(&mut buf[0..32])?;
+        if buf[0..8] != *b"ELEMENT\x00" {
+            return ReadError::err_data("unexpected contents (expected ELEMENT\\x00)",
+                    pos, (0, 8), &buf);
+        }
+        let ident: EltId = BigEndian::read_u64(&buf[8..16]).into();
+        pos += 16;
+        if buf[16..24] != *b"BYTES\x00\x00\x00" {
+            return ReadError::err_data("unexpected contents (expected BYTES\\x00\\x00\\x00)",
+                    pos, (16, 24), &buf);
+        }
+        let data_len = check_len(BigEndian::read_u64(&buf[24..32]), limits.max_elt_len,
+                "element length exceeds limit", pos, (24, 32), &buf)?;
+        pos += 16;
+        
+        let mut data = vec![0; data_len];
+        r.read_exact(&mut data)?;
+        let pad_len = pad_len(data_len, DEFAULT_ALIGN);
+        // skip padding and the legacy element sum
+        skip(&mut r, pad_len + SUM_BYTES)?;
+        pos += data_len + pad_len + SUM_BYTES;
+        
+        let sum = Sum::elt_sum(ident, &data);
+        elt_sum.permute(&sum);
+        let elt = E::from_vec_sum(data, sum)?;
+        match elts.entry(ident) {
+            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
+            Entry::Vacant(e) => e.insert(Rc::new(elt)),
+        };
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if version >= VER_ELTMOVES && buf[0..8] == *b"ELTMOVES" {
+        let n_moves = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_elts,
+                "number of element moves exceeds limit", pos, (8, 16), &buf)?;
+        pos += 16;
+        skip(&mut r, n_moves * 16)?;
+        pos += n_moves * 16;
+        r.read_exact(&mut buf[0..16])?;
+    }
+    
+    if buf[0..8] != *b"STATESUM" {
+        return ReadError::err_data("unexpected contents (expected STATESUM or ELTMOVES)",
+                pos, (0, 8), &buf);
+    }
+    if (BigEndian::read_u64(&buf[8..16]) as usize) != num_elts {
+        return ReadError::err_data("unexpected contents (number of elements \
+            differs from that previously stated)", pos, (8, 16), &buf);
+    }
+    pos += 16;
+    skip(&mut r, SUM_BYTES)?;
+    pos += SUM_BYTES;
+    
+    let sum = r.sum();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err_data("checksum invalid", pos, (0, SUM_BYTES), &buf);
+    }
+    
+    let meta = CommitMeta::new_parents(vec![], mcm);
+    let state = PartState::new_explicit(vec![], elts, meta, elt_sum);
+    info!("Converted legacy snapshot (version {}) with {} elements to state {}",
+            version, state.len(), state.statesum());
+    Ok(LegacySnapshot {
+        name: name,
+        version: version,
+        state: state,
+    })
+}
+
+// Write a legacy snapshot holding `elts`, in the layout read above.
+#[cfg(test)]
+pub(crate) fn legacy_file(version: u32, elts: &[(u64, &[u8])]) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+    
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(data);
+        let mut buf = vec![0; SUM_BYTES];
+        hasher.result(&mut buf);
+        buf
+    }
+    
+    let mut head = Vec::new();
+    write!(head, "PIPPINSS{:08}", version).unwrap();
+    head.extend_from_slice(b"legacy\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+    head.extend_from_slice(b"HPARTID \x00\x00\x00\x00\x00\x00\x00\x01");
+    head.extend_from_slice(b"HSUM SHA-2 256\x00\x00");
+    let sum = sha256(&head);
+    head.extend_from_slice(&sum);
+    
+    let mut body = Vec::new();
+    if version >= 2016_02_27 {
+        body.extend_from_slice(b"SNAPSH\x01U");
+    } else if version >= VER_SS_META {
+        body.extend_from_slice(b"SNAPSHOT");
+    }
+    if version >= VER_SS_META {
+        body.write_i64::<BigEndian>(1_456_000_000).unwrap();
+        body.extend_from_slice(b"CNUM\x00\x00\x00\x02XMTT\x00\x00\x00\x05notes");
+        body.extend_from_slice(&[0; 11]);
+        if version >= 2016_02_27 {
+            body.extend_from_slice(&[0x55; SUM_BYTES]);
+        }
+    }
+    body.extend_from_slice(b"ELEMENTS");
+    body.write_u64::<BigEndian>(elts.len() as u64).unwrap();
+    for &(id, data) in elts {
+        body.extend_from_slice(b"ELEMENT\x00");
+        body.write_u64::<BigEndian>(id).unwrap();
+        body.extend_from_slice(b"BYTES\x00\x00\x00");
+        body.write_u64::<BigEndian>(data.len() as u64).unwrap();
+        body.extend_from_slice(data);
+        body.extend(::std::iter::repeat(0).take(pad_len(data.len(), DEFAULT_ALIGN)));
+        body.extend_from_slice(&[0xAA; SUM_BYTES]);
+    }
+    if version >= VER_ELTMOVES {
+        body.extend_from_slice(b"ELTMOVES\x00\x00\x00\x00\x00\x00\x00\x01");
+        body.extend_from_slice(&[0x11; 16]);
+    }
+    body.extend_from_slice(b"STATESUM");
+    body.write_u64::<BigEndian>(elts.len() as u64).unwrap();
+    body.extend_from_slice(&[0xBB; SUM_BYTES]);
+    let sum = sha256(&body);
+    body.extend_from_slice(&sum);
+    
+    head.extend_from_slice(&body);
+    head
+}
+
+#[test]
+fn read_legacy() {
+    use commit::MakeCommitMeta;
+    use state::StateRead;
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let elts: &[(u64, &[u8])] = &[(0x0100_0001, b"one"), (0x0100_0002, b"sixteen bytes..."),
+            (0x0100_0003, b"")];
+    for &version in &[2015_09_29, 2016_01_05, 2016_02_01, 2016_02_21, 2016_02_22, 2016_02_27] {
+        let file = legacy_file(version, elts);
+        let ss = read_legacy_snapshot::<String>(&mut &file[..], &ReadLimits::default(), &mut MCM)
+                .unwrap_or_else(|e| panic!("version {}: {}", version, e));
+        assert_eq!(ss.name, "legacy");
+        assert_eq!(ss.version, version);
+        assert!(ss.state.parents().is_empty());
+        assert_eq!(ss.state.meta().number(), 0);
+        assert_eq!(ss.state.len(), 3);
+        assert_eq!(**ss.state.get_rc(0x0100_0002.into()).unwrap(), "sixteen bytes...");
+        assert_eq!(**ss.state.get_rc(0x0100_0003.into()).unwrap(), "");
+        
+        // Checksums are verified:
+        let mut bad = file.clone();
+        let len = bad.len();
+        bad[len - 1] ^= 1;
+        assert!(read_legacy_snapshot::<String>(&mut &bad[..], &ReadLimits::default(), &mut MCM)
+                .is_err());
+    }
+    
+    // Only legacy snapshots are converted:
+    let mut file = legacy_file(2016_02_27, elts);
+    file[6..8].copy_from_slice(b"CL");
+    assert!(read_legacy_snapshot::<String>(&mut &file[..], &ReadLimits::default(), &mut MCM)
+            .is_err());
+    let mut file = legacy_file(2016_02_27, elts);
+    file[8..16].copy_from_slice(b"20160310");
+    assert!(read_legacy_snapshot::<String>(&mut &file[..], &ReadLimits::default(), &mut MCM)
+            .is_err());
+}
@@ -0,0 +1,84 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for reading and writing commit log indexes: optional sidecar files
+//! recording where each commit of a log starts, so that specific commits can
+//! be read without replaying the whole log (see `Partition::locate_cnum` and
+//! `Partition::read_commit`).
+//! 
+//! An index is text: the line `PIPPIN LOG INDEX`, then one line per commit
+//! holding the commit's byte offset within the log file (including the file
+//! header), its commit number and its state sum in hexadecimal, separated by
+//! spaces.
+
+use std::io::{Read, Write, BufRead, BufReader};
+
+use sum::Sum;
+use error::{Result, OtherError};
+
+// First line of an index
+const INDEX_HEAD: &'static str = "PIPPIN LOG INDEX";
+
+/// One entry of a commit log index
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LogIndexEntry {
+    /// Sum of the state reached by the commit
+    pub statesum: Sum,
+    /// The commit number (see `CommitMeta::number`)
+    pub cnum: u32,
+    /// Position of the commit in the log file, in bytes from the start of the
+    /// file (as for `Provenance::offset`)
+    pub offset: u64,
+}
+
+/// Write an index (all entries) to a stream
+pub fn write_log_index(entries: &[LogIndexEntry], writer: &mut Write) -> Result<()> {
+    writeln!(writer, "{}", INDEX_HEAD)?;
+    for entry in entries {
+        writeln!(writer, "{} {} {}", entry.offset, entry.cnum, entry.statesum.as_string(false))?;
+    }
+    Ok(())
+}
+
+/// Read an index from a stream
+pub fn read_log_index(reader: &mut Read) -> Result<Vec<LogIndexEntry>> {
+    let mut lines = BufReader::new(reader).lines();
+    match lines.next() {
+        Some(line) => if line?.trim() != INDEX_HEAD {
+            return OtherError::err("unexpected contents (expected PIPPIN LOG INDEX)");
+        },
+        None => return OtherError::err("log index is empty"),
+    }
+    
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        let mut parts = line.split_whitespace();
+        let offset = parts.next().and_then(|s| s.parse().ok());
+        let cnum = parts.next().and_then(|s| s.parse().ok());
+        let statesum = parts.next().and_then(Sum::from_hex);
+        match (offset, cnum, statesum, parts.next()) {
+            (Some(offset), Some(cnum), Some(statesum), None) => {
+                entries.push(LogIndexEntry { statesum: statesum, cnum: cnum, offset: offset });
+            },
+            _ => return OtherError::err("invalid entry in log index"),
+        }
+    }
+    Ok(entries)
+}
+
+#[test]
+fn log_index_write_read() {
+    let entries = vec![
+        LogIndexEntry { statesum: Sum::load(&[7; ::sum::SUM_BYTES]), cnum: 1, offset: 80 },
+        LogIndexEntry { statesum: Sum::zero(), cnum: 12, offset: 1 << 33 },
+    ];
+    let mut obj = Vec::new();
+    write_log_index(&entries, &mut obj).unwrap();
+    assert_eq!(read_log_index(&mut &obj[..]).unwrap(), entries);
+    
+    assert!(read_log_index(&mut &b""[..]).is_err());
+    assert!(read_log_index(&mut &b"PIPPIN LOG INDEX\n80 1\n"[..]).is_err());
+}
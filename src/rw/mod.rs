@@ -6,19 +6,28 @@
 //! 
 //! Many code patterns shamelessly lifted from Alex Crichton's flate2 library.
 
-mod sum;
+pub mod sum;
 pub mod header;
 pub mod snapshot;
 pub mod commitlog;
+pub mod delta;
+pub mod codec;
+pub mod admin;
+pub mod logindex;
+pub mod legacy;
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write, ErrorKind};
 use std::iter::repeat;
-use std::u32;
+use std::mem;
+use std::{u32, usize};
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use commit::{CommitMeta, UserMeta, MetaFlags};
-use error::{Result, ReadError};
+use elt::EltId;
+use error::{Result, ReadError, OtherError, make_io_err};
+use rw::codec::Codec;
+use sum::{Sum, SUM_BYTES};
 
 // —————  module-private data and functions  —————
 
@@ -29,20 +38,211 @@ use error::{Result, ReadError};
 // Note: new versions can be implemented just by updating the three HEAD_...
 // constants and updating code, so long as the code will still read old
 // versions. The file format documentation should also be updated.
-const HEAD_VERSIONS : [u32; 3] = [
-    /* unsupported versions:
+const HEAD_VERSIONS : [u32; 4] = [
+    // unsupported versions: see LEGACY_VERSIONS
+    2016_03_10, // new element and state sums break compatibility
+    2016_05_16, // support Bbbb header sections
+    2016_08_15, // allow non-breaking extensions to commit-meta
+    2016_11_07, // CRC32 of snapshot and commit sections (only written on request)
+];
+
+// Versions of header which are recognised but not supported (these use older
+// checksum algorithms, so states cannot be reproduced with current sums).
+const LEGACY_VERSIONS : [u32; 6] = [
     2015_09_29, // initial standardisation
     2016_01_05, // add 'PARTID' to header blocks (snapshot only)
     2016_02_01, // add memory of new names of moved elements
     2016_02_21, // add metadata to commits (logs only)
     2016_02_22, // add metadata to snapshots (snapshots only)
     2016_02_27, // add parent state-sums to snapshots (snapshots only)
-    */
-    2016_03_10, // new element and state sums break compatibility
-    2016_05_16, // support Bbbb header sections
-    2016_08_15, // allow non-breaking extensions to commit-meta
 ];
 
+/// The file format version written by this library by default. This is
+/// encoded as an integer, e.g. `2016_08_15`.
+pub const LATEST_VERSION: u32 = HEAD_VERSIONS[HEAD_VERSIONS.len() - 2];
+
+/// The file format version adding a CRC32 checksum to each snapshot and
+/// commit, allowing quick verification (see `ReadLimits::quick_verify`).
+/// This is the newest supported version, but is only written on request (see
+/// `Control::write_crc()`) since older library versions cannot read it.
+pub const CRC_VERSION: u32 = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+
+/// The oldest file format version this library can read.
+pub const OLDEST_VERSION: u32 = HEAD_VERSIONS[0];
+
+/// True if this library can read files of the given format version.
+pub fn is_supported_version(ver: u32) -> bool {
+    HEAD_VERSIONS.contains(&ver)
+}
+
+/// True if the given format version is a known legacy version, which this
+/// library cannot read.
+pub fn is_legacy_version(ver: u32) -> bool {
+    LEGACY_VERSIONS.contains(&ver)
+}
+
+/// Limits on lengths read from files.
+/// 
+/// Files specify the length of data before the data itself; these limits
+/// stop a corrupt or malicious file from causing huge allocations. A file
+/// exceeding a limit is rejected with a `ReadError`.
+/// 
+/// Limits may be customised via `Control::read_limits()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReadLimits {
+    /// Maximum length of a single element's data, in bytes
+    pub max_elt_len: usize,
+    /// Maximum number of elements in a snapshot (or in the state recorded by
+    /// a commit log trailer)
+    pub max_elts: usize,
+    /// Maximum number of element changes in a single commit
+    pub max_changes: usize,
+    /// Maximum length of extra (user) commit metadata, in bytes
+    pub max_xm_len: usize,
+    /// Not a limit: if true, files of version `CRC_VERSION` are verified only
+    /// via their CRC32 checksums and the element checksums stored in the file
+    /// are trusted. This is much faster than full verification (which is
+    /// still used for older versions), but only detects accidental
+    /// corruption. Full verification of files may be done later via
+    /// `Partition::verify_files()`.
+    pub quick_verify: bool,
+}
+impl Default for ReadLimits {
+    /// Defaults are generous: 256 MiB per element, 2^28 elements per snapshot
+    /// and changes per commit, and 16 MiB of extra metadata per commit.
+    fn default() -> ReadLimits {
+        ReadLimits {
+            max_elt_len: 1 << 28,
+            max_elts: 1 << 28,
+            max_changes: 1 << 28,
+            max_xm_len: 1 << 24,
+            quick_verify: false,
+        }
+    }
+}
+impl ReadLimits {
+    /// No limits (except those imposed by the file format).
+    pub fn none() -> ReadLimits {
+        ReadLimits {
+            max_elt_len: usize::MAX,
+            max_elts: usize::MAX,
+            max_changes: usize::MAX,
+            max_xm_len: usize::MAX,
+            quick_verify: false,
+        }
+    }
+}
+
+/// Default alignment of element data in files: data is padded to a
+/// multiple of this many bytes. Files may use a smaller alignment, named in
+/// the header (see `FileHeader::align`).
+pub const DEFAULT_ALIGN: usize = 16;
+
+/// True if element data may be aligned to `align` bytes: 1, 2, 4, 8 or 16.
+pub fn is_valid_align(align: usize) -> bool {
+    align <= DEFAULT_ALIGN && align.is_power_of_two()
+}
+
+// Number of padding bytes to write after `len` bytes of element data
+fn pad_len(len: usize, align: usize) -> usize {
+    align * ((len + align - 1) / align) - len
+}
+
+// Read and discard `len` bytes (e.g. element data not needed)
+fn skip(r: &mut Read, len: usize) -> Result<()> {
+    let copied = io::copy(&mut r.take(len as u64), &mut io::sink())?;
+    if copied < len as u64 {
+        return make_io_err(ErrorKind::UnexpectedEof, "failed to fill whole buffer");
+    }
+    Ok(())
+}
+
+// Marker starting the CRC section of a snapshot or commit (`CRC_VERSION`
+// and later). This is followed by the CRC32 of all preceding data of the
+// snapshot or commit (u32) and four zero bytes.
+const CRC_MARKER: [u8; 8] = *b"CRC32\x00\x00\x00";
+
+// Write a CRC section. `w` must be calculating a CRC.
+fn write_crc<W: Write>(w: &mut sum::HashWriter<W>) -> Result<()> {
+    let crc = w.crc().expect("calculating CRC");
+    w.write_all(&CRC_MARKER)?;
+    w.write_u32::<BigEndian>(crc)?;
+    w.write_all(&[0u8; 4])?;
+    Ok(())
+}
+
+// Read and check a CRC section. `r` must be calculating a CRC. Uses the
+// first 16 bytes of `buf`.
+fn read_crc<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize) -> Result<()> {
+    let crc = r.crc().expect("calculating CRC");
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != CRC_MARKER {
+        return ReadError::err_data("unexpected contents (expected CRC32)", *pos, (0, 8), &buf);
+    }
+    let stored = BigEndian::read_u32(&buf[8..12]);
+    if stored != crc {
+        return ReadError::err_data(format!("CRC mismatch (calculated {:08X})", crc),
+                *pos, (8, 12), &buf);
+    }
+    *pos += 16;
+    Ok(())
+}
+
+// Check a length read from a file against some limit and convert to usize.
+// `off` is the position of the length in `buf`.
+fn check_len(len: u64, limit: usize, msg: &'static str, pos: usize,
+        off: (usize, usize), buf: &[u8]) -> Result<usize>
+{
+    if len > limit as u64 {
+        return ReadError::err_data(format!("{} ({} > {})", msg, len, limit), pos, off, buf);
+    }
+    Ok(len as usize)
+}
+
+// Encode element data `data` for writing, using `buf` for the output when
+// the codec is not the identity. Returns the data to write.
+fn encode_elt<'a>(codec: &Codec, data: &'a [u8], buf: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+    if codec.is_identity() {
+        return Ok(data);
+    }
+    buf.clear();
+    codec.encode(data, buf)?;
+    Ok(&buf[..])
+}
+
+// Decode element data read into `data` in place (via `buf`, whose contents
+// are swapped), checking the decoded length against `limit`. `pos` is the
+// position of the data in the file.
+fn decode_elt(codec: &Codec, data: &mut Vec<u8>, buf: &mut Vec<u8>, limit: usize,
+        pos: usize) -> Result<()>
+{
+    if codec.is_identity() {
+        return Ok(());
+    }
+    buf.clear();
+    codec.decode(data, buf)?;
+    if buf.len() > limit {
+        return ReadError::err(format!("decoded element length exceeds limit ({} > {})",
+                buf.len(), limit), pos, (0, data.len()));
+    }
+    mem::swap(data, buf);
+    Ok(())
+}
+
+// Decode element data read as stored (see `decode_elt`), then unless `quick`
+// check it against the stored element sum. `pos` is the position of the sum
+// in the file.
+fn check_elt(codec: &Codec, ident: EltId, data: &mut Vec<u8>, buf: &mut Vec<u8>,
+        elt_sum: &Sum, quick: bool, limit: usize, pos: usize) -> Result<()>
+{
+    decode_elt(codec, data, buf, limit, pos)?;
+    if !quick && Sum::elt_sum(ident, data) != *elt_sum {
+        return ReadError::err(format!("element {} checksum mismatch", ident),
+                pos, (0, SUM_BYTES));
+    }
+    Ok(())
+}
+
 /// Read metadata
 /// 
 /// This is a bit involved. It expects:
@@ -50,19 +250,21 @@ const HEAD_VERSIONS : [u32; 3] = [
 /// *   `r`: a reader
 /// *   `buf`: a buffer of length at least 16 and with bytes 8..16 filled
 /// *   `pos`: a counter, which needs incrementing by 16 after finishing 8 bytes from buf
-fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_ver: u32) -> Result<CommitMeta> {
+fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_ver: u32,
+        limits: &ReadLimits) -> Result<CommitMeta>
+{
     let secs = BigEndian::read_i64(&buf[8..16]);
     (*pos) += 16;
     
     r.read_exact(&mut buf[0..16])?;
     let (ext_len, ext_flags) = if format_ver < 2016_08_15 {
         if buf[0..4] != *b"CNUM" {
-            return ReadError::err("unexpected contents (expected CNUM)", *pos, (0, 4));
+            return ReadError::err_data("unexpected contents (expected CNUM)", *pos, (0, 4), &buf);
         }
         (0, 0)
     } else {
         if buf[0] != b'F' {
-            return ReadError::err("unexpected contents (expected F)", *pos, (0, 1));
+            return ReadError::err_data("unexpected contents (expected F)", *pos, (0, 1), &buf);
         }
         let len = (buf[1] as usize) * 8;
         let flags = BigEndian::read_u16(&buf[2..4]);
@@ -73,12 +275,15 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
     r.read_exact(&mut ext_data)?;
     
     if buf[8..10] != *b"XM" {
-        return ReadError::err("unexpected contents (expected XM)", *pos, (8, 10));
+        return ReadError::err_data("unexpected contents (expected XM)", *pos, (8, 10), &buf);
     }
     let xm_type_txt = buf[10..12] == *b"TT";
-    let xm_len = BigEndian::read_u32(&buf[12..16]) as usize;
+    let xm_len = check_len(BigEndian::read_u32(&buf[12..16]) as u64, limits.max_xm_len,
+            "extra metadata length exceeds limit", *pos, (12, 16), &buf)?;
     (*pos) += 16;
     
+    (*pos) += ext_len;
+    
     let mut xm_data = vec![0; xm_len];
     r.read_exact(&mut xm_data)?;
     let xm = if xm_type_txt {
@@ -104,21 +309,27 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
 fn write_meta(w: &mut Write, meta: &CommitMeta) -> Result<()> {
     w.write_i64::<BigEndian>(meta.timestamp())?;
     
+    // Extension data (length in units of 8 bytes) is the vector clock, if any
+    let ext_data = meta.clock().ext_data();
+    if ext_data.len() > 255 * 8 {
+        return OtherError::err("vector clock has too many devices to write");
+    }
     w.write_all(b"F")?;
-    w.write_all(&[0u8; 1])?; // 0 extension data: we don't use this currently
+    w.write_all(&[(ext_data.len() / 8) as u8])?;
     w.write_u16::<BigEndian>(meta.ext_flags().raw())?;
     w.write_u32::<BigEndian>(meta.number())?;
-    // extension data would go here, but we don't currently have any
     
     match *meta.extra() {
         UserMeta::None => {
             // last four zeros is 0u32 encoded in bytes
             w.write_all(b"XM\x00\x00\x00\x00\x00\x00")?;
+            w.write_all(&ext_data)?;
         },
         UserMeta::Text(ref txt) => {
             w.write_all(b"XMTT")?;
             assert!(txt.len() <= u32::MAX as usize);
             w.write_u32::<BigEndian>(txt.len() as u32)?;
+            w.write_all(&ext_data)?;
             w.write_all(txt.as_bytes())?;
             let pad_len = 16 * ((txt.len() + 15) / 16) - txt.len();
             if pad_len > 0 {
@@ -5,15 +5,19 @@
 //! Support for reading and writing Rust snapshots
 
 use std::io::{Read, Write};
+use std::mem;
 use std::rc::Rc;
 use std::{u8, u32};
 use std::collections::hash_map::{HashMap, Entry};
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use elt::Element;
-use error::{Result, ReadError, ElementOp};
-use rw::{sum, read_meta, write_meta};
+use commit::CommitMeta;
+use elt::{Element, EltId};
+use error::{Result, ReadError, ElementOp, OtherError};
+use rw::{sum, read_meta, write_meta, check_len, read_crc, write_crc, encode_elt, decode_elt,
+        check_elt, pad_len, skip, ReadLimits, LATEST_VERSION, CRC_VERSION, DEFAULT_ALIGN};
+use rw::codec::{Codec, RawCodec};
 use state::{PartState, StateRead};
 use sum::{Sum, SUM_BYTES};
 
@@ -25,11 +29,188 @@ use sum::{Sum, SUM_BYTES};
 /// 
 /// The file version affects how data is read. Get it from a header with
 /// `header.ftype.ver()`.
+/// 
+/// Lengths read are checked against `limits` before any allocation. If
+/// `limits.quick_verify` is set, versions with a CRC are only verified via
+/// this.
+/// 
+/// Element data is assumed not to be encoded; see `read_snapshot_with`.
 pub fn read_snapshot<T: Element>(reader: &mut Read,
-        format_ver: u32) -> Result<PartState<T>>
+        format_ver: u32, limits: &ReadLimits) -> Result<PartState<T>>
+{
+    read_snapshot_with(reader, format_ver, limits, &RawCodec, DEFAULT_ALIGN)
+}
+
+/// Read a snapshot as `read_snapshot`, decoding element data with `codec`
+/// (select this from the file header with `codec::select`) and expecting
+/// element data aligned to `align` bytes (see `FileHeader::align()`).
+pub fn read_snapshot_with<T: Element>(reader: &mut Read,
+        format_ver: u32, limits: &ReadLimits, codec: &Codec, align: usize) ->
+        Result<PartState<T>>
+{
+    let mut elts = HashMap::new();
+    let mode = EltData::Decode(codec);
+    let head = read_elts(reader, format_ver, limits, mode, align, &mut |ident, data, elt_sum, _| {
+        // Take the buffer; a new one is allocated for the next element
+        let elt = T::from_vec_sum(mem::replace(data, Vec::new()), elt_sum)?;
+        match elts.entry(ident) {
+            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
+            Entry::Vacant(e) => e.insert(Rc::new(elt)),
+        };
+        Ok(true)
+    })?;
+    let (parents, meta, elt_sum) = head.expect("reading all elements");
+    let state = PartState::new_explicit(parents, elts, meta, elt_sum);
+    trace!("Read snapshot (with {} elements): {}", state.num_avail(), state.statesum());
+    Ok(state)
+}
+
+/// Read a snapshot as `read_snapshot_with`, unless its state is already
+/// known: element data is read without being decoded, then `known` is
+/// called with the state sum; if it returns true, `None` is returned without
+/// decoding any elements. This saves work when loading states found in
+/// several files (e.g. a historical snapshot of a state also reached by
+/// commit logs).
+/// 
+/// Checksums covering the whole snapshot are verified either way; element
+/// checksums only when elements are decoded.
+pub fn read_snapshot_unless<T: Element>(reader: &mut Read, format_ver: u32,
+        limits: &ReadLimits, codec: &Codec, align: usize, known: &mut FnMut(&Sum) -> bool) ->
+        Result<Option<PartState<T>>>
+{
+    let mut stored = Vec::new();
+    let head = read_elts(reader, format_ver, limits, EltData::Raw, align,
+            &mut |ident, data, elt_sum, pos| {
+        stored.push((ident, mem::replace(data, Vec::new()), elt_sum, pos));
+        Ok(true)
+    })?;
+    let (parents, meta, elt_sum) = head.expect("reading all elements");
+    let statesum = &Sum::state_meta_sum(&parents, &meta) ^ &elt_sum;
+    if known(&statesum) {
+        trace!("Skipped snapshot of known state: {}", statesum);
+        return Ok(None);
+    }
+    
+    let quick = format_ver >= CRC_VERSION && limits.quick_verify;
+    let mut elts = HashMap::new();
+    let mut decode_buf = Vec::new();
+    for (ident, mut data, elt_sum, pos) in stored {
+        check_elt(codec, ident, &mut data, &mut decode_buf, &elt_sum, quick,
+                limits.max_elt_len, pos)?;
+        let elt = T::from_vec_sum(data, elt_sum)?;
+        match elts.entry(ident) {
+            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
+            Entry::Vacant(e) => e.insert(Rc::new(elt)),
+        };
+    }
+    let state = PartState::new_explicit(parents, elts, meta, elt_sum);
+    trace!("Read snapshot (with {} elements): {}", state.num_avail(), state.statesum());
+    Ok(Some(state))
+}
+
+/// Implement this to use `visit_snapshot()`.
+/// 
+/// There is a simple implementation for closures taking the same arguments
+/// as `visit`.
+pub trait EltVisitor {
+    /// Receive an element's identifier and serialised data (as passed to
+    /// `Element::read_buf`). The data is only borrowed, thus a consumer may
+    /// process it without allocating (e.g. to index or export elements).
+    /// Return true to continue or false to stop reading more elements.
+    fn visit(&mut self, id: EltId, data: &[u8]) -> bool;
+}
+impl<F: FnMut(EltId, &[u8]) -> bool> EltVisitor for F {
+    fn visit(&mut self, id: EltId, data: &[u8]) -> bool {
+        self(id, data)
+    }
+}
+
+/// Read a snapshot from a stream, passing each element's data to `visitor`
+/// instead of creating elements. Only a single buffer is allocated for
+/// element data, thus this is much faster than `read_snapshot` for scans
+/// over large snapshots by read-only consumers.
+/// 
+/// Verification is as for `read_snapshot`, except that if `visitor` stops
+/// reading early the rest of the snapshot is not checked (in this case the
+/// result is `Ok(None)`); note also that elements are passed before
+/// verification of the whole snapshot is complete. On success, returns the
+/// snapshot's state sum.
+/// 
+/// Element data is decoded with `codec` before being passed (use `RawCodec`
+/// if the file header names no codec) and is expected to be aligned to
+/// `align` bytes (use `FileHeader::align()`).
+pub fn visit_snapshot(reader: &mut Read, format_ver: u32, limits: &ReadLimits,
+        codec: &Codec, align: usize, visitor: &mut EltVisitor) -> Result<Option<Sum>>
+{
+    let mode = EltData::Decode(codec);
+    let head = read_elts(reader, format_ver, limits, mode, align, &mut |ident, data, _, _| {
+        Ok(visitor.visit(ident, data))
+    })?;
+    Ok(head.map(|(parents, meta, elt_sum)| &Sum::state_meta_sum(&parents, &meta) ^ &elt_sum))
+}
+
+/// Metadata of a snapshot's state, as read by `read_snapshot_meta`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    /// State sum
+    pub statesum: Sum,
+    /// Parents of the state
+    pub parents: Vec<Sum>,
+    /// State metadata
+    pub meta: CommitMeta,
+    /// Number of elements
+    pub num_elts: usize,
+}
+
+/// Read the metadata of a snapshot, skipping element data. This is much
+/// faster than `read_snapshot` since element data is neither decoded nor
+/// allocated; the stored element sums are used. Checksums covering the
+/// whole snapshot are verified as for `read_snapshot`, thus no codec is
+/// needed.
+pub fn read_snapshot_meta(reader: &mut Read, format_ver: u32, limits: &ReadLimits,
+        align: usize) -> Result<SnapshotInfo>
 {
-    // A reader which calculates the checksum of what was read:
-    let mut r = sum::HashReader::new(reader);
+    let mut num_elts = 0;
+    let head = read_elts(reader, format_ver, limits, EltData::Skip, align, &mut |_, _, _, _| {
+        num_elts += 1;
+        Ok(true)
+    })?;
+    let (parents, meta, elt_sum) = head.expect("reading all elements");
+    Ok(SnapshotInfo {
+        statesum: &Sum::state_meta_sum(&parents, &meta) ^ &elt_sum,
+        parents: parents,
+        meta: meta,
+        num_elts: num_elts,
+    })
+}
+
+// How `read_elts` treats element data
+#[derive(Clone, Copy)]
+enum EltData<'a> {
+    // Decode with the codec and verify element sums
+    Decode(&'a Codec),
+    // Pass data as stored, with the stored (unchecked) sum
+    Raw,
+    // Skip data: pass an empty buffer and the stored (unchecked) sum
+    Skip,
+}
+
+// Read a snapshot, passing each element's identifier, data, sum and the
+// position of the sum to `f` (which may take the data buffer) and verifying
+// checksums. If `f` returns false, stops and returns `None`, otherwise
+// returns parents, metadata and the combined element sum.
+fn read_elts(reader: &mut Read, format_ver: u32, limits: &ReadLimits, mode: EltData,
+        align: usize, f: &mut FnMut(EltId, &mut Vec<u8>, Sum, usize) -> Result<bool>) ->
+        Result<Option<(Vec<Sum>, CommitMeta, Sum)>>
+{
+    let crc = format_ver >= CRC_VERSION;
+    let quick = crc && limits.quick_verify;
+    // A reader which calculates the checksum and/or CRC of what was read:
+    let mut r = if crc {
+        sum::HashReader::with_crc(reader, !quick)
+    } else {
+        sum::HashReader::new(reader)
+    };
     
     let mut pos: usize = 0;
     let mut buf = vec![0; 32];
@@ -37,10 +218,11 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
     
     r.read_exact(&mut buf[0..16])?;
     if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
-        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
+        return ReadError::err_data("unexpected contents (expected SNAPSH_U where _ is any)",
+                pos, (0, 8), &buf);
     }
     let num_parents = buf[6] as usize;
-    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
+    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver, limits)?;
     
     let mut parents = Vec::with_capacity(num_parents);
     for _ in 0..num_parents {
@@ -50,52 +232,75 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
     
     r.read_exact(&mut buf[0..16])?;
     if buf[0..8] != *b"ELEMENTS" {
-        return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+        return ReadError::err_data("unexpected contents (expected ELEMENTS)", pos, (0, 8), &buf);
     }
-    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
+    let num_elts = check_len(BigEndian::read_u64(&buf[8..16]), limits.max_elts,
+            "number of elements exceeds limit", pos, (8, 16), &buf)?;
     pos += 16;
     
-    let mut elts = HashMap::new();
+    let mut data = Vec::new();
+    let mut decode_buf = Vec::new();
     let mut combined_elt_sum = Sum::zero();
     for _ in 0..num_elts {
         r.read_exact(&mut buf[0..32])?;
         if buf[0..8] != *b"ELEMENT\x00" {
             println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
-            return ReadError::err("unexpected contents (expected ELEMENT\\x00)", pos, (0, 8));
+            return ReadError::err_data("unexpected contents (expected ELEMENT\\x00)",
+                    pos, (0, 8), &buf);
         }
         let ident = BigEndian::read_u64(&buf[8..16]).into();
         pos += 16;
         
         if buf[16..24] != *b"BYTES\x00\x00\x00" {
-            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (16, 24));
+            return ReadError::err_data("unexpected contents (expected BYTES\\x00\\x00\\x00)",
+                    pos, (16, 24), &buf);
         }
-        let data_len = BigEndian::read_u64(&buf[24..32]) as usize;   // #0015
+        let data_len = check_len(BigEndian::read_u64(&buf[24..32]), limits.max_elt_len,
+                "element length exceeds limit", pos, (24, 32), &buf)?;
         pos += 16;
         
-        let mut data = vec![0; data_len];
-        r.read_exact(&mut data)?;
-        pos += data_len;
-        
-        let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-        if pad_len > 0 {
-            r.read_exact(&mut buf[0..pad_len])?;
-            pos += pad_len;
+        data.clear();
+        let pad_len = pad_len(data_len, align);
+        if let EltData::Skip = mode {
+            skip(&mut r, data_len + pad_len)?;
+            pos += data_len + pad_len;
+        } else {
+            data.resize(data_len, 0);
+            r.read_exact(&mut data)?;
+            pos += data_len;
+            
+            if pad_len > 0 {
+                r.read_exact(&mut buf[0..pad_len])?;
+                pos += pad_len;
+            }
+        }
+        let codec = match mode {
+            EltData::Decode(codec) => Some(codec),
+            EltData::Raw | EltData::Skip => None,
+        };
+        if let Some(codec) = codec {
+            decode_elt(codec, &mut data, &mut decode_buf, limits.max_elt_len, pos)?;
         }
         
-        let elt_sum = Sum::elt_sum(ident, &data);
         r.read_exact(&mut buf[0..SUM_BYTES])?;
-        if elt_sum != buf[0..SUM_BYTES] {
-            return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-        }
+        let sum_pos = pos;
+        let elt_sum = if quick || codec.is_none() {
+            Sum::load(&buf[0..SUM_BYTES])
+        } else {
+            let elt_sum = Sum::elt_sum(ident, &data);
+            if elt_sum != buf[0..SUM_BYTES] {
+                return ReadError::err_data(format!("element {} checksum mismatch", ident),
+                        pos, (0, SUM_BYTES), &buf);
+            }
+            elt_sum
+        };
         pos += SUM_BYTES;
         
         combined_elt_sum.permute(&elt_sum);
         
-        let elt = T::from_vec_sum(data, elt_sum)?;
-        match elts.entry(ident) {
-            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
-            Entry::Vacant(e) => e.insert(Rc::new(elt)),
-        };
+        if !f(ident, &mut data, elt_sum, sum_pos)? {
+            return Ok(None);
+        }
     }
     
     r.read_exact(&mut buf[0..16])?;
@@ -110,34 +315,38 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
         r.read_exact(&mut buf[0..16])?;
     }
     
-    let state = PartState::new_explicit(parents,
-            elts, meta, combined_elt_sum);
+    let statesum = &Sum::state_meta_sum(&parents, &meta) ^ &combined_elt_sum;
     
     if buf[0..8] != *b"STATESUM" {
-        return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", pos, (0, 8));
+        return ReadError::err_data("unexpected contents (expected STATESUM or ELTMOVES)",
+                pos, (0, 8), &buf);
     }
     pos += 8;
     if (BigEndian::read_u64(&buf[8..16]) as usize) != num_elts {
-        return ReadError::err("unexpected contents (number of elements \
-            differs from that previously stated)", pos, (8, 16));
+        return ReadError::err_data("unexpected contents (number of elements \
+            differs from that previously stated)", pos, (8, 16), &buf);
     }
     pos += 8;
     
     r.read_exact(&mut buf[0..SUM_BYTES])?;
-    if *state.statesum() != buf[0..SUM_BYTES] {
-        return ReadError::err("state checksum mismatch", pos, (0, SUM_BYTES));
+    if statesum != buf[0..SUM_BYTES] {
+        return ReadError::err_data(format!("state checksum mismatch (calculated {})",
+                statesum), pos, (0, SUM_BYTES), &buf);
     }
     pos += SUM_BYTES;
     
-    let sum = r.sum();
+    if crc {
+        read_crc(&mut r, &mut buf, &mut pos)?;
+    }
+    
+    let sum = if quick { None } else { Some(r.sum()) };
     let mut r = r.into_inner();
     r.read_exact(&mut buf[0..SUM_BYTES])?;
-    if sum != buf[0..SUM_BYTES] {
-        return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+    if sum.map_or(false, |sum| sum != buf[0..SUM_BYTES]) {
+        return ReadError::err_data("checksum invalid", pos, (0, SUM_BYTES), &buf);
     }
     
-    trace!("Read snapshot (with {} elements): {}", num_elts, state.statesum());
-    Ok(state)
+    Ok(Some((parents, meta, combined_elt_sum)))
 }
 
 /// Write a snapshot of a set of elements to a stream
@@ -146,11 +355,34 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
 /// partition identifier range.
 pub fn write_snapshot<T: Element>(state: &PartState<T>,
     writer: &mut Write) -> Result<()>
+{
+    write_snapshot_ver(state, writer, LATEST_VERSION)
+}
+
+/// Write a snapshot in the given file format version, which must be
+/// `LATEST_VERSION` or `CRC_VERSION` (matching the header written).
+pub fn write_snapshot_ver<T: Element>(state: &PartState<T>,
+    writer: &mut Write, format_ver: u32) -> Result<()>
+{
+    write_snapshot_with(state, writer, format_ver, &RawCodec, DEFAULT_ALIGN)
+}
+
+/// Write a snapshot as `write_snapshot_ver`, encoding element data with
+/// `codec` (which must be named in the header written, unless `RawCodec`)
+/// and aligning it to `align` bytes (which must match the header).
+pub fn write_snapshot_with<T: Element>(state: &PartState<T>,
+    writer: &mut Write, format_ver: u32, codec: &Codec, align: usize) -> Result<()>
 {
     trace!("Writing snapshot (with {} elements): {}", state.num_avail(), state.statesum());
+    assert!(format_ver == LATEST_VERSION || format_ver == CRC_VERSION);
+    let crc = format_ver == CRC_VERSION;
     
-    // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    // A writer which calculates the checksum (and maybe CRC) of what was written:
+    let mut w = if crc {
+        sum::HashWriter::with_crc(writer)
+    } else {
+        sum::HashWriter::new(writer)
+    };
     
     let mut snapsh_u: [u8; 8] = *b"SNAPSH_U";
     assert!(state.parents().len() <= (u8::MAX as usize));
@@ -165,7 +397,9 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
     w.write_all(b"ELEMENTS")?;
     
     let mut elt_buf = Vec::new();
+    let mut encode_buf = Vec::new();
     
+    // Write in order of identifier, so that output is reproducible:
     let mut keys: Vec<_> = state.elts_iter().map(|(k,_)| k).collect();
     keys.sort();
     
@@ -180,10 +414,11 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
         w.write_all(b"BYTES\x00\x00\x00")?;
         elt_buf.clear();
         elt.write_buf(&mut &mut elt_buf)?;
-        w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
+        let stored = encode_elt(codec, &elt_buf, &mut encode_buf)?;
+        w.write_u64::<BigEndian>(stored.len() as u64 /* #0015 */)?;
         
-        w.write_all(&elt_buf)?;
-        let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
+        w.write_all(stored)?;
+        let pad_len = pad_len(stored.len(), align);
         if pad_len > 0 {
             let padding = [0u8; 15];
             w.write_all(&padding[0..pad_len])?;
@@ -198,6 +433,10 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
     w.write_u64::<BigEndian>(num_elts)?;
     state.statesum().write_to(&mut w)?;
     
+    if crc {
+        write_crc(&mut w)?;
+    }
+    
     // Write the checksum of everything above:
     let sum = w.sum();
     sum.write_to(&mut w.into_inner())?;
@@ -208,7 +447,6 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
 #[test]
 fn snapshot_writing() {
     use state::StateWrite;
-    use rw::HEAD_VERSIONS;
     use commit::{CommitMeta, UserMeta, MakeCommitMeta};
     
     struct MMNone {}
@@ -251,6 +489,188 @@ fn snapshot_writing() {
     let mut result = Vec::new();
     assert!(write_snapshot(&state, &mut result).is_ok());
     
-    let state2 = read_snapshot(&mut &result[..], HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
+    let state2 = read_snapshot(&mut &result[..], LATEST_VERSION,
+            &ReadLimits::default()).unwrap();
+    assert_eq!(state, state2);
+}
+
+#[test]
+fn snapshot_limits() {
+    use state::StateWrite;
+    use commit::MakeCommitMeta;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    state.insert_new(::std::iter::repeat('x').take(100).collect()).unwrap();
+    state.insert_new("short".to_string()).unwrap();
+    let state = PartState::from_mut(state, &mut MCM);
+    
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result).unwrap();
+    let ver = LATEST_VERSION;
+    
+    let mut limits = ReadLimits::default();
+    limits.max_elt_len = 100;
+    limits.max_elts = 2;
+    let state2 = read_snapshot(&mut &result[..], ver, &limits).unwrap();
+    assert_eq!(state, state2);
+    
+    limits.max_elt_len = 99;
+    assert!(read_snapshot::<String>(&mut &result[..], ver, &limits).is_err());
+    limits = ReadLimits::default();
+    limits.max_elts = 1;
+    let e = read_snapshot::<String>(&mut &result[..], ver, &limits).unwrap_err();
+    assert!(e.to_string().ends_with("number of elements exceeds limit (2 > 1)"));
+}
+
+#[test]
+fn snapshot_reproducible() {
+    use state::StateWrite;
+    use commit::MakeCommitMeta;
+    use elt::EltId;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {
+        fn make_commit_timestamp(&self) -> i64 { 1234567 }
+    }
+    
+    let ids: Vec<u64> = (1..40).map(|i| i * 0x9E37_79B9).collect();
+    let make = |ids: &[u64]| {
+        let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+        for id in ids {
+            state.insert(EltId::from(*id), format!("element {}", id)).unwrap();
+        }
+        PartState::from_mut(state, &mut MCM)
+    };
+    let state = make(&ids);
+    let mut rev_ids = ids.clone();
+    rev_ids.reverse();
+    let state2 = make(&rev_ids);
+    assert_eq!(state, state2);
+    
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result).unwrap();
+    let mut result2 = Vec::new();
+    write_snapshot(&state2, &mut result2).unwrap();
+    assert_eq!(result, result2);
+    
+    // Elements are written in order of identifier, regardless of map order:
+    let state3 = read_snapshot::<String>(&mut &result[..], LATEST_VERSION,
+            &ReadLimits::default()).unwrap();
+    let mut result3 = Vec::new();
+    write_snapshot(&state3, &mut result3).unwrap();
+    assert_eq!(result, result3);
+}
+
+#[test]
+fn snapshot_visit() {
+    use state::StateWrite;
+    use commit::MakeCommitMeta;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    for i in 1..6 {
+        state.insert(EltId::from(i), format!("element {}", i)).unwrap();
+    }
+    let state = PartState::from_mut(state, &mut MCM);
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result).unwrap();
+    
+    let mut total = 0;
+    let mut ids = vec![];
+    let sum = visit_snapshot(&mut &result[..], LATEST_VERSION, &ReadLimits::default(), &RawCodec, 16,
+            &mut |id: EltId, data: &[u8]| {
+                total += data.len();
+                ids.push(id);
+                true
+            }).unwrap();
+    assert_eq!(sum.as_ref(), Some(state.statesum()));
+    assert_eq!(total, state.byte_len());
+    assert_eq!(ids, (1..6).map(EltId::from).collect::<Vec<_>>());
+    
+    let mut n = 0;
+    let sum = visit_snapshot(&mut &result[..], LATEST_VERSION, &ReadLimits::default(), &RawCodec, 16,
+            &mut |_: EltId, _: &[u8]| { n += 1; n < 2 }).unwrap();
+    assert_eq!(sum, None);
+    assert_eq!(n, 2);
+}
+
+#[test]
+fn snapshot_codec() {
+    use state::StateWrite;
+    use commit::MakeCommitMeta;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    struct Reverse;
+    impl Codec for Reverse {
+        fn name(&self) -> &str { "reverse" }
+        fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.extend(data.iter().rev());
+            Ok(())
+        }
+        fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            self.encode(data, out)
+        }
+    }
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    state.insert(EltId::from(1), "forwards".to_string()).unwrap();
+    let state = PartState::from_mut(state, &mut MCM);
+    let mut result = Vec::new();
+    write_snapshot_with(&state, &mut result, LATEST_VERSION, &Reverse, 16).unwrap();
+    assert!(result.windows(8).any(|w| w == b"sdrawrof"));
+    
+    let state2 = read_snapshot_with::<String>(&mut &result[..], LATEST_VERSION,
+            &ReadLimits::default(), &Reverse, 16).unwrap();
     assert_eq!(state, state2);
+    // Sums are of decoded data, so reading without the codec fails:
+    assert!(read_snapshot::<String>(&mut &result[..], LATEST_VERSION,
+            &ReadLimits::default()).is_err());
+}
+
+#[test]
+fn snapshot_unless() {
+    use std::cell::Cell;
+    use state::StateWrite;
+    use commit::MakeCommitMeta;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    // Counts elements decoded
+    struct Counting(Cell<usize>);
+    impl Codec for Counting {
+        fn name(&self) -> &str { "counting" }
+        fn encode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.extend_from_slice(data);
+            Ok(())
+        }
+        fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            self.0.set(self.0.get() + 1);
+            self.encode(data, out)
+        }
+    }
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    state.insert(EltId::from(1), "one".to_string()).unwrap();
+    state.insert(EltId::from(2), "two".to_string()).unwrap();
+    let state = PartState::from_mut(state, &mut MCM);
+    let codec = Counting(Cell::new(0));
+    let mut result = Vec::new();
+    write_snapshot_with(&state, &mut result, LATEST_VERSION, &codec, 16).unwrap();
+    
+    let limits = ReadLimits::default();
+    let statesum = state.statesum().clone();
+    let skipped = read_snapshot_unless::<String>(&mut &result[..], LATEST_VERSION, &limits,
+            &codec, 16, &mut |sum| *sum == statesum).unwrap();
+    assert_eq!(skipped, None);
+    assert_eq!(codec.0.get(), 0);
+    let state2 = read_snapshot_unless::<String>(&mut &result[..], LATEST_VERSION, &limits,
+            &codec, 16, &mut |_| false).unwrap();
+    assert_eq!(state2, Some(state));
+    assert_eq!(codec.0.get(), 2);
 }
@@ -2,7 +2,13 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! For calculating checksums
+//! For calculating checksums.
+//! 
+//! Besides the `Sum` constructors used for elements and states, this provides
+//! `HashReader` and `HashWriter`, which calculate the sum (and optionally a
+//! CRC) of data passing through; applications may use these to calculate
+//! Pippin-compatible sums of external data, e.g. to check whether an element
+//! is already stored before building it.
 
 use std::io::{Read, Write, Result};
 
@@ -25,7 +31,9 @@ fn mk_hasher() -> Hasher {
 }
 
 impl Sum {
-    /// Calculate an element sum
+    /// Calculate an element sum: that of element `elt_id` whose
+    /// serialisation (see `Element::write_buf`) is `data`. This is the sum
+    /// used by the default implementation of `Element::sum`.
     pub fn elt_sum(elt_id: EltId, data: &[u8]) -> Sum {
         let mut hasher = mk_hasher();
         let mut buf = [0u8; 8];
@@ -66,6 +74,38 @@ impl Sum {
         hasher.input(data);
         Sum::load_hasher(hasher)
     }
+    /// Calculate a standard checksum of all data read from `reader`.
+    /// 
+    /// Returns the number of bytes read and the sum. If `prefix_len` is given
+    /// and at least this many bytes are read, the sum of the first
+    /// `prefix_len` bytes is also returned.
+    pub fn calculate_reader(reader: &mut Read, prefix_len: Option<u64>) ->
+            Result<(u64, Sum, Option<Sum>)>
+    {
+        let mut hasher = mk_hasher();
+        let mut prefix_sum = None;
+        let mut len = 0;
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 { break; }
+            let mut data = &buf[..n];
+            if let Some(plen) = prefix_len {
+                if prefix_sum.is_none() && len + n as u64 >= plen {
+                    let split = (plen - len) as usize;
+                    hasher.input(&data[..split]);
+                    prefix_sum = Some(Sum::load_hasher(hasher));
+                    data = &data[split..];
+                }
+            }
+            hasher.input(data);
+            len += n as u64;
+        }
+        if prefix_len == Some(0) && prefix_sum.is_none() {
+            prefix_sum = Some(Sum::load_hasher(hasher));
+        }
+        Ok((len, Sum::load_hasher(hasher), prefix_sum))
+    }
     /// Load from a hasher
     fn load_hasher(mut hasher: Hasher) -> Sum {
         let mut buf = [0u8; SUM_BYTES];
@@ -76,31 +116,82 @@ impl Sum {
 }
 
 
+// —————  CRC32  —————
+
+/// Calculator for CRC-32 (IEEE 802.3 polynomial, as used by zlib), used for
+/// quick verification of file sections.
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    /// Create, with no data input
+    pub fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 { table: table, crc: 0xFFFF_FFFF }
+    }
+    /// Input some data
+    pub fn input(&mut self, data: &[u8]) {
+        let mut crc = self.crc;
+        for b in data {
+            crc = self.table[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+    /// Get the CRC of all data input so far
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+
 // —————  hash calculators  —————
 
+/// A reader which calculates the sum (see `Sum::calculate`) and optionally
+/// a CRC of all data read through it.
 pub struct HashReader<R> {
-    hasher: Hasher,
+    hasher: Option<Hasher>,
+    crc: Option<Crc32>,
     inner: R
 }
 
 impl<R: Read> HashReader<R> {
     /// Create
     pub fn new(r: R) -> HashReader<R> {
-        HashReader { hasher: mk_hasher(), inner: r }
+        HashReader { hasher: Some(mk_hasher()), crc: None, inner: r }
+    }
+    /// Create, calculating a CRC and, if `hash` is true, also a hash sum
+    pub fn with_crc(r: R, hash: bool) -> HashReader<R> {
+        let hasher = if hash { Some(mk_hasher()) } else { None };
+        HashReader { hasher: hasher, crc: Some(Crc32::new()), inner: r }
     }
 }
 
 #[allow(dead_code)]
 impl<R: Read> HashReader<R> {
-    /// Get the hasher's Digest interface
-    pub fn digest(&mut self) -> &mut Digest { &mut self.hasher }
-    /// Make a Sum from the digest
+    /// Get the hasher's Digest interface (panics if not calculating a sum)
+    pub(crate) fn digest(&mut self) -> &mut Digest { self.hasher.as_mut().expect("hasher") }
+    /// Make a Sum from the digest (panics if not calculating a sum). Call
+    /// once, after reading all data.
     pub fn sum(&mut self) -> Sum {
+        let hasher = self.hasher.as_mut().expect("hasher");
         let mut buf = [0u8; SUM_BYTES];
-        assert_eq!(self.hasher.output_bytes(), buf.len());
-        self.hasher.result(&mut buf);
+        assert_eq!(hasher.output_bytes(), buf.len());
+        hasher.result(&mut buf);
         Sum::load(&buf)
     }
+    /// Get the CRC of data read so far, if calculating one
+    pub fn crc(&self) -> Option<u32> {
+        self.crc.as_ref().map(|crc| crc.value())
+    }
     
     /// Get the inner reader
     pub fn inner(&mut self) -> &mut R { &mut self.inner }
@@ -111,35 +202,61 @@ impl<R: Read> HashReader<R> {
 impl<R: Read> Read for HashReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let len = self.inner.read(buf)?;
-        self.hasher.input(&buf[..len]);
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.input(&buf[..len]);
+        }
+        if let Some(ref mut crc) = self.crc {
+            crc.input(&buf[..len]);
+        }
         Ok(len)
     }
 }
 
 
+/// A writer which calculates the sum (see `Sum::calculate`) and optionally
+/// a CRC of all data written through it. `for_elt` instead calculates an
+/// element sum (see `Sum::elt_sum`).
 pub struct HashWriter<W> {
     hasher: Hasher,
+    crc: Option<Crc32>,
     inner: W
 }
 
 impl<W: Write> HashWriter<W> {
     /// Create
     pub fn new(w: W) -> HashWriter<W> {
-        HashWriter { hasher: mk_hasher(), inner: w }
+        HashWriter { hasher: mk_hasher(), crc: None, inner: w }
+    }
+    /// Create, calculating a CRC as well as a hash sum
+    pub fn with_crc(w: W) -> HashWriter<W> {
+        HashWriter { hasher: mk_hasher(), crc: Some(Crc32::new()), inner: w }
+    }
+    /// Create, calculating the element sum (see `Sum::elt_sum`) of the data
+    /// written as the data of element `elt_id`
+    pub fn for_elt(w: W, elt_id: EltId) -> HashWriter<W> {
+        let mut hasher = mk_hasher();
+        let mut buf = [0u8; 8];
+        BigEndian::write_u64(&mut buf, elt_id.into());
+        hasher.input(&buf);
+        HashWriter { hasher: hasher, crc: None, inner: w }
     }
 }
 
 #[allow(dead_code)]
 impl<W: Write> HashWriter<W> {
     /// Get the hasher's Digest interface
-    pub fn digest(&mut self) -> &mut Digest { &mut self.hasher }
-    /// Make a Sum from the digest
+    pub(crate) fn digest(&mut self) -> &mut Digest { &mut self.hasher }
+    /// Make a Sum from the digest. Call once, after writing all data.
     pub fn sum(&mut self) -> Sum {
         let mut buf = [0u8; SUM_BYTES];
         assert_eq!(self.hasher.output_bytes(), buf.len());
         self.hasher.result(&mut buf);
         Sum::load(&buf)
     }
+    /// Get the CRC of data written so far, if calculating one
+    pub fn crc(&self) -> Option<u32> {
+        self.crc.as_ref().map(|crc| crc.value())
+    }
     
     /// Get the inner writer
     pub fn inner(&mut self) -> &mut W { &mut self.inner }
@@ -152,6 +269,9 @@ impl<W: Write> Write for HashWriter<W> {
         let len = self.inner.write(buf)?;
         if len > 0 {
             self.hasher.input(&buf[..len]);
+            if let Some(ref mut crc) = self.crc {
+                crc.input(&buf[..len]);
+            }
         }
         Ok(len)
     }
@@ -160,3 +280,41 @@ impl<W: Write> Write for HashWriter<W> {
         self.inner.flush()
     }
 }
+
+#[test]
+fn crc32() {
+    let mut crc = Crc32::new();
+    assert_eq!(crc.value(), 0);
+    crc.input(b"123456789");
+    assert_eq!(crc.value(), 0xCBF4_3926);
+    crc.input(b"");
+    assert_eq!(crc.value(), 0xCBF4_3926);
+}
+
+#[test]
+fn hash_reader_writer() {
+    use elt::Element;
+    
+    let data = b"some external payload";
+    let mut crc = Crc32::new();
+    crc.input(data);
+    let mut w = HashWriter::with_crc(Vec::new());
+    w.write_all(&data[..5]).unwrap();
+    w.write_all(&data[5..]).unwrap();
+    assert_eq!(w.crc(), Some(crc.value()));
+    assert_eq!(w.sum(), Sum::calculate(data));
+    assert_eq!(w.into_inner(), data.to_vec());
+    
+    let mut r = HashReader::with_crc(&data[..], true);
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data.to_vec());
+    assert_eq!(r.crc(), Some(crc.value()));
+    assert_eq!(r.sum(), Sum::calculate(data));
+    
+    let id = EltId::from(0x1234_5678);
+    let mut w = HashWriter::for_elt(Vec::new(), id);
+    w.write_all(data).unwrap();
+    assert_eq!(w.sum(), Sum::elt_sum(id, data));
+    assert_eq!(Sum::elt_sum(id, data), Element::sum(&data.to_vec(), id));
+}
@@ -18,14 +18,20 @@
 use std::collections::{HashMap};
 use std::collections::hash_map as hs;
 use std::clone::Clone;
+use std::cmp::min;
+use std::ops::Deref;
 use std::rc::Rc;
+use std::vec;
+use std::usize;
 
 use hashindexed::KeyComparator;
 
 use elt::{Element, EltId};
 use sum::Sum;
 use commit::*;
-use error::{ElementOp, PatchOp};
+use control::Control;
+use error::{ElementOp, PatchOp, TipError};
+use part::Partition;
 
 /// Trait abstracting over read operations on the state of a partition or
 /// repository.
@@ -59,6 +65,15 @@ pub trait StateRead<E: Element> {
     /// Low-level version of `get(id)`: returns a reference to the
     /// reference-counted wrapped container of the element.
     fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp>;
+    /// Get references to several elements at once. The result has one entry
+    /// per identifier, in the same order, being `None` where no element is
+    /// available.
+    /// 
+    /// Implementations which need to load data to find elements may override
+    /// this to do so in a single pass.
+    fn get_many(&self, ids: &[EltId]) -> Vec<Option<&E>> {
+        ids.iter().map(|id| self.get(*id).ok()).collect()
+    }
 }
 
 /// Trait abstracting over write operations on the state of a partition or
@@ -113,6 +128,20 @@ pub trait StateWrite<E: Element>: StateRead<E> {
     fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp>;
 }
 
+/// Decides which elements match a search (see `PartState::search`).
+/// 
+/// There is a simple implementation for closures taking the same arguments
+/// as `matches`.
+pub trait Matcher<E: Element> {
+    /// Return true if the element matches
+    fn matches(&self, id: EltId, elt: &E) -> bool;
+}
+impl<E: Element, F: Fn(EltId, &E) -> bool> Matcher<E> for F {
+    fn matches(&self, id: EltId, elt: &E) -> bool {
+        self(id, elt)
+    }
+}
+
 /// A 'state' is the set of elements in a partition at some point in time.
 /// Partitions have multiple states (the latest and each historical state which
 /// has been loaded, possibly also unmerged branches).
@@ -123,16 +152,27 @@ pub trait StateWrite<E: Element>: StateRead<E> {
 /// 
 /// Essentially this holds a map of elements indexed by their identifiers,
 /// partition-metadata and commit-metadata.
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct PartState<E: Element> {
     parents: Vec<Sum>,
     statesum: Sum,
     elts: HashMap<EltId, Rc<E>>,
+    byte_len: usize,
     meta: CommitMeta,
+    // Sum of each element, if checked on access (see `set_verify_on_get`)
+    elt_sums: Option<HashMap<EltId, Sum>>,
+}
+// Equality does not depend on whether sums are checked on access
+impl<E: Element> PartialEq for PartState<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.parents == other.parents && self.statesum == other.statesum &&
+            self.elts == other.elts && self.byte_len == other.byte_len &&
+            self.meta == other.meta
+    }
 }
 
 /// An editable version of `PartState`.
-///
+/// 
 /// Elements may be inserted, deleted or replaced. Direct modification is not
 /// supported.
 /// 
@@ -149,7 +189,57 @@ pub struct MutPartState<E: Element> {
     parent: Sum,
     elt_sum: Sum,
     elts: HashMap<EltId, Rc<E>>,
+    byte_len: usize,
     meta: CommitMetaPartial,
+    quota: Quota,
+}
+
+/// Hard limits on the contents of a state (see `Control::quota()`), keeping
+/// a partition small enough to fit in memory.
+/// 
+/// These are checked by `MutPartState` when inserting or replacing elements
+/// (if set; see `MutPartState::set_quota`) and by `Partition::push_commit`
+/// and `Partition::push_state`. States read from files are not checked.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quota {
+    /// Maximum number of elements
+    pub max_elts: usize,
+    /// Maximum total length of element data in bytes (see
+    /// `PartState::byte_len()`)
+    pub max_bytes: usize,
+    /// Maximum length of the data of any one element in bytes (see
+    /// `Element::byte_len()`)
+    pub max_elt_bytes: usize,
+}
+impl Default for Quota {
+    /// No limits
+    fn default() -> Quota {
+        Quota {
+            max_elts: usize::MAX,
+            max_bytes: usize::MAX,
+            max_elt_bytes: usize::MAX,
+        }
+    }
+}
+impl Quota {
+    /// Check the length of an element's data
+    pub fn check_elt(&self, byte_len: usize) -> Result<(), ElementOp> {
+        if byte_len > self.max_elt_bytes {
+            return Err(ElementOp::EltTooLarge);
+        }
+        Ok(())
+    }
+    
+    /// Check the number of elements and total data length of a state
+    pub fn check_totals(&self, num_elts: usize, byte_len: usize) -> Result<(), ElementOp> {
+        if num_elts > self.max_elts {
+            return Err(ElementOp::EltLimit);
+        }
+        if byte_len > self.max_bytes {
+            return Err(ElementOp::ByteLimit);
+        }
+        Ok(())
+    }
 }
 
 // Constructors
@@ -167,7 +257,9 @@ impl<E: Element> PartState<E> {
             parents: vec![],
             statesum: metasum /* no elts, so statesum = metasum */,
             elts: HashMap::new(),
+            byte_len: 0,
             meta: meta,
+            elt_sums: None,
         }
     }
     
@@ -179,11 +271,14 @@ impl<E: Element> PartState<E> {
             elts: HashMap<EltId, Rc<E>>,
             meta: CommitMeta, elt_sum: Sum) -> PartState<E> {
         let metasum = Sum::state_meta_sum(&parents, &meta);
+        let byte_len = elts.values().map(|elt| elt.byte_len()).sum();
         PartState {
             parents: parents,
             statesum: &metasum ^ &elt_sum,
             elts: elts,
-            meta: meta
+            byte_len: byte_len,
+            meta: meta,
+            elt_sums: None,
         }
     }
     
@@ -196,7 +291,9 @@ impl<E: Element> PartState<E> {
             parents: parents,
             statesum: &mut_state.elt_sum ^ &metasum,
             elts: mut_state.elts,
-            meta: meta
+            byte_len: mut_state.byte_len,
+            meta: meta,
+            elt_sums: None,
         }
     }
     /// Create a `PartState` from a parent `PartState` and a `Commit`.
@@ -215,7 +312,32 @@ impl<E: Element> PartState<E> {
             parents: commit.parents().to_vec(),
             statesum: statesum,
             elts: mut_state.elts,
-            meta: commit.meta().clone()
+            byte_len: mut_state.byte_len,
+            meta: commit.meta().clone(),
+            elt_sums: None,
+        })
+    }
+    /// Create a `PartState` from a base `PartState` and a `Delta` (the base
+    /// need not be a parent of the new state).
+    pub fn from_state_delta(base: &PartState<E>, delta: &Delta<E>) ->
+            Result<PartState<E>, PatchOp>
+    {
+        if base.statesum() != delta.base() { return Err(PatchOp::WrongParent); }
+        let commit = delta.commit();
+        let mut mut_state = base.clone_mut();
+        commit.apply_mut(&mut mut_state)?;
+        
+        let metasum = Sum::state_meta_sum(commit.parents(), commit.meta());
+        let statesum = &mut_state.elt_sum ^ &metasum;
+        if statesum != *commit.statesum() { return Err(PatchOp::PatchApply); }
+        
+        Ok(PartState {
+            parents: commit.parents().to_vec(),
+            statesum: statesum,
+            elts: mut_state.elts,
+            byte_len: mut_state.byte_len,
+            meta: commit.meta().clone(),
+            elt_sums: None,
         })
     }
 }
@@ -257,6 +379,56 @@ impl<E: Element> PartState<E> {
         EltIter { iter: self.elts.iter() }
     }
     
+    /// Iterate over all elements, ordered by identifier. Unlike `elts_iter`,
+    /// the order is repeatable.
+    /// 
+    /// Operation is `O(n log n)` in the number of elements.
+    pub fn elts_ordered(&self) -> vec::IntoIter<(EltId, &Rc<E>)> {
+        let mut elts: Vec<_> = self.elts_iter().collect();
+        elts.sort_by_key(|&(id, _)| id);
+        elts.into_iter()
+    }
+    
+    /// Get a page of up to `len` elements, starting at position `offset` in
+    /// identifier order (see `elts_ordered`). The result is empty if `offset`
+    /// is not less than `len()`.
+    pub fn elts_page(&self, offset: usize, len: usize) -> Vec<(EltId, &Rc<E>)> {
+        let mut elts: Vec<_> = self.elts_ordered().collect();
+        let end = min(offset.saturating_add(len), elts.len());
+        if offset >= end {
+            return vec![];
+        }
+        elts.truncate(end);
+        elts.split_off(offset)
+    }
+    
+    /// Get all elements matching a search, ordered by identifier.
+    /// 
+    /// Elements are tested sequentially: since they are reference-counted
+    /// (`Rc`), they cannot be shared with other threads.
+    pub fn search(&self, matcher: &Matcher<E>) -> Vec<(EltId, &Rc<E>)> {
+        let mut elts: Vec<_> = self.elts_iter()
+                .filter(|&(id, ref elt)| matcher.matches(id, elt))
+                .collect();
+        elts.sort_by_key(|&(id, _)| id);
+        elts
+    }
+    
+    /// Number of elements (equivalent to `num_avail()`)
+    pub fn len(&self) -> usize {
+        self.elts.len()
+    }
+    /// True if there are no elements
+    pub fn is_empty(&self) -> bool {
+        self.elts.is_empty()
+    }
+    /// Total length of the serialised data of all elements, in bytes (see
+    /// `Element::byte_len()`). This excludes metadata and the overhead of
+    /// snapshot files.
+    pub fn byte_len(&self) -> usize {
+        self.byte_len
+    }
+    
     /// As `gen_id()`, but ensure the generated id is free in both self and
     /// another state.
     pub fn gen_id_binary(&self, s2: &PartState<E>) -> Result<EltId, ElementOp> {
@@ -285,7 +457,9 @@ impl<E: Element> PartState<E> {
             parent: self.statesum.clone(),
             elt_sum: self.statesum() ^ &self.metasum(),
             elts: self.elts.clone(),
+            byte_len: self.byte_len,
             meta: CommitMeta::new_partial(self.statesum.clone(), self.meta.clone()),
+            quota: Quota::default(),
         }
     }
     
@@ -300,11 +474,77 @@ impl<E: Element> PartState<E> {
             parents: self.parents.clone(),
             statesum: self.statesum.clone(),
             elts: self.elts.clone(),
+            byte_len: self.byte_len,
             meta: self.meta.clone(),
+            elt_sums: self.elt_sums.clone(),
         }
     }
-}
     
+    /// Create a copy in which each element is passed through `f`, which may
+    /// return the element, a replacement (e.g. a redacted version), or `None`
+    /// to omit it. Parents and metadata are kept.
+    /// 
+    /// The state-sum of the copy is recalculated; it differs from that of
+    /// this state unless no element was changed. This state is not affected.
+    pub fn filter<F>(&self, f: F) -> Self
+            where F: Fn(EltId, &Rc<E>) -> Option<Rc<E>>
+    {
+        let mut elt_sum = Sum::zero();
+        let mut elts = HashMap::new();
+        for (id, elt) in &self.elts {
+            if let Some(elt) = f(*id, elt) {
+                elt_sum.permute(&elt.sum(*id));
+                elts.insert(*id, elt);
+            }
+        }
+        PartState::new_explicit(self.parents.clone(), elts, self.meta.clone(), elt_sum)
+    }
+    
+    /// Recalculate the sum of each element and check against the state sum
+    /// (and against the sums recorded by `set_verify_on_get`, if enabled).
+    /// 
+    /// Sums are checked when reading from files, but data corrupted in memory
+    /// (or elements mutated via interior mutability) would otherwise go
+    /// unnoticed until written. Fails with `ElementOp::EltCorrupt` on
+    /// mismatch.
+    pub fn verify_elements(&self) -> Result<(), ElementOp> {
+        let mut elt_sum = Sum::zero();
+        for (id, elt) in &self.elts {
+            let sum = elt.sum(*id);
+            if let Some(ref sums) = self.elt_sums {
+                if sums.get(id) != Some(&sum) {
+                    error!("Element {} does not match its checksum", id);
+                    return Err(ElementOp::EltCorrupt);
+                }
+            }
+            elt_sum.permute(&sum);
+        }
+        if &elt_sum ^ &self.metasum() != self.statesum {
+            error!("Elements of state {} do not match its checksum", self.statesum);
+            return Err(ElementOp::EltCorrupt);
+        }
+        Ok(())
+    }
+    
+    /// Enable or disable checking of element sums on access (see
+    /// `Control::verify_on_get`). When enabled, the sum of each element is
+    /// recorded now, and `get` and `get_rc` recalculate the element's sum on
+    /// each access, failing with `ElementOp::EltCorrupt` on mismatch. Other
+    /// accessors (e.g. `elts_iter`) do not check; use `verify_elements` to
+    /// check all.
+    pub fn set_verify_on_get(&mut self, verify: bool) {
+        self.elt_sums = if verify {
+            Some(self.elts.iter().map(|(id, elt)| (*id, elt.sum(*id))).collect())
+        } else {
+            None
+        };
+    }
+    /// True if element sums are checked on access (see `set_verify_on_get`)
+    pub fn verify_on_get(&self) -> bool {
+        self.elt_sums.is_some()
+    }
+}
+
 impl<E: Element> MutPartState<E> {
     /// Get the parent's sum
     pub fn parent(&self) -> &Sum { &self.parent }
@@ -317,11 +557,24 @@ impl<E: Element> MutPartState<E> {
         EltIter { iter: self.elts.iter() }
     }
     
+    /// Total length of the serialised data of all elements, in bytes (see
+    /// `PartState::byte_len()`)
+    pub fn byte_len(&self) -> usize {
+        self.byte_len
+    }
+    
     /// Get access to (partial) metadata
     pub fn meta(&self) -> &CommitMetaPartial { &self.meta }
     /// Get write access to metadata
     pub fn meta_mut(&mut self) -> &mut CommitMetaPartial { &mut self.meta }
     
+    /// Get the quota checked on insertion and replacement (by default, no
+    /// limits)
+    pub fn quota(&self) -> &Quota { &self.quota }
+    /// Set the quota checked on insertion and replacement. Existing elements
+    /// are not checked.
+    pub fn set_quota(&mut self, quota: Quota) { self.quota = quota; }
+    
     /// Looks for a free element identifier (randomly).
     /// 
     /// Can fail if nearly all ids are used, but this is highly unlikely,
@@ -356,7 +609,14 @@ impl<E: Element> StateRead<E> for PartState<E> {
         self.elts.contains_key(&id)
     }
     fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
-        self.elts.get(&id).ok_or(ElementOp::EltNotFound)
+        let elt = self.elts.get(&id).ok_or(ElementOp::EltNotFound)?;
+        if let Some(ref sums) = self.elt_sums {
+            if sums.get(&id) != Some(&elt.sum(id)) {
+                error!("Element {} does not match its checksum", id);
+                return Err(ElementOp::EltCorrupt);
+            }
+        }
+        Ok(elt)
     }
 }
 impl<E: Element> StateRead<E> for MutPartState<E> {
@@ -376,7 +636,11 @@ impl<E: Element> StateRead<E> for MutPartState<E> {
 impl<E: Element> StateWrite<E> for MutPartState<E> {
     fn insert_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp> {
         if self.elts.contains_key(&id) { return Err(ElementOp::IdClash); }
+        let elt_len = elt.byte_len();
+        self.quota.check_elt(elt_len)?;
+        self.quota.check_totals(self.elts.len() + 1, self.byte_len.saturating_add(elt_len))?;
         self.elt_sum.permute(&elt.sum(id));
+        self.byte_len += elt_len;
         self.elts.insert(id, elt);
         Ok(id)
     }
@@ -387,8 +651,18 @@ impl<E: Element> StateWrite<E> for MutPartState<E> {
     }
     
     fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp> {
+        let num_elts = self.elts.len();
         match self.elts.entry(id) {
-            hs::Entry::Occupied(ref mut entry) => Ok(entry.insert(elt)),
+            hs::Entry::Occupied(ref mut entry) => {
+                let elt_len = elt.byte_len();
+                let byte_len = self.byte_len - entry.get().byte_len() + elt_len;
+                self.quota.check_elt(elt_len)?;
+                self.quota.check_totals(num_elts, byte_len)?;
+                self.elt_sum.permute(&entry.get().sum(id));
+                self.elt_sum.permute(&elt.sum(id));
+                self.byte_len = byte_len;
+                Ok(entry.insert(elt))
+            },
             hs::Entry::Vacant(_) => Err(ElementOp::EltNotFound),
         }
     }
@@ -398,12 +672,78 @@ impl<E: Element> StateWrite<E> for MutPartState<E> {
             None => Err(ElementOp::EltNotFound),
             Some(removed) => {
                 self.elt_sum.permute(&removed.sum(id));
+                self.byte_len -= removed.byte_len();
                 Ok(removed)
             }
         }
     }
 }
 
+/// A shared, read-only copy of a state (see `Partition::tip_handle`).
+/// 
+/// Unlike a reference obtained via `Partition::tip()`, this is not tied to a
+/// borrow of the partition, and remains valid (unchanged) while the partition
+/// accepts new commits. Cloning a handle is cheap; dereference to access the
+/// state.
+/// 
+/// ### Snapshot isolation
+/// 
+/// Handles give readers snapshot isolation: each reader holds a handle
+/// pinned to one state (the tip when taken, or any state via
+/// `Partition::state_handle`) and sees exactly that state, however many
+/// commits are pushed meanwhile. Readers never block the writer and no
+/// locking is involved. A reader sees newer changes only when it calls
+/// `refresh`, which moves the handle to the current tip; other handles are
+/// not affected.
+/// 
+/// A reader may also write: derive a new state from the handle
+/// (`clone_mut()`) and push it with `Partition::push_state_if_tip`, which
+/// fails with `PatchOp::TipChanged` if another change was committed since
+/// the handle was taken (first committer wins). The reader may then refresh
+/// and retry. (With `push_state` the change is instead accepted as a
+/// second tip, to be merged.)
+#[derive(Debug)]
+pub struct StateHandle<E: Element> {
+    state: Rc<PartState<E>>,
+}
+impl<E: Element> Clone for StateHandle<E> {
+    fn clone(&self) -> StateHandle<E> {
+        StateHandle { state: self.state.clone() }
+    }
+}
+impl<E: Element> StateHandle<E> {
+    /// Move the handle to the current tip of `part`, if different. Returns
+    /// true if the handle changed. Fails if the partition does not have a
+    /// single tip (see `Partition::tip`).
+    pub fn refresh<C>(&mut self, part: &Partition<C>) -> Result<bool, TipError>
+            where C: Control<Element = E>
+    {
+        if part.tip_key()? == self.statesum() {
+            return Ok(false);
+        }
+        *self = part.tip_handle()?;
+        Ok(true)
+    }
+    
+    /// True if the handle refers to the current tip of `part`
+    pub fn is_tip<C>(&self, part: &Partition<C>) -> bool
+            where C: Control<Element = E>
+    {
+        part.tip_key().ok() == Some(self.statesum())
+    }
+}
+impl<E: Element> Deref for StateHandle<E> {
+    type Target = PartState<E>;
+    fn deref(&self) -> &PartState<E> {
+        &self.state
+    }
+}
+impl<E: Element> From<PartState<E>> for StateHandle<E> {
+    fn from(state: PartState<E>) -> StateHandle<E> {
+        StateHandle { state: Rc::new(state) }
+    }
+}
+
 /// Wrapper around underlying iterator structure
 pub struct EltIter<'a, E: 'a> {
     iter: hs::Iter<'a, EltId, Rc<E>>
@@ -432,3 +772,66 @@ impl<E: Element> KeyComparator<PartState<E>, Sum> for PartStateSumComparator {
         value.statesum()
     }
 }
+
+#[test]
+fn replace_updates_elt_sum() {
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let state = PartState::<String>::new(&mut MCM);
+    let id = EltId::from(1);
+    let mut replaced = state.clone_mut();
+    replaced.insert(id, "one".to_string()).unwrap();
+    replaced.replace(id, "two".to_string()).unwrap();
+    let mut inserted = state.clone_mut();
+    inserted.insert(id, "two".to_string()).unwrap();
+    assert_eq!(replaced.elt_sum(), inserted.elt_sum());
+}
+
+#[test]
+fn elts_ordered() {
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let mut state = PartState::new(&mut MCM).clone_mut();
+    for i in [5, 1, 4, 2, 3].iter() {
+        state.insert(EltId::from(*i), format!("element {}", i)).expect("insert");
+    }
+    let state = PartState::from_mut(state, &mut MCM);
+    assert_eq!(state.len(), 5);
+    assert!(!state.is_empty());
+    let ids: Vec<u64> = state.elts_ordered().map(|(id, _)| id.into()).collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    let page: Vec<u64> = state.elts_page(1, 2).into_iter().map(|(id, _)| id.into()).collect();
+    assert_eq!(page, vec![2, 3]);
+    assert_eq!(state.elts_page(4, 10).len(), 1);
+    assert!(state.elts_page(5, 1).is_empty());
+    
+    let ids = [EltId::from(2), EltId::from(7), EltId::from(5)];
+    let elts: Vec<_> = state.get_many(&ids).into_iter().map(|e| e.cloned()).collect();
+    assert_eq!(elts, vec![Some("element 2".to_string()), None, Some("element 5".to_string())]);
+}
+
+#[test]
+fn byte_len() {
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    assert_eq!(state.byte_len(), 0);
+    let id = state.insert_new("four".to_string()).expect("insert");
+    state.insert_new("seven".to_string()).expect("insert");
+    assert_eq!(state.byte_len(), 9);
+    state.replace(id, "two".to_string()).expect("replace");
+    assert_eq!(state.byte_len(), 8);
+    let state = PartState::from_mut(state, &mut MCM);
+    assert_eq!(state.byte_len(), 8);
+    
+    let mut mut_state = state.clone_mut();
+    mut_state.remove(id).expect("remove");
+    let state2 = PartState::from_mut(mut_state, &mut MCM);
+    assert_eq!(state2.byte_len(), 5);
+    let commit = Commit::from_diff(&state, &state2).expect("commit");
+    let state3 = PartState::from_state_commit(&state, &commit).expect("apply");
+    assert_eq!(state3.byte_len(), 5);
+}
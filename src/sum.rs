@@ -44,6 +44,28 @@ impl Sum {
         Sum{ s: s }
     }
     
+    /// Parse from a hexadecimal string (as output by `as_string(false)`).
+    /// Upper and lower case letters are accepted.
+    /// 
+    /// Returns `None` if the string is not the expected length or contains
+    /// invalid characters.
+    pub fn from_hex(string: &str) -> Option<Sum> {
+        let string = string.as_bytes();
+        if string.len() != 2 * SUM_BYTES {
+            return None;
+        }
+        let mut s = [0u8; SUM_BYTES];
+        for i in 0..SUM_BYTES {
+            let hi = (string[2*i] as char).to_digit(16);
+            let lo = (string[2*i + 1] as char).to_digit(16);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => s[i] = (hi * 16 + lo) as u8,
+                _ => return None,
+            }
+        }
+        Some(Sum { s: s })
+    }
+    
     /// Write the checksum bytes to a stream
     pub fn write_to(&self, w: &mut Write) -> Result<()> {
 //         let mut buf = [0u8; 32];
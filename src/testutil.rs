@@ -0,0 +1,370 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: utilities for testing.
+//! 
+//! This provides generators for random elements, states and commits, and
+//! functions checking that these survive being written and read back, and
+//! that commits replay to the states they were created from. The library
+//! uses these for its own tests; they may also be used to test a custom
+//! `Element`, `Control` or `RepoIO` implementation.
+//! 
+//! Generators take a random number generator, so a seeded generator (e.g.
+//! `rand::XorShiftRng`) gives reproducible results. Checks panic on failure
+//! (like `assert!`), hence are only suitable for use in tests.
+//! 
+//! `Workload` generates larger synthetic data sets of fixed-size elements,
+//! as used by the benchmarks (`benches/`).
+
+use std::cmp::min;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use commit::{Commit, MakeCommitMeta};
+use control::Control;
+use elt::{Element, EltId};
+use part::Partition;
+use rw::ReadLimits;
+use rw::header::{FileType, FileHeader, read_head, write_head};
+use rw::snapshot::{read_snapshot, write_snapshot};
+use rw::commitlog::{read_log, start_log, write_commit};
+use state::{PartState, StateWrite};
+
+
+/// Elements which can be generated randomly, for testing.
+pub trait GenElement: Element {
+    /// Generate a random element
+    fn generate<R: Rng>(rng: &mut R) -> Self;
+}
+
+impl GenElement for String {
+    fn generate<R: Rng>(rng: &mut R) -> Self {
+        let len = rng.gen_range(0, 100);
+        rng.gen_iter::<char>().take(len).collect()
+    }
+}
+
+/// A `MakeCommitMeta` using a fixed timestamp, such that generated states
+/// are reproducible.
+#[derive(Debug, Default)]
+pub struct FixedMeta;
+impl MakeCommitMeta for FixedMeta {
+    fn make_commit_timestamp(&self) -> i64 {
+        0
+    }
+}
+
+/// Create a random state derived from `parent` by applying `n` random
+/// changes (insertions, replacements and deletions).
+/// 
+/// The result may have no changes from its parent (other than metadata),
+/// e.g. if an element is inserted then deleted.
+pub fn random_state<E: GenElement, R: Rng>(rng: &mut R, parent: &PartState<E>,
+        n: usize, mcm: &mut MakeCommitMeta) -> PartState<E>
+{
+    let mut state = parent.clone_mut();
+    // Sort, since iteration order of the underlying hash-map is not repeatable:
+    let mut ids: Vec<EltId> = state.elts_iter().map(|(id, _)| id).collect();
+    ids.sort();
+    for _ in 0..n {
+        let op = if ids.is_empty() { 0 } else { rng.gen_range(0, 3) };
+        match op {
+            0 => {
+                let id = state.free_id_near(EltId::from(rng.gen::<u64>()))
+                    .expect("free_id_near");
+                state.insert(id, E::generate(rng)).expect("insert");
+                ids.push(id);
+            },
+            1 => {
+                let id = ids[rng.gen_range(0, ids.len())];
+                state.replace(id, E::generate(rng)).expect("replace");
+            },
+            _ => {
+                let i = rng.gen_range(0, ids.len());
+                let id = ids.swap_remove(i);
+                state.remove(id).expect("remove");
+            },
+        }
+    }
+    PartState::from_mut(state, mcm)
+}
+
+/// Create a random commit on `parent` (see `random_state`), along with the
+/// resulting state. This retries until the commit has at least one change.
+pub fn random_commit<E: GenElement, R: Rng>(rng: &mut R, parent: &PartState<E>,
+        n: usize, mcm: &mut MakeCommitMeta) -> (Commit<E>, PartState<E>)
+{
+    loop {
+        let state = random_state(rng, parent, n, mcm);
+        if let Some(commit) = Commit::from_diff(parent, &state) {
+            return (commit, state);
+        }
+    }
+}
+
+/// Create a linear history of `len` random commits on top of a new initial
+/// state. Each commit makes between 1 and `max_changes` changes.
+/// 
+/// Returns the initial state and a list of commits with the state each
+/// creates.
+pub fn random_history<E: GenElement, R: Rng>(rng: &mut R, len: usize,
+        max_changes: usize, mcm: &mut MakeCommitMeta) ->
+        (PartState<E>, Vec<(Commit<E>, PartState<E>)>)
+{
+    let initial = PartState::new(mcm);
+    let mut history: Vec<(Commit<E>, PartState<E>)> = Vec::with_capacity(len);
+    for _ in 0..len {
+        let n = rng.gen_range(1, max_changes + 1);
+        let next = random_commit(rng, history.last().map_or(&initial, |item| &item.1), n, mcm);
+        history.push(next);
+    }
+    (initial, history)
+}
+
+/// A synthetic workload, for benchmarks and load tests: an initial state of
+/// `num_elts` elements, each `elt_len` random bytes, and a linear history of
+/// `num_commits` commits each replacing `changes` elements (with new data of
+/// the same length). Generation is deterministic given `seed`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Workload {
+    /// Number of elements
+    pub num_elts: usize,
+    /// Length of each element's data, in bytes
+    pub elt_len: usize,
+    /// Number of commits in the history
+    pub num_commits: usize,
+    /// Number of elements replaced by each commit
+    pub changes: usize,
+    /// Seed for the random number generator (must not be all zero)
+    pub seed: [u32; 4],
+}
+impl Workload {
+    /// A workload of `num_elts` elements of `elt_len` bytes, with no history.
+    pub fn new(num_elts: usize, elt_len: usize) -> Workload {
+        Workload {
+            num_elts: num_elts,
+            elt_len: elt_len,
+            num_commits: 0,
+            changes: 1,
+            seed: [0x5eed, 1, 2, 3],
+        }
+    }
+    
+    /// Set the number of commits and the number of changes per commit.
+    pub fn with_commits(mut self, num_commits: usize, changes: usize) -> Workload {
+        self.num_commits = num_commits;
+        self.changes = changes;
+        self
+    }
+    
+    /// Generate the initial state.
+    pub fn state(&self) -> PartState<Vec<u8>> {
+        self.generate_state(&mut XorShiftRng::from_seed(self.seed))
+    }
+    
+    /// Generate the initial state and history: a list of commits, each with
+    /// the state it creates.
+    pub fn history(&self) ->
+            (PartState<Vec<u8>>, Vec<(Commit<Vec<u8>>, PartState<Vec<u8>>)>)
+    {
+        let mut rng = XorShiftRng::from_seed(self.seed);
+        let initial = self.generate_state(&mut rng);
+        let mut history: Vec<(Commit<Vec<u8>>, PartState<Vec<u8>>)> =
+                Vec::with_capacity(self.num_commits);
+        for _ in 0..self.num_commits {
+            let next = {
+                let parent = history.last().map_or(&initial, |item| &item.1);
+                let state = self.replace(&mut rng, parent);
+                let commit = Commit::from_diff(parent, &state).expect("commit with changes");
+                (commit, state)
+            };
+            history.push(next);
+        }
+        (initial, history)
+    }
+    
+    /// Generate the initial state and two states each derived from it by
+    /// one commit (with `changes` replacements), for merging. Returns
+    /// `(common, a, b)`.
+    pub fn fork(&self) -> (PartState<Vec<u8>>, PartState<Vec<u8>>, PartState<Vec<u8>>) {
+        let mut rng = XorShiftRng::from_seed(self.seed);
+        let common = self.generate_state(&mut rng);
+        let a = self.replace(&mut rng, &common);
+        let b = self.replace(&mut rng, &common);
+        (common, a, b)
+    }
+    
+    fn generate_state<R: Rng>(&self, rng: &mut R) -> PartState<Vec<u8>> {
+        let mut state = PartState::new(&mut FixedMeta).clone_mut();
+        for _ in 0..self.num_elts {
+            let id = state.free_id_near(EltId::from(rng.gen::<u64>())).expect("free_id_near");
+            state.insert(id, self.generate_elt(rng)).expect("insert");
+        }
+        PartState::from_mut(state, &mut FixedMeta)
+    }
+    
+    // Replace `changes` elements (chosen randomly; possibly the same one
+    // more than once)
+    fn replace<R: Rng>(&self, rng: &mut R, parent: &PartState<Vec<u8>>) -> PartState<Vec<u8>> {
+        let mut state = parent.clone_mut();
+        let mut ids: Vec<EltId> = state.elts_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        assert!(!ids.is_empty(), "workload with history requires elements");
+        for _ in 0..self.changes {
+            let id = ids[rng.gen_range(0, ids.len())];
+            state.replace(id, self.generate_elt(rng)).expect("replace");
+        }
+        PartState::from_mut(state, &mut FixedMeta)
+    }
+    
+    fn generate_elt<R: Rng>(&self, rng: &mut R) -> Vec<u8> {
+        let mut data = vec![0; self.elt_len];
+        rng.fill_bytes(&mut data);
+        data
+    }
+}
+
+/// Check that a state is unchanged by writing a snapshot file (including
+/// header) and reading it back.
+pub fn check_snapshot_round_trip<E: Element>(state: &PartState<E>) {
+    let header = FileHeader {
+        ftype: FileType::snapshot(),
+        name: "test snapshot".to_string(),
+        user: vec![],
+        codec: None,
+        align: None,
+        historical: false,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).expect("write_head");
+    write_snapshot(state, &mut buf).expect("write_snapshot");
+    
+    let mut r = &buf[..];
+    let head = read_head(&mut r).expect("read_head");
+    assert_eq!(head.name, header.name);
+    let state2 = read_snapshot(&mut r, head.ftype.ver(), &ReadLimits::default()).expect("read_snapshot");
+    assert_eq!(*state, state2);
+}
+
+/// Check that commits are unchanged by writing a commit log (including
+/// header) and reading it back.
+pub fn check_log_round_trip<E: Element>(commits: &[Commit<E>]) {
+    let header = FileHeader {
+        ftype: FileType::commit_log(),
+        name: "test commit log".to_string(),
+        user: vec![],
+        codec: None,
+        align: None,
+        historical: false,
+        sealed: false,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).expect("write_head");
+    start_log(&mut buf).expect("start_log");
+    for commit in commits {
+        write_commit(commit, &mut buf).expect("write_commit");
+    }
+    
+    let mut r = &buf[..];
+    let head = read_head(&mut r).expect("read_head");
+    assert_eq!(head.name, header.name);
+    let mut commits2: Vec<Commit<E>> = Vec::new();
+    read_log(&mut r, &mut commits2, head.ftype.ver(), &ReadLimits::default())
+        .expect("read_log");
+    assert_eq!(commits, &commits2[..]);
+}
+
+/// Check that applying each commit to the previous state yields the state
+/// paired with it (for example, the output of `random_history`).
+pub fn check_replay<E: Element>(initial: &PartState<E>, history: &[(Commit<E>, PartState<E>)]) {
+    let mut state = initial.clone_exact();
+    for &(ref commit, ref expected) in history {
+        state = PartState::from_state_commit(&state, commit).expect("from_state_commit");
+        assert_eq!(state, *expected);
+    }
+}
+
+/// Check that history pushed to a partition replays correctly after writing
+/// to and re-reading from the control's `RepoIO`.
+/// 
+/// The control's `RepoIO` must be empty and must retain data written (thus
+/// `DummyRepoIO` cannot be used). `n` commits are made, with
+/// `Partition::write_full()` called after every few commits (so snapshots
+/// may be written according to the control's snapshot policy).
+/// 
+/// Returns the control, allowing further checks of data written.
+pub fn check_partition_replay<C: Control, R: Rng>(rng: &mut R, control: C, n: usize) -> C
+        where C::Element: GenElement
+{
+    let mut part = Partition::create(control, "replay test").expect("create");
+    let mut i = 0;
+    while i < n {
+        let batch = min(rng.gen_range(1, 5), n - i);
+        for _ in 0..batch {
+            let (commit, _) = {
+                let tip = part.tip().expect("tip");
+                let changes = rng.gen_range(1, 10);
+                random_commit(rng, tip, changes, &mut FixedMeta)
+            };
+            assert!(part.push_commit(commit).expect("push_commit"));
+        }
+        part.write_full().expect("write_full");
+        i += batch;
+    }
+    let tip = part.tip().expect("tip").clone_exact();
+    let num_states = part.states_len();
+    
+    let mut part = Partition::open(part.unwrap_control(), true).expect("open");
+    part.load_all().expect("load_all");
+    assert_eq!(*part.tip().expect("tip after reload"), tip);
+    assert_eq!(part.states_len(), num_states);
+    part.unwrap_control()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, XorShiftRng};
+    use control::DefaultControl;
+    use io::RepoIO;
+    use io::mem::MemRepoIO;
+    
+    #[test]
+    fn random_round_trips() {
+        let mut rng = XorShiftRng::from_seed([7, 1348, 26, 91]);
+        let (initial, history) = random_history::<String, _>(&mut rng, 20, 15, &mut FixedMeta);
+        check_replay(&initial, &history);
+        check_snapshot_round_trip(&initial);
+        for &(_, ref state) in &history {
+            check_snapshot_round_trip(state);
+        }
+        let commits: Vec<_> = history.into_iter().map(|(commit, _)| commit).collect();
+        check_log_round_trip(&commits);
+    }
+    
+    #[test]
+    fn workload() {
+        let workload = Workload::new(50, 20).with_commits(10, 3);
+        let (initial, history) = workload.history();
+        assert_eq!(initial, workload.state());
+        assert_eq!(initial.len(), 50);
+        assert!(initial.elts_iter().all(|(_, elt)| elt.len() == 20));
+        assert_eq!(history.len(), 10);
+        check_replay(&initial, &history);
+        
+        let (common, a, b) = workload.fork();
+        assert_eq!(common, initial);
+        assert!(a.parents() == b.parents() && a != b);
+    }
+    
+    #[test]
+    fn partition_replay() {
+        let mut rng = XorShiftRng::from_seed([3, 14, 159, 2653]);
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let control = check_partition_replay(&mut rng, control, 30);
+        assert!(control.io().ss_len() >= 1);
+    }
+}
@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: line-based three-way merging of text (like `diff3`).
+//! 
+//! This module is only available with the `text-merge` feature. It provides
+//! `merge_text`, which merges two versions of a text given a common ancestor
+//! version, and `TextSolver2W`, a solver applying this to elements exposing
+//! their content as text (including `String`).
+//! 
+//! Lines matching in all three versions are *stable*. Between these, where
+//! only one of the two versions changed the ancestor text, that change is
+//! taken; where both made the same change it is taken once; otherwise the
+//! result is a conflict.
+
+use std::cmp::max;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use elt::Element;
+use merge::{TwoWaySolver, EltMerge};
+
+/// A section of a merged text
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TextHunk {
+    /// Text which merged cleanly
+    Stable(String),
+    /// Text which could not be merged
+    Conflict {
+        /// Text in the ancestor version
+        ancestor: String,
+        /// Text in version A
+        a: String,
+        /// Text in version B
+        b: String,
+    },
+}
+
+/// Merge texts `a` and `b` given their common `ancestor` (use `""` if there
+/// is none).
+/// 
+/// On success returns the merged text. On conflict, returns the whole text
+/// as a list of hunks, with at least one `TextHunk::Conflict`.
+/// 
+/// Operation is `O(n*m)` in the number of lines of the ancestor and each
+/// version.
+pub fn merge_text(ancestor: &str, a: &str, b: &str) -> Result<String, Vec<TextHunk>> {
+    let o = lines(ancestor);
+    let a = lines(a);
+    let b = lines(b);
+    let match_a = matches(&o, &a);
+    let match_b = matches(&o, &b);
+    
+    let mut hunks = Vec::new();
+    let mut stable = String::new();
+    let mut conflict = false;
+    let (mut lo, mut la, mut lb) = (0, 0, 0);
+    // Each stable line ends a chunk; the final chunk ends at the ends of the texts
+    let syncs = (0..o.len())
+        .filter_map(|i| match (match_a[i], match_b[i]) {
+            (Some(ia), Some(ib)) => Some((i, ia, ib)),
+            _ => None,
+        })
+        .chain(Some((o.len(), a.len(), b.len())));
+    for (io, ia, ib) in syncs {
+        let (co, ca, cb) = (&o[lo..io], &a[la..ia], &b[lb..ib]);
+        if ca == co || ca == cb {
+            push_all(&mut stable, cb);
+        } else if cb == co {
+            push_all(&mut stable, ca);
+        } else {
+            conflict = true;
+            if !stable.is_empty() {
+                hunks.push(TextHunk::Stable(stable));
+                stable = String::new();
+            }
+            hunks.push(TextHunk::Conflict {
+                ancestor: co.concat(),
+                a: ca.concat(),
+                b: cb.concat(),
+            });
+        }
+        if io < o.len() {
+            stable.push_str(o[io]);
+        }
+        lo = io + 1;
+        la = ia + 1;
+        lb = ib + 1;
+    }
+    
+    if !conflict {
+        return Ok(stable);
+    }
+    if !stable.is_empty() {
+        hunks.push(TextHunk::Stable(stable));
+    }
+    Err(hunks)
+}
+
+/// Render hunks (e.g. from a failed `merge_text`) as a single text, with
+/// conflicts marked in the style of `diff3 -m`.
+pub fn conflict_markers(hunks: &[TextHunk]) -> String {
+    let mut text = String::new();
+    for hunk in hunks {
+        match *hunk {
+            TextHunk::Stable(ref s) => text.push_str(s),
+            TextHunk::Conflict { ref ancestor, ref a, ref b } => {
+                text.push_str("<<<<<<< A\n");
+                push_line(&mut text, a);
+                text.push_str("||||||| ancestor\n");
+                push_line(&mut text, ancestor);
+                text.push_str("=======\n");
+                push_line(&mut text, b);
+                text.push_str(">>>>>>> B\n");
+            },
+        }
+    }
+    text
+}
+
+/// Solver using `merge_text` on elements which can be viewed as and created
+/// from text. Deletions and conflicts fail, unless `with_markers` was used,
+/// in which case conflicts yield text with conflict markers.
+pub struct TextSolver2W<E: Element> {
+    markers: bool,
+    p: PhantomData<E>
+}
+impl<E: Element> TextSolver2W<E> {
+    /// Create an instance (requires no parameters)
+    pub fn new() -> Self {
+        TextSolver2W { markers: false, p: PhantomData }
+    }
+    /// Create an instance which solves conflicts by writing conflict
+    /// markers (see `conflict_markers`) for the user to resolve later.
+    pub fn with_markers() -> Self {
+        TextSolver2W { markers: true, p: PhantomData }
+    }
+}
+impl<E: Element+AsRef<str>+From<String>> TwoWaySolver<E> for TextSolver2W<E> {
+    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
+        c: Option<&Rc<E>>) -> EltMerge<E>
+    {
+        let (a, b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return EltMerge::Fail,
+        };
+        let ancestor = c.map_or("", |c| text(&**c));
+        match merge_text(ancestor, text(&**a), text(&**b)) {
+            Ok(merged) => EltMerge::Value(Rc::new(E::from(merged))),
+            Err(ref hunks) if self.markers => {
+                EltMerge::Value(Rc::new(E::from(conflict_markers(hunks))))
+            },
+            Err(_) => EltMerge::Fail,
+        }
+    }
+}
+
+fn text<E: AsRef<str>>(elt: &E) -> &str {
+    elt.as_ref()
+}
+
+// Split into lines, keeping line terminators
+fn lines(text: &str) -> Vec<&str> {
+    let mut v = Vec::new();
+    let mut start = 0;
+    for (i, _) in text.match_indices('\n') {
+        v.push(&text[start..i + 1]);
+        start = i + 1;
+    }
+    if start < text.len() {
+        v.push(&text[start..]);
+    }
+    v
+}
+
+// For each line of `o`, the index of the matching line of `x` in a longest
+// common subsequence, if any.
+fn matches(o: &[&str], x: &[&str]) -> Vec<Option<usize>> {
+    let (n, m) = (o.len(), x.len());
+    // lcs[i * (m+1) + j] is the LCS length of o[i..] and x[j..]
+    let mut lcs = vec![0usize; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if o[i] == x[j] {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                max(lcs[(i + 1) * (m + 1) + j], lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+    let mut result = vec![None; n];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if o[i] == x[j] {
+            result[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+fn push_all(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+}
+
+fn push_line(text: &mut String, s: &str) {
+    text.push_str(s);
+    if !s.is_empty() && !s.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::*;
+    
+    #[test]
+    fn clean_merges() {
+        let o = "one\ntwo\nthree\nfour\n";
+        let a = "one\nTWO\nthree\nfour\n";
+        let b = "one\ntwo\nthree\nfour\nfive\n";
+        assert_eq!(merge_text(o, a, b), Ok("one\nTWO\nthree\nfour\nfive\n".to_string()));
+        assert_eq!(merge_text(o, o, o), Ok(o.to_string()));
+        assert_eq!(merge_text(o, a, a), Ok(a.to_string()));
+        assert_eq!(merge_text(o, "four\n", o), Ok("four\n".to_string()));
+        assert_eq!(merge_text("", "x", ""), Ok("x".to_string()));
+    }
+    
+    #[test]
+    fn conflicts() {
+        let o = "one\ntwo\nthree\n";
+        let a = "one\nTWO\nthree\n";
+        let b = "one\n2\nthree\n";
+        let hunks = merge_text(o, a, b).unwrap_err();
+        assert_eq!(hunks, vec![
+            TextHunk::Stable("one\n".to_string()),
+            TextHunk::Conflict {
+                ancestor: "two\n".to_string(),
+                a: "TWO\n".to_string(),
+                b: "2\n".to_string(),
+            },
+            TextHunk::Stable("three\n".to_string()),
+        ]);
+        assert_eq!(conflict_markers(&hunks), "one\n<<<<<<< A\nTWO\n||||||| ancestor\n\
+                two\n=======\n2\n>>>>>>> B\nthree\n");
+    }
+    
+    #[test]
+    fn solver() {
+        let o = Rc::new("a\nb\nc\n".to_string());
+        let a = Rc::new("A\nb\nc\n".to_string());
+        let b = Rc::new("a\nb\nC\n".to_string());
+        let solver = TextSolver2W::new();
+        match solver.solve(Some(&a), Some(&b), Some(&o)) {
+            EltMerge::Value(elt) => assert_eq!(*elt, "A\nb\nC\n"),
+            _ => panic!("expected a merged value"),
+        }
+        assert!(solver.solve(Some(&a), None, Some(&o)) == EltMerge::Fail);
+        assert!(solver.solve(Some(&a), Some(&b), None) == EltMerge::Fail);
+        let solver = TextSolver2W::with_markers();
+        assert!(solver.solve(Some(&a), Some(&b), None) != EltMerge::Fail);
+    }
+}
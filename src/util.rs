@@ -20,7 +20,7 @@ pub fn rtrim<T: cmp::PartialEq + Copy>(s: &[T], pat: T) -> &[T] {
 fn test_rtrim() {
     assert_eq!(rtrim(&[0, 15, 8], 15), &[0, 15, 8]);
     assert_eq!(rtrim(&[0, 15, 8, 8], 8), &[0, 15]);
-    assert_eq!(rtrim(&[2.5], 2.5), &[]);
+    assert_eq!(rtrim(&[2.5], 2.5), &[] as &'static [f64]);
     assert_eq!(rtrim(&[], 'a'), &[] as &'static [char]);
 }
 
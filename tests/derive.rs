@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Test `#[derive(PippinElement)]` (requires the `derive` feature)
+
+#![cfg(feature = "derive")]
+
+#[macro_use]
+extern crate pippin;
+
+use pippin::pip::*;
+
+#[derive(PippinElement, PartialEq, Eq, Debug)]
+struct Contact {
+    name: String,
+    email: String,
+    photo: Vec<u8>,
+}
+
+#[derive(PippinElement, PartialEq, Eq, Debug)]
+struct Tagged<T>(String, T);
+
+#[derive(PippinElement, PartialEq, Eq, Debug)]
+struct Marker;
+
+// Local items named like those used by generated code must not matter
+mod shadowed {
+    #![allow(dead_code)]
+    mod std {}
+    struct Ok;
+    type Result<T> = Option<T>;
+    
+    #[derive(PippinElement, PartialEq, Eq, Debug)]
+    struct Shadowed(String);
+}
+
+fn round_trip<E: Element>(elt: &E) -> E {
+    let mut buf = Vec::new();
+    elt.write_buf(&mut buf).expect("write_buf");
+    E::read_buf(&buf).expect("read_buf")
+}
+
+#[test]
+fn derive_element() {
+    let contact = Contact {
+        name: "Ann".to_string(),
+        email: String::new(),
+        photo: vec![0, 1, 2],
+    };
+    assert_eq!(round_trip(&contact), contact);
+    let tagged = Tagged("friend".to_string(), contact);
+    assert_eq!(round_trip(&tagged), tagged);
+    assert_eq!(round_trip(&Marker), Marker);
+    
+    // Trailing or missing data is an error
+    let mut buf = Vec::new();
+    tagged.write_buf(&mut buf).expect("write_buf");
+    buf.push(0);
+    assert!(Tagged::<Contact>::read_buf(&buf).is_err());
+    buf.truncate(buf.len() - 2);
+    assert!(Tagged::<Contact>::read_buf(&buf).is_err());
+    assert!(Marker::read_buf(&[0]).is_err());
+    
+    // Elements can be stored as usual
+    let control = DefaultControl::<Tagged<Contact>, _>::new(MemRepoIO::new());
+    let mut part = Partition::create(control, "derive").expect("create");
+    let mut state = part.tip().expect("tip").clone_mut();
+    let id = state.insert_new(tagged).expect("insert");
+    part.push_state(state).expect("push_state");
+    part.write_fast().expect("write_fast");
+    let io = part.unwrap_control().unwrap_io();
+    let part = Partition::open(DefaultControl::<Tagged<Contact>, _>::new(io), true)
+            .expect("open");
+    assert_eq!(part.tip().expect("tip").get(id).expect("get").0, "friend");
+}
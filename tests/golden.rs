@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Golden-file tests of the file format: files written must match those in
+//! `tests/data/golden` byte for byte, and those files must remain readable.
+//! 
+//! If the format is changed deliberately, the fixtures must be replaced (and
+//! `doc/file-format.md` and the format version updated); run with
+//! `PIPPIN_WRITE_GOLDEN` set to a directory to write new files there.
+
+extern crate pippin;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use pippin::pip::*;
+
+// (snapshot number, log number if a commit log, expected content)
+const FILES: [(usize, Option<usize>, &'static [u8]); 5] = [
+    (0, None, include_bytes!("data/golden/golden-ss0.pip")),
+    (0, Some(0), include_bytes!("data/golden/golden-ss0-cl0.piplog")),
+    (0, Some(1), include_bytes!("data/golden/golden-ss0-cl1.piplog")),
+    (1, None, include_bytes!("data/golden/golden-ss1.pip")),
+    (1, Some(0), include_bytes!("data/golden/golden-ss1-cl0.piplog")),
+];
+
+const TIP: &'static str = "E109C538F40CEA833FDA593949D3FB7ECC70004FDB308C802D67CD27C45375A4";
+
+fn timestamp() -> i64 {
+    1_500_000_000
+}
+
+fn control(io: MemRepoIO) -> DefaultControl<String, MemRepoIO> {
+    let mut control = DefaultControl::new(io);
+    control.set_timestamp_source(timestamp);
+    control
+}
+
+// Write a partition with fixed content and timestamps
+fn write_golden() -> MemRepoIO {
+    let mut part = Partition::create(control(MemRepoIO::new()), "golden").expect("create");
+    let mut state = part.tip().expect("tip").clone_mut();
+    state.insert(EltId::from(1), "one".to_string()).expect("insert");
+    state.insert(EltId::from(2), "two".to_string()).expect("insert");
+    state.insert(EltId::from(3), "three".to_string()).expect("insert");
+    part.push_state(state).expect("push_state");
+    part.write_full().expect("write_full");
+    
+    let mut state = part.tip().expect("tip").clone_mut();
+    state.replace(EltId::from(2), "deux".to_string()).expect("replace");
+    state.remove(EltId::from(3)).expect("remove");
+    part.push_state(state).expect("push_state");
+    part.write_full().expect("write_full");
+    part.write_snapshot().expect("write_snapshot");
+    
+    let mut state = part.tip().expect("tip").clone_mut();
+    state.insert(EltId::from(0x1234_5678_9ABC), "big id".to_string()).expect("insert");
+    part.push_state(state).expect("push_state");
+    part.write_full().expect("write_full");
+    assert_eq!(part.tip_key().expect("tip").as_string(false), TIP);
+    
+    part.unwrap_control().unwrap_io()
+}
+
+fn file_name(ss: usize, cl: Option<usize>) -> String {
+    match cl {
+        None => format!("golden-ss{}.pip", ss),
+        Some(cl) => format!("golden-ss{}-cl{}.piplog", ss, cl),
+    }
+}
+
+#[test]
+fn golden_write() {
+    let io = write_golden();
+    if let Ok(dir) = env::var("PIPPIN_WRITE_GOLDEN") {
+        for &(ss, cl, _) in &FILES {
+            let data = match cl {
+                None => io.ss_data(ss),
+                Some(cl) => io.ss_cl_data(ss, cl),
+            }.expect("file written");
+            let mut file = File::create(Path::new(&dir).join(file_name(ss, cl))).expect("create");
+            file.write_all(data).expect("write");
+        }
+    }
+    
+    assert_eq!((io.ss_len(), io.ss_cl_len(0), io.ss_cl_len(1)), (2, 2, 1));
+    for &(ss, cl, expected) in &FILES {
+        let data = match cl {
+            None => io.ss_data(ss),
+            Some(cl) => io.ss_cl_data(ss, cl),
+        };
+        assert!(data == Some(expected), "{} differs from golden file", file_name(ss, cl));
+    }
+}
+
+#[test]
+fn golden_read() {
+    let mut io = MemRepoIO::new();
+    for &(ss, cl, data) in &FILES {
+        let mut w = match cl {
+            None => io.new_ss(ss),
+            Some(cl) => io.new_ss_cl(ss, cl),
+        }.expect("new file").expect("file is new");
+        w.write_all(data).expect("write");
+    }
+    
+    let mut part = Partition::open(control(io), true).expect("open");
+    part.load_all().expect("load_all");
+    part.verify_files().expect("verify_files");
+    assert_eq!(part.tip_key().expect("tip").as_string(false), TIP);
+    let tip = part.tip().expect("tip");
+    let mut elts: Vec<_> = tip.elts_iter().map(|(id, elt)| (id, (**elt).clone())).collect();
+    elts.sort();
+    assert_eq!(elts, vec![(EltId::from(1), "one".to_string()),
+            (EltId::from(2), "deux".to_string()),
+            (EltId::from(0x1234_5678_9ABC), "big id".to_string())]);
+    assert_eq!(part.states_len(), 4);
+    
+    let history = part.load_meta().expect("load_meta");
+    let timestamps: Vec<_> = history.commits.iter().map(|info| info.meta.timestamp()).collect();
+    assert_eq!(timestamps, vec![timestamp(); 3]);
+}